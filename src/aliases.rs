@@ -0,0 +1,63 @@
+use crate::plausible::normalize_name;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Names that refer to the same artist, folded into one canonical row when
+/// duplicates are merged. Loaded from a TOML or JSON file mapping each
+/// canonical name to its aliases:
+///
+/// ```toml
+/// "Kanye West" = ["Ye", "Yeezy"]
+/// ```
+#[derive(Debug, Default)]
+pub struct Aliases {
+    /// Lowercased, normalized alias or canonical name to canonical name.
+    canonical: HashMap<String, String>,
+}
+
+impl Aliases {
+    /// Reads `path`, as JSON when it ends in `.json` and TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let map: HashMap<String, Vec<String>> =
+            if path.extension().is_some_and(|extension| extension == "json") {
+                serde_json::from_str(&contents).map_err(|e| e.to_string())
+            } else {
+                toml::from_str(&contents).map_err(|e| e.to_string())
+            }
+            .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+
+        let mut canonical = HashMap::new();
+        for (name, aliases) in &map {
+            let name = normalize_name(name);
+            for alias in aliases.iter().map(|alias| normalize_name(alias)).chain([name.clone()]) {
+                let key = alias.to_lowercase();
+                match canonical.get(&key) {
+                    Some(existing) if *existing != name => {
+                        return Err(format!(
+                            "Invalid {}: `{}` is listed under both `{}` and `{}`",
+                            path.display(),
+                            alias,
+                            existing,
+                            name
+                        ));
+                    }
+                    _ => {
+                        canonical.insert(key, name.clone());
+                    }
+                }
+            }
+        }
+        Ok(Aliases { canonical })
+    }
+
+    /// The canonical name for a normalized `name`, if it is an alias.
+    pub fn canonical(&self, name: &str) -> Option<&str> {
+        self.canonical.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.canonical.len()
+    }
+}
@@ -0,0 +1,179 @@
+/// Longest label, in characters, before it is cut short with an ellipsis.
+const MAX_LABEL_CHARS: usize = 32;
+
+/// Named colors accepted by `?color=`, as shields.io spells them.
+const COLORS: [(&str, &str); 9] = [
+    ("brightgreen", "#4c1"),
+    ("green", "#97ca00"),
+    ("yellowgreen", "#a4a61d"),
+    ("yellow", "#dfb317"),
+    ("orange", "#fe7d37"),
+    ("red", "#e05d44"),
+    ("blue", "#007ec6"),
+    ("lightgrey", "#9f9f9f"),
+    ("grey", "#555"),
+];
+
+pub const DEFAULT_COLOR: &str = "#007ec6";
+pub const NOT_FOUND_COLOR: &str = "#9f9f9f";
+
+/// Resolves a `?color=` value: a shields.io color name, or a 3 or 6 digit
+/// hex color with or without its `#`.
+pub fn color(value: &str) -> Option<String> {
+    if let Some((_, hex)) = COLORS.iter().find(|(name, _)| name.eq_ignore_ascii_case(value)) {
+        return Some(hex.to_string());
+    }
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    (matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| format!("#{}", hex))
+}
+
+pub fn accepted_colors() -> Vec<&'static str> {
+    COLORS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(["a hex color"])
+        .collect()
+}
+
+/// `n` abbreviated to three significant figures at most: `987`, `12.3k`,
+/// `4.56M`.
+pub fn human(n: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1_000_000_000, "B"), (1_000_000, "M"), (1_000, "k")];
+    for (scale, unit) in UNITS {
+        if n >= scale {
+            let value = n as f64 / scale as f64;
+            let decimals = if value >= 100.0 {
+                0
+            } else if value >= 10.0 {
+                1
+            } else {
+                2
+            };
+            let formatted = format!("{:.*}", decimals, value);
+            let formatted = if formatted.contains('.') {
+                formatted.trim_end_matches('0').trim_end_matches('.')
+            } else {
+                &formatted
+            };
+            return format!("{}{}", formatted, unit);
+        }
+    }
+    n.to_string()
+}
+
+/// `n` with thousands separators: `12,345`.
+pub fn exact(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// A flat, shields.io-style badge: `label` on grey, `value` on `color`.
+/// `color` must already be resolved by [`color`].
+pub fn svg(label: &str, value: &str, color: &str) -> String {
+    let label = truncate(label);
+    let (label_width, value_width) = (text_width(&label) + 10, text_width(value) + 10);
+    let width = label_width + value_width;
+    let (label, value) = (escape(&label), escape(value));
+    let (label_x, value_x) = (label_width * 5, label_width * 10 + value_width * 5);
+
+    format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" "##,
+            r##"aria-label="{label}: {value}"><title>{label}: {value}</title>"##,
+            r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" "##,
+            r##"stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>"##,
+            r##"<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/>"##,
+            r##"</clipPath>"##,
+            r##"<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/>"##,
+            r##"<rect x="{label_width}" width="{value_width}" height="20" fill="{color}"/>"##,
+            r##"<rect width="{width}" height="20" fill="url(#s)"/></g>"##,
+            r##"<g fill="#fff" text-anchor="middle" "##,
+            r##"font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="110" "##,
+            r##"transform="scale(.1)">"##,
+            r##"<text x="{label_x}" y="150" fill="#010101" fill-opacity=".3">{label}</text>"##,
+            r##"<text x="{label_x}" y="140">{label}</text>"##,
+            r##"<text x="{value_x}" y="150" fill="#010101" fill-opacity=".3">{value}</text>"##,
+            r##"<text x="{value_x}" y="140">{value}</text></g></svg>"##,
+        ),
+        width = width,
+        label_width = label_width,
+        value_width = value_width,
+        label_x = label_x,
+        value_x = value_x,
+        label = label,
+        value = value,
+        color = color,
+    )
+}
+
+fn truncate(label: &str) -> String {
+    if label.chars().count() <= MAX_LABEL_CHARS {
+        return label.to_string();
+    }
+    let mut short: String = label.chars().take(MAX_LABEL_CHARS - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Approximate rendered width in pixels of `text` in 11px Verdana, close
+/// enough to size the segments without bundling font metrics.
+fn text_width(text: &str) -> u32 {
+    let tenths: u32 = text
+        .chars()
+        .map(|c| match c {
+            'i' | 'j' | 'l' | '.' | ',' | ':' | ';' | '\'' | '!' | '|' => 35,
+            'f' | 'r' | 't' | 'I' | '(' | ')' | '[' | ']' | ' ' | '-' => 45,
+            'm' | 'w' | 'M' | 'W' => 100,
+            'A'..='Z' => 75,
+            _ => 68,
+        })
+        .sum();
+    tenths.div_ceil(10)
+}
+
+/// Escapes text for XML content and attribute values. Characters XML 1.0
+/// doesn't allow at all, even escaped, such as most C0 controls, become
+/// U+FFFD so the document still parses.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => out.push(char::REPLACEMENT_CHARACTER),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_replaces_what_xml_disallows() {
+        assert_eq!(escape("<a & 'b'>\"c\""), "&lt;a &amp; &apos;b&apos;&gt;&quot;c&quot;");
+        assert_eq!(escape("\u{0}\u{1b}x\u{ffff}"), "\u{fffd}\u{fffd}x\u{fffd}");
+        assert_eq!(escape("\t\n\r\u{20}\u{d7ff}\u{e000}"), "\t\n\r\u{20}\u{d7ff}\u{e000}");
+    }
+
+    #[test]
+    fn badge_with_control_characters_stays_well_formed() {
+        let badge = svg("visitors\u{7}", "12\u{0}k", "#4c1");
+        assert!(!badge.chars().any(|c| c < ' '), "{}", badge);
+        assert!(badge.contains("<title>visitors\u{fffd}: 12\u{fffd}k</title>"), "{}", badge);
+    }
+}
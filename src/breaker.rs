@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stops sending requests to an upstream that keeps failing. After
+/// `threshold` consecutive failures the circuit opens for `cooldown`, during
+/// which every request is refused without touching the network. The first
+/// request after the cooldown is let through as a probe: success closes the
+/// circuit, failure reopens it for another cooldown.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A probe is in flight. Should it never report back, another is let
+    /// through once `cooldown` has passed since `since`.
+    HalfOpen { since: Instant },
+}
+
+/// Point-in-time view of the breaker for logs and status endpoints.
+#[derive(Debug, Serialize)]
+pub struct BreakerStatus {
+    pub state: &'static str,
+    pub consecutive_failures: u32,
+    /// Seconds until the next probe is allowed, while open.
+    pub next_probe_in_secs: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Asks to send a request. `Err` carries how long until the next probe
+    /// may go out.
+    pub fn acquire(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            State::HalfOpen { since } if now < since + self.cooldown => {
+                Err(since + self.cooldown - now)
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                tracing::info!("Circuit half-open, probing upstream");
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            tracing::info!("Upstream probe succeeded, circuit closed");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + self.cooldown;
+        match *state {
+            State::Closed { failures } if failures + 1 < self.threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            State::Closed { failures } => {
+                tracing::warn!(
+                    "Circuit opened after {} consecutive upstream failures, next probe in {:?}",
+                    failures + 1,
+                    self.cooldown
+                );
+                *state = State::Open { until };
+            }
+            State::HalfOpen { .. } => {
+                tracing::warn!(
+                    "Upstream probe failed, circuit reopened, next probe in {:?}",
+                    self.cooldown
+                );
+                *state = State::Open { until };
+            }
+            State::Open { .. } => {}
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let state = *self.state.lock().unwrap();
+        let now = Instant::now();
+        match state {
+            State::Closed { failures } => BreakerStatus {
+                state: "closed",
+                consecutive_failures: failures,
+                next_probe_in_secs: None,
+            },
+            State::Open { until } => BreakerStatus {
+                state: "open",
+                consecutive_failures: self.threshold,
+                next_probe_in_secs: Some(until.saturating_duration_since(now).as_secs()),
+            },
+            State::HalfOpen { .. } => BreakerStatus {
+                state: "half_open",
+                consecutive_failures: self.threshold,
+                next_probe_in_secs: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn opened() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..3 {
+            assert!(breaker.acquire().is_ok());
+            breaker.record_failure();
+        }
+        breaker
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..2 {
+            breaker.record_failure();
+            assert!(breaker.acquire().is_ok());
+        }
+        breaker.record_failure();
+
+        let retry_in = breaker.acquire().unwrap_err();
+        assert!(retry_in <= COOLDOWN, "{:?}", retry_in);
+        assert_eq!(breaker.status().state, "open");
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.status().consecutive_failures, 2);
+    }
+
+    #[test]
+    fn lets_one_probe_through_after_the_cooldown() {
+        let breaker = opened();
+        std::thread::sleep(COOLDOWN + Duration::from_millis(10));
+
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.status().state, "half_open");
+        // The probe hasn't reported back, so nothing else goes out.
+        assert!(breaker.acquire().is_err());
+    }
+
+    #[test]
+    fn probe_result_closes_or_reopens_the_circuit() {
+        let breaker = opened();
+        std::thread::sleep(COOLDOWN + Duration::from_millis(10));
+        assert!(breaker.acquire().is_ok());
+        breaker.record_failure();
+        assert!(breaker.acquire().is_err());
+        assert_eq!(breaker.status().state, "open");
+
+        std::thread::sleep(COOLDOWN + Duration::from_millis(10));
+        assert!(breaker.acquire().is_ok());
+        breaker.record_success();
+        assert!(breaker.acquire().is_ok());
+        assert_eq!(breaker.status().state, "closed");
+    }
+}
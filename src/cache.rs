@@ -0,0 +1,442 @@
+use crate::fetcher::Validators;
+use crate::plausible::Payload;
+use crate::upstream::QueryKind;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Identifies one cached upstream query: a route plus the parameters that
+/// affect the upstream request, sorted so equivalent requests share an entry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    pub fn new<'a>(route: &str, params: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut params: Vec<_> = params.into_iter().collect();
+        params.sort_unstable();
+
+        let mut key = route.to_string();
+        for (i, (name, value)) in params.into_iter().enumerate() {
+            key.push(if i == 0 { '?' } else { '&' });
+            key.push_str(name);
+            key.push('=');
+            key.push_str(value);
+        }
+        CacheKey(key)
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Where a response body came from, reported in `X-Cache`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Stale,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+        }
+    }
+}
+
+pub struct CacheEntry {
+    /// Upstream body exactly as received, served when no transformation
+    /// applies.
+    pub data: String,
+    /// `data` parsed into its typed form.
+    pub payload: Payload,
+    /// Strong validator derived from `data`, already quoted for the header.
+    pub etag: String,
+    /// Digest of `payload`, which a refresh compares to tell whether the
+    /// data really changed; see [`content_hash`].
+    pub content_hash: String,
+    /// What the upstream sent to revalidate `data` with, if anything.
+    pub validators: Validators,
+    /// How long the entry stays fresh, when not `cache_ttl`.
+    pub ttl: Option<Duration>,
+    pub timestamp: Instant,
+    /// Wall-clock counterpart of `timestamp`, which survives restarts.
+    pub fetched_at: SystemTime,
+    /// When `payload` last differed from the one before it; earlier than
+    /// `fetched_at` once refreshes have confirmed it unchanged.
+    pub changed_at: SystemTime,
+}
+
+impl CacheEntry {
+    pub fn new(data: String, payload: Payload) -> Self {
+        Self::fetched_at(data, payload, SystemTime::now())
+    }
+
+    /// Rebuilds an entry fetched at `fetched_at`, back-dating `timestamp` so
+    /// its age carries over.
+    pub fn fetched_at(data: String, payload: Payload, fetched_at: SystemTime) -> Self {
+        let etag = etag(&data);
+        let content_hash = content_hash(&payload);
+        let age = SystemTime::now()
+            .duration_since(fetched_at)
+            .unwrap_or_default();
+        let now = Instant::now();
+        CacheEntry {
+            data,
+            payload,
+            etag,
+            content_hash,
+            validators: Validators::default(),
+            ttl: None,
+            timestamp: now.checked_sub(age).unwrap_or(now),
+            fetched_at,
+            changed_at: fetched_at,
+        }
+    }
+
+    /// A copy fetched just now, for when the upstream confirms `data` is
+    /// still current or sends data with the same `content_hash`.
+    pub fn revalidated(&self, validators: Validators) -> Self {
+        CacheEntry {
+            data: self.data.clone(),
+            payload: self.payload.clone(),
+            etag: self.etag.clone(),
+            content_hash: self.content_hash.clone(),
+            validators,
+            ttl: self.ttl,
+            timestamp: Instant::now(),
+            fetched_at: SystemTime::now(),
+            changed_at: self.changed_at,
+        }
+    }
+}
+
+/// Strong validator for `data`, quoted for use as an `ETag` header value.
+pub fn etag(data: impl AsRef<[u8]>) -> String {
+    format!("\"{:x}\"", Sha256::digest(data.as_ref()))
+}
+
+/// Digest of `payload` as parsed, so bodies differing only in whitespace,
+/// key order or number formatting hash the same.
+pub fn content_hash(payload: &Payload) -> String {
+    let canonical = match payload {
+        Payload::Leaderboard(response) => serde_json::to_vec(response),
+        Payload::Timeseries(series) => serde_json::to_vec(series),
+        Payload::Breakdown(breakdown) => serde_json::to_vec(breakdown),
+        Payload::Realtime(visitors) => serde_json::to_vec(visitors),
+    };
+    format!("{:x}", Sha256::digest(canonical.expect("payloads serialize")))
+}
+
+struct Slot {
+    entry: Arc<CacheEntry>,
+    /// Value of `Cache::clock` at the last lookup. Atomic so lookups only
+    /// need a shared borrow.
+    last_used: AtomicU64,
+}
+
+/// Bounded map of cache entries. Inserting evicts least recently used
+/// entries until there are fewer than `max_entries` and the bodies, the new
+/// one included, fit in `max_bytes`.
+pub struct Cache {
+    slots: HashMap<CacheKey, Slot>,
+    max_entries: usize,
+    max_bytes: usize,
+    /// Total length of the cached bodies.
+    bytes: usize,
+    clock: AtomicU64,
+    evictions: u64,
+    rejections: u64,
+}
+
+/// How full a `Cache` is, for the metrics and health routes.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub evictions: u64,
+    /// Entries never stored because their body alone was over `max_bytes`.
+    pub rejections: u64,
+}
+
+impl Cache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Cache {
+            slots: HashMap::new(),
+            max_entries: max_entries.max(1),
+            max_bytes,
+            bytes: 0,
+            clock: AtomicU64::new(0),
+            evictions: 0,
+            rejections: 0,
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<CacheEntry>> {
+        let slot = self.slots.get(key)?;
+        slot.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(slot.entry.clone())
+    }
+
+    /// Stores `entry` in place of whatever `key` held. An entry whose body
+    /// is over `max_bytes` by itself only drops the old one.
+    pub fn insert(&mut self, key: CacheKey, entry: Arc<CacheEntry>) {
+        if let Some(old) = self.slots.remove(&key) {
+            self.bytes -= old.entry.data.len();
+        }
+        let size = entry.data.len();
+        if size > self.max_bytes {
+            self.rejections += 1;
+            tracing::warn!(
+                "Not caching {}: its {} bytes are over the {}-byte budget",
+                key,
+                size,
+                self.max_bytes
+            );
+            return;
+        }
+        while self.slots.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+            self.evict_lru();
+        }
+
+        self.bytes += size;
+        let last_used = AtomicU64::new(self.tick());
+        self.slots.insert(key, Slot { entry, last_used });
+    }
+
+    /// Removes every entry, returning what was dropped.
+    pub fn clear(&mut self) -> Vec<(CacheKey, Arc<CacheEntry>)> {
+        self.bytes = 0;
+        self.slots
+            .drain()
+            .map(|(key, slot)| (key, slot.entry))
+            .collect()
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            entries: self.slots.len(),
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+            evictions: self.evictions,
+            rejections: self.rejections,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&CacheKey, &Arc<CacheEntry>)> {
+        self.slots.iter().map(|(key, slot)| (key, &slot.entry))
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn evict_lru(&mut self) {
+        let oldest = self
+            .slots
+            .iter()
+            .min_by_key(|(_, slot)| slot.last_used.load(Ordering::Relaxed))
+            .map(|(key, _)| key.clone());
+
+        if let Some((key, slot)) = oldest.and_then(|key| self.slots.remove_entry(&key)) {
+            self.bytes -= slot.entry.data.len();
+            self.evictions += 1;
+            tracing::info!(
+                "Evicted cache entry {} ({} entries, {} bytes, {} evictions)",
+                key,
+                self.slots.len(),
+                self.bytes,
+                self.evictions
+            );
+        }
+    }
+}
+
+/// On-disk form of the cache, written to `CACHE_FILE`.
+#[derive(Serialize, Deserialize)]
+struct PersistedCache {
+    entries: Vec<PersistedEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    key: CacheKey,
+    kind: QueryKind,
+    data: String,
+    /// Seconds since the Unix epoch.
+    fetched_at: u64,
+    #[serde(default)]
+    validators: Validators,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_secs: Option<u64>,
+}
+
+/// Loads previously persisted entries into `cache`, skipping any whose body
+/// no longer parses. Missing, unreadable or corrupt files are logged and
+/// ignored so they never block startup.
+pub async fn load(path: &Path, cache: &mut Cache) {
+    let contents = match tokio::fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!("Ignoring unreadable cache file {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    let persisted: PersistedCache = match serde_json::from_slice(&contents) {
+        Ok(persisted) => persisted,
+        Err(e) => {
+            tracing::warn!("Ignoring corrupt cache file {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    for entry in persisted.entries {
+        let payload = match Payload::parse(entry.kind, &entry.data) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!("Ignoring invalid cache file entry {}: {}", entry.key, e);
+                continue;
+            }
+        };
+
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(entry.fetched_at);
+        let loaded = CacheEntry {
+            validators: entry.validators,
+            ttl: entry.ttl_secs.map(Duration::from_secs),
+            ..CacheEntry::fetched_at(entry.data, payload, fetched_at)
+        };
+        tracing::info!(
+            "Loaded cache entry {} from {} ({}s old)",
+            entry.key,
+            path.display(),
+            loaded.timestamp.elapsed().as_secs()
+        );
+        cache.insert(entry.key, Arc::new(loaded));
+    }
+}
+
+/// Serializes every entry in `cache` for `save`. Kept separate so the cache
+/// lock need not be held during file I/O.
+pub fn snapshot(cache: &Cache) -> Vec<u8> {
+    let persisted = PersistedCache {
+        entries: cache
+            .iter()
+            .map(|(key, entry)| PersistedEntry {
+                key: key.clone(),
+                kind: entry.payload.kind(),
+                data: entry.data.clone(),
+                fetched_at: entry
+                    .fetched_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                validators: entry.validators.clone(),
+                ttl_secs: entry.ttl.map(|ttl| ttl.as_secs()),
+            })
+            .collect(),
+    };
+    serde_json::to_vec(&persisted).expect("PersistedCache serializes")
+}
+
+/// Writes a `snapshot` to `path` via a temporary file and rename, so a crash
+/// mid-write never leaves a truncated cache behind.
+pub async fn save(path: &Path, contents: Vec<u8>) {
+    let tmp = path.with_extension("tmp");
+    let result = async {
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("Failed to write cache file {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::new(name, [])
+    }
+
+    /// An entry whose body is `bytes` long.
+    fn entry(bytes: usize) -> Arc<CacheEntry> {
+        Arc::new(CacheEntry::new("0".repeat(bytes), Payload::Realtime(0)))
+    }
+
+    fn keys(cache: &Cache) -> Vec<String> {
+        let mut keys: Vec<String> = cache.iter().map(|(key, _)| key.to_string()).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn byte_pressure_evicts_least_recently_used_first() {
+        let mut cache = Cache::new(100, 300);
+        cache.insert(key("/a"), entry(100));
+        cache.insert(key("/b"), entry(100));
+        cache.insert(key("/c"), entry(100));
+        // `/a` is now more recent than `/b`.
+        cache.get(&key("/a"));
+
+        cache.insert(key("/d"), entry(100));
+        assert_eq!(keys(&cache), ["/a", "/c", "/d"]);
+
+        // Needs two slots' worth: `/c` then `/a` go, `/d` was used last.
+        cache.get(&key("/d"));
+        cache.insert(key("/e"), entry(200));
+        assert_eq!(keys(&cache), ["/d", "/e"]);
+
+        let usage = cache.usage();
+        assert_eq!((usage.entries, usage.bytes, usage.evictions), (2, 300, 3));
+    }
+
+    #[test]
+    fn replacing_an_entry_frees_its_old_bytes() {
+        let mut cache = Cache::new(100, 300);
+        cache.insert(key("/a"), entry(100));
+        cache.insert(key("/b"), entry(150));
+        cache.insert(key("/a"), entry(150));
+        assert_eq!(keys(&cache), ["/a", "/b"]);
+        assert_eq!(cache.usage().bytes, 300);
+        assert_eq!(cache.usage().evictions, 0);
+    }
+
+    #[test]
+    fn oversized_entry_is_rejected_and_drops_the_old_one() {
+        let mut cache = Cache::new(100, 300);
+        cache.insert(key("/a"), entry(100));
+        cache.insert(key("/b"), entry(100));
+        cache.insert(key("/a"), entry(301));
+        assert_eq!(keys(&cache), ["/b"]);
+
+        let usage = cache.usage();
+        assert_eq!((usage.bytes, usage.evictions, usage.rejections), (100, 0, 1));
+    }
+
+    #[test]
+    fn entry_count_is_bounded_too() {
+        let mut cache = Cache::new(2, 1000);
+        cache.insert(key("/a"), entry(1));
+        cache.insert(key("/b"), entry(1));
+        cache.get(&key("/a"));
+        cache.insert(key("/c"), entry(1));
+        assert_eq!(keys(&cache), ["/a", "/c"]);
+    }
+}
@@ -0,0 +1,1623 @@
+use crate::exclude::Exclusions;
+use crate::plausible::PlausibleResponse;
+use crate::upstream::{self, Breakdown, Period, QueryKind, Site, UpstreamQuery};
+use axum::http::HeaderValue;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Level;
+
+/// Read when `CONFIG_FILE` is not set, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+/// Settings, on the configuration and on each site, whose values
+/// `--print-config` hides.
+const SECRETS: [&str; 8] = [
+    "bearer_token",
+    "umami_api_token",
+    "admin_token",
+    "api_keys",
+    "webhook_secret",
+    "discord_webhook_url",
+    "s3_access_key_id",
+    "s3_secret_access_key",
+];
+
+/// Service configuration. Built from the defaults below, overlaid with
+/// `config.toml` (or the file named by `CONFIG_FILE`), overlaid with
+/// environment variables. Every key can be set from the environment under
+/// its uppercased name: `cache_ttl_secs` is `CACHE_TTL_SECS`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `host:port`, or `unix:/path/to.sock` for a Unix domain socket. A TCP
+    /// address is also settable as `HOST` and `PORT` separately.
+    pub bind_addr: BindAddr,
+    /// Permissions the Unix socket is given, in octal, such as `660`.
+    pub unix_socket_mode: Option<String>,
+    /// User, by name or ID, the Unix socket is handed to.
+    pub unix_socket_owner: Option<String>,
+    /// Group, by name or ID, the Unix socket is handed to.
+    pub unix_socket_group: Option<String>,
+    /// PEM certificate chain. With `tls_key_path`, `bind_addr` serves HTTPS
+    /// instead of plain HTTP. Both files are re-read when they change and
+    /// on SIGHUP.
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key for `tls_cert_path`.
+    pub tls_key_path: Option<PathBuf>,
+    /// Sent to the upstream for every site without its own token.
+    pub bearer_token: Option<String>,
+    /// File holding `bearer_token` instead, such as a mounted secret. It is
+    /// read again when the upstream rejects the token, and on reload, so a
+    /// rotated token is picked up without a restart.
+    pub bearer_token_file: Option<PathBuf>,
+    pub upstream_base_url: String,
+    /// `plausible`, or `umami` to read the same leaderboard from an Umami
+    /// instance instead. The Plausible settings are then unused.
+    pub analytics_backend: AnalyticsBackend,
+    /// Base URL of the Umami instance, required with the `umami` backend.
+    pub umami_base_url: Option<String>,
+    /// Umami website read for every site. Unset, each site's ID is taken
+    /// as its Umami website ID.
+    pub umami_website_id: Option<String>,
+    /// Umami API token, required with the `umami` backend.
+    pub umami_api_token: Option<String>,
+    /// JSON fixtures to answer from instead of any upstream, for
+    /// development without a token; see `fixtures/sample.json`. With more
+    /// than one, each fetch of a query takes the next, so refreshes bring
+    /// changes. From the environment as a comma-separated list.
+    pub mock_data_file: Vec<PathBuf>,
+    /// Allows `mock_data_file` alongside a real token, which is otherwise
+    /// refused so mock data can't be left on in production by accident.
+    pub mock_data_allow_token: bool,
+    /// The URL this service is reached at, such as
+    /// `https://stats.artistgrid.cx`, baked into `/widget.js`. Unset, the
+    /// widget uses the request's `Host` with the embedding page's scheme.
+    pub public_url: Option<String>,
+    /// Plausible site ID served when `sites` is empty.
+    pub site_id: String,
+    /// IANA name of the time zone the sites are configured with in
+    /// Plausible, such as `Europe/Berlin`. Every calendar date worked out
+    /// here is one in this zone, so days start when Plausible's do.
+    pub site_timezone: String,
+    /// Sites served, the first one on the bare routes. From the environment
+    /// as `SITES=key:site_id,...`; per-site tokens as `BEARER_TOKEN_<KEY>`.
+    pub sites: Vec<Site>,
+    /// Goal whose conversions are broken down by artist. `GOAL_NAME` sets
+    /// it from the environment too.
+    pub goal: String,
+    /// More goals a request can ask for with `?goal=` instead of its site's
+    /// own, each cached apart. From the environment as a comma-separated
+    /// list.
+    pub goals: Vec<String>,
+    /// Custom property artists are recorded under.
+    pub property: String,
+    /// More custom properties `/prop/:prop` serves a leaderboard of, each
+    /// cached apart. From the environment as a comma-separated list.
+    pub props: Vec<String>,
+    /// How long a cache entry is fresh. Zero disables caching entirely.
+    #[serde(rename = "cache_ttl_secs", with = "secs")]
+    pub cache_ttl: Duration,
+    /// Seconds each breakdown named here (`country`, `page`, `source`,
+    /// `device` or `browser`) stays fresh, instead of `cache_ttl_secs`.
+    /// From the environment as `BREAKDOWN_CACHE_TTL_SECS=device:3600,...`.
+    pub breakdown_cache_ttl_secs: HashMap<String, u64>,
+    /// Seconds each kind of query (`leaderboard`, `timeseries`, `breakdown`
+    /// or `realtime`) stays fresh, instead of `cache_ttl_secs`, or for
+    /// realtime counts `realtime_cache_ttl_secs`. A breakdown's own entry in
+    /// `breakdown_cache_ttl_secs` still wins. From the environment as
+    /// `KIND_CACHE_TTL_SECS=realtime:15,...`.
+    pub kind_cache_ttl_secs: HashMap<String, u64>,
+    /// How long a realtime visitor count is fresh. Polls within it share
+    /// one upstream request.
+    #[serde(rename = "realtime_cache_ttl_secs", with = "secs")]
+    pub realtime_cache_ttl: Duration,
+    /// Longest `from`/`to` range the list routes accept, in days. Every
+    /// range is cached separately.
+    pub custom_range_max_days: u32,
+    /// Extra directives appended to `Cache-Control` on cacheable responses,
+    /// e.g. `stale-while-revalidate=300`.
+    pub cache_control_extra: Option<String>,
+    /// Where the cache is persisted between restarts, if anywhere.
+    pub cache_file: Option<PathBuf>,
+    pub cache_max_entries: usize,
+    /// Total size of the bodies the cache holds, in bytes. Least recently
+    /// used entries are evicted to stay under it; a body bigger than all of
+    /// it is served but not cached.
+    pub cache_max_bytes: usize,
+    /// How far, as a percentage either way, each entry's TTL is randomly
+    /// moved when it is stored, so keys fetched together don't all expire
+    /// together. 0 keeps TTLs exact.
+    pub cache_ttl_jitter_percent: u32,
+    /// How long a failed fetch is remembered for its key. Until then requests
+    /// for it get the failure, or whatever is still cached, straight away
+    /// instead of another upstream attempt. Must be well under
+    /// `cache_ttl_secs`; zero disables it.
+    #[serde(rename = "negative_cache_ttl_secs", with = "secs")]
+    pub negative_cache_ttl: Duration,
+    /// Safety cap on upstream pages fetched for one paginated query.
+    pub upstream_max_pages: u32,
+    /// Largest upstream response body read, in bytes. A bigger one fails
+    /// the request instead of being buffered and cached.
+    pub upstream_max_body_bytes: u64,
+    /// Tries per upstream request before giving up on transient failures.
+    /// 1 disables retries.
+    pub upstream_attempts: u32,
+    /// Timeout for a single upstream attempt.
+    #[serde(rename = "upstream_attempt_timeout_secs", with = "secs")]
+    pub upstream_attempt_timeout: Duration,
+    /// Total time one upstream request may spend across all its attempts,
+    /// backoff included.
+    #[serde(rename = "upstream_budget_secs", with = "secs")]
+    pub upstream_budget: Duration,
+    /// Time allowed to open a connection to the upstream, within the
+    /// attempt timeout.
+    #[serde(rename = "upstream_connect_timeout_secs", with = "secs")]
+    pub upstream_connect_timeout: Duration,
+    /// How long an unused upstream connection is kept open for reuse.
+    #[serde(rename = "upstream_pool_idle_timeout_secs", with = "secs")]
+    pub upstream_pool_idle_timeout: Duration,
+    /// Unused connections kept open per host; 0 opens a new one for every
+    /// request.
+    pub upstream_pool_max_idle_per_host: usize,
+    /// Fetches of distinct keys sent to the upstream at once; the rest wait
+    /// their turn.
+    pub upstream_max_concurrency: usize,
+    /// How long a fetch waits for its turn before failing with a 503. 0
+    /// fails it straight away when every slot is taken.
+    #[serde(rename = "upstream_queue_timeout_secs", with = "secs")]
+    pub upstream_queue_timeout: Duration,
+    /// Proxy outbound HTTPS requests go through, such as
+    /// `http://proxy.internal:3128`. Only this one is used; reqwest's own
+    /// environment lookup is off.
+    pub https_proxy: Option<String>,
+    /// Proxy outbound plain HTTP requests go through.
+    pub http_proxy: Option<String>,
+    /// Comma-separated hosts, domains and CIDR ranges reached directly,
+    /// bypassing both proxies.
+    pub no_proxy: Option<String>,
+    /// PEM file of extra CA certificates to trust for outbound requests, on
+    /// top of the system ones, as for a proxy that re-signs traffic.
+    pub ca_cert_path: Option<PathBuf>,
+    /// The certificates in `ca_cert_path`.
+    #[serde(skip)]
+    pub ca_certs: Vec<reqwest::Certificate>,
+    /// Skip certificate verification on outbound requests. For development
+    /// only: anyone on the path can read and alter the traffic.
+    pub danger_accept_invalid_certs: bool,
+    /// Consecutive failures that open the circuit breaker.
+    pub circuit_failure_threshold: u32,
+    /// How long the circuit stays open before a probe is let through.
+    #[serde(rename = "circuit_cooldown_secs", with = "secs")]
+    pub circuit_cooldown: Duration,
+    /// Largest `n` accepted by `/top/:n`.
+    pub top_max: usize,
+    /// Most WebSocket clients connected at once; more are refused with a 503.
+    pub ws_max_connections: u32,
+    /// Decimal places `share` is rounded to, at most 10.
+    pub share_decimals: u32,
+    /// Token required by the admin routes. Without one they always answer 401.
+    pub admin_token: Option<String>,
+    /// Keys required on every route but `/healthz` and the API docs, sent as
+    /// `Authorization: Bearer` or `X-Api-Key`. Empty leaves the routes open.
+    /// Several can be listed so one can be rotated out without downtime.
+    /// Answers are then marked `private`, so shared caches don't hand them
+    /// to clients without a key.
+    pub api_keys: Vec<String>,
+    pub metrics_enabled: bool,
+    /// Whether `?callback=` wraps JSON answers for `<script>` embeds.
+    pub jsonp_enabled: bool,
+    /// Whether `/graphql` answers `__schema` and `__type`, so tools can read
+    /// its schema.
+    pub graphql_introspection: bool,
+    /// Deepest nesting of fields a GraphQL query may have, and of inline
+    /// fragments and values, which are refused as they are parsed.
+    pub graphql_max_depth: usize,
+    /// Most fields a GraphQL query may select, fragments expanded.
+    pub graphql_max_complexity: usize,
+    /// Where to POST the new leaderboard each time a refresh changes it.
+    pub webhook_url: Option<String>,
+    /// Key for the `X-Webhook-Signature` HMAC, so receivers can check that
+    /// deliveries came from here.
+    pub webhook_secret: Option<String>,
+    /// Discord webhook told when the top artists change.
+    pub discord_webhook_url: Option<String>,
+    /// How many of the top positions Discord is told about: 1 announces
+    /// only a new #1.
+    pub discord_top: usize,
+    /// Least time between Discord messages for a site. Changes within it
+    /// are held back and only the latest is announced, if it still differs.
+    #[serde(rename = "discord_debounce_secs", with = "secs")]
+    pub discord_debounce: Duration,
+    /// Whether each refresh of an all-time leaderboard is checked for
+    /// artists whose visitors jumped well beyond their recent deltas. Spikes
+    /// are logged, and sent on when `spike_notify` is set.
+    pub spike_detection: bool,
+    /// Standard deviations above the mean of an artist's recent deltas a
+    /// delta must be to count as a spike.
+    pub spike_stddevs: f64,
+    /// Fewest visitors gained that can count as a spike, so small artists
+    /// going from 3 to 9 don't.
+    pub spike_min_delta: u64,
+    /// How many of an artist's recent deltas the baseline is computed from.
+    /// No spike is reported for an artist until that many are on record.
+    pub spike_baseline_samples: usize,
+    /// Least time between alerts for the same artist, so a sustained spike
+    /// is reported once.
+    #[serde(rename = "spike_cooldown_secs", with = "secs")]
+    pub spike_cooldown: Duration,
+    /// Whether spikes are also sent to `webhook_url` and
+    /// `discord_webhook_url`, whichever are set.
+    pub spike_notify: bool,
+    /// `*`, or a comma-separated list of exact origins. Unset allows any.
+    pub cors_origins: Option<String>,
+    /// Sustained requests per minute per client. 0 disables rate limiting.
+    pub rate_limit_per_minute: u32,
+    pub rate_limit_burst: u32,
+    /// Whether to take the client address from `Forwarded`/`X-Forwarded-For`.
+    pub trust_proxy: bool,
+    /// Which of those the proxy records clients in, `x-forwarded-for` or
+    /// `forwarded`. Only that one is read: the proxy passes the other
+    /// through as the client sent it.
+    pub forwarded_header: ForwardedHeader,
+    /// Proxies, as IPs or CIDRs, whose `Forwarded`/`X-Forwarded-For` is
+    /// believed. When set, those headers from any other peer are ignored,
+    /// even with `trust_proxy`. From the environment as a comma-separated
+    /// list.
+    pub trusted_proxies: Vec<String>,
+    /// Networks, as IPs or CIDRs, the admin routes answer; others get a 403
+    /// before their token is looked at. Empty allows any. Forwarded headers
+    /// only count here from `trusted_proxies`. From the environment as a
+    /// comma-separated list.
+    pub admin_allow_cidrs: Vec<String>,
+    /// `trusted_proxies`, parsed.
+    #[serde(skip)]
+    pub trusted_proxy_nets: Vec<IpNet>,
+    /// `admin_allow_cidrs`, parsed.
+    #[serde(skip)]
+    pub admin_allow_nets: Vec<IpNet>,
+    /// Time in-flight requests get to finish on shutdown.
+    #[serde(rename = "shutdown_drain_secs", with = "secs")]
+    pub shutdown_drain: Duration,
+    /// Time a request may take to be answered before it gets a 504. Event
+    /// streams and WebSockets are exempt, having `stream_idle_timeout`.
+    #[serde(rename = "request_timeout_secs", with = "secs")]
+    pub request_timeout: Duration,
+    /// How long an event stream stays open without an update, and a
+    /// WebSocket without hearing from its client, before it is closed.
+    /// WebSocket clients are pinged every 15 seconds, so at least 30.
+    #[serde(rename = "stream_idle_timeout_secs", with = "secs")]
+    pub stream_idle_timeout: Duration,
+    /// Time a client gets to send a request's headers, and an idle
+    /// keep-alive connection to start its next request.
+    #[serde(rename = "header_read_timeout_secs", with = "secs")]
+    pub header_read_timeout: Duration,
+    /// Largest request head, in bytes, accepted; a bigger one gets a 431.
+    /// At least 8192.
+    pub max_request_header_bytes: usize,
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub access_log_level: String,
+    /// TOML or JSON file of artist aliases, re-read on every refresh and on
+    /// SIGHUP.
+    pub aliases_file: Option<PathBuf>,
+    /// SQLite database of leaderboard snapshots behind `/history`. Without
+    /// one, history is disabled and no database is created.
+    pub history_db: Option<PathBuf>,
+    /// The offset from UTC `/digest` weeks start at midnight in, such as
+    /// `+01:00`, or `UTC`. Unset, weeks start at midnight in
+    /// `site_timezone`, daylight saving included.
+    pub digest_utc_offset: String,
+    /// Most rows `/history.csv` exports at once; a request that matches
+    /// more is refused and asked to narrow its range.
+    pub history_csv_max_rows: u64,
+    /// Directory each refresh writes the all-time leaderboard to, as served
+    /// by `/`, for hosting on a plain CDN. Other sites go in a subdirectory
+    /// named by their key.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Whether snapshots include a CSV rendering alongside the JSON.
+    pub snapshot_csv: bool,
+    /// UTC-stamped snapshots kept per format; older ones are deleted. Copies
+    /// stamped in local time by earlier versions count as the oldest.
+    pub snapshot_retention: usize,
+    /// S3-compatible endpoint snapshots are uploaded to, such as
+    /// `https://s3.us-east-1.amazonaws.com` or a MinIO or R2 URL.
+    pub s3_endpoint: Option<String>,
+    /// Bucket snapshots are uploaded to. Setting it enables uploads, and then
+    /// the endpoint and both credentials are required.
+    pub s3_bucket: Option<String>,
+    /// Key prefix the snapshot objects are stored under.
+    pub s3_prefix: String,
+    /// Region requests are signed for. R2 expects `auto`.
+    pub s3_region: String,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    /// `Cache-Control` stored on every uploaded object.
+    pub s3_cache_control: String,
+    /// Whether to check at startup, by writing and deleting a probe object,
+    /// that the bucket accepts uploads, and exit if it doesn't.
+    pub s3_startup_check: bool,
+    /// Names dropped from every public response. From the environment as a
+    /// comma-separated list.
+    pub exclude_names: Vec<String>,
+    /// Regexes; names matching any of them are dropped too.
+    pub exclude_patterns: Vec<String>,
+    /// More names and patterns to exclude, one per line.
+    pub exclude_file: Option<PathBuf>,
+    /// Everything in `exclude_names`, `exclude_patterns` and `exclude_file`,
+    /// compiled.
+    #[serde(skip)]
+    pub exclusions: Exclusions,
+    /// Referrer sources folded into another by `?merge_social=true` on the
+    /// source breakdown, on top of the built-in social networks, e.g.
+    /// `"mastodon.social" = "Mastodon"`. From the environment as
+    /// `SOURCE_ALIASES=source:bucket,...`.
+    pub source_aliases: HashMap<String, String>,
+    /// Sources with fewer visitors are summed into one `Other` row of the
+    /// source breakdown.
+    pub source_min_visitors: u64,
+    /// OTLP/HTTP collector spans are exported to, such as
+    /// `http://tempo:4318`. Unset, nothing is exported.
+    pub otel_exporter_otlp_endpoint: Option<String>,
+    /// `text` for human-readable lines, `json` for one object per line.
+    pub log_format: LogFormat,
+    /// The file the configuration was read from, if any.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+    /// Exit at startup if the upstream rejects a bearer token, instead of
+    /// only logging it.
+    pub strict_startup: bool,
+    /// Leaderboards fetched for every site as the server starts, so their
+    /// first requests are hits, each as `period=<period>`. From the
+    /// environment as a comma-separated list.
+    pub warm_keys: Vec<String>,
+    /// Whether `/readyz` and `/healthz?ready=true` answer 503 until the
+    /// warm-up is done.
+    pub warm_before_ready: bool,
+    /// How old the freshest data may get, with every refresh since failing,
+    /// before `/readyz` answers 503 so load balancers send traffic
+    /// elsewhere. 0 keeps serving stale data as ready.
+    #[serde(rename = "ready_max_staleness_secs", with = "secs")]
+    pub ready_max_staleness: Duration,
+    /// Leaderboard JSON served by `/` when nothing is cached for a request
+    /// and the upstream fails, until the first fetch since startup succeeds.
+    pub fallback_file: Option<PathBuf>,
+    /// The contents of `fallback_file`, checked to be a leaderboard.
+    #[serde(skip)]
+    pub fallback: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: BindAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000))),
+            unix_socket_mode: None,
+            unix_socket_owner: None,
+            unix_socket_group: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            bearer_token: None,
+            bearer_token_file: None,
+            upstream_base_url: "https://plausible.canine.tools".to_string(),
+            analytics_backend: AnalyticsBackend::default(),
+            umami_base_url: None,
+            mock_data_file: Vec::new(),
+            mock_data_allow_token: false,
+            public_url: None,
+            umami_website_id: None,
+            umami_api_token: None,
+            site_id: "artistgrid.cx".to_string(),
+            site_timezone: "UTC".to_string(),
+            sites: Vec::new(),
+            goal: "Artist Click".to_string(),
+            goals: Vec::new(),
+            property: "name".to_string(),
+            props: Vec::new(),
+            cache_ttl: Duration::from_secs(600),
+            // Device and browser splits barely move from hour to hour.
+            breakdown_cache_ttl_secs: HashMap::from([
+                ("device".to_string(), 3600),
+                ("browser".to_string(), 3600),
+            ]),
+            kind_cache_ttl_secs: HashMap::new(),
+            realtime_cache_ttl: Duration::from_secs(15),
+            custom_range_max_days: 731,
+            cache_control_extra: None,
+            cache_file: None,
+            cache_max_entries: 100,
+            cache_max_bytes: 32 * 1024 * 1024,
+            cache_ttl_jitter_percent: 10,
+            negative_cache_ttl: Duration::from_secs(15),
+            upstream_max_pages: 10,
+            upstream_max_body_bytes: 4 * 1024 * 1024,
+            upstream_attempts: 3,
+            upstream_attempt_timeout: Duration::from_secs(10),
+            upstream_budget: Duration::from_secs(30),
+            upstream_connect_timeout: Duration::from_secs(3),
+            upstream_pool_idle_timeout: Duration::from_secs(90),
+            upstream_pool_max_idle_per_host: 8,
+            upstream_max_concurrency: 4,
+            upstream_queue_timeout: Duration::from_secs(5),
+            https_proxy: None,
+            http_proxy: None,
+            no_proxy: None,
+            ca_cert_path: None,
+            ca_certs: Vec::new(),
+            danger_accept_invalid_certs: false,
+            circuit_failure_threshold: 5,
+            circuit_cooldown: Duration::from_secs(30),
+            top_max: 100,
+            share_decimals: 4,
+            ws_max_connections: 100,
+            admin_token: None,
+            api_keys: Vec::new(),
+            webhook_url: None,
+            webhook_secret: None,
+            discord_webhook_url: None,
+            discord_top: 1,
+            discord_debounce: Duration::from_secs(600),
+            spike_detection: false,
+            spike_stddevs: 3.0,
+            spike_min_delta: 50,
+            spike_baseline_samples: 12,
+            spike_cooldown: Duration::from_secs(3600),
+            spike_notify: false,
+            metrics_enabled: true,
+            jsonp_enabled: true,
+            graphql_introspection: true,
+            graphql_max_depth: 15,
+            graphql_max_complexity: 500,
+            cors_origins: None,
+            rate_limit_per_minute: 60,
+            rate_limit_burst: 20,
+            trust_proxy: false,
+            forwarded_header: ForwardedHeader::default(),
+            trusted_proxies: Vec::new(),
+            admin_allow_cidrs: Vec::new(),
+            trusted_proxy_nets: Vec::new(),
+            admin_allow_nets: Vec::new(),
+            shutdown_drain: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(15),
+            stream_idle_timeout: Duration::from_secs(300),
+            header_read_timeout: Duration::from_secs(10),
+            max_request_header_bytes: 16 * 1024,
+            access_log_level: "info".to_string(),
+            aliases_file: None,
+            history_db: None,
+            digest_utc_offset: String::new(),
+            history_csv_max_rows: 100_000,
+            snapshot_dir: None,
+            snapshot_csv: false,
+            snapshot_retention: 48,
+            s3_endpoint: None,
+            s3_bucket: None,
+            s3_prefix: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_access_key_id: None,
+            s3_secret_access_key: None,
+            s3_cache_control: "public, max-age=60".to_string(),
+            s3_startup_check: false,
+            exclude_names: Vec::new(),
+            exclude_patterns: Vec::new(),
+            exclude_file: None,
+            exclusions: Exclusions::default(),
+            source_aliases: HashMap::new(),
+            source_min_visitors: 2,
+            otel_exporter_otlp_endpoint: None,
+            log_format: LogFormat::default(),
+            source: None,
+            strict_startup: false,
+            warm_keys: Vec::new(),
+            warm_before_ready: false,
+            ready_max_staleness: Duration::ZERO,
+            fallback_file: None,
+            fallback: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads and validates the configuration. Errors name the offending key
+    /// or environment variable.
+    pub fn load() -> Result<Self, String> {
+        let mut config = match std::env::var_os("CONFIG_FILE") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.finish()?;
+        Ok(config)
+    }
+
+    /// The configuration as resolved, in the format of the configuration
+    /// file, with every secret that is set shown as `[redacted]`.
+    pub fn redacted(&self) -> String {
+        let mut config = toml::Table::try_from(self).expect("the configuration serializes");
+        redact(&mut config);
+        if let Some(toml::Value::Array(sites)) = config.get_mut("sites") {
+            for site in sites.iter_mut().filter_map(toml::Value::as_table_mut) {
+                redact(site);
+            }
+        }
+        config.to_string()
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut config: Config =
+            toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env(
+            "BIND_ADDR",
+            &mut self.bind_addr,
+            "a socket address such as 127.0.0.1:3000, or unix:/path/to.sock",
+        )?;
+        if let (None, BindAddr::Tcp(addr)) = (std::env::var_os("BIND_ADDR"), &mut self.bind_addr) {
+            let mut host = addr.ip();
+            let mut port = addr.port();
+            env::<IpAddr>("HOST", &mut host, "an IP address such as 127.0.0.1 or ::")?;
+            env::<u16>("PORT", &mut port, "an integer from 0 to 65535")?;
+            *addr = SocketAddr::new(host, port);
+        }
+        env("UNIX_SOCKET_MODE", &mut self.unix_socket_mode, "an octal mode such as 660")?;
+        env("UNIX_SOCKET_OWNER", &mut self.unix_socket_owner, "a user name or ID")?;
+        env("UNIX_SOCKET_GROUP", &mut self.unix_socket_group, "a group name or ID")?;
+
+        env("TLS_CERT_PATH", &mut self.tls_cert_path, "a path")?;
+        env("TLS_KEY_PATH", &mut self.tls_key_path, "a path")?;
+        env("BEARER_TOKEN", &mut self.bearer_token, "a string")?;
+        env("BEARER_TOKEN_FILE", &mut self.bearer_token_file, "a path")?;
+        env("UPSTREAM_BASE_URL", &mut self.upstream_base_url, "a URL")?;
+        env("ANALYTICS_BACKEND", &mut self.analytics_backend, "plausible or umami")?;
+        env("UMAMI_BASE_URL", &mut self.umami_base_url, "a URL")?;
+        env("PUBLIC_URL", &mut self.public_url, "a URL")?;
+        env("UMAMI_WEBSITE_ID", &mut self.umami_website_id, "an Umami website ID")?;
+        env("UMAMI_API_TOKEN", &mut self.umami_api_token, "a string")?;
+        env("MOCK_DATA_FILE", &mut self.mock_data_file, "a comma-separated list of paths")?;
+        env("MOCK_DATA_ALLOW_TOKEN", &mut self.mock_data_allow_token, "true or false")?;
+        env("SITE_ID", &mut self.site_id, "a Plausible site ID")?;
+        env("SITE_TIMEZONE", &mut self.site_timezone, "an IANA time zone name")?;
+        if let Ok(value) = std::env::var("SITES") {
+            self.sites =
+                upstream::parse_sites(&value).map_err(|e| format!("SITES is invalid: {}", e))?;
+        }
+        env("GOAL_NAME", &mut self.goal, "a goal name")?;
+        env("GOAL", &mut self.goal, "a goal name")?;
+        env("GOALS", &mut self.goals, "a comma-separated list")?;
+        env("PROPERTY", &mut self.property, "a custom property name")?;
+        env("PROPS", &mut self.props, "a comma-separated list")?;
+        env("CACHE_TTL_SECS", &mut self.cache_ttl, "a non-negative integer")?;
+        env(
+            "BREAKDOWN_CACHE_TTL_SECS",
+            &mut self.breakdown_cache_ttl_secs,
+            "a list of breakdown:seconds pairs",
+        )?;
+        env(
+            "KIND_CACHE_TTL_SECS",
+            &mut self.kind_cache_ttl_secs,
+            "a list of kind:seconds pairs",
+        )?;
+        env(
+            "REALTIME_CACHE_TTL_SECS",
+            &mut self.realtime_cache_ttl,
+            "a non-negative integer",
+        )?;
+        env("CUSTOM_RANGE_MAX_DAYS", &mut self.custom_range_max_days, "a positive integer")?;
+        env("CACHE_CONTROL_EXTRA", &mut self.cache_control_extra, "a string")?;
+        env("CACHE_FILE", &mut self.cache_file, "a path")?;
+        env("CACHE_MAX_ENTRIES", &mut self.cache_max_entries, "a positive integer")?;
+        env("CACHE_MAX_BYTES", &mut self.cache_max_bytes, "a positive integer")?;
+        env(
+            "CACHE_TTL_JITTER_PERCENT",
+            &mut self.cache_ttl_jitter_percent,
+            "a non-negative integer",
+        )?;
+        env(
+            "NEGATIVE_CACHE_TTL_SECS",
+            &mut self.negative_cache_ttl,
+            "a non-negative integer",
+        )?;
+        env("UPSTREAM_MAX_PAGES", &mut self.upstream_max_pages, "a positive integer")?;
+        env(
+            "UPSTREAM_MAX_BODY_BYTES",
+            &mut self.upstream_max_body_bytes,
+            "a positive integer",
+        )?;
+        env("UPSTREAM_ATTEMPTS", &mut self.upstream_attempts, "a positive integer")?;
+        env(
+            "UPSTREAM_ATTEMPT_TIMEOUT_SECS",
+            &mut self.upstream_attempt_timeout,
+            "a positive integer",
+        )?;
+        env("UPSTREAM_BUDGET_SECS", &mut self.upstream_budget, "a positive integer")?;
+        env(
+            "UPSTREAM_CONNECT_TIMEOUT_SECS",
+            &mut self.upstream_connect_timeout,
+            "a positive integer",
+        )?;
+        env(
+            "UPSTREAM_POOL_IDLE_TIMEOUT_SECS",
+            &mut self.upstream_pool_idle_timeout,
+            "a positive integer",
+        )?;
+        env(
+            "UPSTREAM_POOL_MAX_IDLE_PER_HOST",
+            &mut self.upstream_pool_max_idle_per_host,
+            "a non-negative integer",
+        )?;
+        env(
+            "UPSTREAM_MAX_CONCURRENCY",
+            &mut self.upstream_max_concurrency,
+            "a positive integer",
+        )?;
+        env(
+            "UPSTREAM_QUEUE_TIMEOUT_SECS",
+            &mut self.upstream_queue_timeout,
+            "a non-negative integer",
+        )?;
+        env("HTTPS_PROXY", &mut self.https_proxy, "a URL")?;
+        env("HTTP_PROXY", &mut self.http_proxy, "a URL")?;
+        env("NO_PROXY", &mut self.no_proxy, "a comma-separated list")?;
+        env("CA_CERT_PATH", &mut self.ca_cert_path, "a path")?;
+        env(
+            "DANGER_ACCEPT_INVALID_CERTS",
+            &mut self.danger_accept_invalid_certs,
+            "true or false",
+        )?;
+        env(
+            "CIRCUIT_FAILURE_THRESHOLD",
+            &mut self.circuit_failure_threshold,
+            "a positive integer",
+        )?;
+        env("CIRCUIT_COOLDOWN_SECS", &mut self.circuit_cooldown, "a non-negative integer")?;
+        env("TOP_MAX", &mut self.top_max, "a positive integer")?;
+        env("WS_MAX_CONNECTIONS", &mut self.ws_max_connections, "a non-negative integer")?;
+        env("SHARE_DECIMALS", &mut self.share_decimals, "an integer from 0 to 10")?;
+        env("ADMIN_TOKEN", &mut self.admin_token, "a string")?;
+        env("API_KEYS", &mut self.api_keys, "a comma-separated list")?;
+        env("WEBHOOK_URL", &mut self.webhook_url, "a URL")?;
+        env("WEBHOOK_SECRET", &mut self.webhook_secret, "a string")?;
+        env("DISCORD_WEBHOOK_URL", &mut self.discord_webhook_url, "a URL")?;
+        env("DISCORD_TOP", &mut self.discord_top, "a positive integer")?;
+        env("DISCORD_DEBOUNCE_SECS", &mut self.discord_debounce, "a non-negative integer")?;
+        env("SPIKE_DETECTION", &mut self.spike_detection, "true or false")?;
+        env("SPIKE_STDDEVS", &mut self.spike_stddevs, "a non-negative number")?;
+        env("SPIKE_MIN_DELTA", &mut self.spike_min_delta, "a non-negative integer")?;
+        env("SPIKE_BASELINE_SAMPLES", &mut self.spike_baseline_samples, "an integer, at least 3")?;
+        env("SPIKE_COOLDOWN_SECS", &mut self.spike_cooldown, "a non-negative integer")?;
+        env("SPIKE_NOTIFY", &mut self.spike_notify, "true or false")?;
+        env("METRICS_ENABLED", &mut self.metrics_enabled, "true or false")?;
+        env("JSONP_ENABLED", &mut self.jsonp_enabled, "true or false")?;
+        env("GRAPHQL_INTROSPECTION", &mut self.graphql_introspection, "true or false")?;
+        env("GRAPHQL_MAX_DEPTH", &mut self.graphql_max_depth, "a positive integer")?;
+        env("GRAPHQL_MAX_COMPLEXITY", &mut self.graphql_max_complexity, "a positive integer")?;
+        env("CORS_ORIGINS", &mut self.cors_origins, "a list of origins")?;
+        env("RATE_LIMIT_PER_MINUTE", &mut self.rate_limit_per_minute, "a non-negative integer")?;
+        env("RATE_LIMIT_BURST", &mut self.rate_limit_burst, "a positive integer")?;
+        env("TRUST_PROXY", &mut self.trust_proxy, "true or false")?;
+        env("FORWARDED_HEADER", &mut self.forwarded_header, "x-forwarded-for or forwarded")?;
+        env("TRUSTED_PROXIES", &mut self.trusted_proxies, "a comma-separated list")?;
+        env("ADMIN_ALLOW_CIDRS", &mut self.admin_allow_cidrs, "a comma-separated list")?;
+        env("SHUTDOWN_DRAIN_SECS", &mut self.shutdown_drain, "a non-negative integer")?;
+        env("REQUEST_TIMEOUT_SECS", &mut self.request_timeout, "a positive integer")?;
+        env("STREAM_IDLE_TIMEOUT_SECS", &mut self.stream_idle_timeout, "an integer, at least 30")?;
+        env("HEADER_READ_TIMEOUT_SECS", &mut self.header_read_timeout, "a positive integer")?;
+        env(
+            "MAX_REQUEST_HEADER_BYTES",
+            &mut self.max_request_header_bytes,
+            "an integer, at least 8192",
+        )?;
+        env("ACCESS_LOG_LEVEL", &mut self.access_log_level, "a log level")?;
+        env("ALIASES_FILE", &mut self.aliases_file, "a path")?;
+        env("HISTORY_DB", &mut self.history_db, "a path")?;
+        env("DIGEST_UTC_OFFSET", &mut self.digest_utc_offset, "an offset such as +01:00")?;
+        env("HISTORY_CSV_MAX_ROWS", &mut self.history_csv_max_rows, "a positive integer")?;
+        env("SNAPSHOT_DIR", &mut self.snapshot_dir, "a path")?;
+        env("SNAPSHOT_CSV", &mut self.snapshot_csv, "true or false")?;
+        env("SNAPSHOT_RETENTION", &mut self.snapshot_retention, "a non-negative integer")?;
+        env("S3_ENDPOINT", &mut self.s3_endpoint, "a URL")?;
+        env("S3_BUCKET", &mut self.s3_bucket, "a bucket name")?;
+        env("S3_PREFIX", &mut self.s3_prefix, "a key prefix")?;
+        env("S3_REGION", &mut self.s3_region, "a region")?;
+        env("S3_ACCESS_KEY_ID", &mut self.s3_access_key_id, "a string")?;
+        env("S3_SECRET_ACCESS_KEY", &mut self.s3_secret_access_key, "a string")?;
+        env("S3_CACHE_CONTROL", &mut self.s3_cache_control, "a Cache-Control value")?;
+        env("S3_STARTUP_CHECK", &mut self.s3_startup_check, "true or false")?;
+        env("EXCLUDE_NAMES", &mut self.exclude_names, "a comma-separated list")?;
+        env("EXCLUDE_PATTERNS", &mut self.exclude_patterns, "a comma-separated list")?;
+        env("EXCLUDE_FILE", &mut self.exclude_file, "a path")?;
+        env("OTEL_EXPORTER_OTLP_ENDPOINT", &mut self.otel_exporter_otlp_endpoint, "a URL")?;
+        env("LOG_FORMAT", &mut self.log_format, "text or json")?;
+        env("SOURCE_ALIASES", &mut self.source_aliases, "a list of source:bucket pairs")?;
+        env("SOURCE_MIN_VISITORS", &mut self.source_min_visitors, "a non-negative integer")?;
+        env("STRICT_STARTUP", &mut self.strict_startup, "true or false")?;
+        env("WARM_KEYS", &mut self.warm_keys, "a comma-separated list")?;
+        env("WARM_BEFORE_READY", &mut self.warm_before_ready, "true or false")?;
+        env(
+            "READY_MAX_STALENESS_SECS",
+            &mut self.ready_max_staleness,
+            "a non-negative integer",
+        )?;
+        env("FALLBACK_FILE", &mut self.fallback_file, "a path")?;
+        Ok(())
+    }
+
+    /// Fills in derived values and checks the constraints serde can't.
+    fn finish(&mut self) -> Result<(), String> {
+        for (key, value) in [
+            ("cache_max_entries", self.cache_max_entries as u64),
+            ("cache_max_bytes", self.cache_max_bytes as u64),
+            ("upstream_max_pages", u64::from(self.upstream_max_pages)),
+            ("upstream_max_body_bytes", self.upstream_max_body_bytes),
+            ("upstream_attempts", u64::from(self.upstream_attempts)),
+            ("upstream_attempt_timeout_secs", self.upstream_attempt_timeout.as_secs()),
+            ("upstream_budget_secs", self.upstream_budget.as_secs()),
+            ("upstream_connect_timeout_secs", self.upstream_connect_timeout.as_secs()),
+            ("upstream_pool_idle_timeout_secs", self.upstream_pool_idle_timeout.as_secs()),
+            ("upstream_max_concurrency", self.upstream_max_concurrency as u64),
+            ("custom_range_max_days", u64::from(self.custom_range_max_days)),
+            ("circuit_failure_threshold", u64::from(self.circuit_failure_threshold)),
+            ("top_max", self.top_max as u64),
+            ("graphql_max_depth", self.graphql_max_depth as u64),
+            ("graphql_max_complexity", self.graphql_max_complexity as u64),
+            ("discord_top", self.discord_top as u64),
+            ("rate_limit_burst", u64::from(self.rate_limit_burst)),
+            ("history_csv_max_rows", self.history_csv_max_rows),
+            ("request_timeout_secs", self.request_timeout.as_secs()),
+            ("header_read_timeout_secs", self.header_read_timeout.as_secs()),
+        ] {
+            if value == 0 {
+                return Err(format!("{} must be a positive integer", describe(key)));
+            }
+        }
+
+        if self.cache_ttl_jitter_percent >= 100 {
+            let key = describe("cache_ttl_jitter_percent");
+            return Err(format!("{} must be less than 100", key));
+        }
+        // A remembered failure outliving good data would hide the recovery.
+        let negative = self.negative_cache_ttl;
+        if !self.cache_ttl.is_zero() && negative * 4 > self.cache_ttl {
+            let (key, ttl) = (describe("negative_cache_ttl_secs"), describe("cache_ttl_secs"));
+            return Err(format!("{} must be at most a quarter of {}", key, ttl));
+        }
+
+        if self.stream_idle_timeout < Duration::from_secs(30) {
+            let key = describe("stream_idle_timeout_secs");
+            return Err(format!("{} must be at least 30", key));
+        }
+        // hyper's smallest read buffer.
+        if self.max_request_header_bytes < 8192 {
+            let key = describe("max_request_header_bytes");
+            return Err(format!("{} must be at least 8192", key));
+        }
+
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => {
+                return Err(format!("{} is required with tls_cert_path", describe("tls_key_path")));
+            }
+            (None, Some(_)) => {
+                return Err(format!("{} is required with tls_key_path", describe("tls_cert_path")));
+            }
+            _ => {}
+        }
+
+        if !(self.spike_stddevs.is_finite() && self.spike_stddevs >= 0.0) {
+            return Err(format!("{} must be a non-negative number", describe("spike_stddevs")));
+        }
+        if self.spike_baseline_samples < 3 {
+            let key = describe("spike_baseline_samples");
+            return Err(format!("{} must be at least 3", key));
+        }
+
+        if self.share_decimals > 10 {
+            return Err(format!("{} must be at most 10", describe("share_decimals")));
+        }
+
+        // Blank strings, from the file or the environment, mean unset.
+        for value in [
+            &mut self.unix_socket_mode,
+            &mut self.unix_socket_owner,
+            &mut self.unix_socket_group,
+            &mut self.bearer_token,
+            &mut self.umami_base_url,
+            &mut self.umami_website_id,
+            &mut self.umami_api_token,
+            &mut self.public_url,
+            &mut self.cache_control_extra,
+            &mut self.admin_token,
+            &mut self.webhook_url,
+            &mut self.webhook_secret,
+            &mut self.discord_webhook_url,
+            &mut self.s3_endpoint,
+            &mut self.s3_bucket,
+            &mut self.s3_access_key_id,
+            &mut self.s3_secret_access_key,
+            &mut self.cors_origins,
+            &mut self.otel_exporter_otlp_endpoint,
+            &mut self.https_proxy,
+            &mut self.http_proxy,
+            &mut self.no_proxy,
+        ] {
+            *value = value
+                .take()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty());
+        }
+
+        self.api_keys = self
+            .api_keys
+            .iter()
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+        for key in &self.api_keys {
+            check_token(key, &format!("a key in {}", describe("api_keys")))?;
+        }
+
+        if let BindAddr::Unix(_) = self.bind_addr {
+            if cfg!(not(unix)) {
+                return Err(format!("{} can't be a Unix socket here", describe("bind_addr")));
+            }
+            if self.tls_cert_path.is_some() {
+                return Err(format!(
+                    "{} can't be combined with a Unix socket; terminate TLS in front of it",
+                    describe("tls_cert_path")
+                ));
+            }
+        }
+        if let Some(mode) = &self.unix_socket_mode {
+            if self.socket_mode().is_none() {
+                return Err(format!(
+                    "{} must be an octal mode such as 660, got {:?}",
+                    describe("unix_socket_mode"),
+                    mode
+                ));
+            }
+        }
+
+        self.trusted_proxy_nets = parse_nets("trusted_proxies", &self.trusted_proxies)?;
+        self.admin_allow_nets = parse_nets("admin_allow_cidrs", &self.admin_allow_cidrs)?;
+
+        if self.site_timezone.trim().parse::<chrono_tz::Tz>().is_err() {
+            let key = describe("site_timezone");
+            return Err(format!("{} must be an IANA time zone name such as Europe/Berlin", key));
+        }
+        if !self.digest_utc_offset.trim().is_empty() && self.digest_offset().is_none() {
+            let key = describe("digest_utc_offset");
+            return Err(format!("{} must be UTC or an offset such as +01:00", key));
+        }
+
+        for origin in self.cors_origin_list().unwrap_or_default() {
+            if HeaderValue::from_str(origin).is_err() {
+                let key = describe("cors_origins");
+                return Err(format!("{} has {:?}, which is not a valid origin", key, origin));
+            }
+        }
+
+        if let Some(extra) = &self.cache_control_extra {
+            HeaderValue::from_str(&format!("public, max-age=0, {}", extra)).map_err(|_| {
+                format!("{} must be a valid header value", describe("cache_control_extra"))
+            })?;
+        }
+
+        if !self.access_log_level.eq_ignore_ascii_case("off")
+            && self.access_log_level.parse::<Level>().is_err()
+        {
+            return Err(format!(
+                "{} must be off, error, warn, info, debug or trace",
+                describe("access_log_level")
+            ));
+        }
+
+        let mut names = self.exclude_names.clone();
+        let mut patterns = self.exclude_patterns.clone();
+        if let Some(path) = &self.exclude_file {
+            let (file_names, file_patterns) = Exclusions::read_file(path)
+                .map_err(|e| format!("{}: {}", describe("exclude_file"), e))?;
+            names.extend(file_names);
+            patterns.extend(file_patterns);
+        }
+        self.exclusions = Exclusions::new(&names, &patterns)
+            .map_err(|e| format!("{}: {}", describe("exclude_patterns"), e))?;
+
+        for name in self.breakdown_cache_ttl_secs.keys() {
+            if name.parse::<Breakdown>().is_err() {
+                return Err(format!(
+                    "{} has unknown breakdown {:?}; expected one of {}",
+                    describe("breakdown_cache_ttl_secs"),
+                    name,
+                    Breakdown::accepted().join(", ")
+                ));
+            }
+        }
+
+        for (name, secs) in &self.kind_cache_ttl_secs {
+            if *secs == 0 {
+                let key = describe("kind_cache_ttl_secs");
+                return Err(format!("{} gives {:?} a TTL of 0; it must be positive", key, name));
+            }
+            if name.parse::<QueryKind>().is_err() {
+                return Err(format!(
+                    "{} has unknown kind {:?}; expected one of {}",
+                    describe("kind_cache_ttl_secs"),
+                    name,
+                    QueryKind::accepted().join(", ")
+                ));
+            }
+        }
+
+        for key in &self.warm_keys {
+            if parse_warm_key(key).is_none() {
+                return Err(format!(
+                    "{} has {:?}; expected period=<period> with a period of {}",
+                    describe("warm_keys"),
+                    key,
+                    Period::accepted().join(", ")
+                ));
+            }
+        }
+
+        if let Some(path) = &self.fallback_file {
+            let key = describe("fallback_file");
+            let body = std::fs::read_to_string(path)
+                .map_err(|e| format!("{}: {}: {}", key, path.display(), e))?;
+            serde_json::from_str::<PlausibleResponse>(&body)
+                .map_err(|e| format!("{}: {} is not a leaderboard: {}", key, path.display(), e))?;
+            self.fallback = Some(body);
+        }
+
+        // Sources are matched case-insensitively.
+        let mut aliases = HashMap::new();
+        for (source, bucket) in std::mem::take(&mut self.source_aliases) {
+            if bucket.trim().is_empty() {
+                let key = describe("source_aliases");
+                return Err(format!("{} maps {:?} to an empty name", key, source));
+            }
+            aliases.insert(source.trim().to_ascii_lowercase(), bucket.trim().to_string());
+        }
+        self.source_aliases = aliases;
+
+        self.upstream_base_url = self.upstream_base_url.trim_end_matches('/').to_string();
+        if !self.upstream_base_url.starts_with("http://")
+            && !self.upstream_base_url.starts_with("https://")
+        {
+            return Err(format!("{} must be an http(s) URL", describe("upstream_base_url")));
+        }
+        for (key, url) in [
+            ("umami_base_url", &self.umami_base_url),
+            ("public_url", &self.public_url),
+            ("webhook_url", &self.webhook_url),
+            ("discord_webhook_url", &self.discord_webhook_url),
+            ("s3_endpoint", &self.s3_endpoint),
+            ("otel_exporter_otlp_endpoint", &self.otel_exporter_otlp_endpoint),
+        ] {
+            if let Some(url) = url {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(format!("{} must be an http(s) URL", describe(key)));
+                }
+            }
+        }
+
+        for (key, url) in [("https_proxy", &self.https_proxy), ("http_proxy", &self.http_proxy)] {
+            if let Some(url) = url {
+                reqwest::Proxy::all(url)
+                    .map_err(|_| format!("{} must be a proxy URL", describe(key)))?;
+            }
+        }
+        if let Some(path) = &self.ca_cert_path {
+            let pem = std::fs::read(path).map_err(|e| {
+                format!("{}: failed to read {}: {}", describe("ca_cert_path"), path.display(), e)
+            })?;
+            self.ca_certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .ok()
+                .filter(|certs| !certs.is_empty())
+                .ok_or_else(|| {
+                    let key = describe("ca_cert_path");
+                    format!("{} has no PEM certificates: {}", key, path.display())
+                })?;
+        }
+
+        if self.s3_bucket.is_some() {
+            for (key, value) in [
+                ("s3_endpoint", &self.s3_endpoint),
+                ("s3_access_key_id", &self.s3_access_key_id),
+                ("s3_secret_access_key", &self.s3_secret_access_key),
+            ] {
+                match value {
+                    None => return Err(format!("{} is required with s3_bucket", describe(key))),
+                    Some(value) if !value.bytes().all(|b| b.is_ascii_graphic()) => {
+                        return Err(format!("{} must be printable ASCII", describe(key)));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        self.s3_prefix = self.s3_prefix.trim_matches('/').to_string();
+        if let Some(url) = &mut self.public_url {
+            *url = url.trim_end_matches('/').to_string();
+        }
+        if let Some(endpoint) = &mut self.otel_exporter_otlp_endpoint {
+            *endpoint = endpoint.trim_end_matches('/').to_string();
+        }
+
+        if self.analytics_backend == AnalyticsBackend::Umami {
+            for (key, value) in [
+                ("umami_base_url", &self.umami_base_url),
+                ("umami_api_token", &self.umami_api_token),
+            ] {
+                if value.is_none() {
+                    return Err(format!("{} is required with the umami backend", describe(key)));
+                }
+            }
+            if let Some(url) = &mut self.umami_base_url {
+                *url = url.trim_end_matches('/').to_string();
+            }
+            if let Some(token) = &self.umami_api_token {
+                check_token(token, &describe("umami_api_token"))?;
+            }
+        }
+
+        if let Some(path) = &self.bearer_token_file {
+            if self.bearer_token.is_some() {
+                return Err(format!(
+                    "{} and {} are both set; use one",
+                    describe("bearer_token"),
+                    describe("bearer_token_file")
+                ));
+            }
+            let token = read_token_file(path)
+                .map_err(|e| format!("{}: {}", describe("bearer_token_file"), e))?;
+            self.bearer_token = Some(token);
+        }
+
+        if self.property.trim().is_empty() {
+            return Err(format!("{} must not be empty", describe("property")));
+        }
+        self.props.retain(|prop| *prop != self.property);
+
+        if self.sites.is_empty() {
+            self.sites.push(Site {
+                key: "default".to_string(),
+                id: self.site_id.clone(),
+                bearer_token: None,
+                goal: String::new(),
+                property: String::new(),
+            });
+        }
+        for site in &mut self.sites {
+            if site.goal.is_empty() {
+                site.goal = self.goal.clone();
+            }
+            if site.property.is_empty() {
+                site.property = self.property.clone();
+            }
+            let var = format!("BEARER_TOKEN_{}", site.key.to_uppercase().replace('-', "_"));
+            if let Ok(token) = std::env::var(var) {
+                site.bearer_token = Some(token);
+            }
+            site.bearer_token = site.bearer_token.take().filter(|token| !token.is_empty());
+            if site.bearer_token.is_none()
+                && self.bearer_token.is_none()
+                && self.analytics_backend == AnalyticsBackend::Plausible
+                && self.mock_data_file.is_empty()
+            {
+                return Err(format!(
+                    "{} must be set (in the environment, .env or the config file)",
+                    describe("bearer_token")
+                ));
+            }
+            if let Some(token) = &site.bearer_token {
+                check_token(token, &format!("the bearer token for site `{}`", site.key))?;
+            }
+        }
+        if let Some(token) = &self.bearer_token {
+            check_token(token, &describe("bearer_token"))?;
+        }
+
+        let real_token = self.bearer_token.is_some()
+            || self.umami_api_token.is_some()
+            || self.sites.iter().any(|site| site.bearer_token.is_some());
+        if !self.mock_data_file.is_empty() && real_token && !self.mock_data_allow_token {
+            return Err(format!(
+                "{} is set alongside a real token; unset one, or set {} if that is intended",
+                describe("mock_data_file"),
+                describe("mock_data_allow_token")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// How long the answer to `query` stays fresh when not `cache_ttl`:
+    /// for breakdowns with a TTL of their own in `breakdown_cache_ttl_secs`,
+    /// kinds of query in `kind_cache_ttl_secs`, and realtime counts.
+    pub fn query_cache_ttl(&self, query: &UpstreamQuery) -> Option<Duration> {
+        let breakdown = query
+            .breakdown_of()
+            .and_then(|breakdown| self.breakdown_cache_ttl_secs.get(breakdown.as_str()));
+        let kind = self.kind_cache_ttl_secs.get(query.kind().as_str());
+        if let Some(secs) = breakdown.or(kind) {
+            return Some(Duration::from_secs(*secs));
+        }
+        (query.kind() == QueryKind::Realtime).then_some(self.realtime_cache_ttl)
+    }
+
+    /// Puts back every setting that only takes effect at startup, such as
+    /// the listener and the upstream client, from `running`, returning the
+    /// names of those that differed. Called on a reloaded configuration so
+    /// it reflects what is actually in effect.
+    pub fn keep_fixed(&mut self, running: &Config) -> Vec<&'static str> {
+        let mut differed = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),* $(,)?) => {$(
+                if self.$field != running.$field {
+                    differed.push(stringify!($field));
+                    self.$field = running.$field.clone();
+                }
+            )*};
+        }
+        keep!(
+            bind_addr,
+            unix_socket_mode,
+            unix_socket_owner,
+            unix_socket_group,
+            tls_cert_path,
+            tls_key_path,
+            analytics_backend,
+            upstream_base_url,
+            bearer_token_file,
+            umami_base_url,
+            umami_website_id,
+            umami_api_token,
+            mock_data_file,
+            site_timezone,
+            cache_max_entries,
+            cache_max_bytes,
+            upstream_max_body_bytes,
+            upstream_connect_timeout,
+            upstream_pool_idle_timeout,
+            upstream_pool_max_idle_per_host,
+            upstream_max_concurrency,
+            https_proxy,
+            http_proxy,
+            no_proxy,
+            ca_cert_path,
+            danger_accept_invalid_certs,
+            circuit_failure_threshold,
+            circuit_cooldown,
+            metrics_enabled,
+            discord_webhook_url,
+            rate_limit_per_minute,
+            rate_limit_burst,
+            history_db,
+            s3_endpoint,
+            s3_bucket,
+            s3_region,
+            s3_access_key_id,
+            s3_secret_access_key,
+            otel_exporter_otlp_endpoint,
+            log_format,
+            header_read_timeout,
+            max_request_header_bytes,
+        );
+        // A token from a file is re-read by the fetcher itself.
+        if self.bearer_token != running.bearer_token && self.bearer_token_file.is_none() {
+            differed.push("bearer_token");
+        }
+        self.bearer_token = running.bearer_token.clone();
+        // Read from ca_cert_path, which just came back if it changed.
+        self.ca_certs = running.ca_certs.clone();
+        differed
+    }
+
+    /// The periods `warm_keys` names, checked when the configuration loads.
+    pub fn warm_periods(&self) -> Vec<Period> {
+        self.warm_keys.iter().filter_map(|key| parse_warm_key(key)).collect()
+    }
+
+    /// `site_timezone` parsed, checked when the configuration loads.
+    pub fn timezone(&self) -> chrono_tz::Tz {
+        self.site_timezone.trim().parse().unwrap_or(chrono_tz::Tz::UTC)
+    }
+
+    /// The date it is now in `site_timezone`.
+    pub fn today(&self) -> chrono::NaiveDate {
+        upstream::today(self.timezone())
+    }
+
+    /// `digest_utc_offset` parsed, or `None` when it is unset or isn't an
+    /// offset.
+    pub fn digest_offset(&self) -> Option<chrono::FixedOffset> {
+        match self.digest_utc_offset.trim() {
+            "UTC" | "utc" | "Z" => chrono::FixedOffset::east_opt(0),
+            offset => offset.parse().ok(),
+        }
+    }
+
+    /// The origins `cors_origins` allows, or `None` when it allows any.
+    pub fn cors_origin_list(&self) -> Option<Vec<&str>> {
+        match self.cors_origins.as_deref().map(str::trim) {
+            None | Some("*") => None,
+            Some(list) => Some(
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// `unix_socket_mode` as permission bits.
+    pub fn socket_mode(&self) -> Option<u32> {
+        let mode = self.unix_socket_mode.as_deref()?;
+        u32::from_str_radix(mode, 8).ok().filter(|&mode| mode <= 0o777)
+    }
+
+    /// Level of the per-request access log line, or `None` when silenced.
+    pub fn access_log(&self) -> Option<Level> {
+        self.access_log_level.parse().ok()
+    }
+}
+
+/// Where the server listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    /// `unix:<path>`.
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix: needs a socket path".to_string()),
+            Some(path) => Ok(BindAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(BindAddr::Tcp).map_err(|e| format!("{}: {}", s, e)),
+        }
+    }
+}
+
+impl std::fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{}", addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for BindAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which analytics service the stats are read from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyticsBackend {
+    #[default]
+    Plausible,
+    Umami,
+}
+
+/// The header a proxy records the client address in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Replaces the values of `SECRETS` in `table`, item by item for lists.
+fn redact(table: &mut toml::Table) {
+    let hidden = || toml::Value::String("[redacted]".to_string());
+    for key in SECRETS {
+        match table.get_mut(key) {
+            Some(toml::Value::Array(items)) => items.iter_mut().for_each(|item| *item = hidden()),
+            Some(value) => *value = hidden(),
+            None => {}
+        }
+    }
+}
+
+/// Rejects tokens that can't be sent in an `Authorization` header, which
+/// would otherwise only fail once a request is made.
+fn check_token(token: &str, name: &str) -> Result<(), String> {
+    HeaderValue::from_str(&format!("Bearer {}", token))
+        .map(|_| ())
+        .map_err(|_| format!("{} contains characters not allowed in an HTTP header", name))
+}
+
+/// The token in `path`, without the trailing newline secrets files tend to
+/// end with. The error never includes the file's contents.
+pub fn read_token_file(path: &Path) -> Result<String, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let token = contents.trim_end();
+    if token.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+    Ok(token.to_string())
+}
+
+/// `values` of `key` as networks, a bare IP standing for itself alone.
+fn parse_nets(key: &str, values: &[String]) -> Result<Vec<IpNet>, String> {
+    values
+        .iter()
+        .map(|value| {
+            let value = value.trim();
+            value
+                .parse()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    format!("{} has {:?}, which is not an IP or CIDR", describe(key), value)
+                })
+        })
+        .collect()
+}
+
+/// Names a key both ways it can be set, for error messages.
+fn describe(key: &str) -> String {
+    format!("`{}` ({})", key, key.to_uppercase())
+}
+
+/// The period a `warm_keys` entry names.
+fn parse_warm_key(key: &str) -> Option<Period> {
+    key.trim().strip_prefix("period=")?.parse().ok()
+}
+
+/// Overrides `target` with the environment variable `name`, if set.
+fn env<T: FromEnv>(name: &str, target: &mut T, expected: &str) -> Result<(), String> {
+    if let Ok(value) = std::env::var(name) {
+        *target = T::from_env(value.trim())
+            .ok_or_else(|| format!("{} must be {}, got {:?}", name, expected, value))?;
+    }
+    Ok(())
+}
+
+trait FromEnv: Sized {
+    fn from_env(value: &str) -> Option<Self>;
+}
+
+macro_rules! from_env_via_parse {
+    ($($ty:ty),*) => {
+        $(impl FromEnv for $ty {
+            fn from_env(value: &str) -> Option<Self> {
+                value.parse().ok()
+            }
+        })*
+    };
+}
+
+from_env_via_parse!(u16, u32, u64, usize, f64, String, IpAddr, BindAddr);
+
+impl FromEnv for bool {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl FromEnv for AnalyticsBackend {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "plausible" => Some(AnalyticsBackend::Plausible),
+            "umami" => Some(AnalyticsBackend::Umami),
+            _ => None,
+        }
+    }
+}
+
+impl FromEnv for ForwardedHeader {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Some(ForwardedHeader::XForwardedFor),
+            "forwarded" => Some(ForwardedHeader::Forwarded),
+            _ => None,
+        }
+    }
+}
+
+impl FromEnv for LogFormat {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl FromEnv for Duration {
+    fn from_env(value: &str) -> Option<Self> {
+        value.parse().ok().map(Duration::from_secs)
+    }
+}
+
+impl FromEnv for Option<String> {
+    fn from_env(value: &str) -> Option<Self> {
+        Some(Some(value.to_string()))
+    }
+}
+
+impl FromEnv for Vec<String> {
+    fn from_env(value: &str) -> Option<Self> {
+        Some(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+}
+
+impl<T: FromStr> FromEnv for HashMap<String, T> {
+    fn from_env(value: &str) -> Option<Self> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                let (key, value) = item.rsplit_once(':')?;
+                Some((key.trim().to_string(), value.trim().parse().ok()?))
+            })
+            .collect()
+    }
+}
+
+impl FromEnv for Vec<PathBuf> {
+    fn from_env(value: &str) -> Option<Self> {
+        let paths = Vec::<String>::from_env(value)?;
+        Some(paths.into_iter().map(PathBuf::from).collect())
+    }
+}
+
+impl FromEnv for Option<PathBuf> {
+    fn from_env(value: &str) -> Option<Self> {
+        Some(Some(PathBuf::from(value)))
+    }
+}
+
+/// Durations are written as whole seconds.
+mod secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished(mut config: Config) -> Result<Config, String> {
+        config.finish().map(|()| config)
+    }
+
+    #[test]
+    fn finish_tidies_a_valid_config() {
+        let config = finished(Config {
+            upstream_base_url: "https://plausible.example/".to_string(),
+            bearer_token: Some(" token\n".to_string()),
+            admin_token: Some("  ".to_string()),
+            api_keys: vec![" key-one ".to_string(), String::new()],
+            ..Config::default()
+        })
+        .unwrap();
+        assert_eq!(config.upstream_base_url, "https://plausible.example");
+        assert_eq!(config.bearer_token.as_deref(), Some("token"));
+        assert_eq!(config.admin_token, None);
+        assert_eq!(config.api_keys, ["key-one"]);
+    }
+
+    #[test]
+    fn finish_names_the_setting_at_fault() {
+        let cases: [(Config, &str); 9] = [
+            (
+                Config {
+                    upstream_attempts: 0,
+                    ..Config::default()
+                },
+                "`upstream_attempts` (UPSTREAM_ATTEMPTS) must be a positive integer",
+            ),
+            (
+                Config {
+                    cache_ttl_jitter_percent: 100,
+                    ..Config::default()
+                },
+                "`cache_ttl_jitter_percent` (CACHE_TTL_JITTER_PERCENT) must be less than 100",
+            ),
+            (
+                Config {
+                    cache_ttl: Duration::from_secs(60),
+                    negative_cache_ttl: Duration::from_secs(16),
+                    ..Config::default()
+                },
+                "`negative_cache_ttl_secs` (NEGATIVE_CACHE_TTL_SECS) must be at most a quarter",
+            ),
+            (
+                Config {
+                    spike_baseline_samples: 2,
+                    ..Config::default()
+                },
+                "`spike_baseline_samples` (SPIKE_BASELINE_SAMPLES) must be at least 3",
+            ),
+            (
+                Config {
+                    tls_cert_path: Some(PathBuf::from("cert.pem")),
+                    ..Config::default()
+                },
+                "`tls_key_path` (TLS_KEY_PATH) is required with tls_cert_path",
+            ),
+            (
+                Config {
+                    site_timezone: "Mars/Olympus_Mons".to_string(),
+                    ..Config::default()
+                },
+                "`site_timezone` (SITE_TIMEZONE) must be an IANA time zone name",
+            ),
+            (
+                Config {
+                    digest_utc_offset: "+25:00".to_string(),
+                    ..Config::default()
+                },
+                "`digest_utc_offset` (DIGEST_UTC_OFFSET) must be UTC or an offset",
+            ),
+            (
+                Config {
+                    upstream_base_url: "plausible.example".to_string(),
+                    ..Config::default()
+                },
+                "`upstream_base_url` (UPSTREAM_BASE_URL) must be an http(s) URL",
+            ),
+            (Config::default(), "`bearer_token` (BEARER_TOKEN) must be set"),
+        ];
+        for (config, expected) in cases {
+            let e = finished(config).expect_err(expected);
+            assert!(e.starts_with(expected), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::plausible::ArtistRow;
+use crate::spikes::Spike;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// Color down the side of the embed.
+const COLOR: u32 = 0x007ec6;
+/// Tries per message when Discord answers 429.
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts to a Discord webhook, keeping to the rate limit Discord reports
+/// in its response headers.
+pub struct Discord {
+    client: reqwest::Client,
+    url: String,
+    /// Earliest time the next message may be sent.
+    ready_at: Instant,
+}
+
+impl Discord {
+    pub fn new(client: reqwest::Client, url: String) -> Self {
+        Discord {
+            client,
+            url,
+            ready_at: Instant::now(),
+        }
+    }
+
+    /// Sends `body`, waiting out the rate limit first and retrying when
+    /// Discord still answers 429.
+    pub async fn send(&mut self, body: &serde_json::Value) -> Result<(), String> {
+        for attempt in 1..=ATTEMPTS {
+            tokio::time::sleep_until(self.ready_at).await;
+            let response = self
+                .client
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .timeout(TIMEOUT)
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let status = response.status();
+            let headers = response.headers();
+            let header = |name: &str| {
+                headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<f64>().ok())
+            };
+            if header("x-ratelimit-remaining") == Some(0.0) {
+                if let Some(reset_after) = header("x-ratelimit-reset-after") {
+                    self.ready_at = Instant::now() + Duration::from_secs_f64(reset_after.max(0.0));
+                }
+            }
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response
+                    .json::<serde_json::Value>()
+                    .await
+                    .ok()
+                    .and_then(|body| body["retry_after"].as_f64())
+                    .unwrap_or(1.0);
+                self.ready_at = Instant::now() + Duration::from_secs_f64(retry_after.max(0.0));
+                tracing::debug!(
+                    "Discord rate limited attempt {}/{}, retrying in {:.1}s",
+                    attempt,
+                    ATTEMPTS,
+                    retry_after
+                );
+                continue;
+            }
+            if !status.is_success() {
+                return Err(format!("Discord answered {}", status));
+            }
+            return Ok(());
+        }
+        Err("still rate limited".to_string())
+    }
+}
+
+/// The message announcing that the leaderboard's top positions went from
+/// `before` to `after`. Only positions whose artist changed are listed.
+pub fn announcement(
+    site: &str,
+    before: &[ArtistRow],
+    after: &[ArtistRow],
+    at: SystemTime,
+) -> serde_json::Value {
+    let (title, description) = match (before.first(), after.first()) {
+        (Some(old), Some(new)) if after.len() == 1 && old.name != new.name => (
+            format!("New #1: {}", new.name),
+            format!(
+                "**{}** is now the top artist with {}, displacing **{}**.",
+                escape(&new.name),
+                counts(new),
+                escape(&old.name)
+            ),
+        ),
+        _ => {
+            let mut description = String::new();
+            for (index, row) in after.iter().enumerate() {
+                let was = before.get(index).map(|row| row.name.as_str());
+                if was == Some(row.name.as_str()) {
+                    continue;
+                }
+                let name = escape(&row.name);
+                let _ = write!(description, "**#{}** {} — {}", index + 1, name, counts(row));
+                if let Some(was) = was {
+                    let _ = write!(description, " (was {})", escape(was));
+                }
+                description.push('\n');
+            }
+            (format!("Top {} changed", after.len()), description)
+        }
+    };
+
+    serde_json::json!({
+        "embeds": [{
+            "title": title,
+            "description": description,
+            "color": COLOR,
+            "timestamp": chrono::DateTime::<chrono::Utc>::from(at).to_rfc3339(),
+            "footer": { "text": site },
+        }],
+        // Artist names are untrusted; never let them ping anyone.
+        "allowed_mentions": { "parse": [] },
+    })
+}
+
+/// The message announcing that `spikes` showed up in `site`'s leaderboard.
+pub fn spikes(site: &str, spikes: &[Spike], at: SystemTime) -> serde_json::Value {
+    let mut description = String::new();
+    for spike in spikes {
+        let _ = writeln!(
+            description,
+            "**{}** gained {} visitors (usually {:.0} ± {:.0}), now at {}",
+            escape(&spike.artist),
+            spike.delta,
+            spike.baseline.mean,
+            spike.baseline.stddev,
+            spike.visitors
+        );
+    }
+    let title = match spikes {
+        [spike] => format!("Spike: {}", spike.artist),
+        _ => format!("{} artists spiked", spikes.len()),
+    };
+
+    serde_json::json!({
+        "embeds": [{
+            "title": title,
+            "description": description,
+            "color": COLOR,
+            "timestamp": chrono::DateTime::<chrono::Utc>::from(at).to_rfc3339(),
+            "footer": { "text": site },
+        }],
+        "allowed_mentions": { "parse": [] },
+    })
+}
+
+fn counts(row: &ArtistRow) -> String {
+    format!("{} visitors ({} clicks)", row.visitors, row.events)
+}
+
+/// Escapes Discord markdown so names are shown as written.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,351 @@
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::time::Duration;
+
+/// Upstream failure. Holds rendered messages rather than the `reqwest::Error`
+/// itself so a single result can be handed to every coalesced waiter.
+#[derive(Clone, Debug)]
+pub enum FetchError {
+    Timeout(String),
+    Connect(String),
+    Request(String),
+    Body(String),
+    /// The body ran past `upstream_max_body_bytes` and was abandoned.
+    TooLarge { limit: u64 },
+    Status {
+        status: reqwest::StatusCode,
+        snippet: String,
+        /// The upstream's `Retry-After`, when it sent one.
+        retry_after: Option<Duration>,
+    },
+    Invalid(String),
+    /// The analytics backend has no equivalent of the query.
+    Unsupported(String),
+    /// Refused without a request because the circuit breaker is open.
+    CircuitOpen { retry_in: Duration },
+    /// Refused without a request because the upstream rate-limited us and
+    /// asked for time.
+    Throttled { retry_in: Duration },
+    /// Refused without a request because every upstream slot stayed taken
+    /// for `upstream_queue_timeout`.
+    Busy { waited: Duration },
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Timeout(e) | FetchError::Connect(e) | FetchError::Request(e) => {
+                write!(f, "Error fetching data: {}", e)
+            }
+            FetchError::Body(e) => write!(f, "Error reading response: {}", e),
+            FetchError::TooLarge { limit } => {
+                write!(f, "Upstream response is larger than {} bytes", limit)
+            }
+            FetchError::Status { status, snippet, .. } => {
+                write!(f, "Upstream returned {}: {}", status, snippet)
+            }
+            FetchError::Invalid(reason) => write!(f, "Invalid upstream response: {}", reason),
+            FetchError::Unsupported(what) => write!(f, "Backend does not support {}", what),
+            FetchError::CircuitOpen { retry_in } => {
+                write!(f, "Circuit open, next upstream probe in {:.1?}", retry_in)
+            }
+            FetchError::Throttled { retry_in } => {
+                write!(f, "Upstream rate limit, next request allowed in {:.1?}", retry_in)
+            }
+            FetchError::Busy { waited } => {
+                write!(f, "No upstream slot came free within {:.1?}", waited)
+            }
+        }
+    }
+}
+
+impl FetchError {
+    pub fn from_request(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            FetchError::Timeout(e.to_string())
+        } else if e.is_connect() {
+            FetchError::Connect(e.to_string())
+        } else {
+            FetchError::Request(e.to_string())
+        }
+    }
+
+    /// 504 when the upstream could not be reached in time, 503 while the
+    /// circuit is open, the upstream's rate limit is waited out or too many
+    /// fetches are already running, 501 when the backend can't answer the
+    /// query at all, 502 when it answered with something unusable.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            FetchError::Timeout(_) | FetchError::Connect(_) => StatusCode::GATEWAY_TIMEOUT,
+            FetchError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            FetchError::CircuitOpen { .. }
+            | FetchError::Throttled { .. }
+            | FetchError::Busy { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Stable machine-readable identifier for the failure.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FetchError::Timeout(_) => "upstream_timeout",
+            FetchError::Connect(_) => "upstream_unreachable",
+            FetchError::Request(_) => "upstream_request_failed",
+            FetchError::Body(_) => "upstream_read_failed",
+            FetchError::TooLarge { .. } => "upstream_response_too_large",
+            FetchError::Status { .. } => "upstream_error",
+            FetchError::Invalid(_) => "upstream_invalid_response",
+            FetchError::Unsupported(_) => "upstream_unsupported",
+            FetchError::CircuitOpen { .. } => "upstream_circuit_open",
+            FetchError::Throttled { .. } => "upstream_rate_limited",
+            FetchError::Busy { .. } => "upstream_busy",
+        }
+    }
+
+    /// Client-facing description. Unlike `Display`, this never includes
+    /// the upstream URL or response body.
+    pub fn public_message(&self) -> &'static str {
+        match self {
+            FetchError::Timeout(_) => "Upstream request timed out",
+            FetchError::Connect(_) => "Could not connect to upstream",
+            FetchError::Request(_) => "Upstream request failed",
+            FetchError::Body(_) => "Failed to read upstream response",
+            FetchError::TooLarge { .. } => "Upstream response was too large",
+            FetchError::Status { .. } => "Upstream returned an error",
+            FetchError::Invalid(_) => "Upstream returned an invalid response",
+            FetchError::Unsupported(_) => "Not available from the configured analytics backend",
+            FetchError::CircuitOpen { .. } => "Upstream is temporarily unavailable",
+            FetchError::Throttled { .. } => "Upstream is rate limiting requests",
+            FetchError::Busy { .. } => "Too many upstream requests are in progress",
+        }
+    }
+
+    /// Whether trying again later might succeed. Upstream 4xx responses
+    /// (bad token, bad query) won't fix themselves, except rate limiting.
+    pub fn retryable(&self) -> bool {
+        match self {
+            FetchError::Status { status, .. } => status.is_server_error() || status.as_u16() == 429,
+            FetchError::Unsupported(_) | FetchError::TooLarge { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Connect errors, timeouts and upstream 5xx responses: the failures
+    /// worth retrying straight away.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FetchError::Timeout(_) | FetchError::Connect(_) => true,
+            FetchError::Status { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// Turned away before any request was sent: by the circuit breaker, the
+    /// upstream's rate limit or a full queue.
+    pub fn is_refusal(&self) -> bool {
+        matches!(
+            self,
+            FetchError::CircuitOpen { .. } | FetchError::Throttled { .. } | FetchError::Busy { .. }
+        )
+    }
+
+    pub fn upstream_status(&self) -> Option<u16> {
+        match self {
+            FetchError::Status { status, .. } => Some(status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// How long the client should wait before trying again, when known.
+    /// The upstream's own `Retry-After` is passed on, so a client turned
+    /// away by its rate limit doesn't come back before the limit resets.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::CircuitOpen { retry_in } | FetchError::Throttled { retry_in } => {
+                Some(*retry_in)
+            }
+            FetchError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Error body returned by every non-success response:
+/// `{"error": {"code": ..., "message": ..., "retryable": ...}}`. Codes are
+/// stable strings; internal details belong in the logs, never here.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: ErrorBody,
+}
+
+/// The `error` object of [`ErrorResponse`].
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable, machine-readable code such as `invalid_parameter`.
+    #[schema(value_type = String)]
+    code: &'static str,
+    message: String,
+    retryable: bool,
+    /// Valid values for a rejected parameter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accepted: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+    /// The oldest point that can be asked for, when the one asked for is
+    /// older.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oldest_available: Option<String>,
+}
+
+/// The body every error is sent as.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    error: ErrorBody,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            retry_after: None,
+            body: ErrorBody {
+                code,
+                message: message.into(),
+                retryable: false,
+                accepted: None,
+                upstream_status: None,
+                oldest_available: None,
+            },
+        }
+    }
+
+    /// A request parameter the client got wrong, with its valid values.
+    pub fn invalid_param(name: &str, accepted: &[&str]) -> Self {
+        let mut error = Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_parameter",
+            format!("Invalid `{}` parameter", name),
+        );
+        error.body.accepted = Some(accepted.iter().map(|value| value.to_string()).collect());
+        error
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        let mut error = Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests",
+        );
+        error.body.retryable = true;
+        error.retry_after = Some(retry_after);
+        error
+    }
+
+    /// The request took longer than `request_timeout` to answer.
+    pub fn timed_out() -> Self {
+        let mut error = Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "request_timeout",
+            "The request took too long to answer",
+        );
+        error.body.retryable = true;
+        error
+    }
+
+    pub fn body_not_allowed() -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_not_allowed",
+            "GET and HEAD requests must not have a body",
+        )
+    }
+
+    /// Nothing is retained from as far back as asked; `oldest` is the
+    /// oldest point that is, as RFC 3339 when there is one.
+    pub fn gone(oldest: Option<String>) -> Self {
+        let mut error = Self::new(
+            StatusCode::GONE,
+            "history_expired",
+            "Nothing that old is retained; fetch the full leaderboard instead",
+        );
+        error.body.oldest_available = oldest;
+        error
+    }
+
+    /// Too few snapshots cover the week asked for; `earliest` is the first
+    /// week enough do, when any does.
+    pub fn insufficient_history(earliest: Option<String>) -> Self {
+        let mut error = Self::not_found(
+            "insufficient_history",
+            "Not enough snapshots were recorded during that week",
+        );
+        error.body.oldest_available = earliest;
+        error
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.body.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.body.message
+    }
+
+    /// What the response's `error` object holds, for embedding elsewhere.
+    pub fn body(&self) -> &ErrorBody {
+        &self.body
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", "Not allowed from this address")
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or invalid credentials",
+        )
+    }
+}
+
+impl From<FetchError> for ApiError {
+    fn from(e: FetchError) -> Self {
+        let mut error = ApiError::new(e.status_code(), e.code(), e.public_message());
+        error.body.retryable = e.retryable();
+        error.body.upstream_status = e.upstream_status();
+        error.retry_after = e.retry_after();
+        error
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status,
+            [(CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+            Json(ErrorResponse { error: self.body }),
+        )
+            .into_response();
+        if let Some(retry_after) = self.retry_after {
+            // Round up so clients never come back before the probe is allowed.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
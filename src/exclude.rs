@@ -0,0 +1,60 @@
+use crate::plausible::normalize_name;
+use regex::Regex;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Junk names kept out of public output: bot and test events such as
+/// `undefined` or `null`. Exact names match case-insensitively after
+/// whitespace is normalized; patterns are regexes matched against the name
+/// as displayed.
+///
+/// A file of exclusions holds one name per line; a line wrapped in slashes,
+/// like `/^test\d*$/`, is a pattern, and blank lines and lines starting with
+/// `#` are skipped.
+#[derive(Debug, Default)]
+pub struct Exclusions {
+    names: HashSet<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Exclusions {
+    pub fn new(names: &[String], patterns: &[String]) -> Result<Self, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("invalid exclude pattern `{}`: {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Exclusions {
+            names: names
+                .iter()
+                .map(|name| normalize_name(name).to_lowercase())
+                .collect(),
+            patterns,
+        })
+    }
+
+    /// Reads the names and patterns in `path`.
+    pub fn read_file(path: &Path) -> Result<(Vec<String>, Vec<String>), String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut names = Vec::new();
+        let mut patterns = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+                Some(pattern) => patterns.push(pattern.to_string()),
+                None => names.push(line.to_string()),
+            }
+        }
+        Ok((names, patterns))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.contains(&normalize_name(name).to_lowercase())
+            || self.patterns.iter().any(|pattern| pattern.is_match(name))
+    }
+}
@@ -0,0 +1,110 @@
+use crate::history::ExportRow;
+use crate::plausible::ArtistRow;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Renders ranked rows as CSV: `rank,name,visitors,events`, then `share`
+/// and `previous_rank,rank_delta` when the rows carry them, followed by any
+/// extra metrics present on the rows, in sorted order. An empty slice yields
+/// just the header row.
+pub fn csv(rows: &[(usize, &ArtistRow)]) -> String {
+    let extra: BTreeSet<&str> = rows
+        .iter()
+        .flat_map(|(_, row)| row.extra.keys().map(String::as_str))
+        .collect();
+
+    let share = rows.iter().any(|(_, row)| row.share.is_some());
+    let movement = rows.iter().any(|(_, row)| row.movement.is_some());
+
+    let mut out = String::from("rank,name,visitors,events");
+    if share {
+        out.push_str(",share");
+    }
+    if movement {
+        out.push_str(",previous_rank,rank_delta");
+    }
+    for column in &extra {
+        out.push(',');
+        out.push_str(&field(column));
+    }
+    out.push_str("\r\n");
+
+    for (rank, row) in rows {
+        out.push_str(&format!(
+            "{},{},{},{}",
+            rank,
+            field(&row.name),
+            row.visitors,
+            row.events
+        ));
+        if share {
+            out.push(',');
+            if let Some(value) = row.share {
+                out.push_str(&value.to_string());
+            }
+        }
+        if movement {
+            out.push(',');
+            if let Some(rank) = row.movement.as_ref().and_then(|m| m.previous_rank) {
+                out.push_str(&rank.to_string());
+            }
+            out.push(',');
+            if let Some(delta) = row.movement.as_ref().and_then(|m| m.rank_delta) {
+                out.push_str(&delta.to_string());
+            }
+        }
+        for column in &extra {
+            out.push(',');
+            match row.extra.get(*column) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) => out.push_str(&field(s)),
+                Some(value) => out.push_str(&field(&value.to_string())),
+            }
+        }
+        out.push_str("\r\n");
+    }
+
+    out
+}
+
+/// The header row of a history export: with `artist`, one artist's totals
+/// per snapshot, otherwise every artist's.
+pub fn history_header(artist: bool) -> &'static str {
+    if artist {
+        "taken_at,visitors,events\r\n"
+    } else {
+        "taken_at,artist,visitors,events\r\n"
+    }
+}
+
+/// `rows` as CSV lines under `history_header(artist)`.
+pub fn history_rows(rows: &[ExportRow], artist: bool) -> String {
+    let mut out = String::new();
+    for row in rows {
+        let taken_at = chrono::DateTime::from_timestamp(row.taken_at, 0)
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_else(|| row.taken_at.to_string());
+        if artist {
+            out.push_str(&format!("{},{},{}\r\n", taken_at, row.visitors, row.events));
+        } else {
+            out.push_str(&format!(
+                "{},{},{},{}\r\n",
+                taken_at,
+                field(&row.name),
+                row.visitors,
+                row.events
+            ));
+        }
+    }
+    out
+}
+
+/// Quotes a field when it contains a delimiter, quote or line break,
+/// doubling embedded quotes per RFC 4180.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,103 @@
+use crate::badge::escape;
+use crate::plausible::ArtistRow;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::SystemTime;
+
+/// Artists listed in each entry.
+pub const TOP: usize = 10;
+/// Entries kept, newest first; older ones fall off the feed.
+const MAX_ENTRIES: usize = 20;
+
+/// An Atom feed of top-artist snapshots. A new entry is added only when
+/// the top artists or their counts actually change.
+#[derive(Default)]
+pub struct Feed {
+    entries: VecDeque<Entry>,
+}
+
+struct Entry {
+    /// Digest of the ranked names and counts, to spot unchanged refreshes.
+    digest: String,
+    updated: DateTime<Utc>,
+    rows: Vec<(String, u64)>,
+}
+
+impl Feed {
+    /// Adds an entry for the top of `rows`, fetched at `fetched_at`, unless
+    /// it matches the newest entry. Returns whether one was added.
+    pub fn record(&mut self, fetched_at: SystemTime, rows: &[ArtistRow]) -> bool {
+        let rows: Vec<(String, u64)> = rows
+            .iter()
+            .take(TOP)
+            .map(|row| (row.name.clone(), row.visitors))
+            .collect();
+        let mut hasher = Sha256::new();
+        for (name, visitors) in &rows {
+            hasher.update(format!("{}\n{}\n", name, visitors));
+        }
+        let digest = format!("{:x}", hasher.finalize());
+
+        if self.entries.front().is_some_and(|newest| newest.digest == digest) {
+            return false;
+        }
+        self.entries.push_front(Entry {
+            digest,
+            updated: fetched_at.into(),
+            rows,
+        });
+        self.entries.truncate(MAX_ENTRIES);
+        true
+    }
+
+    /// The feed as Atom XML. `authority` is a domain the feed's tag URIs are
+    /// minted under, normally the Plausible site ID.
+    pub fn render(&self, authority: &str) -> String {
+        let updated = self
+            .entries
+            .front()
+            .map_or_else(Utc::now, |newest| newest.updated)
+            .to_rfc3339();
+        let authority = escape(authority);
+
+        let mut out = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+        out.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+        let _ = write!(
+            out,
+            "<id>tag:{},2024:top-artists</id><title>Top artists</title><updated>{}</updated>",
+            authority, updated
+        );
+        let _ = write!(out, "<author><name>{}</name></author>", authority);
+        out.push_str(r#"<link rel="alternate" type="text/html" href="leaderboard"/>"#);
+
+        for entry in &self.entries {
+            let date = entry.updated.format("%Y-%m-%d");
+            let mut list = String::from("<ol>");
+            for (name, visitors) in &entry.rows {
+                let _ = write!(list, "<li>{} ({})</li>", escape(name), visitors);
+            }
+            list.push_str("</ol>");
+
+            // Stable across restarts: the same snapshot on the same day keeps
+            // its ID, so readers don't show it twice.
+            let _ = write!(
+                out,
+                concat!(
+                    "<entry><id>tag:{},{}:top-artists/{}</id>",
+                    "<title>Top artists — {}</title><updated>{}</updated>",
+                    r#"<content type="html">{}</content></entry>"#
+                ),
+                authority,
+                date,
+                &entry.digest[..16],
+                date,
+                entry.updated.to_rfc3339(),
+                escape(&list)
+            );
+        }
+        out.push_str("</feed>");
+        out
+    }
+}
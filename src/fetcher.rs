@@ -0,0 +1,275 @@
+use crate::config::Config;
+use crate::error::FetchError;
+use crate::upstream::{self, UpstreamQuery};
+use reqwest::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    RETRY_AFTER,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Issues single requests to the stats API. Retries, pagination, the
+/// circuit breaker and validation all sit on top of it, so a stand-in that
+/// answers from fixtures exercises the same paths the reqwest one does.
+#[axum::async_trait]
+pub trait StatsFetcher: Send + Sync {
+    /// Fetches page `page` of `query` within `timeout`, returning the body
+    /// of a successful answer. Any other status is a `FetchError::Status`.
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError>;
+
+    /// Like `fetch`, but lets the upstream answer `NotModified` when the
+    /// body `validators` came with is still current. Only answers that
+    /// when `validators` has one. By default validators are neither sent
+    /// nor recorded, leaving change detection to the caller.
+    async fn fetch_if_modified(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+        validators: &Validators,
+    ) -> Result<Fetched, FetchError> {
+        let _ = validators;
+        let body = self.fetch(query, page, timeout).await?;
+        Ok(Fetched::Modified {
+            body,
+            validators: Validators::default(),
+        })
+    }
+
+    /// Re-reads credentials kept in files, for a reload. Nothing by default.
+    fn reload_credentials(&self) {}
+}
+
+/// The `ETag` and `Last-Modified` an upstream body came with, sent back on
+/// the next request for it as `If-None-Match` and `If-Modified-Since`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Validators {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+}
+
+/// The answer to a conditional request.
+pub enum Fetched {
+    /// A body, with the validators to send for it next time.
+    Modified { body: String, validators: Validators },
+    /// 304 Not Modified: the body the validators came with is current.
+    NotModified,
+}
+
+/// Fetches from the Plausible API at `upstream_base_url`.
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    base_url: String,
+    /// Used for sites without a token of their own.
+    bearer_token: RwLock<Option<String>>,
+    /// Where `bearer_token` is read from, when it is kept in a file.
+    token_file: Option<PathBuf>,
+    max_body_bytes: u64,
+    /// Where the `date` each request is sent on is reckoned, so relative
+    /// periods end on the day Plausible's dashboard shows.
+    timezone: chrono_tz::Tz,
+}
+
+impl HttpFetcher {
+    pub fn new(config: &Config) -> Self {
+        HttpFetcher {
+            client: crate::http_client(config),
+            base_url: config.upstream_base_url.clone(),
+            bearer_token: RwLock::new(config.bearer_token.clone()),
+            token_file: config.bearer_token_file.clone(),
+            max_body_bytes: config.upstream_max_body_bytes,
+            timezone: config.timezone(),
+        }
+    }
+
+    /// The `Authorization` header for `query`, marked sensitive so it is
+    /// redacted from debug output.
+    fn authorization(&self, query: &UpstreamQuery) -> HeaderValue {
+        let shared = self.bearer_token.read().unwrap();
+        let token = query.bearer_token().or(shared.as_deref()).unwrap_or_default();
+        let mut value: HeaderValue = format!("Bearer {}", token)
+            .parse()
+            .expect("bearer tokens are validated when they are read");
+        value.set_sensitive(true);
+        value
+    }
+
+    /// Reads `token_file` again, returning whether it now holds a different
+    /// token. One that can't be read or sent is logged, without its
+    /// contents, and the current token kept.
+    fn reread_token(&self) -> bool {
+        let Some(path) = &self.token_file else {
+            return false;
+        };
+        let token = match crate::config::read_token_file(path) {
+            Ok(token) if HeaderValue::from_str(&format!("Bearer {}", token)).is_ok() => token,
+            Ok(_) => {
+                let reason = "it contains characters not allowed in an HTTP header";
+                tracing::warn!("Kept the bearer token, {} is invalid: {}", path.display(), reason);
+                return false;
+            }
+            Err(e) => {
+                tracing::warn!("Kept the bearer token: {}", e);
+                return false;
+            }
+        };
+        let mut current = self.bearer_token.write().unwrap();
+        if current.as_deref() == Some(token.as_str()) {
+            return false;
+        }
+        *current = Some(token);
+        tracing::info!("Read a new bearer token from {}", path.display());
+        true
+    }
+
+    async fn send(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+        validators: &Validators,
+    ) -> Result<reqwest::Response, FetchError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, self.authorization(query));
+        // Values the upstream sent, so they are valid header values.
+        if let Some(etag) = validators.etag.as_deref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = validators.last_modified.as_deref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_MODIFIED_SINCE, date);
+        }
+
+        self.client
+            .get(query.url(&self.base_url))
+            .query(&query.params(upstream::today(self.timezone), page))
+            .headers(headers)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(FetchError::from_request)
+    }
+}
+
+#[axum::async_trait]
+impl StatsFetcher for HttpFetcher {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError> {
+        match self.fetch_if_modified(query, page, timeout, &Validators::default()).await? {
+            Fetched::Modified { body, .. } => Ok(body),
+            Fetched::NotModified => unreachable!("no validators were sent"),
+        }
+    }
+
+    async fn fetch_if_modified(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+        validators: &Validators,
+    ) -> Result<Fetched, FetchError> {
+        let mut response = self.send(query, page, timeout, validators).await?;
+        // A rejected token from a file may have been rotated since it was
+        // read; retrying with the new one makes the rotation hitless.
+        if response.status() == StatusCode::UNAUTHORIZED
+            && query.bearer_token().is_none()
+            && self.reread_token()
+        {
+            response = self.send(query, page, timeout, validators).await?;
+        }
+
+        let status = response.status();
+        let code = i64::from(status.as_u16());
+        tracing::Span::current().set_attribute("http.response.status_code", code);
+        if status == StatusCode::NOT_MODIFIED && !validators.is_empty() {
+            return Ok(Fetched::NotModified);
+        }
+        let received = Validators::from_headers(response.headers());
+        let retry_after = retry_after(response.headers());
+        let body = read_body(response, self.max_body_bytes).await?;
+
+        if !status.is_success() {
+            return Err(FetchError::Status {
+                status,
+                snippet: crate::snippet(&body),
+                retry_after,
+            });
+        }
+        Ok(Fetched::Modified {
+            body,
+            validators: received,
+        })
+    }
+
+    fn reload_credentials(&self) {
+        self.reread_token();
+    }
+}
+
+/// Reads `response`'s body a chunk at a time, giving up as soon as it
+/// passes `max_bytes` rather than buffering the rest.
+pub(crate) async fn read_body(
+    mut response: reqwest::Response,
+    max_bytes: u64,
+) -> Result<String, FetchError> {
+    let too_large = FetchError::TooLarge { limit: max_bytes };
+    if response.content_length().is_some_and(|length| length > max_bytes) {
+        return Err(too_large);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| FetchError::Body(e.to_string()))?
+    {
+        if (body.len() + chunk.len()) as u64 > max_bytes {
+            return Err(too_large);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// The wait a `Retry-After` header asks for, given either as seconds or as
+/// an HTTP date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let until = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((until.to_utc() - chrono::Utc::now()).to_std().unwrap_or_default())
+}
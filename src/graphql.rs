@@ -0,0 +1,1939 @@
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Map, Value as Json};
+use std::collections::HashMap;
+
+/// Body of a `POST /graphql`.
+#[derive(Deserialize)]
+pub struct Request {
+    pub query: String,
+    #[serde(default, rename = "operationName")]
+    pub operation_name: Option<String>,
+    #[serde(default)]
+    pub variables: Option<Map<String, Json>>,
+}
+
+/// What a query may do, from the configuration.
+pub struct Options {
+    /// Whether `Query.history` exists.
+    pub history: bool,
+    /// Whether `__schema` and `__type` answer.
+    pub introspection: bool,
+    /// Deepest nesting of fields allowed, counting the root fields as 1.
+    /// Inline fragments count as a level too, and so, within a value, do
+    /// lists and input objects.
+    pub max_depth: usize,
+    /// Most fields a query may select, counting each occurrence once with
+    /// fragments expanded.
+    pub max_complexity: usize,
+}
+
+/// An entry of a response's `errors`.
+#[derive(Debug, Serialize)]
+pub struct Error {
+    pub message: String,
+    /// Response keys and list indexes down to the field that failed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<Json>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Extensions>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Extensions {
+    /// The code the REST routes answer the same failure with.
+    pub code: &'static str,
+}
+
+impl Error {
+    pub fn new(message: impl Into<String>) -> Self {
+        Error {
+            message: message.into(),
+            path: Vec::new(),
+            extensions: None,
+        }
+    }
+
+    pub fn with_code(message: impl Into<String>, code: &'static str) -> Self {
+        Error {
+            extensions: Some(Extensions { code }),
+            ..Error::new(message)
+        }
+    }
+
+    fn at(mut self, segment: impl Into<Json>) -> Self {
+        self.path.insert(0, segment.into());
+        self
+    }
+}
+
+/// An object type of the schema.
+struct ObjectType {
+    name: &'static str,
+    description: &'static str,
+    fields: &'static [FieldDef],
+}
+
+struct FieldDef {
+    name: &'static str,
+    description: &'static str,
+    args: &'static [ArgDef],
+    /// In SDL notation, such as `[Artist!]`.
+    ty: &'static str,
+}
+
+struct ArgDef {
+    name: &'static str,
+    description: &'static str,
+    ty: &'static str,
+}
+
+const fn field(name: &'static str, ty: &'static str, description: &'static str) -> FieldDef {
+    FieldDef {
+        name,
+        description,
+        args: &[],
+        ty,
+    }
+}
+
+const QUERY: ObjectType = ObjectType {
+    name: "Query",
+    description: "The leaderboard read model. Every field reads the same cache as the REST \
+        routes, fetching upstream only when they would.",
+    fields: &[
+        FieldDef {
+            name: "leaderboard",
+            description: "Artists by visitors, most first.",
+            args: &[
+                ArgDef {
+                    name: "period",
+                    description: "Time range, as `?period=` on the REST routes; all time \
+                        by default.",
+                    ty: "String",
+                },
+                ArgDef {
+                    name: "limit",
+                    description: "Most artists to return, from 1 to 1000.",
+                    ty: "Int",
+                },
+                ArgDef {
+                    name: "minVisitors",
+                    description: "Leave out artists with fewer visitors.",
+                    ty: "Int",
+                },
+            ],
+            ty: "[Artist!]",
+        },
+        FieldDef {
+            name: "artist",
+            description: "One artist on the all-time leaderboard, matched case-insensitively; \
+                null when there is no such artist.",
+            args: &[ArgDef {
+                name: "name",
+                description: "The artist's name.",
+                ty: "String!",
+            }],
+            ty: "Artist",
+        },
+        FieldDef {
+            name: "summary",
+            description: "Totals across the whole leaderboard.",
+            args: &[ArgDef {
+                name: "period",
+                description: "Time range, as `?period=` on the REST routes; all time by default.",
+                ty: "String",
+            }],
+            ty: "Summary",
+        },
+        FieldDef {
+            name: "history",
+            description: "One artist's totals per recorded snapshot, oldest first.",
+            args: &[
+                ArgDef {
+                    name: "artist",
+                    description: "The artist's name.",
+                    ty: "String!",
+                },
+                ArgDef {
+                    name: "from",
+                    description: "Earliest snapshot, as an RFC 3339 timestamp or a \
+                        `YYYY-MM-DD` date.",
+                    ty: "String",
+                },
+                ArgDef {
+                    name: "to",
+                    description: "Latest snapshot, as an RFC 3339 timestamp or a `YYYY-MM-DD` \
+                        date, which covers the whole day.",
+                    ty: "String",
+                },
+            ],
+            ty: "[Point!]",
+        },
+    ],
+};
+
+const ARTIST: ObjectType = ObjectType {
+    name: "Artist",
+    description: "One artist's row of a leaderboard.",
+    fields: &[
+        field("name", "String!", ""),
+        field("rank", "Int!", "Position on the leaderboard, from 1."),
+        field("visitors", "Int!", ""),
+        field("events", "Int!", ""),
+        field("share", "Float", "Fraction of all visitors on the leaderboard."),
+        field(
+            "previousRank",
+            "Int",
+            "Position on the previous leaderboard; null for an artist that wasn't on it.",
+        ),
+        field("rankDelta", "Int", "Places moved up since the previous leaderboard."),
+    ],
+};
+
+const SUMMARY: ObjectType = ObjectType {
+    name: "Summary",
+    description: "Totals across a leaderboard.",
+    fields: &[
+        field("totalVisitors", "Int!", ""),
+        field("totalEvents", "Int!", ""),
+        field("artistCount", "Int!", ""),
+        field("topArtist", "String", ""),
+        field(
+            "generatedAt",
+            "String!",
+            "When the leaderboard was fetched upstream, as RFC 3339.",
+        ),
+    ],
+};
+
+const POINT: ObjectType = ObjectType {
+    name: "Point",
+    description: "One artist's totals as of a snapshot.",
+    fields: &[
+        field("takenAt", "String!", "When the snapshot was taken, as RFC 3339."),
+        field("visitors", "Int!", ""),
+        field("events", "Int!", ""),
+    ],
+};
+
+const SCALARS: [(&str, &str); 4] = [
+    ("Boolean", "`true` or `false`."),
+    ("Float", "A double-precision floating-point number."),
+    ("Int", "A signed 32-bit integer."),
+    ("String", "UTF-8 text."),
+];
+
+/// A value written in a query.
+#[derive(Clone, Debug)]
+enum Value {
+    Null,
+    Int(i64),
+    Float(f64),
+    String(String),
+    Boolean(bool),
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+    Variable(String),
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    arguments: Vec<(String, Value)>,
+}
+
+#[derive(Debug)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    arguments: Vec<(String, Value)>,
+    directives: Vec<Directive>,
+    selections: Vec<Selection>,
+}
+
+impl Field {
+    fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug)]
+enum Selection {
+    Field(Field),
+    Spread {
+        name: String,
+        directives: Vec<Directive>,
+    },
+    Inline {
+        on: Option<String>,
+        directives: Vec<Directive>,
+        selections: Vec<Selection>,
+    },
+}
+
+struct VariableDef {
+    name: String,
+    required: bool,
+    default: Option<Value>,
+}
+
+struct Operation {
+    name: Option<String>,
+    kind: String,
+    variables: Vec<VariableDef>,
+    selections: Vec<Selection>,
+}
+
+struct Fragment {
+    on: String,
+    selections: Vec<Selection>,
+}
+
+/// A parsed, validated query, ready to run.
+pub struct Query {
+    operation: Operation,
+    fragments: HashMap<String, Fragment>,
+    variables: Map<String, Json>,
+    history: bool,
+}
+
+/// A field of the `Query` type to resolve, with every selection of it under
+/// the same response key merged.
+pub struct RootField<'a> {
+    pub key: String,
+    pub name: String,
+    pub args: Map<String, Json>,
+    fields: Vec<&'a Field>,
+}
+
+/// A response body, with fields in the order they were asked for.
+enum Output {
+    Value(Json),
+    List(Vec<Output>),
+    Object(Vec<(String, Output)>),
+}
+
+impl Serialize for Output {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Output::Value(value) => value.serialize(serializer),
+            Output::List(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Output::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (key, value) in fields {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// `{"data": ..., "errors": [...]}`.
+#[derive(Serialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Output>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Error>,
+}
+
+impl Response {
+    /// A request refused before anything ran.
+    pub fn rejected(errors: Vec<Error>) -> Self {
+        Response { data: None, errors }
+    }
+}
+
+impl Query {
+    /// Parses and validates `request`, picking the operation to run.
+    pub fn prepare(request: Request, options: &Options) -> Result<Query, Vec<Error>> {
+        let (operations, fragments) = Parser::new(&request.query, options.max_depth)
+            .and_then(|mut parser| parser.document())
+            .map_err(|e| vec![e])?;
+
+        let mut operations = operations;
+        let operation = match request.operation_name.as_deref() {
+            Some(name) => match operations.iter().position(|op| op.name.as_deref() == Some(name)) {
+                Some(index) => operations.swap_remove(index),
+                None => return Err(vec![Error::new(format!("Unknown operation `{}`", name))]),
+            },
+            None if operations.len() == 1 => operations.remove(0),
+            None => {
+                let message = "`operationName` is required when a query has several operations";
+                return Err(vec![Error::new(message)]);
+            }
+        };
+        if operation.kind != "query" {
+            let message = format!("Only queries are supported, not {}s", operation.kind);
+            return Err(vec![Error::new(message)]);
+        }
+
+        let mut provided = request.variables.unwrap_or_default();
+        let mut variables = Map::new();
+        for variable in &operation.variables {
+            match provided.remove(&variable.name) {
+                Some(value) => {
+                    variables.insert(variable.name.clone(), value);
+                }
+                None => match &variable.default {
+                    Some(default) => {
+                        let value = constant(default).map_err(|e| vec![e])?;
+                        variables.insert(variable.name.clone(), value);
+                    }
+                    None if variable.required => {
+                        let message = format!("Variable `${}` is required", variable.name);
+                        return Err(vec![Error::new(message)]);
+                    }
+                    None => {}
+                },
+            }
+        }
+
+        let query = Query {
+            operation,
+            fragments,
+            variables,
+            history: options.history,
+        };
+        // Measured first: it catches fragments that spread themselves,
+        // which validating would recurse into forever.
+        let (depth, complexity) = query
+            .measure(&query.operation.selections, &mut Vec::new(), options.max_depth)
+            .map_err(|e| vec![e])?;
+        let mut errors = Vec::new();
+        query.validate(QUERY.name, &query.operation.selections, options, &mut errors);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        if depth > options.max_depth {
+            let message = format!(
+                "Query is nested {} fields deep; at most {} is allowed",
+                depth, options.max_depth
+            );
+            return Err(vec![Error::with_code(message, "query_too_deep")]);
+        }
+        if complexity > options.max_complexity {
+            let message = format!(
+                "Query selects {} fields; at most {} are allowed",
+                complexity, options.max_complexity
+            );
+            return Err(vec![Error::with_code(message, "query_too_complex")]);
+        }
+        Ok(query)
+    }
+
+    /// The fields of `Query` the operation asks for, in order.
+    pub fn root_fields(&self) -> Result<Vec<RootField<'_>>, Error> {
+        let selections: Vec<&Selection> = self.operation.selections.iter().collect();
+        self.collect(QUERY.name, &selections)?
+            .into_iter()
+            .map(|(key, fields)| {
+                let mut args = Map::new();
+                for (name, value) in &fields[0].arguments {
+                    if let Some(value) = self.resolve(value).map_err(|e| e.at(key.as_str()))? {
+                        args.insert(name.clone(), value);
+                    }
+                }
+                Ok(RootField {
+                    name: fields[0].name.clone(),
+                    key,
+                    args,
+                    fields,
+                })
+            })
+            .collect()
+    }
+
+    /// The value of a root field answered here rather than from the read
+    /// model: `__typename` and introspection.
+    pub fn builtin(&self, field: &RootField<'_>) -> Option<Json> {
+        match field.name.as_str() {
+            "__typename" => Some(QUERY.name.into()),
+            "__schema" => Some(self.schema_json()),
+            "__type" => {
+                let name = field.args.get("name").and_then(Json::as_str).unwrap_or_default();
+                Some(self.named_type(name).unwrap_or(Json::Null))
+            }
+            _ => None,
+        }
+    }
+
+    /// The response for the root fields' values, each cut down to what was
+    /// selected from it.
+    pub fn respond(&self, resolved: Vec<(RootField<'_>, Result<Json, Error>)>) -> Response {
+        let mut data = Vec::new();
+        let mut errors = Vec::new();
+        for (field, value) in resolved {
+            let ty = self.field_type(QUERY.name, &field.name);
+            let selections: Vec<&Selection> =
+                field.fields.iter().flat_map(|field| &field.selections).collect();
+            let output = value.and_then(|value| self.project(&value, ty, &selections));
+            match output {
+                Ok(output) => data.push((field.key, output)),
+                Err(e) => {
+                    errors.push(e.at(field.key.as_str()));
+                    data.push((field.key, Output::Value(Json::Null)));
+                }
+            }
+        }
+        Response {
+            data: Some(Output::Object(data)),
+            errors,
+        }
+    }
+
+    fn object(&self, name: &str) -> Option<&'static ObjectType> {
+        let types: &[&ObjectType] = if self.history {
+            &[&QUERY, &ARTIST, &SUMMARY, &POINT]
+        } else {
+            &[&QUERY, &ARTIST, &SUMMARY]
+        };
+        types.iter().copied().find(|ty| ty.name == name)
+    }
+
+    fn field_def(&self, ty: &str, name: &str) -> Option<&'static FieldDef> {
+        let object = self.object(ty)?;
+        if object.name == QUERY.name && name == "history" && !self.history {
+            return None;
+        }
+        object.fields.iter().find(|field| field.name == name)
+    }
+
+    /// The named type of field `name` of `ty`, or "" when the schema doesn't
+    /// say, as for introspection.
+    fn field_type(&self, ty: &str, name: &str) -> &'static str {
+        self.field_def(ty, name).map_or("", |field| named(field.ty))
+    }
+
+    fn validate(
+        &self,
+        ty: &str,
+        selections: &[Selection],
+        options: &Options,
+        errors: &mut Vec<Error>,
+    ) {
+        for selection in selections {
+            let (directives, on, nested) = match selection {
+                Selection::Field(field) => {
+                    self.validate_field(ty, field, options, errors);
+                    (&field.directives, None, None)
+                }
+                Selection::Spread { name, directives } => {
+                    let fragment = &self.fragments[name];
+                    (directives, Some(&fragment.on), Some(&fragment.selections))
+                }
+                Selection::Inline {
+                    on,
+                    directives,
+                    selections,
+                } => (directives, on.as_ref(), Some(selections)),
+            };
+            for directive in directives {
+                if directive.name != "skip" && directive.name != "include" {
+                    errors.push(Error::new(format!("Unknown directive `@{}`", directive.name)));
+                }
+            }
+            if let Some(on) = on.filter(|on| *on != ty) {
+                let message = format!("Fragment on `{}` can't be spread within `{}`", on, ty);
+                errors.push(Error::new(message));
+            } else if let Some(nested) = nested {
+                self.validate(ty, nested, options, errors);
+            }
+        }
+    }
+
+    fn validate_field(&self, ty: &str, field: &Field, options: &Options, errors: &mut Vec<Error>) {
+        for (_, value) in &field.arguments {
+            self.validate_value(value, errors);
+        }
+        if field.name == "__typename" {
+            if !field.selections.is_empty() {
+                let message = "`__typename` is a `String!` and has no fields to select";
+                errors.push(Error::new(message));
+            }
+            return;
+        }
+        if ty == QUERY.name && (field.name == "__schema" || field.name == "__type") {
+            if !options.introspection {
+                let message = "Introspection is disabled on this server";
+                errors.push(Error::with_code(message, "introspection_disabled"));
+            } else if field.name == "__type" && !field.arguments.iter().any(|(n, _)| n == "name") {
+                errors.push(Error::new("`__type` requires a `name` argument"));
+            }
+            return;
+        }
+        let Some(def) = self.field_def(ty, &field.name) else {
+            let message = format!("Cannot query field `{}` on type `{}`", field.name, ty);
+            errors.push(Error::new(message));
+            return;
+        };
+
+        for (name, _) in &field.arguments {
+            if !def.args.iter().any(|arg| arg.name == name) {
+                let message = format!("Unknown argument `{}` on `{}.{}`", name, ty, def.name);
+                errors.push(Error::new(message));
+            }
+        }
+        for arg in def.args.iter().filter(|arg| arg.ty.ends_with('!')) {
+            if !field.arguments.iter().any(|(name, _)| name == arg.name) {
+                let message = format!("`{}.{}` requires a `{}` argument", ty, def.name, arg.name);
+                errors.push(Error::new(message));
+            }
+        }
+
+        let child = named(def.ty);
+        match (self.object(child).is_some(), field.selections.is_empty()) {
+            (true, true) => {
+                let message = format!("`{}` is a `{}`; select the fields wanted", def.name, def.ty);
+                errors.push(Error::new(message));
+            }
+            (false, false) => {
+                let message =
+                    format!("`{}` is a `{}` and has no fields to select", def.name, def.ty);
+                errors.push(Error::new(message));
+            }
+            (true, false) => self.validate(child, &field.selections, options, errors),
+            (false, true) => {}
+        }
+    }
+
+    /// Reports variables `value` uses that the operation doesn't define.
+    fn validate_value(&self, value: &Value, errors: &mut Vec<Error>) {
+        match value {
+            Value::Variable(name) if !self.operation.variables.iter().any(|v| v.name == *name) => {
+                errors.push(Error::new(format!("Variable `${}` is not defined", name)));
+            }
+            Value::List(items) => {
+                items.iter().for_each(|item| self.validate_value(item, errors));
+            }
+            Value::Object(fields) => {
+                fields.iter().for_each(|(_, value)| self.validate_value(value, errors));
+            }
+            _ => {}
+        }
+    }
+
+    /// The depth and number of fields of `selections`, with fragments
+    /// expanded. `spreading` holds the fragments being expanded, to catch
+    /// ones that spread themselves, and chains of more than `max_depth`.
+    fn measure<'q>(
+        &'q self,
+        selections: &'q [Selection],
+        spreading: &mut Vec<&'q str>,
+        max_depth: usize,
+    ) -> Result<(usize, usize), Error> {
+        let (mut depth, mut count) = (0, 0);
+        for selection in selections {
+            let (nested_depth, nested_count) = match selection {
+                Selection::Field(field) => {
+                    let (d, c) = self.measure(&field.selections, spreading, max_depth)?;
+                    (d + 1, c + 1)
+                }
+                Selection::Spread { name, .. } => {
+                    if spreading.contains(&name.as_str()) {
+                        return Err(Error::new(format!("Fragment `{}` spreads itself", name)));
+                    }
+                    if spreading.len() >= max_depth {
+                        let message =
+                            format!("Fragments are spread more than {} deep", max_depth);
+                        return Err(Error::with_code(message, "query_too_deep"));
+                    }
+                    let fragment = self.fragment(name)?;
+                    spreading.push(name);
+                    let measured = self.measure(&fragment.selections, spreading, max_depth)?;
+                    spreading.pop();
+                    measured
+                }
+                Selection::Inline { selections, .. } => {
+                    self.measure(selections, spreading, max_depth)?
+                }
+            };
+            depth = depth.max(nested_depth);
+            count += nested_count;
+        }
+        Ok((depth, count))
+    }
+
+    /// The fields `selections` ask of a `ty`, grouped by response key in the
+    /// order they first appear, leaving out skipped ones.
+    fn collect<'q>(
+        &'q self,
+        ty: &str,
+        selections: &[&'q Selection],
+    ) -> Result<Vec<(String, Vec<&'q Field>)>, Error> {
+        let mut grouped: Vec<(String, Vec<&Field>)> = Vec::new();
+        let mut pending: Vec<&Selection> = selections.iter().rev().copied().collect();
+        while let Some(selection) = pending.pop() {
+            let (directives, on, nested) = match selection {
+                Selection::Field(field) => (&field.directives, None, None),
+                Selection::Spread { name, directives } => {
+                    let fragment = self.fragment(name)?;
+                    (directives, Some(&fragment.on), Some(&fragment.selections))
+                }
+                Selection::Inline {
+                    on,
+                    directives,
+                    selections,
+                } => (directives, on.as_ref(), Some(selections)),
+            };
+            if !self.included(directives)? || on.is_some_and(|on| !ty.is_empty() && on != ty) {
+                continue;
+            }
+            match (selection, nested) {
+                (Selection::Field(field), _) => {
+                    match grouped.iter_mut().find(|(key, _)| key == field.key()) {
+                        Some((_, fields)) => fields.push(field),
+                        None => grouped.push((field.key().to_string(), vec![field])),
+                    }
+                }
+                (_, Some(nested)) => pending.extend(nested.iter().rev()),
+                (_, None) => {}
+            }
+        }
+        Ok(grouped)
+    }
+
+    fn fragment(&self, name: &str) -> Result<&Fragment, Error> {
+        self.fragments
+            .get(name)
+            .ok_or_else(|| Error::new(format!("Unknown fragment `{}`", name)))
+    }
+
+    /// Whether `@skip` and `@include` leave a selection in.
+    fn included(&self, directives: &[Directive]) -> Result<bool, Error> {
+        for directive in directives {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| self.resolve(value))
+                .transpose()?
+                .flatten();
+            let condition = match condition {
+                Some(Json::Bool(condition)) => condition,
+                _ => {
+                    let message = format!("`@{}` requires a Boolean `if`", directive.name);
+                    return Err(Error::new(message));
+                }
+            };
+            if condition == (directive.name == "skip") {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// `value` with variables filled in; `None` for an unset variable.
+    fn resolve(&self, value: &Value) -> Result<Option<Json>, Error> {
+        Ok(Some(match value {
+            Value::Variable(name) => return Ok(self.variables.get(name).cloned()),
+            Value::List(items) => {
+                let items = items.iter().map(|item| self.resolve(item));
+                let items: Vec<_> = items.collect::<Result<_, _>>()?;
+                Json::Array(items.into_iter().map(Option::unwrap_or_default).collect())
+            }
+            Value::Object(fields) => {
+                let mut object = Map::new();
+                for (name, value) in fields {
+                    if let Some(value) = self.resolve(value)? {
+                        object.insert(name.clone(), value);
+                    }
+                }
+                Json::Object(object)
+            }
+            value => constant(value)?,
+        }))
+    }
+
+    /// `value` cut down to `selections`. `ty` names the object type it is
+    /// an instance of; introspection objects carry their own `__typename`.
+    fn project(&self, value: &Json, ty: &str, selections: &[&Selection]) -> Result<Output, Error> {
+        let object = match value {
+            Json::Array(items) => {
+                let items = items
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        self.project(item, ty, selections).map_err(|e| e.at(index))
+                    })
+                    .collect::<Result<_, _>>()?;
+                return Ok(Output::List(items));
+            }
+            Json::Object(object) => object,
+            value => return Ok(Output::Value(value.clone())),
+        };
+        let ty = object.get("__typename").and_then(Json::as_str).unwrap_or(ty);
+
+        let mut projected = Vec::new();
+        for (key, fields) in self.collect(ty, selections)? {
+            let name = fields[0].name.as_str();
+            let value = match object.get(name) {
+                _ if name == "__typename" => Output::Value(ty.into()),
+                Some(value) => {
+                    let nested: Vec<&Selection> =
+                        fields.iter().flat_map(|field| &field.selections).collect();
+                    if nested.is_empty() {
+                        Output::Value(value.clone())
+                    } else {
+                        let child = self.field_type(ty, name);
+                        self.project(value, child, &nested).map_err(|e| e.at(key.as_str()))?
+                    }
+                }
+                None => {
+                    let message = format!("Cannot query field `{}` on type `{}`", name, ty);
+                    return Err(Error::new(message).at(key.as_str()));
+                }
+            };
+            projected.push((key, value));
+        }
+        Ok(Output::Object(projected))
+    }
+
+    /// `__schema`, as introspection describes it.
+    fn schema_json(&self) -> Json {
+        let mut types: Vec<Json> = [&QUERY, &ARTIST, &SUMMARY, &POINT]
+            .into_iter()
+            .filter(|ty| self.object(ty.name).is_some())
+            .map(|ty| self.object_json(ty))
+            .collect();
+        types.extend(SCALARS.iter().map(|(name, description)| scalar_json(name, description)));
+        let condition = [ArgDef {
+            name: "if",
+            description: "",
+            ty: "Boolean!",
+        }];
+        let directive = |name: &str, description: &str| {
+            json!({
+                "__typename": "__Directive",
+                "name": name,
+                "description": description,
+                "locations": ["FIELD", "FRAGMENT_SPREAD", "INLINE_FRAGMENT"],
+                "args": condition.iter().map(|arg| self.arg_json(arg)).collect::<Vec<_>>(),
+                "isRepeatable": false,
+            })
+        };
+        json!({
+            "__typename": "__Schema",
+            "description": null,
+            "queryType": self.object_json(&QUERY),
+            "mutationType": null,
+            "subscriptionType": null,
+            "types": types,
+            "directives": [
+                directive("include", "Selects the field only when `if` is true."),
+                directive("skip", "Leaves the field out when `if` is true."),
+            ],
+        })
+    }
+
+    fn named_type(&self, name: &str) -> Option<Json> {
+        if let Some(object) = self.object(name) {
+            return Some(self.object_json(object));
+        }
+        SCALARS
+            .iter()
+            .find(|(scalar, _)| *scalar == name)
+            .map(|(name, description)| scalar_json(name, description))
+    }
+
+    /// The `__Type` for `ty`, in SDL notation, with wrapping types unwrapped
+    /// into `ofType`.
+    fn type_ref(&self, ty: &str) -> Json {
+        if let Some(inner) = ty.strip_suffix('!') {
+            return wrapper_json("NON_NULL", self.type_ref(inner));
+        }
+        if let Some(inner) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+            return wrapper_json("LIST", self.type_ref(inner));
+        }
+        self.named_type(ty).unwrap_or(Json::Null)
+    }
+
+    fn object_json(&self, object: &ObjectType) -> Json {
+        let fields: Vec<Json> = object
+            .fields
+            .iter()
+            .filter(|field| self.field_def(object.name, field.name).is_some())
+            .map(|field| {
+                json!({
+                    "__typename": "__Field",
+                    "name": field.name,
+                    "description": description(field.description),
+                    "args": field.args.iter().map(|arg| self.arg_json(arg)).collect::<Vec<_>>(),
+                    "type": self.type_ref(field.ty),
+                    "isDeprecated": false,
+                    "deprecationReason": null,
+                })
+            })
+            .collect();
+        let mut ty = wrapper_json("OBJECT", Json::Null);
+        ty["name"] = object.name.into();
+        ty["description"] = description(object.description);
+        ty["fields"] = fields.into();
+        ty["interfaces"] = json!([]);
+        ty
+    }
+
+    fn arg_json(&self, arg: &ArgDef) -> Json {
+        json!({
+            "__typename": "__InputValue",
+            "name": arg.name,
+            "description": description(arg.description),
+            "type": self.type_ref(arg.ty),
+            "defaultValue": null,
+            "isDeprecated": false,
+            "deprecationReason": null,
+        })
+    }
+}
+
+/// The type `ty`, in SDL notation, names once unwrapped.
+fn named(ty: &str) -> &str {
+    ty.trim_matches(|c| c == '[' || c == ']' || c == '!')
+}
+
+fn description(text: &str) -> Json {
+    if text.is_empty() {
+        Json::Null
+    } else {
+        text.into()
+    }
+}
+
+/// A `__Type` of kind `kind`, every field but `ofType` empty.
+fn wrapper_json(kind: &str, of: Json) -> Json {
+    json!({
+        "__typename": "__Type",
+        "kind": kind,
+        "name": null,
+        "description": null,
+        "specifiedByURL": null,
+        "fields": null,
+        "interfaces": null,
+        "possibleTypes": null,
+        "enumValues": null,
+        "inputFields": null,
+        "ofType": of,
+    })
+}
+
+fn scalar_json(name: &str, text: &str) -> Json {
+    let mut ty = wrapper_json("SCALAR", Json::Null);
+    ty["name"] = name.into();
+    ty["description"] = description(text);
+    ty
+}
+
+/// `value`, which holds no variables, as JSON.
+fn constant(value: &Value) -> Result<Json, Error> {
+    Ok(match value {
+        Value::Null => Json::Null,
+        Value::Int(n) => (*n).into(),
+        Value::Float(n) => (*n).into(),
+        Value::String(s) | Value::Enum(s) => s.clone().into(),
+        Value::Boolean(b) => (*b).into(),
+        Value::List(items) => Json::Array(items.iter().map(constant).collect::<Result<_, _>>()?),
+        Value::Object(fields) => {
+            let mut object = Map::new();
+            for (name, value) in fields {
+                object.insert(name.clone(), constant(value)?);
+            }
+            Json::Object(object)
+        }
+        Value::Variable(name) => {
+            return Err(Error::new(format!("Variable `${}` can't be used here", name)))
+        }
+    })
+}
+
+const OPERATION_KINDS: [&str; 3] = ["query", "mutation", "subscription"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Punctuator(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+/// Reads a document, keeping where each token starts for errors. Selection
+/// sets, values and types nested more than `max_depth` deep are refused as
+/// they are reached, so a hostile document can't recurse without bound.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    max_depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, max_depth: usize) -> Result<Self, Error> {
+        let mut parser = Parser {
+            source,
+            tokens: Vec::new(),
+            next: 0,
+            max_depth,
+        };
+        parser.lex()?;
+        Ok(parser)
+    }
+
+    fn syntax_error(&self, at: usize, message: &str) -> Error {
+        let before = &self.source[..at];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+        Error::new(format!("Syntax error at line {}, column {}: {}", line, column, message))
+    }
+
+    fn lex(&mut self) -> Result<(), Error> {
+        let source = self.source;
+        let mut chars = source.char_indices().peekable();
+        while let Some(&(at, c)) = chars.peek() {
+            let token = match c {
+                '\u{feff}' | ' ' | '\t' | '\n' | '\r' | ',' => {
+                    chars.next();
+                    continue;
+                }
+                '#' => {
+                    while chars.next_if(|&(_, c)| c != '\n' && c != '\r').is_some() {}
+                    continue;
+                }
+                '!' | '$' | '&' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '|' | '}' => {
+                    chars.next();
+                    Token::Punctuator(c)
+                }
+                '.' if source[at..].starts_with("...") => {
+                    chars.nth(2);
+                    Token::Spread
+                }
+                '"' if source[at..].starts_with("\"\"\"") => {
+                    let body = at + 3;
+                    let Some(length) = block_string_end(&source[body..]) else {
+                        return Err(self.syntax_error(at, "unterminated string"));
+                    };
+                    while chars.next_if(|&(i, _)| i < body + length + 3).is_some() {}
+                    Token::String(block_string(&source[body..body + length]))
+                }
+                '"' => {
+                    chars.next();
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((escape, '\\')) => {
+                                let escaped = match chars.next().map(|(_, c)| c) {
+                                    Some('u') => {
+                                        let hex: String = (0..4)
+                                            .filter_map(|_| chars.next().map(|(_, c)| c))
+                                            .collect();
+                                        u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                                    }
+                                    Some('n') => Some('\n'),
+                                    Some('r') => Some('\r'),
+                                    Some('t') => Some('\t'),
+                                    Some('b') => Some('\u{8}'),
+                                    Some('f') => Some('\u{c}'),
+                                    Some(c @ ('"' | '\\' | '/')) => Some(c),
+                                    _ => None,
+                                };
+                                match escaped {
+                                    Some(c) => value.push(c),
+                                    None => {
+                                        return Err(self.syntax_error(escape, "invalid escape"))
+                                    }
+                                }
+                            }
+                            Some((_, '\n' | '\r')) | None => {
+                                return Err(self.syntax_error(at, "unterminated string"))
+                            }
+                            Some((_, c)) => value.push(c),
+                        }
+                    }
+                    Token::String(value)
+                }
+                c if c == '_' || c.is_ascii_alphabetic() => {
+                    let mut end = at;
+                    while let Some((i, c)) =
+                        chars.next_if(|&(_, c)| c == '_' || c.is_ascii_alphanumeric())
+                    {
+                        end = i + c.len_utf8();
+                    }
+                    Token::Name(source[at..end].to_string())
+                }
+                c if c == '-' || c.is_ascii_digit() => {
+                    let mut end = at;
+                    let mut previous = ' ';
+                    while let Some((i, c)) = chars.next_if(|&(i, c)| {
+                        c.is_ascii_digit()
+                            || c == '.'
+                            || c == 'e'
+                            || c == 'E'
+                            || (i == at && c == '-')
+                            || ((c == '-' || c == '+') && matches!(previous, 'e' | 'E'))
+                    }) {
+                        end = i + 1;
+                        previous = c;
+                    }
+                    let number = &source[at..end];
+                    if number.contains(['.', 'e', 'E']) {
+                        match number.parse() {
+                            Ok(n) => Token::Float(n),
+                            Err(_) => return Err(self.syntax_error(at, "invalid number")),
+                        }
+                    } else {
+                        match number.parse() {
+                            Ok(n) => Token::Int(n),
+                            Err(_) => return Err(self.syntax_error(at, "invalid number")),
+                        }
+                    }
+                }
+                c => return Err(self.syntax_error(at, &format!("unexpected `{}`", c))),
+            };
+            self.tokens.push((token, at));
+        }
+        Ok(())
+    }
+
+    /// Fails once `depth` is past `max_depth`.
+    fn nest(&self, depth: usize) -> Result<(), Error> {
+        if depth <= self.max_depth {
+            return Ok(());
+        }
+        let message = format!("Query is nested more than {} levels deep", self.max_depth);
+        Err(Error::with_code(message, "query_too_deep"))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn error(&self, expected: &str) -> Error {
+        match self.tokens.get(self.next) {
+            Some((_, at)) => self.syntax_error(*at, &format!("expected {}", expected)),
+            None => self.syntax_error(self.source.len(), &format!("expected {}", expected)),
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punctuator(c)) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", c)))
+        }
+    }
+
+    fn peek_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(n)) if n == name)
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.next += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    fn document(&mut self) -> Result<(Vec<Operation>, HashMap<String, Fragment>), Error> {
+        let mut operations = Vec::new();
+        let mut fragments = HashMap::new();
+        while self.peek().is_some() {
+            if self.peek() == Some(&Token::Punctuator('{')) {
+                operations.push(Operation {
+                    name: None,
+                    kind: "query".to_string(),
+                    variables: Vec::new(),
+                    selections: self.selections(1)?,
+                });
+            } else if self.peek_name("fragment") {
+                self.next += 1;
+                let name = self.name()?;
+                if !self.peek_name("on") {
+                    return Err(self.error("`on`"));
+                }
+                self.next += 1;
+                let on = self.name()?;
+                self.directives()?;
+                let selections = self.selections(1)?;
+                if fragments.insert(name.clone(), Fragment { on, selections }).is_some() {
+                    return Err(Error::new(format!("Fragment `{}` is defined twice", name)));
+                }
+            } else if OPERATION_KINDS.iter().any(|kind| self.peek_name(kind)) {
+                let kind = self.name()?;
+                let name = match self.peek() {
+                    Some(Token::Name(_)) => Some(self.name()?),
+                    _ => None,
+                };
+                let variables = self.variable_defs()?;
+                self.directives()?;
+                operations.push(Operation {
+                    name,
+                    kind,
+                    variables,
+                    selections: self.selections(1)?,
+                });
+            } else {
+                return Err(self.error("an operation or fragment"));
+            }
+        }
+        if operations.is_empty() {
+            return Err(Error::new("The document has no operation to run"));
+        }
+        Ok((operations, fragments))
+    }
+
+    fn variable_defs(&mut self) -> Result<Vec<VariableDef>, Error> {
+        let mut variables = Vec::new();
+        if !self.eat('(') {
+            return Ok(variables);
+        }
+        while !self.eat(')') {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let required = self.type_ref(1)?;
+            let default = if self.eat('=') {
+                Some(self.value(1)?)
+            } else {
+                None
+            };
+            self.directives()?;
+            variables.push(VariableDef {
+                name,
+                required,
+                default,
+            });
+        }
+        Ok(variables)
+    }
+
+    /// Skips a type, returning whether it is non-null.
+    fn type_ref(&mut self, depth: usize) -> Result<bool, Error> {
+        self.nest(depth)?;
+        if self.eat('[') {
+            self.type_ref(depth + 1)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        Ok(self.eat('!'))
+    }
+
+    /// A selection set `depth` deep, counting the outermost as 1 and each
+    /// field or inline fragment as a level.
+    fn selections(&mut self, depth: usize) -> Result<Vec<Selection>, Error> {
+        self.nest(depth)?;
+        self.expect('{')?;
+        let mut selections = Vec::new();
+        while !self.eat('}') {
+            selections.push(self.selection(depth)?);
+        }
+        if selections.is_empty() {
+            return Err(self.error("a field"));
+        }
+        Ok(selections)
+    }
+
+    fn selection(&mut self, depth: usize) -> Result<Selection, Error> {
+        if self.peek() == Some(&Token::Spread) {
+            self.next += 1;
+            if self.peek_name("on") {
+                self.next += 1;
+                let on = Some(self.name()?);
+                let directives = self.directives()?;
+                let selections = self.selections(depth + 1)?;
+                return Ok(Selection::Inline {
+                    on,
+                    directives,
+                    selections,
+                });
+            }
+            if let Some(Token::Name(_)) = self.peek() {
+                let name = self.name()?;
+                let directives = self.directives()?;
+                return Ok(Selection::Spread { name, directives });
+            }
+            let directives = self.directives()?;
+            let selections = self.selections(depth + 1)?;
+            return Ok(Selection::Inline {
+                on: None,
+                directives,
+                selections,
+            });
+        }
+
+        let mut name = self.name()?;
+        let mut alias = None;
+        if self.eat(':') {
+            alias = Some(name);
+            name = self.name()?;
+        }
+        let arguments = self.arguments()?;
+        let directives = self.directives()?;
+        let selections = if self.peek() == Some(&Token::Punctuator('{')) {
+            self.selections(depth + 1)?
+        } else {
+            Vec::new()
+        };
+        Ok(Selection::Field(Field {
+            alias,
+            name,
+            arguments,
+            directives,
+            selections,
+        }))
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Value)>, Error> {
+        let mut arguments = Vec::new();
+        if !self.eat('(') {
+            return Ok(arguments);
+        }
+        while !self.eat(')') {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value(1)?));
+        }
+        Ok(arguments)
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, Error> {
+        let mut directives = Vec::new();
+        while self.eat('@') {
+            let name = self.name()?;
+            let arguments = self.arguments()?;
+            directives.push(Directive { name, arguments });
+        }
+        Ok(directives)
+    }
+
+    /// A value `depth` deep, counting each list or input object around it.
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        self.nest(depth)?;
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error("a value"));
+        };
+        self.next += 1;
+        Ok(match token {
+            Token::Punctuator('$') => Value::Variable(self.name()?),
+            Token::Punctuator('[') => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value(depth + 1)?);
+                }
+                Value::List(items)
+            }
+            Token::Punctuator('{') => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value(depth + 1)?));
+                }
+                Value::Object(fields)
+            }
+            Token::Int(n) => Value::Int(n),
+            Token::Float(n) => Value::Float(n),
+            Token::String(s) => Value::String(s),
+            Token::Name(name) => match name.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                "null" => Value::Null,
+                _ => Value::Enum(name),
+            },
+            Token::Punctuator(_) | Token::Spread => {
+                self.next -= 1;
+                return Err(self.error("a value"));
+            }
+        })
+    }
+}
+
+/// The length of a block string's body, up to its closing `"""`.
+fn block_string_end(body: &str) -> Option<usize> {
+    let mut from = 0;
+    loop {
+        let end = from + body[from..].find("\"\"\"")?;
+        if !body[..end].ends_with('\\') {
+            return Some(end);
+        }
+        from = end + 3;
+    }
+}
+
+/// A block string's value: its lines with their common indentation and
+/// the blank lines around them removed.
+fn block_string(raw: &str) -> String {
+    let raw = raw.replace("\\\"\"\"", "\"\"\"");
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| if i == 0 { line } else { line.get(indent..).unwrap_or("") })
+        .collect();
+    let first = lines.iter().position(|line| !line.trim().is_empty());
+    let last = lines.iter().rposition(|line| !line.trim().is_empty());
+    match (first, last) {
+        (Some(first), Some(last)) => lines[first..=last].join("\n"),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: Options = Options {
+        history: false,
+        introspection: true,
+        max_depth: 15,
+        max_complexity: 500,
+    };
+
+    fn request(query: &str) -> Request {
+        Request {
+            query: query.to_string(),
+            operation_name: None,
+            variables: None,
+        }
+    }
+
+    fn prepare(query: &str) -> Result<Query, Vec<Error>> {
+        Query::prepare(request(query), &OPTIONS)
+    }
+
+    /// The one error preparing `query` fails with.
+    fn refusal(query: &str) -> Error {
+        let mut errors = prepare(query).err().expect("the query is refused");
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors.remove(0)
+    }
+
+    fn code(error: &Error) -> Option<&'static str> {
+        error.extensions.as_ref().map(|extensions| extensions.code)
+    }
+
+    /// Root field keys and the arguments they resolved to.
+    fn roots(query: &Query) -> Vec<(String, Json)> {
+        let fields = query.root_fields().unwrap();
+        fields.into_iter().map(|field| (field.key, Json::Object(field.args))).collect()
+    }
+
+    /// The response body for `query`, each root field answered from
+    /// `values` by name.
+    fn respond(query: &str, values: Json) -> Json {
+        let query = prepare(query).unwrap();
+        let fields = query.root_fields().unwrap();
+        let resolved = fields
+            .into_iter()
+            .map(|field| {
+                let value = query.builtin(&field).unwrap_or_else(|| values[&field.name].clone());
+                (field, Ok(value))
+            })
+            .collect();
+        serde_json::to_value(query.respond(resolved)).unwrap()
+    }
+
+    #[test]
+    fn nesting_past_max_depth_is_refused_without_recursing() {
+        let deep = |open: &str, close: &str| {
+            let (open, close) = (open.repeat(5000), close.repeat(5000));
+            format!("{{ leaderboard(limit: {}1{}) {{ name }} }}", open, close)
+        };
+        for query in [
+            format!("{}{}", "{ a ".repeat(5000), "}".repeat(5000)),
+            format!("{{ {}name{} }}", "... { ".repeat(5000), " }".repeat(5000)),
+            deep("[", "]"),
+            deep("{a: ", "}"),
+            format!(
+                "query ($x: {}Int{}) {{ summary {{ totalVisitors }} }}",
+                "[".repeat(5000),
+                "]".repeat(5000)
+            ),
+        ] {
+            let error = refusal(&query);
+            assert_eq!(code(&error), Some("query_too_deep"), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn long_fragment_chains_are_refused_without_recursing() {
+        let mut query = "{ ...F0 }".to_string();
+        for i in 0..5000 {
+            query.push_str(&format!(" fragment F{} on Query {{ ...F{} }}", i, i + 1));
+        }
+        query.push_str(" fragment F5000 on Query { summary { totalVisitors } }");
+        let error = refusal(&query);
+        assert_eq!(code(&error), Some("query_too_deep"), "{}", error.message);
+    }
+
+    #[test]
+    fn max_depth_counts_fields_from_the_root() {
+        let options = |max_depth| Options {
+            max_depth,
+            ..OPTIONS
+        };
+        let query = "{ summary { totalVisitors } }";
+        assert!(Query::prepare(request(query), &options(2)).is_ok());
+        let errors = Query::prepare(request(query), &options(1)).err().unwrap();
+        assert_eq!(code(&errors[0]), Some("query_too_deep"));
+
+        // Inline fragments count while parsing: this is 2 deep, plus one
+        // for each.
+        let wrapped = |times| {
+            let mut query = query.to_string();
+            for _ in 0..times {
+                query = format!("{{ ... on Query {} }}", query);
+            }
+            query
+        };
+        assert!(prepare(&wrapped(13)).is_ok());
+        assert_eq!(code(&refusal(&wrapped(14))), Some("query_too_deep"));
+    }
+
+    #[test]
+    fn complexity_counts_fields_with_fragments_expanded() {
+        let options = Options {
+            max_complexity: 4,
+            ..OPTIONS
+        };
+        let query = "{ ...S ...S } fragment S on Query { summary { totalVisitors } }";
+        assert!(Query::prepare(request(query), &options).is_ok());
+        let query = "{ ...S ...S a: summary { artistCount } } \
+            fragment S on Query { summary { totalVisitors } }";
+        let errors = Query::prepare(request(query), &options).err().unwrap();
+        assert_eq!(code(&errors[0]), Some("query_too_complex"));
+    }
+
+    #[test]
+    fn values_of_every_kind_are_read() {
+        let query = prepare(
+            r#"
+            # Commas are whitespace, and so is a byte order mark.
+            {
+              leaderboard(period: "7d", limit: -12, minVisitors: 2.5e1,,,) { name }
+              artist(name: "Tyler, \"The\" Creator \u00e9\n") { name }
+              other: artist(name: """
+                  Block
+                    "string"
+              """) { name }
+              summary(period: [1, true, null, ALL, {a: [0.5]}]) { totalVisitors }
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            roots(&query),
+            [
+                (
+                    "leaderboard".to_string(),
+                    json!({"period": "7d", "limit": -12, "minVisitors": 25.0})
+                ),
+                ("artist".to_string(), json!({"name": "Tyler, \"The\" Creator é\n"})),
+                ("other".to_string(), json!({"name": "Block\n  \"string\""})),
+                (
+                    "summary".to_string(),
+                    json!({"period": [1, true, null, "ALL", {"a": [0.5]}]})
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn syntax_errors_say_where() {
+        let cases = [
+            ("{ summary { totalVisitors }", "line 1, column 28: expected a name"),
+            ("{\n  artist(name: \"Kanye) { name } }", "line 2, column 16: unterminated string"),
+            ("{ artist(name: \"\\q\") { name } }", "line 1, column 17: invalid escape"),
+            ("{ leaderboard(limit: 1.2.3) { name } }", "line 1, column 22: invalid number"),
+            ("{ summary ? }", "line 1, column 11: unexpected `?`"),
+            ("{ }", "line 1, column 4: expected a field"),
+            ("{ leaderboard(limit: ) { name } }", "line 1, column 22: expected a value"),
+            ("subscribe { summary }", "line 1, column 1: expected an operation or fragment"),
+        ];
+        for (query, expected) in cases {
+            let error = refusal(query);
+            assert!(error.message.ends_with(expected), "{}: {}", query, error.message);
+        }
+        assert_eq!(refusal("").message, "The document has no operation to run");
+    }
+
+    #[test]
+    fn operations_are_picked_by_name() {
+        let query = "query A { summary { totalVisitors } }
+            query B { artist(name: \"x\") { name } }";
+        let pick = |name: &str| {
+            let request = Request {
+                operation_name: Some(name.to_string()),
+                ..request(query)
+            };
+            Query::prepare(request, &OPTIONS)
+        };
+        assert_eq!(roots(&pick("B").unwrap())[0].0, "artist");
+        assert_eq!(pick("C").err().unwrap()[0].message, "Unknown operation `C`");
+        assert!(refusal(query).message.contains("`operationName` is required"));
+        assert_eq!(
+            refusal("mutation { summary { totalVisitors } }").message,
+            "Only queries are supported, not mutations"
+        );
+    }
+
+    #[test]
+    fn variables_are_filled_in() {
+        let query = "query ($name: String!, $limit: Int = 5, $period: [String!]) {
+            artist(name: $name) { name }
+            leaderboard(limit: $limit, period: $period) { name }
+        }";
+        let mut variables = Map::new();
+        variables.insert("name".to_string(), json!("Frank Ocean"));
+        let request = Request {
+            variables: Some(variables),
+            ..request(query)
+        };
+        let query = Query::prepare(request, &OPTIONS).unwrap();
+        assert_eq!(
+            roots(&query),
+            [
+                ("artist".to_string(), json!({"name": "Frank Ocean"})),
+                ("leaderboard".to_string(), json!({"limit": 5})),
+            ]
+        );
+
+        assert_eq!(
+            refusal(
+                "query ($name: String!) { artist(name: $name) { name } }"
+            )
+            .message,
+            "Variable `$name` is required"
+        );
+        assert_eq!(
+            refusal("{ artist(name: $name) { name } }").message,
+            "Variable `$name` is not defined"
+        );
+        assert_eq!(
+            refusal("query ($x: Int = $y) { summary { totalVisitors } }").message,
+            "Variable `$y` can't be used here"
+        );
+    }
+
+    #[test]
+    fn fields_are_checked_against_the_schema() {
+        let cases = [
+            ("{ votes }", "Cannot query field `votes` on type `Query`"),
+            (
+                "{ summary(limit: 1) { totalVisitors } }",
+                "Unknown argument `limit` on `Query.summary`",
+            ),
+            ("{ artist { name } }", "`Query.artist` requires a `name` argument"),
+            ("{ summary }", "`summary` is a `Summary`; select the fields wanted"),
+            (
+                "{ summary { topArtist { name } } }",
+                "`topArtist` is a `String` and has no fields to select",
+            ),
+            ("{ __typename { a } }", "`__typename` is a `String!` and has no fields to select"),
+            (
+                "{ history(artist: \"x\") { visitors } }",
+                "Cannot query field `history` on type `Query`",
+            ),
+            ("{ summary @cached { totalVisitors } }", "Unknown directive `@cached`"),
+            (
+                "{ summary { ... on Artist { name } } }",
+                "Fragment on `Artist` can't be spread within `Summary`",
+            ),
+            ("{ ...Missing }", "Unknown fragment `Missing`"),
+            ("{ ...A } fragment A on Query { ...A }", "Fragment `A` spreads itself"),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(refusal(query).message, expected, "{}", query);
+        }
+        assert_eq!(
+            refusal("{ ...A } fragment A on Query { __typename } fragment A on Query { a }")
+                .message,
+            "Fragment `A` is defined twice"
+        );
+
+        let options = Options {
+            history: true,
+            ..OPTIONS
+        };
+        let query = "{ history(artist: \"x\") { visitors } }";
+        assert!(Query::prepare(request(query), &options).is_ok());
+    }
+
+    #[test]
+    fn introspection_can_be_disabled() {
+        let options = Options {
+            introspection: false,
+            ..OPTIONS
+        };
+        let errors = Query::prepare(request("{ __schema { types { name } } }"), &options)
+            .err()
+            .unwrap();
+        assert_eq!(code(&errors[0]), Some("introspection_disabled"));
+        assert_eq!(
+            refusal("{ __type { name } }").message,
+            "`__type` requires a `name` argument"
+        );
+    }
+
+    #[test]
+    fn responses_follow_the_selection() {
+        let query = "query ($brief: Boolean!) {
+            top: leaderboard { rank, who: name, visitors @skip(if: $brief) }
+            ... on Query { __typename }
+            summary { ...Totals }
+        }
+        fragment Totals on Summary { __typename totalVisitors, artistCount @include(if: false) }";
+        let values = json!({
+            "leaderboard": [
+                {"name": "Playboi Carti", "rank": 1, "visitors": 1840, "events": 2392},
+                {"name": "Kanye West", "rank": 2, "visitors": 1622, "events": 2108},
+            ],
+            "summary": {"totalVisitors": 3462, "artistCount": 2},
+        });
+        let mut variables = Map::new();
+        variables.insert("brief".to_string(), json!(true));
+        let prepared = Query::prepare(
+            Request {
+                variables: Some(variables),
+                ..request(query)
+            },
+            &OPTIONS,
+        )
+        .unwrap();
+        let fields = prepared.root_fields().unwrap();
+        let resolved = fields
+            .into_iter()
+            .map(|field| {
+                let value = prepared
+                    .builtin(&field)
+                    .unwrap_or_else(|| values[&field.name].clone());
+                (field, Ok(value))
+            })
+            .collect();
+        // Compared as text, since the order of the keys matters too.
+        let body = serde_json::to_string(&prepared.respond(resolved)).unwrap();
+        let top = r#"[{"rank":1,"who":"Playboi Carti"},{"rank":2,"who":"Kanye West"}]"#;
+        let summary = r#"{"__typename":"Summary","totalVisitors":3462}"#;
+        let expected = format!(
+            r#"{{"data":{{"top":{},"__typename":"Query","summary":{}}}}}"#,
+            top, summary
+        );
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn failed_fields_are_null_with_an_error() {
+        let query = prepare("{ artist(name: \"x\") { name } summary { totalVisitors } }").unwrap();
+        let mut fields = query.root_fields().unwrap().into_iter();
+        let artist = fields.next().unwrap();
+        let summary = fields.next().unwrap();
+        let failed = Error::with_code("No such artist", "not_found");
+        let body = query.respond(vec![
+            (artist, Err(failed)),
+            (summary, Ok(json!({"totalVisitors": 1}))),
+        ]);
+        assert_eq!(
+            serde_json::to_value(body).unwrap(),
+            json!({
+                "data": {"artist": null, "summary": {"totalVisitors": 1}},
+                "errors": [{
+                    "message": "No such artist",
+                    "path": ["artist"],
+                    "extensions": {"code": "not_found"},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn introspection_describes_the_schema() {
+        let query = "{ __type(name: \"Artist\") {
+            name kind fields { name type { kind ofType { name } } }
+        } }";
+        let body = respond(query, json!({}));
+        let ty = &body["data"]["__type"];
+        assert_eq!(ty["name"], "Artist");
+        assert_eq!(ty["kind"], "OBJECT");
+        let name = json!({"kind": "NON_NULL", "ofType": {"name": "String"}});
+        assert_eq!(ty["fields"][0], json!({"name": "name", "type": name}));
+
+        let body = respond("{ __schema { queryType { name } types { name } } }", json!({}));
+        let types = body["data"]["__schema"]["types"].as_array().unwrap();
+        let names: Vec<&str> = types.iter().filter_map(|ty| ty["name"].as_str()).collect();
+        assert_eq!(names, ["Query", "Artist", "Summary", "Boolean", "Float", "Int", "String"]);
+    }
+
+    /// splitmix64 from a fixed seed, so every run draws the same values.
+    fn draws(mut seed: u64) -> impl Iterator<Item = u64> {
+        std::iter::repeat_with(move || {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+    }
+
+    /// What generated documents are made of: the language's punctuation and
+    /// keywords, the schema's names, values of each kind, and the broken
+    /// forms of each.
+    const PIECES: &[&str] = &[
+        "{", "}", "(", ")", "[", "]", ":", "$", "@", "!", "=", "|", "&", "...", "..", ".", ",",
+        " ", "\n", "\r", "\t", "# note\n", "\u{feff}", "query", "mutation", "fragment", "on",
+        "F", "Query", "leaderboard", "artist", "summary", "history", "name", "visitors",
+        "totalVisitors", "__typename", "__schema", "__type", "@include(if: $x)", "@skip(if: true)",
+        "$x", "Int", "String!", "[Int!]!", "0", "-12", "2.5e1", "1e", "01", "-", "1.", "\"",
+        "\"\"\"", "\"a\\\"b\"", "\"\\u00e9\"", "\"\\uZZZZ\"", "\"\\", "\\", "true", "null", "ALL",
+        "é", "🎤", "\u{0}",
+    ];
+
+    /// Prepares `query` and, if that succeeds, answers it with every field
+    /// null. Any outcome but a panic will do.
+    fn survive(query: String, variables: bool) {
+        let variables = variables.then(|| match json!({ "x": 1 }) {
+            Json::Object(variables) => variables,
+            _ => unreachable!(),
+        });
+        let request = Request {
+            query,
+            operation_name: None,
+            variables,
+        };
+        let Ok(query) = Query::prepare(request, &OPTIONS) else {
+            return;
+        };
+        if let Ok(fields) = query.root_fields() {
+            let resolved = fields
+                .into_iter()
+                .map(|field| {
+                    let value = query.builtin(&field).unwrap_or(Json::Null);
+                    (field, Ok(value))
+                })
+                .collect();
+            let _ = serde_json::to_value(query.respond(resolved));
+        }
+    }
+
+    #[test]
+    fn arbitrary_documents_are_answered_without_panicking() {
+        let mut draws = draws(99);
+        for _ in 0..20_000 {
+            let len = draws.next().unwrap() % 48;
+            let query: String = (0..len)
+                .map(|_| PIECES[draws.next().unwrap() as usize % PIECES.len()])
+                .collect();
+            survive(query, draws.next().unwrap().is_multiple_of(2));
+        }
+        // And plain noise, a character at a time.
+        for _ in 0..5_000 {
+            let len = draws.next().unwrap() % 64;
+            let query: String = (0..len)
+                .filter_map(|_| char::from_u32(draws.next().unwrap() as u32 % 0x300))
+                .collect();
+            survive(query, false);
+        }
+    }
+
+    #[test]
+    fn mangled_queries_are_answered_without_panicking() {
+        let valid = [
+            "{ leaderboard(period: \"7d\", limit: 3) { name visitors } }",
+            "query ($x: Int = 2) { top: leaderboard(limit: $x) { ...A } } \
+             fragment A on Artist { name @include(if: true) }",
+            "{ summary { totalVisitors } ... on Query { __typename } }",
+            "{ __type(name: \"Artist\") { fields { name type { ofType { name } } } } }",
+        ];
+        let mut draws = draws(7);
+        for query in valid {
+            assert!(prepare(query).is_ok(), "{}", query);
+            for _ in 0..5_000 {
+                let mut chars: Vec<char> = query.chars().collect();
+                for _ in 0..1 + draws.next().unwrap() % 4 {
+                    let at = draws.next().unwrap() as usize % chars.len();
+                    let other = chars[draws.next().unwrap() as usize % chars.len()];
+                    match draws.next().unwrap() % 3 {
+                        0 => drop(chars.remove(at)),
+                        1 => chars.insert(at, other),
+                        _ => chars[at] = other,
+                    }
+                    if chars.is_empty() {
+                        break;
+                    }
+                }
+                survive(chars.into_iter().collect(), true);
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_nesting_is_refused_within_a_small_stack() {
+        // Openers with the closers that match them, one per thing that nests.
+        const NESTS: [(&str, &str); 6] = [
+            ("a { ", "}"),
+            ("... { ", "}"),
+            ("... on Query { ", "}"),
+            ("[", "]"),
+            ("{a: ", "}"),
+            ("[{a: ", "}]"),
+        ];
+        let check = || {
+            let mut draws = draws(1_099);
+            for _ in 0..200 {
+                let depth = OPTIONS.max_depth + 1 + draws.next().unwrap() as usize % 20_000;
+                let mixed = draws.next().unwrap().is_multiple_of(4);
+                let fixed = draws.next().unwrap() as usize;
+                let (mut open, mut close) = (String::new(), Vec::new());
+                for _ in 0..depth {
+                    let pick = if mixed { draws.next().unwrap() as usize } else { fixed };
+                    let (opener, closer) = NESTS[pick % NESTS.len()];
+                    open.push_str(opener);
+                    close.push(closer);
+                }
+                close.reverse();
+                // Values nest inside an argument, selections inside the root.
+                let (open, close) = (open.as_str(), close.concat());
+                let query = match (fixed % NESTS.len(), mixed) {
+                    (3.., false) => format!("{{ artist(name: {}1{}) {{ name }} }}", open, close),
+                    _ => format!("{{ {}name{} }}", open, close),
+                };
+                let errors = prepare(&query).err().expect("nesting that deep is refused");
+                if !mixed {
+                    assert_eq!(code(&errors[0]), Some("query_too_deep"), "{}", errors[0].message);
+                }
+            }
+        };
+        // Far less than recursing once per level through 20,000 would need.
+        let small = std::thread::Builder::new().stack_size(256 * 1024).spawn(check).unwrap();
+        small.join().expect("no panic or overflow");
+    }
+}
@@ -0,0 +1,342 @@
+use crate::plausible::{normalize_name, ArtistRow};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Schema changes, applied in order. `PRAGMA user_version` records how many
+/// have run, so only new ones are applied on startup. Append; never edit.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE snapshots (
+        id INTEGER PRIMARY KEY,
+        site TEXT NOT NULL,
+        taken_at INTEGER NOT NULL,
+        UNIQUE (site, taken_at)
+    );
+    CREATE TABLE snapshot_rows (
+        snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        name_key TEXT NOT NULL,
+        visitors INTEGER NOT NULL,
+        events INTEGER NOT NULL
+    );
+    CREATE INDEX snapshot_rows_by_name ON snapshot_rows (name_key, snapshot_id);",
+];
+
+/// Leaderboard snapshots kept in SQLite, one per refresh, for charting
+/// growth over time. Calls block; run them off the async executor.
+pub struct History {
+    conn: Mutex<Connection>,
+}
+
+/// One artist's totals as of a snapshot.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Point {
+    /// Seconds since the Unix epoch, serialized as RFC 3339.
+    #[serde(serialize_with = "rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub taken_at: i64,
+    pub visitors: u64,
+    pub events: u64,
+}
+
+/// One artist's totals in one snapshot, as exported.
+pub struct ExportRow {
+    /// Seconds since the Unix epoch.
+    pub taken_at: i64,
+    pub name: String,
+    pub visitors: u64,
+    pub events: u64,
+    /// Where the row sorts, for asking for the rows after it.
+    pub position: (i64, i64),
+}
+
+/// A recorded leaderboard.
+pub struct Snapshot {
+    pub id: i64,
+    /// Seconds since the Unix epoch.
+    pub taken_at: i64,
+    /// Most visitors first, as recorded.
+    pub rows: Vec<ArtistRow>,
+}
+
+impl History {
+    /// Opens or creates the database at `path`, migrating it to the current
+    /// schema.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let context = |e: rusqlite::Error| format!("{}: {}", path.display(), e);
+        let mut conn = Connection::open(path).map_err(context)?;
+        conn.pragma_update(None, "foreign_keys", true).map_err(context)?;
+
+        let applied: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(context)?;
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = conn.transaction().map_err(context)?;
+            tx.execute_batch(migration).map_err(context)?;
+            tx.pragma_update(None, "user_version", version + 1).map_err(context)?;
+            tx.commit().map_err(context)?;
+            tracing::info!("Applied history migration {}", version + 1);
+        }
+
+        Ok(History {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Stores `rows` as `site`'s leaderboard at `taken_at`, unless the site
+    /// already has a snapshot less than `window`, and at least a second,
+    /// older. Returns whether one was written.
+    pub fn record(
+        &self,
+        site: &str,
+        taken_at: SystemTime,
+        window: Duration,
+        rows: &[ArtistRow],
+    ) -> rusqlite::Result<bool> {
+        let taken_at = unix_secs(taken_at);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let latest: Option<i64> = tx
+            .query_row(
+                "SELECT MAX(taken_at) FROM snapshots WHERE site = ?1",
+                params![site],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        let window = window.as_secs().max(1) as i64;
+        if latest.is_some_and(|latest| taken_at - latest < window) {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "INSERT INTO snapshots (site, taken_at) VALUES (?1, ?2)",
+            params![site, taken_at],
+        )?;
+        let snapshot = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO snapshot_rows (snapshot_id, name, name_key, visitors, events)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for row in rows {
+                insert.execute(params![
+                    snapshot,
+                    row.name,
+                    name_key(&row.name),
+                    row.visitors as i64,
+                    row.events as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// `artist`'s totals in each of `site`'s snapshots taken between `from`
+    /// and `to` inclusive, oldest first. Snapshots the artist is missing from
+    /// are left out.
+    pub fn series(
+        &self,
+        site: &str,
+        artist: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> rusqlite::Result<Vec<Point>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached(
+            "SELECT s.taken_at, r.visitors, r.events
+             FROM snapshot_rows r JOIN snapshots s ON s.id = r.snapshot_id
+             WHERE s.site = ?1 AND r.name_key = ?2 AND s.taken_at >= ?3 AND s.taken_at <= ?4
+             ORDER BY s.taken_at",
+        )?;
+        let points = select.query_map(
+            params![site, name_key(artist), from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX)],
+            |row| {
+                Ok(Point {
+                    taken_at: row.get(0)?,
+                    visitors: row.get::<_, i64>(1)? as u64,
+                    events: row.get::<_, i64>(2)? as u64,
+                })
+            },
+        )?;
+        points.collect()
+    }
+
+    /// When each of `site`'s snapshots was taken, oldest first, in seconds
+    /// since the Unix epoch.
+    pub fn snapshots(&self, site: &str) -> rusqlite::Result<Vec<i64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn
+            .prepare_cached("SELECT taken_at FROM snapshots WHERE site = ?1 ORDER BY taken_at")?;
+        let times = select.query_map(params![site], |row| row.get(0))?;
+        times.collect()
+    }
+
+    /// How many rows `export` would go through for the same arguments.
+    pub fn count_rows(
+        &self,
+        site: &str,
+        artist: Option<&str>,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> rusqlite::Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached(
+            "SELECT COUNT(*)
+             FROM snapshot_rows r JOIN snapshots s ON s.id = r.snapshot_id
+             WHERE s.site = ?1 AND (?2 IS NULL OR r.name_key = ?2)
+               AND s.taken_at >= ?3 AND s.taken_at <= ?4",
+        )?;
+        let count: i64 = select.query_row(
+            params![site, artist.map(name_key), from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX)],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// Up to `limit` rows of `site`'s snapshots taken between `from` and
+    /// `to` inclusive, only `artist`'s when given, that sort after `after`:
+    /// by snapshot, oldest first, then by rank. Read a page at a time so
+    /// an export never holds the database for long.
+    pub fn export(
+        &self,
+        site: &str,
+        artist: Option<&str>,
+        from: Option<i64>,
+        to: Option<i64>,
+        after: Option<(i64, i64)>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<ExportRow>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached(
+            "SELECT s.taken_at, r.rowid, r.name, r.visitors, r.events
+             FROM snapshot_rows r JOIN snapshots s ON s.id = r.snapshot_id
+             WHERE s.site = ?1 AND (?2 IS NULL OR r.name_key = ?2)
+               AND s.taken_at >= ?3 AND s.taken_at <= ?4 AND (s.taken_at, r.rowid) > (?5, ?6)
+             ORDER BY s.taken_at, r.rowid
+             LIMIT ?7",
+        )?;
+        let (after_time, after_row) = after.unwrap_or((i64::MIN, i64::MIN));
+        let rows = select.query_map(
+            params![
+                site,
+                artist.map(name_key),
+                from.unwrap_or(i64::MIN),
+                to.unwrap_or(i64::MAX),
+                after_time,
+                after_row,
+                limit as i64,
+            ],
+            |row| {
+                let taken_at = row.get(0)?;
+                Ok(ExportRow {
+                    taken_at,
+                    name: row.get(2)?,
+                    visitors: row.get::<_, i64>(3)? as u64,
+                    events: row.get::<_, i64>(4)? as u64,
+                    position: (taken_at, row.get(1)?),
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// `site`'s latest snapshot taken at or before `at`, in seconds since
+    /// the Unix epoch.
+    pub fn snapshot_at(&self, site: &str, at: i64) -> rusqlite::Result<Option<Snapshot>> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT id, taken_at FROM snapshots WHERE site = ?1 AND taken_at <= ?2
+                 ORDER BY taken_at DESC LIMIT 1",
+                params![site, at],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        found.map(|(id, taken_at)| snapshot(&conn, id, taken_at)).transpose()
+    }
+
+    /// `site`'s earliest snapshot taken at or after `at`, in seconds since
+    /// the Unix epoch.
+    pub fn snapshot_from(&self, site: &str, at: i64) -> rusqlite::Result<Option<Snapshot>> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT id, taken_at FROM snapshots WHERE site = ?1 AND taken_at >= ?2
+                 ORDER BY taken_at LIMIT 1",
+                params![site, at],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        found.map(|(id, taken_at)| snapshot(&conn, id, taken_at)).transpose()
+    }
+
+    /// `site`'s snapshot `id`, if it has one by that ID.
+    pub fn snapshot(&self, site: &str, id: i64) -> rusqlite::Result<Option<Snapshot>> {
+        let conn = self.conn.lock().unwrap();
+        let taken_at = conn
+            .query_row(
+                "SELECT taken_at FROM snapshots WHERE site = ?1 AND id = ?2",
+                params![site, id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        taken_at.map(|taken_at| snapshot(&conn, id, taken_at)).transpose()
+    }
+
+    /// When `site`'s first snapshot was taken, in seconds since the Unix
+    /// epoch.
+    pub fn oldest(&self, site: &str) -> rusqlite::Result<Option<i64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT MIN(taken_at) FROM snapshots WHERE site = ?1",
+            params![site],
+            |row| row.get(0),
+        )
+    }
+}
+
+fn snapshot(conn: &Connection, id: i64, taken_at: i64) -> rusqlite::Result<Snapshot> {
+    let mut select = conn.prepare_cached(
+        "SELECT name, visitors, events FROM snapshot_rows WHERE snapshot_id = ?1 ORDER BY rowid",
+    )?;
+    let rows = select.query_map(params![id], |row| {
+        Ok(ArtistRow {
+            name: row.get(0)?,
+            visitors: row.get::<_, i64>(1)? as u64,
+            events: row.get::<_, i64>(2)? as u64,
+            share: None,
+            movement: None,
+            extra: Default::default(),
+        })
+    })?;
+    Ok(Snapshot {
+        id,
+        taken_at,
+        rows: rows.collect::<rusqlite::Result<_>>()?,
+    })
+}
+
+/// Names are matched the way lookups elsewhere match them: whitespace
+/// collapsed, case ignored.
+fn name_key(name: &str) -> String {
+    normalize_name(name).to_lowercase()
+}
+
+fn rfc3339<S: serde::Serializer>(secs: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    match chrono::DateTime::from_timestamp(*secs, 0) {
+        Some(time) => serializer.serialize_str(&time.to_rfc3339()),
+        None => serializer.serialize_i64(*secs),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
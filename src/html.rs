@@ -0,0 +1,123 @@
+use crate::plausible::ArtistRow;
+use crate::upstream::Period;
+use maud::{html, Markup, DOCTYPE};
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 0 auto; max-width: 40rem; padding: 1rem; }
+h1 { font-size: 1.4rem; }
+nav a { margin-right: .6rem; }
+nav a.current { font-weight: bold; text-decoration: none; color: inherit; }
+table { border-collapse: collapse; width: 100%; }
+th, td { padding: .4rem; text-align: left; border-bottom: 1px solid #ddd; }
+td.number, th.number { text-align: right; font-variant-numeric: tabular-nums; }
+td.artist { overflow-wrap: anywhere; }
+footer { color: #666; font-size: .85rem; margin-top: 1rem; }
+";
+
+const RAPIDOC: &str = "https://unpkg.com/rapidoc@9.3.8/dist/rapidoc-min.js";
+
+/// The leaderboard for `period`, or a custom range when there is none, as
+/// a standalone page, with links to the periods.
+pub fn leaderboard(period: Option<Period>, rows: &[ArtistRow], generated_at: &str) -> String {
+    page(html! {
+        h1 { "Artist leaderboard" }
+        nav {
+            @for other in Period::VALUES {
+                a href={ "?period=" (other.as_str()) }
+                    class=[(Some(other) == period).then_some("current")] { (other.as_str()) }
+            }
+        }
+        @if rows.is_empty() {
+            p { "No artists yet for this period." }
+        } @else {
+            table {
+                thead {
+                    tr {
+                        th.number { "#" }
+                        th { "Artist" }
+                        th.number { "Visitors" }
+                        th.number { "Share" }
+                    }
+                }
+                tbody {
+                    @for (index, row) in rows.iter().enumerate() {
+                        tr {
+                            td.number { (index + 1) }
+                            td.artist { (row.name) }
+                            td.number { (row.visitors) }
+                            td.number {
+                                @if let Some(share) = row.share {
+                                    (format!("{:.1}%", share * 100.0))
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        footer { "Generated " (generated_at) }
+    })
+}
+
+/// Shown when there is no cached data and upstream can't be reached.
+pub fn unavailable() -> String {
+    page(html! {
+        h1 { "Artist leaderboard" }
+        p { "Data is temporarily unavailable. Please try again in a few minutes." }
+    })
+}
+
+/// API reference for `openapi.json`, rendered in the browser by RapiDoc.
+pub fn docs() -> String {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "API reference" }
+                script type="module" src=(RAPIDOC) {}
+            }
+            body {
+                rapi-doc spec-url="openapi.json" render-style="read" show-header="false" {}
+            }
+        }
+    }
+    .into_string()
+}
+
+/// A page showing the widget loaded from `script`, with the tag that embeds
+/// it.
+pub fn widget_preview(script: &str) -> String {
+    let tag = format!(
+        r##"<script src="{}" data-target="#stats" data-limit="10"></script>"##,
+        script
+    );
+    page(html! {
+        h1 { "Widget preview" }
+        p {
+            "Paste this where the leaderboard should appear, next to a "
+            code { "#stats" }
+            " element:"
+        }
+        pre { code { (tag) } }
+        div id="stats" {}
+        script src=(script) data-target="#stats" data-limit="10" {}
+    })
+}
+
+fn page(content: Markup) -> String {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "Artist leaderboard" }
+                style { (STYLE) }
+            }
+            body { (content) }
+        }
+    }
+    .into_string()
+}
@@ -0,0 +1,287 @@
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::ratelimit::in_nets;
+use crate::{random_u64, ratelimit, telemetry, AppState, X_API_KEY, X_CACHE, X_REQUEST_ID};
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::header::HeaderValue,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use tracing::{Instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Longest client-supplied request ID that is propagated rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Counts every request by matched route and response status.
+pub async fn track_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    tracing::Span::current().set_attribute("http.route", route.clone());
+
+    let response = next.run(request).await;
+    state
+        .metrics
+        .record_request(&route, response.status().as_u16());
+    response
+}
+
+/// Tags every request with an `X-Request-Id`, propagating a sane one from
+/// the client or generating one, and logs a line per request once the
+/// response is ready. Everything logged while handling the request,
+/// including upstream fetches it starts, carries the ID in its span.
+pub async fn log_requests(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .filter(|value| {
+            let bytes = value.as_bytes();
+            !bytes.is_empty()
+                && bytes.len() <= MAX_REQUEST_ID_LEN
+                && bytes.iter().all(|b| b.is_ascii_graphic())
+        })
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&format!("{:016x}", random_u64())).expect("hex is a valid header")
+        });
+    request.headers_mut().insert(X_REQUEST_ID, request_id.clone());
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let consumer = api_key(&state, request.headers()).as_deref().map(key_id);
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id.to_str().unwrap_or_default()
+    );
+    // Trace-only attributes, kept out of the log lines.
+    span.set_parent(telemetry::parent(request.headers()));
+    span.set_attribute("http.request.method", method.to_string());
+    span.set_attribute("url.path", path.clone());
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    let latency = started.elapsed();
+
+    let status = response.status().as_u16();
+    span.set_attribute("http.response.status_code", i64::from(status));
+    let bytes = response.body().size_hint().exact();
+    let cache = response
+        .headers()
+        .get(&X_CACHE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-");
+    let api_key = consumer.as_deref().unwrap_or("-");
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+    macro_rules! access_event {
+        ($level:expr) => {
+            tracing::event!(
+                $level,
+                %method,
+                %path,
+                status,
+                latency_ms,
+                bytes,
+                cache,
+                api_key,
+                "{} {} {} in {:.1?}",
+                method,
+                path,
+                status,
+                latency
+            )
+        };
+    }
+    span.in_scope(|| match state.config().access_log() {
+        None => {}
+        Some(Level::ERROR) => access_event!(Level::ERROR),
+        Some(Level::WARN) => access_event!(Level::WARN),
+        Some(Level::INFO) => access_event!(Level::INFO),
+        Some(Level::DEBUG) => access_event!(Level::DEBUG),
+        Some(Level::TRACE) => access_event!(Level::TRACE),
+    });
+
+    response.headers_mut().insert(X_REQUEST_ID, request_id);
+    response
+}
+
+/// Answers 429 once a client exhausts its bucket.
+pub async fn rate_limit(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+
+    let client = client_ip(&state.config(), request.headers(), peer);
+    match limiter.check(client) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            tracing::debug!("Rate limited {}", client);
+            ApiError::rate_limited(retry_after).into_response()
+        }
+    }
+}
+
+/// The address a request came from: the peer, or what a proxy forwarded
+/// when `trust_proxy` is set or the peer is one of `trusted_proxies`.
+fn client_ip(config: &Config, headers: &axum::http::HeaderMap, peer: SocketAddr) -> IpAddr {
+    let nets = &config.trusted_proxy_nets;
+    let trusted = match nets.as_slice() {
+        [] => config.trust_proxy,
+        nets => in_nets(nets, peer.ip()),
+    };
+    ratelimit::client_ip(headers, peer, trusted, config.forwarded_header, nets)
+}
+
+/// Routes answered with a stream, which `request_timeout` leaves to
+/// `stream_idle_timeout`.
+const STREAMING_ROUTES: [&str; 4] = ["/events", "/ws", "/:site/events", "/:site/ws"];
+
+/// Answers 504 to requests not answered within `request_timeout`. An
+/// upstream fetch the request started carries on, so a retry finds it
+/// cached.
+pub async fn request_timeout(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let streaming = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| STREAMING_ROUTES.contains(&path.as_str()));
+    if streaming {
+        return next.run(request).await;
+    }
+
+    let timeout = state.config().request_timeout;
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("Gave up on a request after {:?}", timeout);
+            ApiError::timed_out().into_response()
+        }
+    }
+}
+
+/// Answers 413 to GET and HEAD requests that come with a body. No route
+/// reads one, so it could only tie up the connection.
+pub async fn reject_body(request: Request, next: Next) -> Response {
+    let method = request.method();
+    let safe = method == axum::http::Method::GET || method == axum::http::Method::HEAD;
+    if safe && !request.body().is_end_stream() {
+        return ApiError::body_not_allowed().into_response();
+    }
+    next.run(request).await
+}
+
+/// The routes `admin_allow_cidrs` restricts.
+const ADMIN_ROUTES: [&str; 5] =
+    ["/cache/purge", "/admin/reload", "/status", "/webhook", "/:site/webhook"];
+
+/// Answers 403 to admin route requests from outside `admin_allow_cidrs`,
+/// ahead of any token check. Forwarded addresses are only believed from
+/// `trusted_proxies` here, since `trust_proxy` alone would let any client
+/// claim an allowed address.
+pub async fn admin_allowlist(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    let admin = request
+        .extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|path| ADMIN_ROUTES.contains(&path.as_str()));
+    if admin && !config.admin_allow_nets.is_empty() {
+        let nets = &config.trusted_proxy_nets;
+        let trusted = in_nets(nets, peer.ip());
+        let header = config.forwarded_header;
+        let client = ratelimit::client_ip(request.headers(), peer, trusted, header, nets);
+        if !in_nets(&config.admin_allow_nets, client) {
+            tracing::warn!("Refused admin request from {}", client);
+            return ApiError::forbidden().into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Checks `Authorization: Bearer <ADMIN_TOKEN>` in constant time.
+pub fn is_admin(state: &AppState, headers: &axum::http::HeaderMap) -> bool {
+    let Some(expected) = &state.config().admin_token else {
+        return false;
+    };
+
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Rejects requests without a valid API key when `api_keys` is set. The
+/// admin token is accepted in its place, so admin routes need only that.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config().api_keys.is_empty()
+        || api_key(&state, request.headers()).is_some()
+        || is_admin(&state, request.headers())
+    {
+        return next.run(request).await;
+    }
+    ApiError::unauthorized().into_response()
+}
+
+/// The configured key presented as `Authorization: Bearer <key>` or
+/// `X-Api-Key: <key>`, if any. Every key is compared, in constant time, so
+/// timing doesn't give away which one matched.
+fn api_key(state: &AppState, headers: &axum::http::HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let header = headers.get(&X_API_KEY).and_then(|value| value.to_str().ok());
+
+    let mut found = None;
+    let config = state.config();
+    for key in &config.api_keys {
+        for presented in [bearer, header].into_iter().flatten() {
+            if constant_time_eq(presented.as_bytes(), key.as_bytes()) {
+                found = Some(key.clone());
+            }
+        }
+    }
+    found
+}
+
+/// Identifies an API key in the access log without revealing it: the first
+/// eight hex digits of its SHA-256.
+fn key_id(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))[..8].to_string()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,1156 @@
+mod aliases;
+mod badge;
+mod breaker;
+mod cache;
+pub mod config;
+mod countries;
+mod discord;
+pub mod error;
+mod exclude;
+mod feed;
+mod fetcher;
+mod graphql;
+mod history;
+mod html;
+mod export;
+mod layers;
+mod metrics;
+mod mock;
+mod openapi;
+mod params;
+mod plausible;
+mod publish;
+mod ratelimit;
+mod refresh;
+mod respond;
+mod routes;
+mod s3;
+mod search;
+mod snapshot;
+mod sources;
+mod sparkline;
+mod spikes;
+mod telemetry;
+mod tls;
+mod umami;
+#[cfg(unix)]
+mod unix;
+pub mod upstream;
+mod webhook;
+mod widget;
+mod xml;
+
+pub use fetcher::{Fetched, HttpFetcher, StatsFetcher, Validators};
+pub use mock::MockFetcher;
+pub use telemetry::init_logging;
+pub use umami::UmamiFetcher;
+
+use aliases::Aliases;
+use axum::{
+    http::header::{HeaderName, HeaderValue, CACHE_CONTROL, VARY},
+    middleware,
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use breaker::CircuitBreaker;
+use cache::{Cache, CacheEntry, CacheKey};
+use config::{AnalyticsBackend, BindAddr, Config};
+use error::FetchError;
+use feed::Feed;
+use history::History;
+use layers::{
+    admin_allowlist, log_requests, rate_limit, reject_body, request_timeout, require_api_key,
+    track_requests,
+};
+use metrics::Metrics;
+use params::{Search, Sort, View};
+use plausible::{ArtistRow, PlausibleResponse};
+use publish::{check_bucket, discord_loop, write_snapshots};
+use ratelimit::RateLimiter;
+use refresh::{attempt_page, refresh_loop, warm_up};
+use routes::admin::{metrics_handler, purge, reload, status, webhook_test};
+use routes::artists::{artist, sparkline_handler, timeseries};
+use routes::breakdowns::{
+    browser_breakdown, country_breakdown, device_breakdown, page_breakdown, realtime,
+    source_breakdown,
+};
+use routes::compare::{compare, trending};
+use routes::formats::{
+    badge_handler, feed_handler, jsonp, leaderboard_page, shields_artist, shields_total,
+    widget_preview, widget_script,
+};
+use routes::graphql::graphql_handler;
+use routes::health::{docs, healthz, livez, openapi_handler, readyz};
+use routes::history::{changes, digest, history_csv, history_handler, movers, snapshots};
+use routes::leaderboard::{bundle, handler, prop, stats_csv, summary, top};
+use routes::live::{events, ws};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, watch, RwLock, Semaphore};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, Any, CorsLayer},
+};
+use upstream::UpstreamQuery;
+
+/// Clients tracked by the rate limiter before the least recent is forgotten.
+const RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+
+/// Everything the handlers and background tasks share.
+#[derive(Clone)]
+pub struct AppState {
+    /// For webhooks and uploads; upstream requests go through `fetcher`.
+    client: reqwest::Client,
+    fetcher: Arc<dyn StatsFetcher>,
+    cache: Arc<RwLock<Cache>>,
+    /// Swapped whole when the configuration is reloaded; see `config()`.
+    config: Arc<std::sync::RwLock<Arc<Config>>>,
+    /// Fetches currently in flight, by key. Concurrent refreshes of the same
+    /// key subscribe to the existing fetch instead of issuing their own.
+    inflight: Arc<Mutex<HashMap<CacheKey, watch::Receiver<Option<FetchResult>>>>>,
+    /// One permit per fetch the upstream may be sent at once, taken after
+    /// coalescing so only distinct keys wait for one.
+    upstream_slots: Arc<Semaphore>,
+    started_at: Instant,
+    /// When an upstream fetch last succeeded, for any key.
+    last_success: Arc<Mutex<Option<SystemTime>>>,
+    /// How long that fetch took, retries and pages included.
+    last_success_duration: Arc<Mutex<Option<Duration>>>,
+    /// The last upstream fetch that failed, and when.
+    last_error: Arc<Mutex<Option<(SystemTime, FetchError)>>>,
+    /// Recently failed fetches by key, answered from memory until they
+    /// expire; see `negative_cache_ttl_secs`. Kept apart from the cache so
+    /// a failure never displaces good data.
+    failures: Arc<Mutex<HashMap<CacheKey, Failure>>>,
+    /// Long-running background tasks by name, for `/status`.
+    tasks: Arc<Mutex<Vec<(&'static str, tokio::task::AbortHandle)>>>,
+    metrics: Arc<Metrics>,
+    /// Short-circuits upstream requests during an outage.
+    breaker: Arc<CircuitBreaker>,
+    /// Until when upstream requests are held off, after the upstream
+    /// answered 429.
+    rate_limited_until: Arc<Mutex<Option<Instant>>>,
+    /// Per-client limits on the public routes, unless disabled.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Artist aliases applied when merging rows, reloaded from
+    /// `aliases_file`.
+    aliases: Arc<std::sync::RwLock<Aliases>>,
+    /// The entry each cached entry replaced, by key, to report rank movement
+    /// against.
+    previous: Arc<Mutex<HashMap<CacheKey, Arc<CacheEntry>>>>,
+    /// Leaderboard snapshots, when `history_db` is set.
+    history: Option<Arc<History>>,
+    /// Atom feed of top-artist changes, by site key.
+    feeds: Arc<Mutex<HashMap<String, Feed>>>,
+    /// Entries whose data changed on refresh, for streaming clients.
+    updates: broadcast::Sender<Update>,
+    /// What spike detection knows of each artist, by site key.
+    spikes: Arc<Mutex<HashMap<String, spikes::Detector>>>,
+    /// Spikes to announce on Discord, when `spike_notify` is set.
+    alerts: broadcast::Sender<Alert>,
+    /// Flips to `true` when the server starts shutting down.
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Where snapshots are uploaded, when `s3_bucket` is set.
+    bucket: Option<Arc<s3::Bucket>>,
+    /// Set by `--snapshot-only`: uploads finish before the refresh that
+    /// started them returns, since the process exits right after.
+    run_once: bool,
+    /// Set once the startup warm-up has fetched every `warm_keys` entry,
+    /// or straight away when there are none.
+    warmed: Arc<AtomicBool>,
+}
+
+/// A refresh that brought different data for `key`.
+#[derive(Clone)]
+struct Update {
+    key: CacheKey,
+    entry: Arc<CacheEntry>,
+}
+
+/// A fetch that failed, and until when its key isn't tried again.
+struct Failure {
+    error: FetchError,
+    at: SystemTime,
+    until: Instant,
+}
+
+/// Spikes found in one refresh of a site's all-time leaderboard.
+#[derive(Clone)]
+struct Alert {
+    site: String,
+    spikes: Arc<Vec<spikes::Spike>>,
+    at: SystemTime,
+}
+
+/// Updates buffered per streaming client. One that falls further behind is
+/// disconnected rather than holding up the refresh.
+const UPDATES_CAPACITY: usize = 16;
+/// How often idle event streams get a comment, and WebSockets a ping, so
+/// proxies keep them open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+const X_CACHE_EXPIRES_IN: HeaderName = HeaderName::from_static("x-cache-expires-in");
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Alternative to `Authorization: Bearer` for presenting an API key.
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+/// When the leaderboard that rank movement is reported against was fetched.
+const X_COMPARED_TO: HeaderName = HeaderName::from_static("x-compared-to");
+/// Rows across every page of a paged list, and the pages they fill.
+const X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+const X_TOTAL_PAGES: HeaderName = HeaderName::from_static("x-total-pages");
+const X_PAGE: HeaderName = HeaderName::from_static("x-page");
+const X_PER_PAGE: HeaderName = HeaderName::from_static("x-per-page");
+/// Marks `fallback_file` served in place of an upstream error.
+const X_FALLBACK: HeaderName = HeaderName::from_static("x-fallback");
+
+/// Maximum number of characters of an upstream body included in logs.
+const SNIPPET_LEN: usize = 200;
+
+type FetchResult = Result<Arc<CacheEntry>, FetchError>;
+
+impl AppState {
+    /// State for serving `config` with stats from `fetcher`: loads
+    /// `cache_file` and opens `history_db` when they are set.
+    pub async fn new(config: Config, fetcher: Arc<dyn StatsFetcher>) -> Result<Self, String> {
+        let rate_limiter = if config.rate_limit_per_minute == 0 {
+            tracing::info!("Rate limiting disabled (rate_limit_per_minute = 0)");
+            None
+        } else {
+            tracing::info!(
+                "Rate limit: {} requests/minute per client, burst {}",
+                config.rate_limit_per_minute,
+                config.rate_limit_burst
+            );
+            Some(Arc::new(RateLimiter::new(
+                config.rate_limit_per_minute,
+                config.rate_limit_burst,
+                RATE_LIMIT_MAX_CLIENTS,
+            )))
+        };
+
+        let mut initial = Cache::new(config.cache_max_entries, config.cache_max_bytes);
+        if let Some(path) = &config.cache_file {
+            cache::load(path, &mut initial).await;
+        }
+
+        let history = match &config.history_db {
+            Some(path) => {
+                let history = History::open(path)
+                    .map_err(|e| format!("failed to open history database {}", e))?;
+                tracing::info!("Recording history in {}", path.display());
+                Some(Arc::new(history))
+            }
+            None => None,
+        };
+
+        let client = http_client(&config);
+
+        let bucket = config.s3_bucket.as_ref().map(|name| {
+            tracing::info!("Uploading snapshots to bucket {}", name);
+            Arc::new(s3::Bucket::new(
+                client.clone(),
+                config.s3_endpoint.as_deref().unwrap_or_default(),
+                name,
+                &config.s3_region,
+                config.s3_access_key_id.as_deref().unwrap_or_default(),
+                config.s3_secret_access_key.as_deref().unwrap_or_default(),
+            ))
+        });
+
+        let state = AppState {
+            client,
+            fetcher,
+            cache: Arc::new(RwLock::new(initial)),
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+            upstream_slots: Arc::new(Semaphore::new(config.upstream_max_concurrency)),
+            started_at: Instant::now(),
+            last_success: Arc::new(Mutex::new(None)),
+            last_success_duration: Arc::new(Mutex::new(None)),
+            last_error: Arc::new(Mutex::new(None)),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+            metrics: Arc::new(Metrics::default()),
+            breaker: Arc::new(CircuitBreaker::new(
+                config.circuit_failure_threshold,
+                config.circuit_cooldown,
+            )),
+            rate_limited_until: Arc::new(Mutex::new(None)),
+            rate_limiter,
+            aliases: Arc::default(),
+            previous: Arc::new(Mutex::new(HashMap::new())),
+            history,
+            feeds: Arc::new(Mutex::new(HashMap::new())),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+            spikes: Arc::new(Mutex::new(HashMap::new())),
+            alerts: broadcast::channel(UPDATES_CAPACITY).0,
+            shutdown: Arc::new(watch::channel(false).0),
+            bucket,
+            run_once: false,
+            warmed: Arc::new(AtomicBool::new(config.warm_keys.is_empty())),
+            config: Arc::new(std::sync::RwLock::new(Arc::new(config))),
+        };
+        reload_aliases(&state);
+        Ok(state)
+    }
+    /// The configuration in effect. Hold on to it rather than calling this
+    /// repeatedly, so a reload can't change it part way through a request.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+}
+
+/// Every route `state.config` enables, with its middleware.
+pub fn build_router(state: AppState) -> Router {
+    let config = state.config();
+    let mut app = Router::new()
+        .route("/", get(handler))
+        .route("/stats.csv", get(stats_csv))
+        .route("/artist/:name", get(artist))
+        .route("/artist/:name/timeseries", get(timeseries))
+        .route("/artist/:name/sparkline.svg", get(sparkline_handler))
+        .route("/top/:n", get(top))
+        .route("/summary", get(summary))
+        .route("/bundle", get(bundle))
+        .route("/feed.xml", get(feed_handler))
+        .route("/events", get(events))
+        .route("/ws", get(ws))
+        .route("/leaderboard", get(leaderboard_page))
+        .route("/badge/:file", get(badge_handler))
+        .route("/shields/total", get(shields_total))
+        .route("/shields/:name", get(shields_artist))
+        .route("/widget.js", get(widget_script))
+        .route("/widget/preview", get(widget_preview))
+        .route("/trending", get(trending))
+        .route("/compare", get(compare))
+        .route("/prop/:prop", get(prop))
+        .route("/breakdown/country", get(country_breakdown))
+        .route("/breakdown/page", get(page_breakdown))
+        .route("/breakdown/source", get(source_breakdown))
+        .route("/breakdown/device", get(device_breakdown))
+        .route("/breakdown/browser", get(browser_breakdown))
+        .route("/realtime", get(realtime))
+        .route("/changes", get(changes))
+        .route("/graphql", post(graphql_handler))
+        .route("/:site/", get(handler))
+        .route("/:site/stats.csv", get(stats_csv))
+        .route("/:site/artist/:name", get(artist))
+        .route("/:site/artist/:name/timeseries", get(timeseries))
+        .route("/:site/artist/:name/sparkline.svg", get(sparkline_handler))
+        .route("/:site/top/:n", get(top))
+        .route("/:site/summary", get(summary))
+        .route("/:site/bundle", get(bundle))
+        .route("/:site/feed.xml", get(feed_handler))
+        .route("/:site/events", get(events))
+        .route("/:site/ws", get(ws))
+        .route("/:site/leaderboard", get(leaderboard_page))
+        .route("/:site/badge/:file", get(badge_handler))
+        .route("/:site/shields/total", get(shields_total))
+        .route("/:site/shields/:name", get(shields_artist))
+        .route("/:site/widget.js", get(widget_script))
+        .route("/:site/widget/preview", get(widget_preview))
+        .route("/:site/trending", get(trending))
+        .route("/:site/compare", get(compare))
+        .route("/:site/prop/:prop", get(prop))
+        .route("/:site/breakdown/country", get(country_breakdown))
+        .route("/:site/breakdown/page", get(page_breakdown))
+        .route("/:site/breakdown/source", get(source_breakdown))
+        .route("/:site/breakdown/device", get(device_breakdown))
+        .route("/:site/breakdown/browser", get(browser_breakdown))
+        .route("/:site/realtime", get(realtime))
+        .route("/:site/changes", get(changes))
+        .route("/:site/graphql", post(graphql_handler))
+        .route("/cache/purge", post(purge))
+        .route("/status", get(status))
+        .route("/admin/reload", post(reload))
+        .route("/webhook", post(webhook_test))
+        .route("/:site/webhook", post(webhook_test));
+    if config.history_db.is_some() {
+        app = app
+            .route("/history", get(history_handler))
+            .route("/history/snapshots", get(snapshots))
+            .route("/history.csv", get(history_csv))
+            .route("/digest", get(digest))
+            .route("/movers", get(movers))
+            .route("/:site/history", get(history_handler))
+            .route("/:site/history/snapshots", get(snapshots))
+            .route("/:site/history.csv", get(history_csv))
+            .route("/:site/digest", get(digest))
+            .route("/:site/movers", get(movers));
+    }
+    // The API description stays readable without a key, so the docs page
+    // can load it.
+    app = app
+        .route_layer(middleware::from_fn_with_state(state.clone(), jsonp))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), admin_allowlist))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/docs", get(docs))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .route_layer(middleware::from_fn(reject_body))
+        .route_layer(middleware::from_fn_with_state(state.clone(), request_timeout))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_requests));
+
+    // Registered after the tracking and rate limiting layers so probes and
+    // scrapes are neither counted as traffic nor throttled.
+    app = app
+        .route("/healthz", get(healthz))
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz));
+    if config.metrics_enabled {
+        let metrics = get(metrics_handler)
+            .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key));
+        app = app.route("/metrics", metrics);
+    }
+
+    // Negotiates gzip/br from Accept-Encoding and adds Vary; clients that
+    // send no Accept-Encoding get the identity body.
+    let cors = cors_layer(&state);
+    app.with_state(state.clone())
+        .layer(CompressionLayer::new())
+        .layer(cors)
+        .layer(middleware::from_fn_with_state(state, log_requests))
+}
+
+/// Serves `config` against the Plausible API until ctrl-c or SIGTERM. With
+/// `snapshot_only`, fetches each site once, publishes its snapshot and
+/// exits instead.
+pub async fn run(config: Config, snapshot_only: bool) {
+    if let Some(path) = &config.source {
+        tracing::info!("Loaded configuration from {}", path.display());
+    }
+    for site in &config.sites {
+        tracing::info!("Serving site {} as /{}/", site.id, site.key);
+    }
+    if config.cache_ttl.is_zero() {
+        tracing::info!("Caching disabled (cache_ttl_secs = 0)");
+    } else {
+        tracing::info!("Cache TTL: {}s", config.cache_ttl.as_secs());
+    }
+    tracing::info!(
+        "Cache holds up to {} entries and {} bytes",
+        config.cache_max_entries,
+        config.cache_max_bytes
+    );
+    tracing::info!(
+        "Upstream responses are limited to {} bytes",
+        config.upstream_max_body_bytes
+    );
+    tracing::info!("Up to {} upstream fetches run at once", config.upstream_max_concurrency);
+    if config.admin_token.is_none() {
+        tracing::info!("No admin token set, admin routes are disabled");
+    }
+    if config.danger_accept_invalid_certs {
+        tracing::warn!(
+            "DANGER_ACCEPT_INVALID_CERTS is on: outbound TLS certificates are NOT verified, \
+             so anyone on the network path can read and alter the traffic. Never use this \
+             outside development"
+        );
+    }
+
+    if !config.mock_data_file.is_empty() {
+        let files: Vec<String> =
+            config.mock_data_file.iter().map(|path| path.display().to_string()).collect();
+        tracing::warn!("Serving mock data from {}; no upstream is contacted", files.join(", "));
+    } else if config.analytics_backend == AnalyticsBackend::Umami {
+        let url = config.umami_base_url.as_deref().unwrap_or_default();
+        tracing::info!("Reading stats from Umami at {}", url);
+    }
+    let fetcher = match build_fetcher(&config) {
+        Ok(fetcher) => fetcher,
+        Err(e) => {
+            tracing::error!("Failed to start: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut state = match AppState::new(config, fetcher).await {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::error!("Failed to start: {}", e);
+            std::process::exit(1);
+        }
+    };
+    state.run_once = snapshot_only;
+    let config = state.config();
+    if let (Some(bucket), true) = (&state.bucket, config.s3_startup_check) {
+        if !check_bucket(&state, bucket).await {
+            std::process::exit(1);
+        }
+    }
+    if snapshot_only {
+        let ok = write_snapshots(&state).await;
+        telemetry::shutdown().await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Some(url) = config.discord_webhook_url.clone() {
+        // Subscribed before the first fetch so its change isn't missed.
+        let (updates, alerts) = (state.updates.subscribe(), state.alerts.subscribe());
+        let task = tokio::spawn(discord_loop(state.clone(), url, updates, alerts));
+        track_task(&state, "discord", &task);
+    }
+
+    if !check_credentials(&state).await && config.strict_startup {
+        tracing::error!("Exiting: upstream rejected the bearer token and strict_startup is set");
+        std::process::exit(1);
+    }
+
+    if !config.warm_keys.is_empty() {
+        let task = tokio::spawn(warm_up(state.clone()));
+        track_task(&state, "warm_up", &task);
+    }
+
+    let refresher = if config.cache_ttl.is_zero() {
+        None
+    } else {
+        let task = tokio::spawn(refresh_loop(state.clone(), state.shutdown.subscribe()));
+        track_task(&state, "refresh", &task);
+        Some(task)
+    };
+
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => match tls::Tls::load(cert, key).await {
+            Ok(tls) => {
+                let task = tokio::spawn(tls.clone().watch(state.shutdown.subscribe()));
+                track_task(&state, "tls_watch", &task);
+                Some(tls)
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    #[cfg(unix)]
+    {
+        let shutdown = state.shutdown.subscribe();
+        let task = tokio::spawn(reload_on_sighup(state.clone(), tls.clone(), shutdown));
+        track_task(&state, "sighup_reload", &task);
+    }
+
+    let shutdown = state.shutdown.clone();
+    let app = build_router(state);
+
+    let stopped = wait_for_shutdown(shutdown.subscribe());
+    let mut server = match &config.bind_addr {
+        BindAddr::Tcp(addr) => {
+            let listener = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("Failed to bind {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            };
+
+            // With port 0 the OS picks the port, so log what was actually bound.
+            let local_addr = listener.local_addr().unwrap_or(*addr);
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            tracing::info!("Server running on {}://{}", scheme, local_addr);
+
+            match tls {
+                Some(tls) => {
+                    let config = config.clone();
+                    tokio::spawn(async move {
+                        tls::serve(listener, app, &tls, &config, stopped).await
+                    })
+                }
+                None => {
+                    let config = config.clone();
+                    tokio::spawn(async move { serve(listener, app, &config, stopped).await })
+                }
+            }
+        }
+        #[cfg(unix)]
+        BindAddr::Unix(path) => {
+            let access = unix::Access {
+                mode: config.socket_mode(),
+                owner: config.unix_socket_owner.as_deref(),
+                group: config.unix_socket_group.as_deref(),
+            };
+            let listener = match unix::bind(path, &access) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            tracing::info!("Server running on unix:{}", path.display());
+            let config = config.clone();
+            tokio::spawn(async move { unix::serve(listener, app, &config, stopped).await })
+        }
+        #[cfg(not(unix))]
+        BindAddr::Unix(_) => unreachable!("rejected when the configuration is loaded"),
+    };
+
+    tokio::select! {
+        result = &mut server => {
+            tracing::error!("Server stopped unexpectedly: {:?}", result);
+            std::process::exit(1);
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    // Stop accepting connections and tell background tasks to finish their
+    // current iteration; whatever is still running at the deadline is dropped.
+    tracing::info!("Shutting down, draining for up to {:?}", config.shutdown_drain);
+    let deadline = tokio::time::Instant::now() + config.shutdown_drain;
+    let _ = shutdown.send(true);
+
+    if tokio::time::timeout_at(deadline, server).await.is_err() {
+        tracing::warn!("Drain timeout elapsed, dropping open connections");
+    }
+    if let Some(refresher) = refresher {
+        if tokio::time::timeout_at(deadline, refresher).await.is_err() {
+            tracing::warn!("Background refresh did not stop before the drain timeout");
+        }
+    }
+
+    #[cfg(unix)]
+    if let BindAddr::Unix(path) = &config.bind_addr {
+        unix::remove(path);
+    }
+    telemetry::shutdown().await;
+
+    tracing::info!("Shutdown complete");
+}
+
+/// The fetcher stats are read through: one answering from `mock_data_file`
+/// when it is set, else one for the analytics backend.
+fn build_fetcher(config: &Config) -> Result<Arc<dyn StatsFetcher>, String> {
+    if !config.mock_data_file.is_empty() {
+        let fetcher = MockFetcher::new(&config.mock_data_file)
+            .map_err(|e| format!("invalid mock data: {}", e))?;
+        return Ok(Arc::new(fetcher));
+    }
+    Ok(match config.analytics_backend {
+        AnalyticsBackend::Plausible => Arc::new(HttpFetcher::new(config)),
+        AnalyticsBackend::Umami => Arc::new(UmamiFetcher::new(config)),
+    })
+}
+
+/// What is wrong with `config` that loading it doesn't catch, for
+/// `--check`: the files only read once serving starts and, with `upstream`,
+/// any site whose probe the upstream fails. Empty when `config` is fit to
+/// serve. Nothing is written.
+pub async fn check(config: &Config, upstream: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(path) = &config.aliases_file {
+        if let Err(e) = Aliases::load(path) {
+            problems.push(e);
+        }
+    }
+    if let (Some(cert), Some(key)) = (&config.tls_cert_path, &config.tls_key_path) {
+        if let Err(e) = tls::Tls::load(cert, key).await {
+            problems.push(e);
+        }
+    }
+    // Created when missing, but only inside a directory that exists.
+    if let Some(path) = &config.history_db {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        if !dir.is_dir() {
+            problems.push(format!("Directory {} for history_db does not exist", dir.display()));
+        }
+    }
+
+    let fetcher = match build_fetcher(config) {
+        Ok(fetcher) => fetcher,
+        Err(e) => {
+            problems.push(e);
+            return problems;
+        }
+    };
+    if upstream {
+        for site in &config.sites {
+            let query = UpstreamQuery::probe(site);
+            match fetcher.fetch(&query, 1, config.upstream_attempt_timeout).await {
+                Ok(_) => tracing::info!("Upstream answered the probe for {}", site.key),
+                Err(e) => {
+                    problems.push(format!("Upstream probe for site {} failed: {}", site.key, e))
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Sends one authenticated request per site so a wrong or expired token
+/// shows up at boot rather than on the first cache miss. Returns `false`
+/// only when the upstream rejects a token; being unreachable is left to
+/// the usual retry and fallback handling.
+async fn check_credentials(state: &AppState) -> bool {
+    let mut ok = true;
+    for site in &state.config().sites {
+        let query = UpstreamQuery::probe(site);
+        let timeout = state.config().upstream_attempt_timeout;
+        match attempt_page(state, &query, 1, timeout, 1, &Validators::default()).await {
+            Ok(_) => tracing::info!("Upstream accepted the bearer token for {}", site.key),
+            Err(FetchError::Status { status, .. })
+                if status == reqwest::StatusCode::UNAUTHORIZED
+                    || status == reqwest::StatusCode::FORBIDDEN =>
+            {
+                let source = match site.bearer_token {
+                    _ if state.config().analytics_backend == AnalyticsBackend::Umami => {
+                        "UMAMI_API_TOKEN".to_string()
+                    }
+                    Some(_) => {
+                        format!("BEARER_TOKEN_{}", site.key.to_uppercase().replace('-', "_"))
+                    }
+                    None if state.config().bearer_token_file.is_some() => {
+                        "BEARER_TOKEN_FILE".to_string()
+                    }
+                    None => "BEARER_TOKEN".to_string(),
+                };
+                tracing::error!(
+                    "Upstream rejected the bearer token for site {} ({}), check {}",
+                    site.key,
+                    status,
+                    source
+                );
+                ok = false;
+            }
+            Err(e) => tracing::warn!("Could not verify the bearer token for {}: {}", site.key, e),
+        }
+    }
+    ok
+}
+
+/// Resolves on ctrl-c, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received ctrl-c"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// Re-reads `aliases_file`. A file that can't be read or parsed logs an
+/// error and disables aliasing until it is fixed, rather than failing the
+/// refresh that triggered the reload.
+fn reload_aliases(state: &AppState) {
+    let Some(path) = &state.config().aliases_file else {
+        return;
+    };
+
+    let aliases = match Aliases::load(path) {
+        Ok(aliases) => {
+            tracing::debug!("Loaded {} artist names from {}", aliases.len(), path.display());
+            aliases
+        }
+        Err(e) => {
+            tracing::error!("{}, aliasing disabled", e);
+            Aliases::default()
+        }
+    };
+    *state.aliases.write().unwrap() = aliases;
+}
+
+/// Reads the configuration again, with `aliases_file`, and swaps both in.
+/// Anything invalid is logged and leaves the running configuration as it
+/// was. Returns the settings whose changes wait for a restart, since only
+/// startup reads them.
+fn reload_config(state: &AppState) -> Result<Vec<&'static str>, String> {
+    let loaded = Config::load().and_then(|config| {
+        let aliases = match &config.aliases_file {
+            Some(path) => Aliases::load(path)?,
+            None => Aliases::default(),
+        };
+        Ok((config, aliases))
+    });
+    let (mut config, aliases) = loaded.map_err(|e| {
+        tracing::error!("Kept the running configuration, the new one is invalid: {}", e);
+        e
+    })?;
+    let restart_required = config.keep_fixed(&state.config());
+    state.fetcher.reload_credentials();
+
+    *state.config.write().unwrap() = Arc::new(config);
+    *state.aliases.write().unwrap() = aliases;
+    tracing::info!("Reloaded configuration");
+    if !restart_required.is_empty() {
+        tracing::warn!(
+            "Changes to {} take effect on the next restart",
+            restart_required.join(", ")
+        );
+    }
+    Ok(restart_required)
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(
+    state: AppState,
+    tls: Option<tls::Tls>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                tracing::info!("Received SIGHUP, reloading configuration and certificates");
+                let _ = reload_config(&state);
+                if let Some(tls) = &tls {
+                    tls.reload().await;
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+}
+
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+/// Serves `app` over HTTP on `listener` until `shutdown` resolves, then
+/// waits for open connections to finish.
+async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    config: &Config,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    let mut server = axum_server::from_tcp(listener.into_std()?).handle(handle);
+    limit_connections(server.http_builder(), config);
+    server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+}
+
+/// Applies `header_read_timeout` and `max_request_header_bytes` to the
+/// connections `builder` serves, so a client can't hold one open by
+/// sending its headers slowly or without end.
+pub(crate) fn limit_connections(
+    builder: &mut hyper_util::server::conn::auto::Builder<hyper_util::rt::TokioExecutor>,
+    config: &Config,
+) {
+    builder
+        .http1()
+        .timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(config.header_read_timeout)
+        .max_buf_size(config.max_request_header_bytes);
+    let max = u32::try_from(config.max_request_header_bytes).unwrap_or(u32::MAX);
+    builder.http2().max_header_list_size(max);
+}
+
+/// How long browsers may cache a preflight response.
+const CORS_MAX_AGE: Duration = Duration::from_secs(86400);
+
+/// Builds the CORS policy from `CORS_ORIGINS`: `*`, or a comma-separated list
+/// of exact origins. Unset keeps the historical allow-any policy. Origins
+/// are checked against the configuration in effect, so a reload applies to
+/// the next request.
+fn cors_layer(state: &AppState) -> CorsLayer {
+    if let Some(origins) = state.config().cors_origin_list() {
+        tracing::info!("CORS allowed origins: {}", origins.join(", "));
+    }
+    let state = state.clone();
+    let allow_origin = AllowOrigin::predicate(move |origin, _| {
+        let config = state.config();
+        match config.cors_origin_list() {
+            None => true,
+            Some(origins) => origins.iter().any(|allowed| origin == allowed.as_bytes()),
+        }
+    });
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([
+            axum::http::Method::GET,
+            axum::http::Method::HEAD,
+            // For `/graphql`; the other POST routes need the admin token.
+            axum::http::Method::POST,
+            axum::http::Method::OPTIONS,
+        ])
+        .allow_headers(Any)
+        .expose_headers([
+            X_COMPARED_TO,
+            X_TOTAL_COUNT,
+            X_TOTAL_PAGES,
+            X_PAGE,
+            X_PER_PAGE,
+            X_FALLBACK,
+            axum::http::header::LINK,
+        ])
+        .max_age(CORS_MAX_AGE)
+}
+
+/// Lists `task` under `name` in `/status`.
+fn track_task<T>(state: &AppState, name: &'static str, task: &tokio::task::JoinHandle<T>) {
+    state.tasks.lock().unwrap().push((name, task.abort_handle()));
+}
+
+const JSON: &str = "application/json";
+const SVG: &str = "image/svg+xml";
+const HTML: &str = "text/html; charset=utf-8";
+const ATOM: &str = "application/atom+xml; charset=utf-8";
+const CSV: &str = "text/csv; charset=utf-8";
+const XML: &str = "application/xml; charset=utf-8";
+const MSGPACK: &str = "application/msgpack";
+const JAVASCRIPT: &str = "application/javascript; charset=utf-8";
+
+/// The ranked rows of `board` `search` matches, ordered by `sort` and cut
+/// to `limit`.
+fn ranked_rows<'a>(
+    board: &'a PlausibleResponse,
+    search: Option<&Search>,
+    sort: Option<&Sort>,
+    limit: Option<usize>,
+) -> Vec<(usize, &'a ArtistRow)> {
+    let mut rows = ranked(board.results.iter());
+    if let Some(search) = search {
+        rows.retain(|(_, row)| search.matches(&row.name));
+    }
+    if let Some(sort) = sort {
+        rows.sort_by(|(_, a), (_, b)| sort.compare(a, b));
+    }
+    rows.truncate(limit.unwrap_or(rows.len()));
+    rows
+}
+
+/// `rows` paired with their 1-based positions, the ranks they are served
+/// with whatever order they are shown in.
+fn ranked<'a>(rows: impl IntoIterator<Item = &'a ArtistRow>) -> Vec<(usize, &'a ArtistRow)> {
+    rows.into_iter().enumerate().map(|(index, row)| (index + 1, row)).collect()
+}
+
+/// Marks `response` as depending on `Accept`, which can pick its format.
+fn negotiated(mut response: Response) -> Response {
+    response.headers_mut().append(VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+/// A leaderboard row with its rank, as XML lists it.
+#[derive(Serialize)]
+struct RankedRow<'a> {
+    rank: usize,
+    #[serde(flatten)]
+    row: &'a ArtistRow,
+}
+
+/// `rows` as an `<artists>` document, each cut down to `fields` when set.
+fn artists_xml(rows: &[impl Serialize], fields: Option<&[&str]>) -> String {
+    match fields {
+        None => xml::render(&rows, "artists", "artist"),
+        Some(fields) => {
+            let mut rows = serde_json::to_value(rows).expect("rows serialize");
+            if let Some(rows) = rows.as_array_mut() {
+                rows.iter_mut().for_each(|row| project(row, fields));
+            }
+            xml::render(&rows, "artists", "artist")
+        }
+    }
+}
+
+/// Drops the keys of `row` that aren't `name` or among `fields`.
+fn project(row: &mut serde_json::Value, fields: &[&str]) {
+    if let serde_json::Value::Object(row) = row {
+        row.retain(|key, _| key == "name" || fields.contains(&key.as_str()));
+    }
+}
+
+/// `body` as JSON, indented when `view` asks for it.
+fn json_body(view: View, body: &impl Serialize) -> String {
+    let body = if view.pretty {
+        serde_json::to_string_pretty(body)
+    } else {
+        serde_json::to_string(body)
+    };
+    body.expect("response bodies serialize")
+}
+
+/// Keeps admin-only views out of shared caches.
+fn finish_view(view: View, mut response: Response) -> Response {
+    if view.unfiltered {
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("private, no-store"));
+    }
+    response
+}
+
+/// A fresh random number, for jitter and generated IDs. `RandomState` is
+/// seeded afresh per instance, which is random enough for both.
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// A client with the configured timeouts, connection pool, proxies and
+/// CA certificates. Requests set their own attempt timeout;
+/// `upstream_budget` is only a backstop.
+fn http_client(config: &Config) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .timeout(config.upstream_budget)
+        .connect_timeout(config.upstream_connect_timeout)
+        .pool_idle_timeout(config.upstream_pool_idle_timeout)
+        .pool_max_idle_per_host(config.upstream_pool_max_idle_per_host)
+        .danger_accept_invalid_certs(config.danger_accept_invalid_certs)
+        // Must come before the proxies: it also drops any already added.
+        .no_proxy();
+
+    let bypass = config.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+    let proxies = [
+        config.https_proxy.as_deref().map(reqwest::Proxy::https),
+        config.http_proxy.as_deref().map(reqwest::Proxy::http),
+    ];
+    for proxy in proxies.into_iter().flatten() {
+        let proxy = proxy.expect("proxy URLs are validated at startup");
+        builder = builder.proxy(proxy.no_proxy(bypass.clone()));
+    }
+    for cert in &config.ca_certs {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+
+    builder.build().expect("Failed to create HTTP client")
+}
+
+fn snippet(body: &str) -> String {
+    match body.char_indices().nth(SNIPPET_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Answers every fetch with `status`.
+    struct Refusing {
+        status: reqwest::StatusCode,
+    }
+
+    #[axum::async_trait]
+    impl StatsFetcher for Refusing {
+        async fn fetch(
+            &self,
+            _query: &UpstreamQuery,
+            _page: u32,
+            _timeout: Duration,
+        ) -> Result<String, FetchError> {
+            Err(FetchError::Status {
+                status: self.status,
+                snippet: String::new(),
+                retry_after: None,
+            })
+        }
+    }
+
+    fn fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/sample.json")
+    }
+
+    fn config() -> Config {
+        Config {
+            sites: vec![upstream::Site {
+                key: "default".to_string(),
+                id: "example.com".to_string(),
+                bearer_token: None,
+                goal: "Artist Click".to_string(),
+                property: "name".to_string(),
+            }],
+            upstream_attempt_timeout: Duration::from_secs(2),
+            ..Config::default()
+        }
+    }
+
+    async fn credentials_pass(fetcher: Arc<dyn StatsFetcher>) -> bool {
+        let state = AppState::new(config(), fetcher).await.unwrap();
+        check_credentials(&state).await
+    }
+
+    #[tokio::test]
+    async fn only_a_rejected_token_fails_the_credentials_check() {
+        for status in [reqwest::StatusCode::UNAUTHORIZED, reqwest::StatusCode::FORBIDDEN] {
+            assert!(!credentials_pass(Arc::new(Refusing { status })).await, "{}", status);
+        }
+        // Left to the usual retries rather than stopping the boot.
+        let status = reqwest::StatusCode::BAD_GATEWAY;
+        assert!(credentials_pass(Arc::new(Refusing { status })).await);
+        let fetcher = MockFetcher::new(&[fixture()]).unwrap();
+        assert!(credentials_pass(Arc::new(fetcher)).await);
+    }
+
+    #[tokio::test]
+    async fn check_passes_a_config_fit_to_serve() {
+        let config = Config {
+            mock_data_file: vec![fixture()],
+            ..config()
+        };
+        assert_eq!(check(&config, true).await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn check_names_each_problem() {
+        let missing = std::env::temp_dir().join("plausible-proxy-check-missing");
+        let config = Config {
+            aliases_file: Some(missing.join("aliases.toml")),
+            history_db: Some(missing.join("history.db")),
+            mock_data_file: vec![missing.join("sample.json")],
+            ..config()
+        };
+        let problems = check(&config, false).await;
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[1].starts_with("Directory"), "{}", problems[1]);
+        assert!(problems[2].starts_with("invalid mock data"), "{}", problems[2]);
+    }
+
+    #[tokio::test]
+    async fn check_upstream_reports_an_unreachable_upstream() {
+        // Nothing listens on the discard port.
+        let config = Config {
+            upstream_base_url: "http://127.0.0.1:9".to_string(),
+            ..config()
+        };
+        assert!(check(&config, false).await.is_empty());
+        let problems = check(&config, true).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("Upstream probe for site default failed"));
+    }
+}
@@ -1,125 +1,64 @@
-use axum::{
-    extract::State,
-    response::{IntoResponse, Response},
-    routing::get,
-    Router,
-};
-use once_cell::sync::Lazy;
-use reqwest::header::{AUTHORIZATION, HeaderMap};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::time::{Duration, Instant};
-use tower_http::cors::{CorsLayer, Any};
-
-const API_URL: &str = "https://plausible.canine.tools/api/stats/artistgrid.cx/custom-prop-values/name/?period=all&date=2025-11-07&filters=%5B%5B%22is%22%2C%22event%3Agoal%22%2C%5B%22Artist%20Click%22%5D%5D%5D&with_imported=true&detailed=true&order_by=%5B%5B%22visitors%22%2C%22desc%22%5D%5D&limit=100&page=1";
-const CACHE_DURATION: Duration = Duration::from_secs(600);
-
-#[derive(Clone)]
-struct CacheEntry {
-    data: String,
-    timestamp: Instant,
-}
-
-#[derive(Clone)]
-struct AppState {
-    client: reqwest::Client,
-    cache: Arc<RwLock<Option<CacheEntry>>>,
-    bearer_token: String,
-}
-
-static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
-    reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .expect("Failed to create HTTP client")
-});
+use plausible_proxy::config::Config;
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    tracing_subscriber::fmt::init();
-
-    let bearer_token = std::env::var("BEARER_TOKEN")
-        .expect("BEARER_TOKEN must be set in environment or .env file");
-
-    let state = AppState {
-        client: HTTP_CLIENT.clone(),
-        cache: Arc::new(RwLock::new(None)),
-        bearer_token,
-    };
-
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    let app = Router::new()
-        .route("/", get(handler))
-        .with_state(state)
-        .layer(cors);
+    // Loaded first since it says where traces go; it logs nothing itself.
+    let config = Config::load();
+    plausible_proxy::init_logging(config.as_ref().ok());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .unwrap();
-    
-    tracing::info!("Server running on http://0.0.0.0:3000");
-    
-    axum::serve(listener, app).await.unwrap();
-}
-
-async fn handler(State(state): State<AppState>) -> Response {
-    {
-        let cache = state.cache.read().await;
-        if let Some(entry) = cache.as_ref() {
-            if entry.timestamp.elapsed() < CACHE_DURATION {
-                tracing::info!("Returning cached response");
-                return entry.data.clone().into_response();
+    // With `--snapshot-only`, fetch each site once, write its snapshot and
+    // exit, for running from cron instead of serving. `--check` validates
+    // the configuration and the files it names without serving, probing
+    // the upstream too with `--check-upstream`; `--print-config` prints it
+    // as resolved, secrets redacted.
+    let (mut snapshot_only, mut check, mut check_upstream, mut print_config) =
+        (false, false, false, false);
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--snapshot-only" => snapshot_only = true,
+            "--check" => check = true,
+            "--check-upstream" => (check, check_upstream) = (true, true),
+            "--print-config" => print_config = true,
+            other => {
+                tracing::error!("Unknown argument {}", other);
+                std::process::exit(2);
             }
         }
     }
 
-    tracing::info!("Fetching fresh data from API");
-    
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        format!("Bearer {}", state.bearer_token)
-            .parse()
-            .expect("Invalid bearer token"),
-    );
-
-    match state.client.get(API_URL).headers(headers).send().await {
-        Ok(response) => {
-            match response.text().await {
-                Ok(body) => {
-                    let entry = CacheEntry {
-                        data: body.clone(),
-                        timestamp: Instant::now(),
-                    };
-                    
-                    let mut cache = state.cache.write().await;
-                    *cache = Some(entry);
-                    
-                    body.into_response()
-                }
-                Err(e) => {
-                    tracing::error!("Failed to read response body: {}", e);
-                    (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Error reading response: {}", e),
-                    )
-                        .into_response()
-                }
-            }
+    let config = match config {
+        Ok(config) => config,
+        Err(e) if check => {
+            eprintln!("Configuration has 1 problem:\n  {}", e);
+            std::process::exit(1);
         }
         Err(e) => {
-            tracing::error!("Failed to fetch data: {}", e);
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Error fetching data: {}", e),
-            )
-                .into_response()
+            tracing::error!("Invalid configuration: {}", e);
+            std::process::exit(1);
         }
+    };
+
+    if print_config {
+        print!("{}", config.redacted());
     }
+    if check {
+        let problems = plausible_proxy::check(&config, check_upstream).await;
+        if problems.is_empty() {
+            eprintln!("Configuration is valid");
+            std::process::exit(0);
+        }
+        let plural = if problems.len() == 1 { "" } else { "s" };
+        eprintln!("Configuration has {} problem{}:", problems.len(), plural);
+        for problem in &problems {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+    if print_config {
+        return;
+    }
+
+    plausible_proxy::run(config, snapshot_only).await;
 }
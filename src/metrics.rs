@@ -0,0 +1,268 @@
+use crate::cache::{CacheStatus, Usage};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Process-wide counters, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_stale: AtomicU64,
+    upstream_requests: AtomicU64,
+    upstream_failures: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum_micros: AtomicU64,
+    latency_count: AtomicU64,
+    upstream_queued: AtomicU64,
+    queue_wait_sum_micros: AtomicU64,
+    queue_wait_count: AtomicU64,
+    queue_timeouts: AtomicU64,
+    websocket_connections: AtomicU64,
+    snapshot_failures: AtomicU64,
+    refreshes_changed: AtomicU64,
+    refreshes_unchanged: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status))
+            .or_default() += 1;
+    }
+
+    pub fn record_cache(&self, status: CacheStatus) {
+        let counter = match status {
+            CacheStatus::Hit => &self.cache_hits,
+            CacheStatus::Miss => &self.cache_misses,
+            CacheStatus::Stale => &self.cache_stale,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records one upstream HTTP request and how long it took.
+    pub fn record_upstream(&self, elapsed: Duration, ok: bool) {
+        self.upstream_requests.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.upstream_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a fetch waiting for an upstream slot, returning how many are
+    /// waiting now.
+    pub fn enter_upstream_queue(&self) -> u64 {
+        self.upstream_queued.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Records how long a fetch waited for an upstream slot, and whether it
+    /// got one. Fetches that found one free count too, having waited 0.
+    pub fn record_upstream_wait(&self, waited: Duration, queued: bool, acquired: bool) {
+        if queued {
+            self.upstream_queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        if !acquired {
+            self.queue_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        self.queue_wait_sum_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        self.queue_wait_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upstream_queued(&self) -> u64 {
+        self.upstream_queued.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new WebSocket connection unless `max` are already open.
+    pub fn try_open_websocket(&self, max: u64) -> Result<u64, u64> {
+        self.websocket_connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .map(|open| open + 1)
+    }
+
+    pub fn close_websocket(&self) {
+        self.websocket_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_snapshot_failure(&self) {
+        self.snapshot_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot_failures(&self) -> u64 {
+        self.snapshot_failures.load(Ordering::Relaxed)
+    }
+
+    /// Counts a successful refresh, by whether it brought changed data.
+    pub fn record_refresh(&self, changed: bool) {
+        let counter = match changed {
+            true => &self.refreshes_changed,
+            false => &self.refreshes_unchanged,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Successful refreshes so far, by whether they brought changed data.
+    pub fn refreshes(&self) -> serde_json::Value {
+        serde_json::json!({
+            "changed": self.refreshes_changed.load(Ordering::Relaxed),
+            "unchanged": self.refreshes_unchanged.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Renders every metric, plus the cache gauges passed in by the caller.
+    pub fn render(&self, cache: Usage) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total Requests served, by route and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        out.push_str("# HELP cache_lookups_total Cache lookups, by result.\n");
+        out.push_str("# TYPE cache_lookups_total counter\n");
+        for (result, counter) in [
+            ("hit", &self.cache_hits),
+            ("miss", &self.cache_misses),
+            ("stale", &self.cache_stale),
+        ] {
+            let _ = writeln!(
+                out,
+                "cache_lookups_total{{result=\"{}\"}} {}",
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str(
+            "# HELP cache_refreshes_total Successful refreshes, by whether the data changed.\n",
+        );
+        out.push_str("# TYPE cache_refreshes_total counter\n");
+        for (result, counter) in [
+            ("changed", &self.refreshes_changed),
+            ("unchanged", &self.refreshes_unchanged),
+        ] {
+            let _ = writeln!(
+                out,
+                "cache_refreshes_total{{result=\"{}\"}} {}",
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP cache_entries Entries currently cached.\n");
+        out.push_str("# TYPE cache_entries gauge\n");
+        let _ = writeln!(out, "cache_entries {}", cache.entries);
+        out.push_str("# HELP cache_bytes Total size of the cached bodies.\n");
+        out.push_str("# TYPE cache_bytes gauge\n");
+        let _ = writeln!(out, "cache_bytes {}", cache.bytes);
+        out.push_str("# HELP cache_max_bytes Size the cached bodies are kept under.\n");
+        out.push_str("# TYPE cache_max_bytes gauge\n");
+        let _ = writeln!(out, "cache_max_bytes {}", cache.max_bytes);
+        out.push_str("# HELP cache_evictions_total Entries evicted to stay under the size bound.\n");
+        out.push_str("# TYPE cache_evictions_total counter\n");
+        let _ = writeln!(out, "cache_evictions_total {}", cache.evictions);
+        out.push_str("# HELP cache_rejections_total Bodies too big to cache at all.\n");
+        out.push_str("# TYPE cache_rejections_total counter\n");
+        let _ = writeln!(out, "cache_rejections_total {}", cache.rejections);
+
+        out.push_str("# HELP upstream_requests_total Requests sent to the upstream API.\n");
+        out.push_str("# TYPE upstream_requests_total counter\n");
+        let _ = writeln!(
+            out,
+            "upstream_requests_total {}",
+            self.upstream_requests.load(Ordering::Relaxed)
+        );
+        out.push_str("# HELP upstream_failures_total Upstream requests that failed.\n");
+        out.push_str("# TYPE upstream_failures_total counter\n");
+        let _ = writeln!(
+            out,
+            "upstream_failures_total {}",
+            self.upstream_failures.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP upstream_request_duration_seconds Upstream request latency.\n");
+        out.push_str("# TYPE upstream_request_duration_seconds histogram\n");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            let _ = writeln!(
+                out,
+                "upstream_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "upstream_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "upstream_request_duration_seconds_sum {}",
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "upstream_request_duration_seconds_count {}", count);
+
+        out.push_str("# HELP upstream_queue_depth Fetches waiting for an upstream slot.\n");
+        out.push_str("# TYPE upstream_queue_depth gauge\n");
+        let _ = writeln!(out, "upstream_queue_depth {}", self.upstream_queued());
+        out.push_str("# HELP upstream_queue_wait_seconds Time fetches waited for a slot.\n");
+        out.push_str("# TYPE upstream_queue_wait_seconds summary\n");
+        let _ = writeln!(
+            out,
+            "upstream_queue_wait_seconds_sum {}",
+            self.queue_wait_sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "upstream_queue_wait_seconds_count {}",
+            self.queue_wait_count.load(Ordering::Relaxed)
+        );
+        out.push_str("# HELP upstream_queue_timeouts_total Fetches that gave up waiting.\n");
+        out.push_str("# TYPE upstream_queue_timeouts_total counter\n");
+        let _ = writeln!(
+            out,
+            "upstream_queue_timeouts_total {}",
+            self.queue_timeouts.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP websocket_connections Open WebSocket connections.\n");
+        out.push_str("# TYPE websocket_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "websocket_connections {}",
+            self.websocket_connections.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP snapshot_failures_total Snapshots that could not be written.\n");
+        out.push_str("# TYPE snapshot_failures_total counter\n");
+        let _ = writeln!(out, "snapshot_failures_total {}", self.snapshot_failures());
+
+        out
+    }
+}
@@ -0,0 +1,143 @@
+use crate::error::FetchError;
+use crate::fetcher::StatsFetcher;
+use crate::plausible::Payload;
+use crate::upstream::{self, Breakdown, QueryKind, UpstreamQuery};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Answers every query from JSON fixture files instead of the network, for
+/// running without a token. A fixture holds one body per kind of query,
+/// exactly as Plausible sends it, so everything above the fetcher runs as
+/// it would against the real API. Files are read on every fetch, so edits
+/// show up on the next refresh.
+pub struct MockFetcher {
+    files: Vec<PathBuf>,
+    /// Fetches answered so far by cache key, which picks each query's next
+    /// file.
+    served: Mutex<HashMap<String, usize>>,
+}
+
+/// One fixture file. Sections left out are answered as unsupported queries
+/// are, with a 501.
+#[derive(Deserialize)]
+struct Fixture {
+    /// The custom property breakdown, served for every leaderboard
+    /// whatever its site, period or property.
+    leaderboard: serde_json::Value,
+    /// The main graph, served for every artist.
+    timeseries: Option<serde_json::Value>,
+    /// Breakdown API bodies, by breakdown name such as `country`.
+    #[serde(default)]
+    breakdowns: HashMap<String, serde_json::Value>,
+    /// The realtime visitor count.
+    realtime: Option<serde_json::Value>,
+}
+
+impl MockFetcher {
+    /// A fetcher answering from `files`, taken in turn. Fails unless every
+    /// one reads and parses as the bodies it stands in for.
+    pub fn new(files: &[PathBuf]) -> Result<Self, String> {
+        for path in files {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(MockFetcher {
+            files: files.to_vec(),
+            served: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Queries fetched so far, each counted once however many pages it
+    /// took.
+    pub fn fetches(&self) -> usize {
+        self.served.lock().unwrap().values().sum()
+    }
+
+    /// The file the fetch of `page` of `query` is answered from: the next
+    /// one for a first page, and the one the first page came from after.
+    fn file(&self, query: &UpstreamQuery, page: u32) -> &Path {
+        let mut served = self.served.lock().unwrap();
+        let count = served.entry(query.cache_key().to_string()).or_default();
+        if page <= 1 {
+            *count += 1;
+        }
+        &self.files[(*count - 1) % self.files.len()]
+    }
+}
+
+#[axum::async_trait]
+impl StatsFetcher for MockFetcher {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        _timeout: Duration,
+    ) -> Result<String, FetchError> {
+        let path = self.file(query, page);
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| FetchError::Request(format!("{}: {}", path.display(), e)))?;
+        let mut fixture = parse(&contents)
+            .map_err(|e| FetchError::Invalid(format!("{}: {}", path.display(), e)))?;
+
+        let missing =
+            |what: &str| FetchError::Unsupported(format!("{} in {}", what, path.display()));
+        let body = match query.kind() {
+            QueryKind::Leaderboard => fixture.leaderboard,
+            QueryKind::Timeseries => fixture.timeseries.ok_or_else(|| missing("time series"))?,
+            QueryKind::Realtime => fixture.realtime.ok_or_else(|| missing("realtime visitors"))?,
+            QueryKind::Breakdown => {
+                let breakdown = query.breakdown_of().map_or("unknown", Breakdown::as_str);
+                let what = format!("the {} breakdown", breakdown);
+                fixture.breakdowns.remove(breakdown).ok_or_else(|| missing(&what))?
+            }
+        };
+        if !query.paginated() {
+            return Ok(body.to_string());
+        }
+
+        let limit = query
+            .param("limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(upstream::PAGE_LIMIT);
+        Ok(page_of(body, page, limit).to_string())
+    }
+}
+
+/// `contents` as a fixture, with every section checked against the type
+/// its queries parse into.
+fn parse(contents: &str) -> Result<Fixture, String> {
+    let fixture: Fixture = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let mut sections = vec![
+        ("leaderboard".to_string(), QueryKind::Leaderboard, Some(&fixture.leaderboard)),
+        ("timeseries".to_string(), QueryKind::Timeseries, fixture.timeseries.as_ref()),
+        ("realtime".to_string(), QueryKind::Realtime, fixture.realtime.as_ref()),
+    ];
+    for (name, body) in &fixture.breakdowns {
+        if name.parse::<Breakdown>().is_err() {
+            let accepted = Breakdown::accepted().join(", ");
+            return Err(format!("unknown breakdown `{}`, expected one of {}", name, accepted));
+        }
+        sections.push((format!("breakdowns.{}", name), QueryKind::Breakdown, Some(body)));
+    }
+    for (name, kind, body) in sections {
+        if let Some(body) = body {
+            Payload::parse(kind, &body.to_string()).map_err(|e| format!("`{}`: {}", name, e))?;
+        }
+    }
+    Ok(fixture)
+}
+
+/// `body` with only page `page` of its `results`, `limit` rows to a page,
+/// as the upstream would send it.
+fn page_of(mut body: serde_json::Value, page: u32, limit: usize) -> serde_json::Value {
+    if let Some(results) = body["results"].as_array_mut() {
+        let skip = (page.saturating_sub(1) as usize).saturating_mul(limit);
+        *results = results.drain(..).skip(skip).take(limit).collect();
+    }
+    body
+}
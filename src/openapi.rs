@@ -0,0 +1,289 @@
+use crate::config::Config;
+use utoipa::openapi::path::{Operation, ParameterBuilder, ParameterIn};
+use utoipa::openapi::security::{
+    ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+};
+use utoipa::openapi::{ObjectBuilder, SchemaType};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Artist leaderboards from Plausible custom events. Every route but \
+        the health checks, `/metrics` and the admin routes is also served per site as \
+        `/{site}/...`; the bare routes serve the first configured site. Those \
+        routes also take `?goal=` to count one of the configured `goals` \
+        instead of the site's own."),
+    paths(
+        crate::routes::leaderboard::handler,
+        crate::routes::leaderboard::stats_csv,
+        crate::routes::leaderboard::top,
+        crate::routes::leaderboard::summary,
+        crate::routes::leaderboard::bundle,
+        crate::routes::compare::trending,
+        crate::routes::compare::compare,
+        crate::routes::history::changes,
+        crate::routes::graphql::graphql_handler,
+        crate::routes::leaderboard::prop,
+        crate::routes::breakdowns::country_breakdown,
+        crate::routes::breakdowns::page_breakdown,
+        crate::routes::breakdowns::source_breakdown,
+        crate::routes::breakdowns::device_breakdown,
+        crate::routes::breakdowns::browser_breakdown,
+        crate::routes::breakdowns::realtime,
+        crate::routes::formats::leaderboard_page,
+        crate::routes::formats::feed_handler,
+        crate::routes::artists::artist,
+        crate::routes::artists::timeseries,
+        crate::routes::artists::sparkline_handler,
+        crate::routes::formats::badge_handler,
+        crate::routes::formats::shields_total,
+        crate::routes::formats::shields_artist,
+        crate::routes::formats::widget_script,
+        crate::routes::formats::widget_preview,
+        crate::routes::live::events,
+        crate::routes::live::ws,
+        crate::routes::history::history_handler,
+        crate::routes::history::snapshots,
+        crate::routes::history::history_csv,
+        crate::routes::history::digest,
+        crate::routes::history::movers,
+        crate::routes::admin::purge,
+        crate::routes::admin::status,
+        crate::routes::admin::reload,
+        crate::routes::admin::webhook_test,
+        crate::routes::health::healthz,
+        crate::routes::health::livez,
+        crate::routes::health::readyz,
+        crate::routes::admin::metrics_handler,
+    ),
+    components(schemas(
+        crate::plausible::PlausibleResponse,
+        crate::plausible::ArtistRow,
+        crate::plausible::Movement,
+        crate::upstream::Period,
+        crate::error::ErrorResponse,
+        crate::error::ErrorBody,
+        crate::history::Point,
+        crate::webhook::Delivery,
+        crate::routes::leaderboard::TopRow,
+        crate::routes::breakdowns::BreakdownItem,
+        crate::routes::breakdowns::Realtime,
+        crate::routes::leaderboard::Summary,
+        crate::routes::leaderboard::Bundle,
+        crate::routes::compare::Trending,
+        crate::routes::compare::TrendingRow,
+        crate::routes::compare::Comparison,
+        crate::routes::compare::ComparisonRow,
+        crate::routes::history::Changes,
+        crate::routes::history::Compared,
+        crate::routes::history::Change,
+        crate::routes::compare::DateRange,
+        crate::routes::artists::ArtistTimeseries,
+        crate::routes::artists::TimeseriesPoint,
+        crate::routes::history::ArtistHistory,
+        crate::routes::history::Snapshots,
+        crate::routes::history::WeeklyDigest,
+        crate::routes::history::WeeklyDigestRow,
+        crate::routes::history::Movers,
+        crate::routes::history::Mover,
+        crate::routes::formats::Shield,
+    )),
+    modifiers(&AdminToken),
+    tags(
+        (name = "leaderboard", description = "The artist leaderboard and views of it"),
+        (name = "artists", description = "Single artists"),
+        (name = "breakdowns", description = "Where conversions come from"),
+        (name = "realtime", description = "Who is on the site right now"),
+        (name = "badges", description = "Embeddable badges and the leaderboard widget"),
+        (name = "streaming", description = "Pushed leaderboard updates"),
+        (name = "history", description = "Recorded snapshots, when `history_db` is set"),
+        (name = "admin", description = "Routes that need `ADMIN_TOKEN`"),
+        (name = "operations", description = "Health and metrics"),
+    )
+)]
+struct ApiDoc;
+
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// The OpenAPI document for the routes `config` enables.
+pub fn document(config: &Config) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    let paths = &mut openapi.paths.paths;
+    if config.history_db.is_none() {
+        paths.remove("/history");
+        paths.remove("/history/snapshots");
+        paths.remove("/history.csv");
+        paths.remove("/digest");
+        paths.remove("/movers");
+    }
+    if !config.metrics_enabled {
+        paths.remove("/metrics");
+    }
+    for operation in paths.values_mut().flat_map(|path| path.operations.values_mut()) {
+        summarize(operation);
+        let per_site = operation
+            .tags
+            .iter()
+            .flatten()
+            .all(|tag| tag != "admin" && tag != "operations");
+        if per_site && !config.goals.is_empty() {
+            add_goal_param(operation, &config.goals);
+        }
+        if per_site && config.jsonp_enabled {
+            add_callback_param(operation);
+        }
+    }
+    if !config.api_keys.is_empty() {
+        require_api_key(&mut openapi);
+    }
+    openapi
+}
+
+/// Documents `?goal=` on a route served per site.
+fn add_goal_param(operation: &mut Operation, goals: &[String]) {
+    let schema = ObjectBuilder::new()
+        .schema_type(SchemaType::String)
+        .enum_values(Some(goals.iter().cloned()));
+    let param = ParameterBuilder::new()
+        .name("goal")
+        .parameter_in(ParameterIn::Query)
+        .description(Some("Count this goal instead of the site's own"))
+        .schema(Some(schema));
+    operation.parameters.get_or_insert_with(Vec::new).push(param.build());
+}
+
+/// Documents `?callback=`, which only changes JSON answers.
+fn add_callback_param(operation: &mut Operation) {
+    let schema = ObjectBuilder::new()
+        .schema_type(SchemaType::String)
+        .pattern(Some("^[A-Za-z_][A-Za-z0-9_.]{0,63}$"));
+    let param = ParameterBuilder::new()
+        .name("callback")
+        .parameter_in(ParameterIn::Query)
+        .description(Some("Serve the JSON as JSONP, wrapped in a call to this function"))
+        .schema(Some(schema));
+    operation.parameters.get_or_insert_with(Vec::new).push(param.build());
+}
+
+/// Requires the `api_key` scheme everywhere but the health checks. Admin
+/// routes keep their own requirement, since the admin token is accepted
+/// instead.
+fn require_api_key(openapi: &mut utoipa::openapi::OpenApi) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+    components.add_security_scheme(
+        "api_key",
+        SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+    );
+    openapi.security = Some(vec![SecurityRequirement::new("api_key", Vec::<String>::new())]);
+    for path in ["/healthz", "/livez", "/readyz"] {
+        if let Some(health) = openapi.paths.paths.get_mut(path) {
+            for operation in health.operations.values_mut() {
+                operation.security = Some(Vec::new());
+            }
+        }
+    }
+}
+
+/// utoipa takes the first line of a handler's doc comment as the summary and
+/// the rest as the description, but the comments wrap mid-sentence. Makes
+/// the first sentence the summary and the whole comment the description.
+fn summarize(operation: &mut Operation) {
+    let Some(first) = operation.summary.take() else {
+        return;
+    };
+    let text = match operation.description.take() {
+        Some(rest) if !rest.is_empty() => format!("{}\n{}", first, rest),
+        _ => first,
+    };
+    let flat = text.replace('\n', " ");
+    let summary = match flat.find(". ") {
+        Some(end) => &flat[..end],
+        None => flat.trim_end_matches('.'),
+    };
+    operation.summary = Some(summary.to_string());
+    operation.description = Some(text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utoipa::openapi::{PathItemType, RefOr};
+
+    /// `config`'s document as a client would read it back from
+    /// `/openapi.json`.
+    fn served(config: &Config) -> utoipa::openapi::OpenApi {
+        let json = document(config).to_json().expect("the document serializes");
+        serde_json::from_str(&json).expect("the document parses back")
+    }
+
+    fn get<'a>(openapi: &'a utoipa::openapi::OpenApi, path: &str) -> &'a Operation {
+        &openapi.paths.paths[path].operations[&PathItemType::Get]
+    }
+
+    fn param_names(operation: &Operation) -> Vec<&str> {
+        operation.parameters.iter().flatten().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn document_round_trips() {
+        let config = Config::default();
+        let json = |openapi: &utoipa::openapi::OpenApi| serde_json::to_value(openapi).unwrap();
+        assert_eq!(json(&served(&config)), json(&document(&config)));
+    }
+
+    #[test]
+    fn errors_have_a_schema() {
+        let openapi = served(&Config::default());
+        let schemas = &openapi.components.as_ref().unwrap().schemas;
+        assert!(schemas.contains_key("ErrorResponse"));
+        assert!(schemas.contains_key("ErrorBody"));
+
+        let RefOr::T(response) = &get(&openapi, "/").responses.responses["400"] else {
+            panic!("400 is described inline");
+        };
+        let content = &response.content["application/json"];
+        let RefOr::Ref(schema) = &content.schema else {
+            panic!("the 400 body refers to a schema");
+        };
+        assert_eq!(schema.ref_location, "#/components/schemas/ErrorResponse");
+    }
+
+    #[test]
+    fn query_params_are_documented() {
+        let openapi = served(&Config::default());
+        let leaderboard = param_names(get(&openapi, "/"));
+        for name in ["period", "limit", "from", "to", "fields", "sort", "q", "page", "format"] {
+            assert!(leaderboard.contains(&name), "`/` is missing ?{}", name);
+        }
+        assert!(param_names(get(&openapi, "/top/{n}")).contains(&"n"));
+    }
+
+    #[test]
+    fn config_shapes_the_document() {
+        let openapi = served(&Config::default());
+        assert!(!openapi.paths.paths.contains_key("/history"));
+        assert!(!param_names(get(&openapi, "/")).contains(&"goal"));
+
+        let config = Config {
+            goals: vec!["Album Click".to_string()],
+            history_db: Some("history.db".into()),
+            jsonp_enabled: false,
+            ..Config::default()
+        };
+        let openapi = served(&config);
+        assert!(openapi.paths.paths.contains_key("/history"));
+        let params = param_names(get(&openapi, "/"));
+        assert!(params.contains(&"goal"));
+        assert!(!params.contains(&"callback"));
+    }
+}
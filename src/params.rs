@@ -0,0 +1,722 @@
+use crate::error::ApiError;
+use crate::layers::is_admin;
+use crate::plausible::{self, ArtistRow};
+use crate::upstream::{Period, Site, UpstreamQuery};
+use crate::{search, AppState, X_PAGE, X_PER_PAGE, X_TOTAL_COUNT, X_TOTAL_PAGES};
+use axum::{
+    extract::{FromRequestParts, Query, RawPathParams},
+    http::{
+        header::{HeaderValue, ACCEPT},
+        request::Parts,
+        StatusCode,
+    },
+    response::Response,
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+/// Largest `limit` accepted on list routes.
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LeaderboardParams {
+    #[param(value_type = Option<Period>)]
+    period: Option<String>,
+    /// Most rows to return.
+    #[param(value_type = Option<u32>, minimum = 1, maximum = 1000)]
+    limit: Option<String>,
+}
+
+impl LeaderboardParams {
+    pub fn period(&self) -> Result<Period, ApiError> {
+        self.period_or(Period::default())
+    }
+
+    pub fn period_or(&self, default: Period) -> Result<Period, ApiError> {
+        match self.period.as_deref().map(str::parse) {
+            None => Ok(default),
+            Some(Ok(period)) => Ok(period),
+            Some(Err(())) => Err(ApiError::invalid_param("period", &Period::accepted())),
+        }
+    }
+
+    pub fn limit(&self) -> Result<Option<usize>, ApiError> {
+        match self.limit.as_deref().map(str::parse::<usize>) {
+            None => Ok(None),
+            Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => Ok(Some(limit)),
+            Some(_) => Err(ApiError::invalid_param("limit", &["an integer from 1 to 1000"])),
+        }
+    }
+}
+
+/// `?from=YYYY-MM-DD&to=YYYY-MM-DD` on the list routes asks for the days
+/// between the two, inclusive, instead of a `period`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RangeParams {
+    /// First day of a custom range, instead of `period`.
+    #[param(value_type = Option<String>, format = Date)]
+    from: Option<String>,
+    /// Last day of the range, inclusive. Required with `from`.
+    #[param(value_type = Option<String>, format = Date)]
+    to: Option<String>,
+}
+
+impl RangeParams {
+    /// The leaderboard query these and `params` ask for on `site`, and the
+    /// period it covers when it isn't a custom range.
+    pub fn leaderboard(
+        &self,
+        state: &AppState,
+        site: &Site,
+        params: &LeaderboardParams,
+    ) -> Result<(UpstreamQuery, Option<Period>), ApiError> {
+        let (from, to) = match (self.from.as_deref(), self.to.as_deref()) {
+            (None, None) => {
+                let period = params.period()?;
+                return Ok((UpstreamQuery::leaderboard(site, period), Some(period)));
+            }
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_range",
+                    "`from` and `to` must be sent together",
+                ))
+            }
+        };
+        if params.period.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "conflicting_parameters",
+                "`period` can't be combined with `from` and `to`; send one or the other",
+            ));
+        }
+
+        let date = |name: &str, value: &str| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|_| ApiError::invalid_param(name, &["a date as YYYY-MM-DD"]))
+        };
+        let (from, to) = (date("from", from)?, date("to", to)?);
+        if from > to {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_range",
+                "`from` is after `to`",
+            ));
+        }
+        let max = state.config().custom_range_max_days;
+        if (to - from).num_days() >= i64::from(max) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_range",
+                format!("Ranges can span at most {} days", max),
+            ));
+        }
+        Ok((UpstreamQuery::leaderboard_between(site, from, to), None))
+    }
+}
+
+/// Rows per page when only `page` is sent.
+const DEFAULT_PER_PAGE: usize = 50;
+
+/// `?page=N&per_page=M` on the list routes serves one page of the rows
+/// left after filtering, searching, sorting and `limit`, in that order.
+/// The totals go in `X-Total-Count`, `X-Total-Pages`, `X-Page` and
+/// `X-Per-Page`, with `Link` pointing at the neighbouring pages; a page
+/// past the last is empty.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PagingParams {
+    /// Page to serve, from 1.
+    #[param(value_type = Option<u32>, minimum = 1)]
+    page: Option<String>,
+    /// Rows per page; 50 by default.
+    #[param(value_type = Option<u32>, minimum = 1, maximum = 1000)]
+    per_page: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Paging {
+    page: usize,
+    per_page: usize,
+}
+
+impl PagingParams {
+    pub fn paging(&self) -> Result<Option<Paging>, ApiError> {
+        if self.page.is_none() && self.per_page.is_none() {
+            return Ok(None);
+        }
+        let page = match self.page.as_deref().map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(page)) if page >= 1 => page,
+            Some(_) => return Err(ApiError::invalid_param("page", &["a positive integer"])),
+        };
+        let per_page = match self.per_page.as_deref().map(str::parse::<usize>) {
+            None => DEFAULT_PER_PAGE,
+            Some(Ok(per_page)) if (1..=MAX_LIMIT).contains(&per_page) => per_page,
+            Some(_) => {
+                return Err(ApiError::invalid_param("per_page", &["an integer from 1 to 1000"]))
+            }
+        };
+        Ok(Some(Paging { page, per_page }))
+    }
+}
+
+impl Paging {
+    /// The rows of `total` on this page.
+    pub fn rows(&self, total: usize) -> std::ops::Range<usize> {
+        let start = (self.page - 1).saturating_mul(self.per_page).min(total);
+        start..start.saturating_add(self.per_page).min(total)
+    }
+
+    /// Adds the paging headers for a list of `total` rows served at `uri`.
+    pub fn add_headers(&self, response: &mut Response, uri: &axum::http::Uri, total: usize) {
+        let pages = total.div_ceil(self.per_page);
+        let link = |page: usize, rel: &str| {
+            let mut query: Vec<&str> = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|pair| !pair.is_empty() && !pair.starts_with("page="))
+                .collect();
+            let page = format!("page={}", page);
+            query.push(&page);
+            format!("<{}?{}>; rel=\"{}\"", uri.path(), query.join("&"), rel)
+        };
+        let last = pages.max(1);
+        let mut links = vec![link(1, "first")];
+        if self.page > 1 {
+            links.push(link((self.page - 1).min(last), "prev"));
+        }
+        if self.page < pages {
+            links.push(link(self.page + 1, "next"));
+        }
+        links.push(link(last, "last"));
+
+        let headers = response.headers_mut();
+        headers.insert(X_TOTAL_COUNT, HeaderValue::from(total));
+        headers.insert(X_TOTAL_PAGES, HeaderValue::from(pages));
+        headers.insert(X_PAGE, HeaderValue::from(self.page));
+        headers.insert(X_PER_PAGE, HeaderValue::from(self.per_page));
+        if let Ok(links) = HeaderValue::from_str(&links.join(", ")) {
+            headers.insert(axum::http::header::LINK, links);
+        }
+    }
+}
+
+/// `?min_visitors=N` on the list routes drops rows with fewer than `N`
+/// visitors before they are sorted, ranked and limited.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FilterParams {
+    /// Leave out artists with fewer visitors.
+    #[param(value_type = Option<u64>)]
+    min_visitors: Option<String>,
+}
+
+impl FilterParams {
+    pub fn min_visitors(&self) -> Result<u64, ApiError> {
+        match self.min_visitors.as_deref().map(str::parse::<u64>) {
+            None => Ok(0),
+            Some(Ok(min)) => Ok(min),
+            Some(Err(_)) => {
+                Err(ApiError::invalid_param("min_visitors", &["a non-negative integer"]))
+            }
+        }
+    }
+}
+
+/// `?sort=visitors|events|name&order=asc|desc` on the list routes orders
+/// the rows for display once they are merged and filtered. Ranks still
+/// count from the most visitors.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SortParams {
+    /// `visitors`, `events` or `name`, to order rows by instead of
+    /// upstream order.
+    #[param(value_type = Option<String>)]
+    sort: Option<String>,
+    /// `asc` or `desc`; descending for counts and ascending for names by
+    /// default.
+    #[param(value_type = Option<String>)]
+    order: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum SortKey {
+    Visitors,
+    Events,
+    Name,
+}
+
+#[derive(Clone, Copy)]
+pub struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl SortParams {
+    pub fn sort(&self) -> Result<Option<Sort>, ApiError> {
+        let key = match (self.sort.as_deref(), &self.order) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) | (Some("visitors"), _) => SortKey::Visitors,
+            (Some("events"), _) => SortKey::Events,
+            (Some("name"), _) => SortKey::Name,
+            (Some(_), _) => {
+                return Err(ApiError::invalid_param("sort", &["visitors", "events", "name"]))
+            }
+        };
+        let descending = match self.order.as_deref() {
+            None => !matches!(key, SortKey::Name),
+            Some("asc") => false,
+            Some("desc") => true,
+            Some(_) => return Err(ApiError::invalid_param("order", &["asc", "desc"])),
+        };
+        Ok(Some(Sort { key, descending }))
+    }
+}
+
+impl Sort {
+    /// Orders `a` and `b` by the key, ties by name, and names by their
+    /// lowercase forms before their exact spelling.
+    pub fn compare(&self, a: &ArtistRow, b: &ArtistRow) -> std::cmp::Ordering {
+        let by_name = || {
+            a.name
+                .to_lowercase()
+                .cmp(&b.name.to_lowercase())
+                .then_with(|| a.name.cmp(&b.name))
+        };
+        let ordering = match self.key {
+            SortKey::Visitors => a.visitors.cmp(&b.visitors),
+            SortKey::Events => a.events.cmp(&b.events),
+            SortKey::Name => by_name(),
+        };
+        let ordering = if self.descending { ordering.reverse() } else { ordering };
+        ordering.then_with(by_name)
+    }
+}
+
+/// Longest `?q=` accepted, in characters.
+const MAX_SEARCH_LEN: usize = 100;
+
+/// `?q=term` on the list routes keeps the rows whose name contains the
+/// term, ignoring case, once aliases are merged and before `limit`.
+/// `?fold_diacritics=true` also ignores accents, so `bjork` finds `Björk`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Only artists whose name contains this.
+    #[param(value_type = Option<String>, max_length = 100)]
+    q: Option<String>,
+    /// Match `q` ignoring diacritics.
+    #[param(value_type = Option<bool>)]
+    fold_diacritics: Option<String>,
+}
+
+pub struct Search {
+    term: String,
+    fold: bool,
+}
+
+impl SearchParams {
+    pub fn search(&self) -> Result<Option<Search>, ApiError> {
+        let fold = match self.fold_diacritics.as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(_) => return Err(ApiError::invalid_param("fold_diacritics", &["true", "false"])),
+        };
+        let Some(term) = self.q.as_deref() else {
+            return Ok(None);
+        };
+        if term.chars().count() > MAX_SEARCH_LEN {
+            let accepted = format!("at most {} characters", MAX_SEARCH_LEN);
+            return Err(ApiError::invalid_param("q", &[&accepted]));
+        }
+        Ok(Some(Search {
+            term: Search::key(term, fold),
+            fold,
+        }))
+    }
+}
+
+impl Search {
+    /// What `name` is matched as: its whitespace collapsed and lowercased,
+    /// without diacritics when `fold` is set.
+    fn key(name: &str, fold: bool) -> String {
+        let name = plausible::normalize_name(name);
+        if fold {
+            search::fold_diacritics(&name)
+        } else {
+            name.to_lowercase()
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        Search::key(name, self.fold).contains(&self.term)
+    }
+}
+
+/// How a leaderboard is presented. `?raw=true` serves the upstream rows
+/// without merging near-duplicate names or adding shares;
+/// `?unfiltered=true`, for admins only, keeps the rows the exclusion list
+/// would drop; `?pretty=1` indents the JSON for reading.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ViewParams {
+    /// Serve the upstream rows as they are, without merging or shares.
+    #[param(value_type = Option<bool>)]
+    raw: Option<String>,
+    /// Keep excluded names. Admins only.
+    #[param(value_type = Option<bool>)]
+    unfiltered: Option<String>,
+    /// Indent the JSON body.
+    #[param(value_type = Option<bool>)]
+    pretty: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub struct View {
+    pub raw: bool,
+    pub unfiltered: bool,
+    /// Rendered from the cached rows on each request; the cache keeps
+    /// only the compact body.
+    pub pretty: bool,
+}
+
+impl View {
+    /// The leaderboard as anyone may see it, compact: what answers use
+    /// when they don't take a view from the request.
+    pub const PUBLIC: View = View {
+        raw: false,
+        unfiltered: false,
+        pretty: false,
+    };
+}
+
+impl ViewParams {
+    pub fn view(
+        &self,
+        state: &AppState,
+        headers: &axum::http::HeaderMap,
+    ) -> Result<View, ApiError> {
+        let flag = |name: &str, value: Option<&str>| match value {
+            None | Some("false" | "0") => Ok(false),
+            Some("true" | "1") => Ok(true),
+            Some(_) => Err(ApiError::invalid_param(name, &["true", "false", "1", "0"])),
+        };
+
+        let view = View {
+            raw: flag("raw", self.raw.as_deref())?,
+            unfiltered: flag("unfiltered", self.unfiltered.as_deref())?,
+            pretty: flag("pretty", self.pretty.as_deref())?,
+        };
+        if view.unfiltered && !is_admin(state, headers) {
+            return Err(ApiError::unauthorized());
+        }
+        Ok(view)
+    }
+}
+
+/// `?format=xml` or `?format=msgpack`, or an `Accept` header ranking
+/// `application/xml` (or `text/xml`) or `application/msgpack` above
+/// everything else, serves XML or MessagePack instead of JSON.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FormatParams {
+    /// `json`, `xml` or `msgpack`. Without it, `Accept` decides.
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Xml,
+    /// The JSON document, encoded as MessagePack.
+    MessagePack,
+}
+
+impl FormatParams {
+    pub fn format(&self, headers: &axum::http::HeaderMap) -> Result<Format, ApiError> {
+        match self.format.as_deref() {
+            Some("json") => Ok(Format::Json),
+            Some("xml") => Ok(Format::Xml),
+            Some("msgpack") => Ok(Format::MessagePack),
+            Some(_) => Err(ApiError::invalid_param("format", &["json", "xml", "msgpack"])),
+            None => Ok(accepted_format(headers)),
+        }
+    }
+}
+
+/// The format of the media range `Accept` ranks highest, the first of any
+/// tied; JSON unless that is XML or MessagePack. Browsers rank HTML above
+/// the XML they also list.
+fn accepted_format(headers: &axum::http::HeaderMap) -> Format {
+    let Some(accept) = headers.get(ACCEPT).and_then(|value| value.to_str().ok()) else {
+        return Format::Json;
+    };
+    let mut best: Option<(f32, &str)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|quality| quality.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(highest, _)| quality > highest) {
+            best = Some((quality, media));
+        }
+    }
+    let Some((_, media)) = best.filter(|(quality, _)| *quality > 0.0) else {
+        return Format::Json;
+    };
+    let is = |types: &[&str]| types.iter().any(|ty| media.eq_ignore_ascii_case(ty));
+    if is(&["application/xml", "text/xml"]) {
+        Format::Xml
+    } else if is(&["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"]) {
+        Format::MessagePack
+    } else {
+        Format::Json
+    }
+}
+
+/// Row fields `?fields=` can pick. `rank` is only on the routes that rank
+/// rows; the movement fields only when there is a leaderboard to compare.
+const FIELDS: [&str; 7] = [
+    "name",
+    "rank",
+    "visitors",
+    "events",
+    "share",
+    "previous_rank",
+    "rank_delta",
+];
+
+/// `?fields=name,visitors` on the JSON leaderboard routes cuts each row
+/// down to the fields named, once it is merged, filtered and ranked.
+/// `name` is always kept.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FieldsParams {
+    /// Comma-separated row fields to return; all of them by default.
+    #[param(value_type = Option<String>)]
+    fields: Option<String>,
+}
+
+impl FieldsParams {
+    pub fn fields(&self) -> Result<Option<Vec<&str>>, ApiError> {
+        let Some(fields) = self.fields.as_deref() else {
+            return Ok(None);
+        };
+        let fields: Vec<&str> = fields.split(',').map(str::trim).collect();
+        if fields.iter().any(|field| !FIELDS.contains(field)) {
+            return Err(ApiError::invalid_param("fields", &FIELDS));
+        }
+        Ok(Some(fields))
+    }
+}
+
+/// The site a request addresses: the `:site` path segment, or the first
+/// configured site on the bare routes. Unknown keys are a 404. `?goal=`
+/// swaps the site's goal for one of `goals`; any other goal is a 400.
+pub struct SelectedSite(pub Site);
+
+#[derive(Deserialize)]
+struct GoalParams {
+    goal: Option<String>,
+}
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for SelectedSite {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let key = RawPathParams::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|params| {
+                params
+                    .iter()
+                    .find(|(name, _)| *name == "site")
+                    .map(|(_, value)| value.to_string())
+            });
+
+        let config = state.config();
+        let site = match key {
+            None => Some(default_site(state)),
+            Some(key) => config.sites.iter().find(|site| site.key == key).cloned(),
+        };
+        let mut site = site.ok_or_else(|| ApiError::not_found("site_not_found", "Unknown site"))?;
+
+        let goal = Query::<GoalParams>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(params)| params.goal);
+        if let Some(goal) = goal.filter(|goal| *goal != site.goal) {
+            if !config.goals.contains(&goal) {
+                let mut accepted = vec![site.goal.as_str()];
+                accepted.extend(config.goals.iter().map(String::as_str));
+                return Err(ApiError::invalid_param("goal", &accepted));
+            }
+            site.goal = goal;
+        }
+        Ok(SelectedSite(site))
+    }
+}
+
+/// The site behind the bare routes.
+pub fn default_site(state: &AppState) -> Site {
+    state.config().sites.first().expect("at least one site is configured").clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(name: &str, visitors: u64, events: u64) -> ArtistRow {
+        let row = serde_json::json!({ "name": name, "visitors": visitors, "events": events });
+        serde_json::from_value(row).unwrap()
+    }
+
+    fn sort(sort: Option<&str>, order: Option<&str>) -> Result<Option<Sort>, ApiError> {
+        SortParams {
+            sort: sort.map(str::to_string),
+            order: order.map(str::to_string),
+        }
+        .sort()
+    }
+
+    fn sorted(rows: &[ArtistRow], by: Sort) -> Vec<&str> {
+        let mut rows: Vec<&ArtistRow> = rows.iter().collect();
+        rows.sort_by(|a, b| by.compare(a, b));
+        rows.iter().map(|row| row.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sort_defaults_counts_descending_and_names_ascending() {
+        assert!(sort(None, None).unwrap().is_none());
+        let rows = [row("b", 1, 30), row("C", 3, 10), row("a", 2, 20)];
+
+        let by_visitors = sort(Some("visitors"), None).unwrap().unwrap();
+        assert_eq!(sorted(&rows, by_visitors), ["C", "a", "b"]);
+        // `order` alone sorts by visitors.
+        let ascending = sort(None, Some("asc")).unwrap().unwrap();
+        assert_eq!(sorted(&rows, ascending), ["b", "a", "C"]);
+        let by_events = sort(Some("events"), None).unwrap().unwrap();
+        assert_eq!(sorted(&rows, by_events), ["b", "a", "C"]);
+        let by_name = sort(Some("name"), None).unwrap().unwrap();
+        assert_eq!(sorted(&rows, by_name), ["a", "b", "C"]);
+        let by_name = sort(Some("name"), Some("desc")).unwrap().unwrap();
+        assert_eq!(sorted(&rows, by_name), ["C", "b", "a"]);
+    }
+
+    #[test]
+    fn sort_ties_are_broken_by_name() {
+        let rows = [row("beta", 5, 1), row("Alpha", 5, 1), row("alpha", 5, 1)];
+        let by_visitors = sort(Some("visitors"), None).unwrap().unwrap();
+        // Lowercase first, then the exact spelling, which puts capitals first.
+        assert_eq!(sorted(&rows, by_visitors), ["Alpha", "alpha", "beta"]);
+    }
+
+    #[test]
+    fn sort_rejects_unknown_keys_and_orders() {
+        for (key, order, param) in [(Some("rank"), None, "sort"), (None, Some("up"), "order")] {
+            let error = sort(key, order).err().expect("rejected");
+            assert_eq!(error.code(), "invalid_parameter");
+            let expected = format!("Invalid `{}` parameter", param);
+            assert!(error.message().starts_with(&expected), "{}", error.message());
+        }
+    }
+
+    fn search(q: Option<&str>, fold: Option<&str>) -> Result<Option<Search>, ApiError> {
+        SearchParams {
+            q: q.map(str::to_string),
+            fold_diacritics: fold.map(str::to_string),
+        }
+        .search()
+    }
+
+    #[test]
+    fn search_matches_substrings_ignoring_case_and_spacing() {
+        assert!(search(None, None).unwrap().is_none());
+        let kanye = search(Some("YE  w"), None).unwrap().unwrap();
+        assert!(kanye.matches("Kanye West"));
+        assert!(kanye.matches("kanye   west"));
+        assert!(!kanye.matches("Kanye"));
+    }
+
+    #[test]
+    fn search_ignores_diacritics_only_when_asked() {
+        let plain = search(Some("beyonce"), None).unwrap().unwrap();
+        assert!(!plain.matches("Beyoncé"));
+        let folded = search(Some("beyonce"), Some("true")).unwrap().unwrap();
+        assert!(folded.matches("Beyoncé"));
+        // Folding works both ways.
+        let folded = search(Some("Björk"), Some("true")).unwrap().unwrap();
+        assert!(folded.matches("bjork"));
+    }
+
+    #[test]
+    fn search_rejects_long_terms_and_bad_flags() {
+        assert!(search(Some(&"a".repeat(MAX_SEARCH_LEN)), None).is_ok());
+        // Counted in characters, not bytes.
+        assert!(search(Some(&"é".repeat(MAX_SEARCH_LEN)), None).is_ok());
+        assert!(search(Some(&"a".repeat(MAX_SEARCH_LEN + 1)), None).is_err());
+        assert!(search(Some("a"), Some("yes")).is_err());
+    }
+
+    fn paging(page: Option<&str>, per_page: Option<&str>) -> Result<Option<Paging>, ApiError> {
+        PagingParams {
+            page: page.map(str::to_string),
+            per_page: per_page.map(str::to_string),
+        }
+        .paging()
+    }
+
+    #[test]
+    fn paging_defaults_and_bounds() {
+        assert!(paging(None, None).unwrap().is_none());
+        let first = paging(None, Some("10")).unwrap().unwrap();
+        assert_eq!((first.page, first.per_page), (1, 10));
+        let default = paging(Some("2"), None).unwrap().unwrap();
+        assert_eq!(default.per_page, DEFAULT_PER_PAGE);
+
+        assert!(paging(None, Some(&MAX_LIMIT.to_string())).is_ok());
+        let invalid = [("0", "10"), ("-1", "10"), ("x", "10"), ("1", "0"), ("1", "1001")];
+        for (page, per_page) in invalid {
+            assert!(paging(Some(page), Some(per_page)).is_err(), "{} {}", page, per_page);
+        }
+    }
+
+    #[test]
+    fn pages_past_the_end_are_empty() {
+        let paging = |page| Paging { page, per_page: 4 };
+        assert_eq!(paging(1).rows(10), 0..4);
+        assert_eq!(paging(3).rows(10), 8..10);
+        assert_eq!(paging(4).rows(10), 10..10);
+        assert_eq!(paging(usize::MAX).rows(10), 10..10);
+        assert_eq!(paging(1).rows(0), 0..0);
+    }
+
+    #[test]
+    fn paging_links_keep_the_other_parameters() {
+        let mut response = Response::default();
+        let uri: axum::http::Uri = "/top/10?page=2&per_page=4&sort=name".parse().unwrap();
+        Paging { page: 2, per_page: 4 }.add_headers(&mut response, &uri, 10);
+
+        let headers = response.headers();
+        assert_eq!(headers[X_TOTAL_COUNT], "10");
+        assert_eq!(headers[X_TOTAL_PAGES], "3");
+        assert_eq!(headers[X_PAGE], "2");
+        assert_eq!(headers[X_PER_PAGE], "4");
+        let links = headers[axum::http::header::LINK].to_str().unwrap();
+        for link in [
+            "</top/10?per_page=4&sort=name&page=1>; rel=\"first\"",
+            "</top/10?per_page=4&sort=name&page=1>; rel=\"prev\"",
+            "</top/10?per_page=4&sort=name&page=3>; rel=\"next\"",
+            "</top/10?per_page=4&sort=name&page=3>; rel=\"last\"",
+        ] {
+            assert!(links.contains(link), "{} in {}", link, links);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::aliases::Aliases;
+use crate::upstream::QueryKind;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// Body of the custom property breakdown endpoint. Fields this service does
+/// not use are kept in `extra` so they survive a round trip.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct PlausibleResponse {
+    pub results: Vec<ArtistRow>,
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub extra: Map<String, Value>,
+}
+
+impl PlausibleResponse {
+    /// Copy with near-duplicate names merged. Tracking isn't consistent
+    /// about case and spacing, so rows whose names match once whitespace is
+    /// collapsed and case ignored are combined, as are rows `aliases` maps
+    /// to the same canonical name: visitors and events are summed, the
+    /// display name is the canonical name or else the spelling with the most
+    /// visitors, and other fields come from the highest-ranked row. The
+    /// result is re-sorted by visitors.
+    pub fn merged(&self, aliases: &Aliases) -> PlausibleResponse {
+        let mut rows: Vec<ArtistRow> = Vec::with_capacity(self.results.len());
+        // Per merged row: the visitors behind each spelling seen.
+        let mut spellings: Vec<Vec<(String, u64)>> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+
+        for row in &self.results {
+            let mut name = normalize_name(&row.name);
+            if let Some(canonical) = aliases.canonical(&name) {
+                name = canonical.to_string();
+            }
+            match index.get(&name.to_lowercase()) {
+                Some(&i) => {
+                    rows[i].visitors += row.visitors;
+                    rows[i].events += row.events;
+                    match spellings[i].iter_mut().find(|(spelling, _)| *spelling == name) {
+                        Some((_, visitors)) => *visitors += row.visitors,
+                        None => spellings[i].push((name, row.visitors)),
+                    }
+                }
+                None => {
+                    index.insert(name.to_lowercase(), rows.len());
+                    spellings.push(vec![(name.clone(), row.visitors)]);
+                    rows.push(ArtistRow {
+                        name,
+                        ..row.clone()
+                    });
+                }
+            }
+        }
+
+        for (row, spellings) in rows.iter_mut().zip(spellings) {
+            // `max_by_key` keeps the last maximum; reverse so ties go to the
+            // first spelling seen.
+            let most_visited = spellings
+                .into_iter()
+                .rev()
+                .max_by_key(|(_, visitors)| *visitors);
+            if let Some((name, _)) = most_visited {
+                row.name = name;
+            }
+        }
+        rows.sort_by_key(|row| std::cmp::Reverse(row.visitors));
+
+        PlausibleResponse {
+            results: rows,
+            extra: self.extra.clone(),
+        }
+    }
+
+    /// Sets each row's `movement` since `previous`, matching rows by
+    /// normalized, case-insensitive name. Ranks are positions in each list.
+    pub fn add_movement(&mut self, previous: &PlausibleResponse) {
+        let ranks: HashMap<String, usize> = previous
+            .results
+            .iter()
+            .enumerate()
+            .map(|(index, row)| (normalize_name(&row.name).to_lowercase(), index + 1))
+            .collect();
+        for (index, row) in self.results.iter_mut().enumerate() {
+            let previous_rank = ranks.get(&normalize_name(&row.name).to_lowercase()).copied();
+            row.movement = Some(Movement {
+                previous_rank,
+                rank_delta: previous_rank.map(|rank| rank as i64 - (index + 1) as i64),
+            });
+        }
+    }
+
+    /// Sets each row's `share` of the visitors across all rows, rounded to
+    /// `decimals` places. With no visitors at all every share is zero.
+    pub fn add_shares(&mut self, decimals: u32) {
+        let total: u64 = self.results.iter().map(|row| row.visitors).sum();
+        let scale = 10f64.powi(decimals as i32);
+        for row in &mut self.results {
+            let share = if total == 0 {
+                0.0
+            } else {
+                row.visitors as f64 / total as f64
+            };
+            row.share = Some((share * scale).round() / scale);
+        }
+    }
+}
+
+/// Trims `name` and collapses internal runs of whitespace to one space.
+pub fn normalize_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// One artist's metrics, keyed by the `name` custom property.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ArtistRow {
+    pub name: String,
+    #[serde(default)]
+    pub visitors: u64,
+    #[serde(default)]
+    pub events: u64,
+    /// Fraction of all visitors, filled in by `add_shares`; upstream never
+    /// sends it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share: Option<f64>,
+    /// Filled in by `add_movement` when there is an earlier leaderboard to
+    /// compare against.
+    #[serde(flatten, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub movement: Option<Movement>,
+    #[serde(flatten)]
+    #[schema(value_type = Object)]
+    pub extra: Map<String, Value>,
+}
+
+/// Where a row stood in the previous leaderboard. Both are `None` for an
+/// artist that wasn't in it; a positive delta means the artist moved up.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Movement {
+    pub previous_rank: Option<usize>,
+    pub rank_delta: Option<i64>,
+}
+
+/// Body of the main graph endpoint: one value per interval, labelled with
+/// the date it covers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Timeseries {
+    pub labels: Vec<String>,
+    /// Visitors per label; Plausible sends `null` for intervals in the
+    /// future.
+    pub plot: Vec<Option<u64>>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Body of the breakdown endpoint: one row per value of the property.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Breakdown {
+    pub results: Vec<BreakdownRow>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BreakdownRow {
+    /// The property's value, sent under the property's name: an ISO
+    /// 3166-1 alpha-2 code for `visit:country`, a path for `event:page`,
+    /// a referrer such as `Twitter` for `visit:source`, `Desktop`, `Mobile`
+    /// or `Tablet` for `visit:device`, a browser family for `visit:browser`.
+    #[serde(
+        alias = "country",
+        alias = "page",
+        alias = "source",
+        alias = "device",
+        alias = "browser"
+    )]
+    pub value: String,
+    #[serde(default)]
+    pub visitors: u64,
+    #[serde(default)]
+    pub events: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Parsed form of a cached upstream body.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    Leaderboard(PlausibleResponse),
+    Timeseries(Timeseries),
+    Breakdown(Breakdown),
+    /// Current visitors, sent as a bare number.
+    Realtime(u64),
+}
+
+impl Payload {
+    /// Parses `body` as the response type `kind` queries return.
+    pub fn parse(kind: QueryKind, body: &str) -> Result<Self, serde_json::Error> {
+        match kind {
+            QueryKind::Leaderboard => serde_json::from_str(body).map(Payload::Leaderboard),
+            QueryKind::Timeseries => serde_json::from_str(body).map(Payload::Timeseries),
+            QueryKind::Breakdown => serde_json::from_str(body).map(Payload::Breakdown),
+            QueryKind::Realtime => serde_json::from_str(body).map(Payload::Realtime),
+        }
+    }
+
+    pub fn kind(&self) -> QueryKind {
+        match self {
+            Payload::Leaderboard(_) => QueryKind::Leaderboard,
+            Payload::Timeseries(_) => QueryKind::Timeseries,
+            Payload::Breakdown(_) => QueryKind::Breakdown,
+            Payload::Realtime(_) => QueryKind::Realtime,
+        }
+    }
+
+    pub fn leaderboard(&self) -> Option<&PlausibleResponse> {
+        match self {
+            Payload::Leaderboard(response) => Some(response),
+            _ => None,
+        }
+    }
+
+    pub fn timeseries(&self) -> Option<&Timeseries> {
+        match self {
+            Payload::Timeseries(timeseries) => Some(timeseries),
+            _ => None,
+        }
+    }
+
+    pub fn breakdown(&self) -> Option<&Breakdown> {
+        match self {
+            Payload::Breakdown(breakdown) => Some(breakdown),
+            _ => None,
+        }
+    }
+
+    pub fn realtime(&self) -> Option<u64> {
+        match self {
+            Payload::Realtime(visitors) => Some(*visitors),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,440 @@
+use crate::cache::{CacheEntry, CacheKey};
+use crate::discord::Discord;
+use crate::params::{default_site, View};
+use crate::plausible::ArtistRow;
+use crate::refresh::fetch_and_store;
+use crate::respond::{add_movement, leaderboard};
+use crate::routes::live::leaderboard_delta;
+use crate::upstream::{Period, Site, UpstreamQuery};
+use crate::{
+    discord, export, ranked, s3, snapshot, spikes, webhook, Alert, AppState, Update, CSV, JSON,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::sync::broadcast;
+
+/// Fetches every site's all-time leaderboard once, which publishes its
+/// snapshot. Returns whether all of them were published.
+pub async fn write_snapshots(state: &AppState) -> bool {
+    if state.config().snapshot_dir.is_none() && state.bucket.is_none() {
+        tracing::error!("--snapshot-only needs snapshot_dir or s3_bucket to be set");
+        return false;
+    }
+
+    let mut ok = true;
+    for site in &state.config().sites {
+        let query = UpstreamQuery::leaderboard(site, Period::default());
+        let failures = state.metrics.snapshot_failures();
+        match fetch_and_store(state, &query, &query.cache_key()).await {
+            Ok(_) => ok &= state.metrics.snapshot_failures() == failures,
+            Err(e) => {
+                tracing::error!("No snapshot written for {}: {}", site.key, e);
+                ok = false;
+            }
+        }
+    }
+    if ok {
+        tracing::info!("Published snapshots for {} sites", state.config().sites.len());
+    }
+    ok
+}
+
+/// The site whose all-time leaderboard is cached under `key`, if any.
+fn all_time_site(state: &AppState, key: &CacheKey) -> Option<Site> {
+    state
+        .config()
+        .sites
+        .iter()
+        .find(|site| UpstreamQuery::leaderboard(site, Period::default()).cache_key() == *key)
+        .cloned()
+}
+
+/// Adds a freshly fetched all-time leaderboard to its site's feed, if the
+/// top artists changed.
+pub fn record_feed(state: &AppState, key: &CacheKey, entry: &CacheEntry) {
+    if let Some(site) = all_time_site(state, key) {
+        add_to_feed(state, &site, entry);
+    }
+}
+
+pub fn add_to_feed(state: &AppState, site: &Site, entry: &CacheEntry) {
+    let view = View::PUBLIC;
+    let board = leaderboard(state, entry, view);
+    let mut feeds = state.feeds.lock().unwrap();
+    if feeds
+        .entry(site.key.clone())
+        .or_default()
+        .record(entry.fetched_at, &board.results)
+    {
+        tracing::debug!("Added feed entry for {}", site.key);
+    }
+}
+
+/// Checks a freshly fetched all-time leaderboard for artists whose visitors
+/// spiked, when `spike_detection` is set. Each spike is logged, and with
+/// `spike_notify` sent to `webhook_url` and Discord in the background.
+pub fn detect_spikes(state: &AppState, key: &CacheKey, entry: &CacheEntry) {
+    let config = state.config();
+    if !config.spike_detection {
+        return;
+    }
+    let Some(site) = all_time_site(state, key) else {
+        return;
+    };
+    let view = View::PUBLIC;
+    let board = leaderboard(state, entry, view);
+    let thresholds = spikes::Thresholds {
+        stddevs: config.spike_stddevs,
+        min_delta: config.spike_min_delta,
+        samples: config.spike_baseline_samples,
+        cooldown: config.spike_cooldown,
+    };
+    let spikes = state
+        .spikes
+        .lock()
+        .unwrap()
+        .entry(site.key.clone())
+        .or_default()
+        .observe(&board.results, Instant::now(), &thresholds);
+    for spike in &spikes {
+        tracing::warn!(
+            site = %site.key,
+            artist = %spike.artist,
+            visitors = spike.visitors,
+            delta = spike.delta,
+            baseline_mean = spike.baseline.mean,
+            baseline_stddev = spike.baseline.stddev,
+            "Visitor spike: {} gained {} visitors, against {:.1} ± {:.1}",
+            spike.artist,
+            spike.delta,
+            spike.baseline.mean,
+            spike.baseline.stddev
+        );
+    }
+    if spikes.is_empty() || !config.spike_notify {
+        return;
+    }
+
+    let alert = Alert {
+        site: site.id.clone(),
+        spikes: Arc::new(spikes),
+        at: entry.fetched_at,
+    };
+    if let Some(url) = config.webhook_url.clone() {
+        let notification = webhook::SpikeNotification {
+            event: "spike",
+            site: &site.key,
+            detected_at: chrono::DateTime::<chrono::Utc>::from(alert.at).to_rfc3339(),
+            spikes: &alert.spikes,
+        };
+        let body = serde_json::to_string(&notification).expect("webhook notifications serialize");
+        let state = state.clone();
+        tokio::spawn(async move {
+            let config = state.config();
+            let secret = config.webhook_secret.as_deref();
+            let delivery = webhook::deliver(&state.client, &url, secret, body).await;
+            if let Some(e) = delivery.error {
+                let attempts = delivery.attempts;
+                tracing::warn!("Spike webhook failed after {} attempts: {}", attempts, e);
+            }
+        });
+    }
+    // No Discord webhook, and so no subscriber, is not an error.
+    let _ = state.alerts.send(alert);
+}
+
+/// Sends an all-time leaderboard that changed from the one it `replaced`
+/// to `webhook_url`, if set, in the background so a slow or failing
+/// receiver never holds up the refresh.
+pub fn spawn_webhook(state: &AppState, key: &CacheKey, entry: &CacheEntry, replaced: &CacheEntry) {
+    let Some(url) = state.config().webhook_url.clone() else {
+        return;
+    };
+    let Some(site) = all_time_site(state, key) else {
+        return;
+    };
+
+    let body = webhook_body(state, &site, entry, Some(replaced), false);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let config = state.config();
+        let secret = config.webhook_secret.as_deref();
+        let delivery = webhook::deliver(&state.client, &url, secret, body).await;
+        match delivery.error {
+            None => tracing::info!("Delivered webhook ({} attempts)", delivery.attempts),
+            Some(e) => tracing::warn!(
+                "Webhook delivery failed after {} attempts: {}",
+                delivery.attempts,
+                e
+            ),
+        }
+    });
+}
+
+/// The webhook notification for `entry`, diffed against the entry it
+/// `replaced`, both viewed the way the public routes serve them.
+pub fn webhook_body(
+    state: &AppState,
+    site: &Site,
+    entry: &CacheEntry,
+    replaced: Option<&CacheEntry>,
+    test: bool,
+) -> String {
+    let view = View::PUBLIC;
+    let board = leaderboard(state, entry, view);
+    let before = replaced.map(|replaced| leaderboard(state, replaced, view));
+    let before = before.as_ref().map_or(&[][..], |before| &before.results);
+    let diff = leaderboard_delta(before, &board.results).map_or_else(Default::default, |delta| {
+        webhook::Diff {
+            changed: delta.changed.len(),
+            added: delta.added.iter().map(|row| row.name.clone()).collect(),
+            removed: delta.removed.iter().map(|name| name.to_string()).collect(),
+        }
+    });
+
+    let notification = webhook::Notification {
+        site: &site.key,
+        refreshed_at: chrono::DateTime::<chrono::Utc>::from(entry.fetched_at).to_rfc3339(),
+        test,
+        top: &board.results[..board.results.len().min(webhook::TOP)],
+        diff,
+    };
+    serde_json::to_string(&notification).expect("webhook notifications serialize")
+}
+
+/// A site's top artists as last announced on Discord, or as first seen.
+struct Announced {
+    top: Vec<ArtistRow>,
+    sent_at: Option<tokio::time::Instant>,
+}
+
+/// Announces changes in each site's top `discord_top` artists on Discord,
+/// at most once per `discord_debounce`. A change that is undone before it
+/// is announced, like two artists trading #1 back and forth, is dropped.
+/// Spikes are announced as they come, their cooldown already applied.
+pub async fn discord_loop(
+    state: AppState,
+    url: String,
+    mut updates: broadcast::Receiver<Update>,
+    mut alerts: broadcast::Receiver<Alert>,
+) {
+    let mut discord = Discord::new(state.client.clone(), url);
+    let mut shutdown = state.shutdown.subscribe();
+    let debounce = state.config().discord_debounce;
+    let top = |entry: &CacheEntry| {
+        let view = View::PUBLIC;
+        let mut rows = leaderboard(&state, entry, view).into_owned().results;
+        rows.truncate(state.config().discord_top);
+        rows
+    };
+    let same = |a: &[ArtistRow], b: &[ArtistRow]| {
+        a.iter().map(|row| &row.name).eq(b.iter().map(|row| &row.name))
+    };
+
+    // By site key. Data loaded from the cache file is the starting point, so
+    // a restart doesn't announce the current leaders again.
+    let mut announced: HashMap<String, Announced> = HashMap::new();
+    for site in state.config().sites.iter() {
+        let key = UpstreamQuery::leaderboard(site, Period::default()).cache_key();
+        if let Some(entry) = state.cache.read().await.get(&key) {
+            let baseline = Announced {
+                top: top(&entry),
+                sent_at: None,
+            };
+            announced.insert(site.key.clone(), baseline);
+        }
+    }
+    // Changes waiting out the debounce, by site key.
+    let mut pending: HashMap<String, (String, Vec<ArtistRow>, SystemTime)> = HashMap::new();
+
+    loop {
+        let now = tokio::time::Instant::now();
+        let ready_at = |announced: &HashMap<String, Announced>, key: &String| {
+            announced[key].sent_at.map(|sent_at| sent_at + debounce)
+        };
+        let due: Vec<String> = pending
+            .keys()
+            .filter(|key| ready_at(&announced, key).is_none_or(|ready_at| ready_at <= now))
+            .cloned()
+            .collect();
+        for key in due {
+            let (site_id, rows, at) = pending.remove(&key).expect("due keys are pending");
+            let last = announced.get_mut(&key).expect("pending sites have a baseline");
+            let message = discord::announcement(&site_id, &last.top, &rows, at);
+            match discord.send(&message).await {
+                Ok(()) => tracing::info!("Announced top artist change for {} on Discord", key),
+                Err(e) => tracing::warn!("Failed to notify Discord: {}", e),
+            }
+            // Even when sending failed, so a Discord outage isn't retried on
+            // every refresh.
+            *last = Announced {
+                top: rows,
+                sent_at: Some(tokio::time::Instant::now()),
+            };
+        }
+        let next = pending.keys().filter_map(|key| ready_at(&announced, key)).min();
+
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    let Some(site) = all_time_site(&state, &update.key) else {
+                        continue;
+                    };
+                    let rows = top(&update.entry);
+                    match announced.get(&site.key) {
+                        None => {
+                            let baseline = Announced { top: rows, sent_at: None };
+                            announced.insert(site.key.clone(), baseline);
+                        }
+                        Some(last) if same(&last.top, &rows) => {
+                            pending.remove(&site.key);
+                        }
+                        Some(_) => {
+                            let change = (site.id.clone(), rows, update.entry.fetched_at);
+                            pending.insert(site.key.clone(), change);
+                        }
+                    }
+                }
+                // Each update carries the whole leaderboard, so the next one
+                // makes up for any missed.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            alert = alerts.recv() => match alert {
+                Ok(alert) => {
+                    let message = discord::spikes(&alert.site, &alert.spikes, alert.at);
+                    match discord.send(&message).await {
+                        Ok(()) => tracing::info!("Announced spikes for {} on Discord", alert.site),
+                        Err(e) => tracing::warn!("Failed to notify Discord: {}", e),
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Dropped {} spike alerts for Discord", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or(now)), if next.is_some() => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/// Publishes a freshly fetched all-time leaderboard, as `/` serves it by
+/// default, to `snapshot_dir` and the S3 bucket, whichever are set. Uploads
+/// run in the background unless `--snapshot-only` is waiting on them.
+pub async fn publish_snapshot(state: &AppState, key: &CacheKey, entry: &CacheEntry) {
+    if state.config().snapshot_dir.is_none() && state.bucket.is_none() {
+        return;
+    }
+    let Some(site) = all_time_site(state, key) else {
+        return;
+    };
+    // Other sites go under their key, mirroring the `/:site/` routes.
+    let subdirectory = (site.key != default_site(state).key).then(|| site.key.clone());
+
+    let view = View::PUBLIC;
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let mut board = leaderboard(state, entry, view);
+    add_movement(state, &query, view, 0, &mut board);
+    let json = serde_json::to_string(&*board).expect("PlausibleResponse serializes");
+    let csv = state.config().snapshot_csv.then(|| export::csv(&ranked(&board.results)));
+
+    if let Some(dir) = &state.config().snapshot_dir {
+        let dir = match &subdirectory {
+            Some(subdirectory) => dir.join(subdirectory),
+            None => dir.clone(),
+        };
+        let retention = state.config().snapshot_retention;
+        let taken_at = entry.fetched_at;
+        let mut written = snapshot::write(&dir, "json", taken_at, &json, retention).await;
+        if let (Ok(()), Some(csv)) = (&written, &csv) {
+            written = snapshot::write(&dir, "csv", taken_at, csv, retention).await;
+        }
+        match written {
+            Ok(()) => tracing::debug!("Wrote snapshot to {}", dir.display()),
+            Err(e) => {
+                tracing::warn!("Failed to write snapshot to {}: {}", dir.display(), e);
+                state.metrics.record_snapshot_failure();
+            }
+        }
+    }
+
+    if let Some(bucket) = state.bucket.clone() {
+        let prefix = [state.config().s3_prefix.as_str(), subdirectory.as_deref().unwrap_or("")]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| format!("{}/", part))
+            .collect::<String>();
+        let mut objects = vec![(format!("{}latest.json", prefix), json, JSON)];
+        if let Some(csv) = csv {
+            objects.push((format!("{}latest.csv", prefix), csv, CSV));
+        }
+
+        let state = state.clone();
+        let cache_control = state.config().s3_cache_control.clone();
+        let run_once = state.run_once;
+        let upload = async move {
+            for (key, body, content_type) in objects {
+                match bucket.put(&key, body.into_bytes(), content_type, &cache_control).await {
+                    Ok(()) => tracing::debug!("Uploaded {}", key),
+                    Err(e) => {
+                        tracing::warn!("Failed to upload {}: {}", key, e);
+                        state.metrics.record_snapshot_failure();
+                    }
+                }
+            }
+        };
+        if run_once {
+            upload.await;
+        } else {
+            tokio::spawn(upload);
+        }
+    }
+}
+
+/// Checks that the bucket accepts uploads by writing, then deleting, a
+/// probe object, so bad credentials show up at boot.
+pub async fn check_bucket(state: &AppState, bucket: &s3::Bucket) -> bool {
+    let key = match state.config().s3_prefix.as_str() {
+        "" => ".write-check".to_string(),
+        prefix => format!("{}/.write-check", prefix),
+    };
+    let written = bucket.put(&key, b"ok".to_vec(), "text/plain", "no-store").await;
+    match written.and(bucket.delete(&key).await) {
+        Ok(()) => {
+            tracing::info!("Bucket accepted a probe upload");
+            true
+        }
+        Err(e) => {
+            tracing::error!("Bucket check failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Snapshots a freshly fetched all-time leaderboard into the history
+/// database, merged and filtered as the public routes serve it. Other
+/// periods aren't cumulative totals, so they aren't recorded.
+pub async fn record_history(state: &AppState, key: &CacheKey, entry: &CacheEntry) {
+    let Some(history) = state.history.clone() else {
+        return;
+    };
+    let Some(site) = all_time_site(state, key) else {
+        return;
+    };
+
+    let view = View::PUBLIC;
+    let rows = leaderboard(state, entry, view).into_owned().results;
+    let site = site.key.clone();
+    let taken_at = entry.fetched_at;
+    let window = state.config().cache_ttl;
+    let recorded =
+        tokio::task::spawn_blocking(move || history.record(&site, taken_at, window, &rows)).await;
+    match recorded {
+        Ok(Ok(true)) => tracing::debug!("Recorded history snapshot for {}", key),
+        Ok(Ok(false)) => {}
+        Ok(Err(e)) => tracing::warn!("Failed to record history snapshot: {}", e),
+        Err(e) => tracing::warn!("Failed to record history snapshot: {}", e),
+    }
+}
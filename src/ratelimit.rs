@@ -0,0 +1,289 @@
+use crate::config::ForwardedHeader;
+use axum::http::{header::FORWARDED, HeaderMap};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Per-client token buckets. Each client may burst up to `burst` requests,
+/// refilled at `per_minute` requests a minute. Only the `max_clients` most
+/// recently seen clients are tracked; a forgotten client starts over with a
+/// full bucket. Time is tokio's, so tests can pause and advance it.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    max_clients: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32, max_clients: usize) -> Self {
+        RateLimiter {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst),
+            max_clients,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `client`. `Err` carries how long until one is
+    /// available.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+
+        if !buckets.contains_key(&client) && buckets.len() >= self.max_clients {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                buckets.remove(&ip);
+            }
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// The address to rate limit a request by. Behind a trusted proxy that is
+/// the last hop recorded in `header` that isn't one of `proxies`: the hop
+/// the nearest untrusted party connected from. Entries left of it are
+/// client-supplied and can't be trusted, and so is the other header, which
+/// the proxy passes through as sent. Otherwise it is the peer address, as
+/// it is when that hop doesn't parse.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    trust_proxy: bool,
+    header: ForwardedHeader,
+    proxies: &[IpNet],
+) -> IpAddr {
+    if !trust_proxy {
+        return peer.ip();
+    }
+
+    let name = match header {
+        ForwardedHeader::XForwardedFor => "x-forwarded-for",
+        ForwardedHeader::Forwarded => FORWARDED.as_str(),
+    };
+    let hops: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    let mut client = None;
+    for hop in hops.iter().rev() {
+        let ip = match header {
+            ForwardedHeader::XForwardedFor => parse_node(hop),
+            ForwardedHeader::Forwarded => hop.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.trim().eq_ignore_ascii_case("for").then(|| parse_node(value))?
+            }),
+        };
+        let Some(ip) = ip else {
+            return peer.ip();
+        };
+        client = Some(ip);
+        if !in_nets(proxies, ip) {
+            break;
+        }
+    }
+    client.unwrap_or_else(|| peer.ip())
+}
+
+pub fn in_nets(nets: &[IpNet], ip: IpAddr) -> bool {
+    // A dual-stack listener sees IPv4 clients as IPv4-mapped IPv6.
+    let ip = ip.to_canonical();
+    nets.iter().any(|net| net.contains(&ip))
+}
+
+/// Parses a forwarded node: a bare or bracketed IP, optionally quoted and
+/// with a port.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|ip| ip.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.2:443";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn headers_of(name: &'static str, value: &str) -> HeaderMap {
+        headers(&[(name, value)])
+    }
+
+    fn headers_without(headers: &HeaderMap, name: &str) -> HeaderMap {
+        let mut headers = headers.clone();
+        headers.remove(name);
+        headers
+    }
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn client(headers: &HeaderMap, header: ForwardedHeader) -> IpAddr {
+        client_ip(headers, PEER.parse().unwrap(), true, header, &proxies())
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+        let peer = PEER.parse().unwrap();
+        let header = ForwardedHeader::XForwardedFor;
+        assert_eq!(client_ip(&headers, peer, false, header, &proxies()), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_is_ignored_when_the_proxy_sets_x_forwarded_for() {
+        let headers = headers(&[
+            ("forwarded", "for=198.51.100.66"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(client(&headers, ForwardedHeader::XForwardedFor), ip("203.0.113.7"));
+        // With nothing from the proxy, a spoofed `Forwarded` isn't a fallback.
+        let spoofed = headers_without(&headers, "x-forwarded-for");
+        assert_eq!(client(&spoofed, ForwardedHeader::XForwardedFor), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn x_forwarded_for_is_ignored_when_the_proxy_sets_forwarded() {
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.66"),
+            ("forwarded", r#"for="[2001:db8::7]:4711";proto=https"#),
+        ]);
+        assert_eq!(client(&headers, ForwardedHeader::Forwarded), ip("2001:db8::7"));
+    }
+
+    #[test]
+    fn client_supplied_hops_left_of_the_proxy_are_ignored() {
+        // The client sent the first entry; the proxy appended the second.
+        let headers = headers(&[("x-forwarded-for", "198.51.100.66, 203.0.113.7")]);
+        assert_eq!(client(&headers, ForwardedHeader::XForwardedFor), ip("203.0.113.7"));
+
+        let headers = headers_of("forwarded", "for=198.51.100.66, for=203.0.113.7");
+        assert_eq!(client(&headers, ForwardedHeader::Forwarded), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn trusted_proxy_hops_are_skipped() {
+        // Client, then an edge proxy, then the one in front of us.
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.66, 203.0.113.7"),
+            ("x-forwarded-for", "10.1.2.3"),
+        ]);
+        assert_eq!(client(&headers, ForwardedHeader::XForwardedFor), ip("203.0.113.7"));
+
+        // Every hop a proxy: the furthest one is as close as it gets.
+        let headers = headers_of("x-forwarded-for", "10.9.9.9, ::ffff:10.1.2.3");
+        assert_eq!(client(&headers, ForwardedHeader::XForwardedFor), ip("10.9.9.9"));
+    }
+
+    #[test]
+    fn unparseable_hop_falls_back_to_the_peer() {
+        let headers = headers_of("forwarded", "for=198.51.100.66, for=unknown");
+        assert_eq!(client(&headers, ForwardedHeader::Forwarded), ip("10.0.0.2"));
+
+        let headers = headers_of("x-forwarded-for", "203.0.113.7, not-an-ip");
+        assert_eq!(client(&headers, ForwardedHeader::XForwardedFor), ip("10.0.0.2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_allowed_then_limited_until_refilled() {
+        // A token a second.
+        let limiter = RateLimiter::new(60, 3, 10);
+        let client = ip("203.0.113.7");
+        for _ in 0..3 {
+            assert_eq!(limiter.check(client), Ok(()));
+        }
+        assert_eq!(limiter.check(client), Err(Duration::from_secs(1)));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check(client), Err(Duration::from_millis(500)));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check(client), Ok(()));
+        assert!(limiter.check(client).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_stops_at_the_burst() {
+        let limiter = RateLimiter::new(60, 2, 10);
+        let client = ip("203.0.113.7");
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        for _ in 0..2 {
+            assert_eq!(limiter.check(client), Ok(()));
+        }
+        assert!(limiter.check(client).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_have_their_own_buckets() {
+        let limiter = RateLimiter::new(60, 1, 10);
+        assert_eq!(limiter.check(ip("203.0.113.7")), Ok(()));
+        assert!(limiter.check(ip("203.0.113.7")).is_err());
+        assert_eq!(limiter.check(ip("198.51.100.66")), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn least_recent_client_is_forgotten_past_max_clients() {
+        let limiter = RateLimiter::new(60, 1, 2);
+        assert_eq!(limiter.check(ip("203.0.113.1")), Ok(()));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(limiter.check(ip("203.0.113.2")), Ok(()));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(limiter.check(ip("203.0.113.3")), Ok(()));
+
+        // The first was dropped for the third, so it starts over full.
+        assert_eq!(limiter.check(ip("203.0.113.1")), Ok(()));
+        assert!(limiter.check(ip("203.0.113.3")).is_err());
+    }
+}
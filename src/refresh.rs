@@ -0,0 +1,693 @@
+use crate::cache::{CacheEntry, CacheKey, CacheStatus};
+use crate::error::FetchError;
+use crate::fetcher::{Fetched, Validators};
+use crate::plausible::Payload;
+use crate::publish::{detect_spikes, publish_snapshot, record_feed, record_history, spawn_webhook};
+use crate::upstream::{Period, QueryKind, UpstreamQuery};
+use crate::{
+    cache, random_u64, reload_aliases, snippet, upstream, AppState, Failure, FetchResult, Update,
+};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, SemaphorePermit};
+use tracing::Instrument;
+
+/// How long past the cache TTL a stale entry may still be served while a
+/// background refresh runs.
+const STALE_GRACE: Duration = Duration::from_secs(600);
+/// How soon the background task retries after a failed refresh.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Backoff before the first retry; doubled for each one after.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+/// How long to hold off after an upstream 429 without `Retry-After`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Longest an upstream `Retry-After` is honoured for, so a bogus one can't
+/// stop fetching for good.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// Fetches every site's `warm_keys` leaderboards at once, so their first
+/// requests after a deploy are hits, then marks the server warm. Failures
+/// are only logged: those keys are fetched on first request as usual.
+pub async fn warm_up(state: AppState) {
+    let started = Instant::now();
+    let periods = state.config().warm_periods();
+    let queries: Vec<UpstreamQuery> = state
+        .config()
+        .sites
+        .iter()
+        .flat_map(|site| periods.iter().map(|&period| UpstreamQuery::leaderboard(site, period)))
+        .collect();
+    let results =
+        futures_util::future::join_all(queries.iter().map(|query| refresh(&state, query))).await;
+
+    let mut warmed = 0;
+    for (query, result) in queries.iter().zip(results) {
+        match result {
+            Ok(_) => warmed += 1,
+            Err(e) => tracing::warn!("Failed to warm {}: {}", query.cache_key(), e),
+        }
+    }
+    tracing::info!(
+        "Warmed {} of {} cache keys in {:?}",
+        warmed,
+        queries.len(),
+        started.elapsed()
+    );
+    state.warmed.store(true, Ordering::Relaxed);
+}
+
+/// Keeps the cache warm so requests are normally served without waiting on
+/// the upstream. Fetches every site's default leaderboard immediately on
+/// startup, then once per cache TTL, or every `RETRY_INTERVAL` after any
+/// failure. Exits when `shutdown` flips.
+pub async fn refresh_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let mut delay = Duration::MAX;
+        for site in state.config().sites.iter() {
+            match refresh(&state, &UpstreamQuery::leaderboard(site, Period::default())).await {
+                Ok(entry) => {
+                    tracing::info!("Background refresh of {} succeeded", site.key);
+                    delay = delay.min(ttl(&state, &entry));
+                }
+                Err(_) => {
+                    tracing::warn!(
+                        "Background refresh of {} failed, retrying in {:?}",
+                        site.key,
+                        RETRY_INTERVAL
+                    );
+                    delay = RETRY_INTERVAL;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    tracing::info!("Background refresh task stopped");
+}
+
+/// Returns the cached entry for `query`, fetching it when there is none or
+/// it is too stale to serve. Entries within the stale grace period are
+/// served immediately while a background refresh runs. When a fetch fails,
+/// whatever was cached is served instead, however old, and so it is without
+/// trying again while the failure is remembered.
+pub async fn lookup(
+    state: &AppState,
+    query: &UpstreamQuery,
+) -> Result<(Arc<CacheEntry>, CacheStatus), FetchError> {
+    let result = lookup_entry(state, query).await;
+    if let Ok((_, status)) = &result {
+        state.metrics.record_cache(*status);
+    }
+    result
+}
+
+/// How long `entry` stays fresh once fetched.
+pub fn ttl(state: &AppState, entry: &CacheEntry) -> Duration {
+    entry.ttl.unwrap_or(state.config().cache_ttl)
+}
+
+async fn lookup_entry(
+    state: &AppState,
+    query: &UpstreamQuery,
+) -> Result<(Arc<CacheEntry>, CacheStatus), FetchError> {
+    let key = query.cache_key();
+    let cached = state.cache.read().await.get(&key);
+
+    if let Some(entry) = cached.as_ref().filter(|_| !state.config().cache_ttl.is_zero()) {
+        let age = entry.timestamp.elapsed();
+        if age < ttl(state, entry) {
+            tracing::info!("Returning cached response for {}", key);
+            return Ok((entry.clone(), CacheStatus::Hit));
+        }
+    }
+
+    if let Some(e) = remembered_failure(state, &key) {
+        return match cached {
+            Some(entry) => {
+                tracing::info!("Serving stale response for {}, upstream failed recently", key);
+                Ok((entry, CacheStatus::Stale))
+            }
+            None => {
+                tracing::info!("Returning remembered failure for {}", key);
+                Err(e)
+            }
+        };
+    }
+
+    if let Some(entry) = cached.as_ref().filter(|_| !state.config().cache_ttl.is_zero()) {
+        let age = entry.timestamp.elapsed();
+        if age < ttl(state, entry) + STALE_GRACE {
+            tracing::info!("Returning stale response for {}, revalidating in background", key);
+            spawn_revalidate(state, query);
+            return Ok((entry.clone(), CacheStatus::Stale));
+        }
+    }
+
+    tracing::info!("Fetching fresh data from API for {}", key);
+
+    match refresh(state, query).await {
+        Ok(entry) => Ok((entry, CacheStatus::Miss)),
+        Err(e) => match cached {
+            // Last known good data beats an error page, however old it is.
+            Some(entry) => {
+                tracing::warn!("Serving stale response for {} after failed refresh", key);
+                Ok((entry, CacheStatus::Stale))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// The failure remembered for `key`, unless it has expired.
+fn remembered_failure(state: &AppState, key: &CacheKey) -> Option<FetchError> {
+    let mut failures = state.failures.lock().unwrap();
+    let failure = failures.get(key)?;
+    if failure.until > Instant::now() {
+        return Some(failure.error.clone());
+    }
+    failures.remove(key);
+    None
+}
+
+/// Refreshes `query` in the background without waiting for the result.
+/// Joins the fetch already in flight if there is one.
+fn spawn_revalidate(state: &AppState, query: &UpstreamQuery) {
+    start_refresh(state, query);
+}
+
+/// Refreshes `query`, sharing a single upstream request between all
+/// concurrent callers for the same cache key. Every caller receives the same
+/// result.
+pub async fn refresh(state: &AppState, query: &UpstreamQuery) -> FetchResult {
+    let mut rx = start_refresh(state, query);
+
+    let result = match rx.wait_for(Option::is_some).await {
+        Ok(result) => result.clone().expect("wait_for guarantees a result"),
+        Err(_) => Err(FetchError::Request("refresh task exited unexpectedly".into())),
+    };
+    result
+}
+
+/// Returns a receiver for the in-flight fetch, starting one if none is
+/// running. The fetch runs in its own task so it completes even when the
+/// request that started it is cancelled.
+fn start_refresh(state: &AppState, query: &UpstreamQuery) -> watch::Receiver<Option<FetchResult>> {
+    let key = query.cache_key();
+    let mut inflight = state.inflight.lock().unwrap();
+
+    if let Some(rx) = inflight.get(&key) {
+        if rx.has_changed().is_ok() {
+            return rx.clone();
+        }
+    }
+
+    let (tx, rx) = watch::channel(None);
+    inflight.insert(key.clone(), rx.clone());
+
+    let state = state.clone();
+    let query = query.clone();
+    // The fetch is attributed to the request that started it; coalesced
+    // waiters share it.
+    tokio::spawn(
+        async move {
+            let result = fetch_and_store(&state, &query, &key).await;
+            tx.send_replace(Some(result));
+            state.inflight.lock().unwrap().remove(&key);
+        }
+        .in_current_span(),
+    );
+
+    rx
+}
+
+/// Fetches `query` from the upstream API and stores the result in the cache
+/// under `key`. On failure the existing entry, if any, is left untouched.
+/// The request is conditional on the cached entry's validators; when the
+/// upstream confirms it, or sends data with the same content hash, only the
+/// entry's age is reset and nothing that reacts to changed data runs.
+pub async fn fetch_and_store(
+    state: &AppState,
+    query: &UpstreamQuery,
+    key: &CacheKey,
+) -> FetchResult {
+    reload_aliases(state);
+
+    let cached = state.cache.read().await.get(key);
+    let validators = cached
+        .as_ref()
+        .map(|cached| cached.validators.clone())
+        .unwrap_or_default();
+    let started = Instant::now();
+    let fetched = match fetch(state, query, &validators).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            tracing::error!("{}", e);
+            *state.last_error.lock().unwrap() = Some((SystemTime::now(), e.clone()));
+            // Refusals already skip the upstream, and lift on their own.
+            let ttl = state.config().negative_cache_ttl;
+            if !ttl.is_zero() && !e.is_refusal() {
+                let failure = Failure {
+                    error: e.clone(),
+                    at: SystemTime::now(),
+                    until: Instant::now() + ttl,
+                };
+                state.failures.lock().unwrap().insert(key.clone(), failure);
+            }
+            return Err(e);
+        }
+    };
+    state.failures.lock().unwrap().remove(key);
+    *state.last_success_duration.lock().unwrap() = Some(started.elapsed());
+
+    let unchanged = match (&fetched, &cached) {
+        (None, Some(cached)) => Some(cached.revalidated(validators)),
+        (Some((_, payload, validators)), Some(cached))
+            if cache::content_hash(payload) == cached.content_hash =>
+        {
+            Some(cached.revalidated(validators.clone()))
+        }
+        _ => None,
+    };
+    if let Some(entry) = unchanged {
+        let entry = Arc::new(entry);
+        *state.last_success.lock().unwrap() = Some(entry.fetched_at);
+        state.cache.write().await.insert(key.clone(), entry.clone());
+        state.metrics.record_refresh(false);
+        tracing::info!("Revalidated {}, unchanged", key);
+        return Ok(entry);
+    }
+
+    let (body, payload, validators) = fetched.expect("only a cached entry is revalidated");
+    let ttl = state.config().query_cache_ttl(query);
+    let percent = state.config().cache_ttl_jitter_percent;
+    let entry = Arc::new(CacheEntry {
+        validators,
+        ttl: match percent {
+            0 => ttl,
+            _ => Some(jittered(ttl.unwrap_or(state.config().cache_ttl), percent, random_u64())),
+        },
+        ..CacheEntry::new(body, payload)
+    });
+    *state.last_success.lock().unwrap() = Some(entry.fetched_at);
+    state.metrics.record_refresh(true);
+
+    let (snapshot, replaced) = {
+        let mut cache = state.cache.write().await;
+        let replaced = cache.get(key);
+        if let Some(replaced) = &replaced {
+            state.previous.lock().unwrap().insert(key.clone(), replaced.clone());
+        }
+        cache.insert(key.clone(), entry.clone());
+        let usage = cache.usage();
+        tracing::info!(
+            "Cached {} ({} entries, {} bytes, {} evictions)",
+            key,
+            usage.entries,
+            usage.bytes,
+            usage.evictions
+        );
+        let snapshot = state.config().cache_file.as_ref().map(|_| cache::snapshot(&cache));
+        (snapshot, replaced)
+    };
+
+    if let (Some(path), Some(snapshot)) = (&state.config().cache_file, snapshot) {
+        cache::save(path, snapshot).await;
+    }
+    record_history(state, key, &entry).await;
+    record_feed(state, key, &entry);
+    detect_spikes(state, key, &entry);
+    publish_snapshot(state, key, &entry).await;
+    // No subscribers is not an error.
+    let _ = state.updates.send(Update {
+        key: key.clone(),
+        entry: entry.clone(),
+    });
+    if let Some(replaced) = &replaced {
+        spawn_webhook(state, key, &entry, replaced);
+    }
+
+    Ok(entry)
+}
+
+/// Fetches `query`, following pagination until a short page or the
+/// `max_pages` cap, and merges every page's `results` into the first page's
+/// document, then parses the result into its typed form. A failure after
+/// the first page keeps the rows already fetched. `None` when the upstream
+/// answered that the body `validators` came with is current.
+async fn fetch(
+    state: &AppState,
+    query: &UpstreamQuery,
+    validators: &Validators,
+) -> Result<Option<(String, Payload, Validators)>, FetchError> {
+    let _slot = upstream_slot(state, query).await?;
+    let Some((body, validators)) = fetch_pages(state, query, validators).await? else {
+        return Ok(None);
+    };
+    let body = keyed_by_name(query, body);
+    let payload = Payload::parse(query.kind(), &body)
+        .map_err(|e| FetchError::Invalid(format!("unexpected response shape: {}", e)))?;
+    Ok(Some((body, payload, validators)))
+}
+
+/// Waits for one of the `upstream_max_concurrency` upstream slots, held
+/// for the whole fetch, giving up after `upstream_queue_timeout`.
+async fn upstream_slot<'a>(
+    state: &'a AppState,
+    query: &UpstreamQuery,
+) -> Result<SemaphorePermit<'a>, FetchError> {
+    if let Ok(slot) = state.upstream_slots.try_acquire() {
+        state.metrics.record_upstream_wait(Duration::ZERO, false, true);
+        return Ok(slot);
+    }
+
+    let key = query.cache_key();
+    let queued = state.metrics.enter_upstream_queue();
+    tracing::info!("Waiting for an upstream slot for {} ({} waiting)", key, queued);
+    let timeout = state.config().upstream_queue_timeout;
+    let started = Instant::now();
+    let acquired = tokio::time::timeout(timeout, state.upstream_slots.acquire()).await;
+    let waited = started.elapsed();
+    match acquired {
+        Ok(slot) => {
+            state.metrics.record_upstream_wait(waited, true, true);
+            tracing::info!("Got an upstream slot for {} after {:.1?}", key, waited);
+            Ok(slot.expect("the semaphore is never closed"))
+        }
+        Err(_) => {
+            state.metrics.record_upstream_wait(waited, true, false);
+            Err(FetchError::Busy { waited })
+        }
+    }
+}
+
+/// `body` with any leaderboard rows the upstream keyed by the property's
+/// own name, rather than `name`, keyed by `name` as `ArtistRow` expects.
+fn keyed_by_name(query: &UpstreamQuery, body: String) -> String {
+    if query.kind() != QueryKind::Leaderboard {
+        return body;
+    }
+    let Ok(mut document) = serde_json::from_str::<serde_json::Value>(&body) else {
+        return body;
+    };
+    let Some(rows) = document.get_mut("results").and_then(|rows| rows.as_array_mut()) else {
+        return body;
+    };
+    let mut renamed = false;
+    for row in rows.iter_mut().filter_map(|row| row.as_object_mut()) {
+        if row.contains_key("name") {
+            continue;
+        }
+        if let Some(value) = row.remove(query.property()) {
+            row.insert("name".to_string(), value);
+            renamed = true;
+        }
+    }
+    if renamed {
+        document.to_string()
+    } else {
+        body
+    }
+}
+
+/// Only the first page is conditional, and only a single page keeps its
+/// validators: a 304 for the first says nothing about the rest.
+async fn fetch_pages(
+    state: &AppState,
+    query: &UpstreamQuery,
+    validators: &Validators,
+) -> Result<Option<(String, Validators)>, FetchError> {
+    let Some(first) = fetch_page(state, query, 1, validators).await? else {
+        return Ok(None);
+    };
+    if !query.paginated() || results_len(&first.document) < upstream::PAGE_LIMIT {
+        return Ok(Some((first.body, first.validators)));
+    }
+
+    let mut document = first.document;
+    for page in 2..=state.config().upstream_max_pages {
+        let mut next = match fetch_page(state, query, page, &Validators::default()).await {
+            Ok(next) => next.expect("no validators were sent").document,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to fetch page {} ({}), keeping {} rows from earlier pages",
+                    page,
+                    e,
+                    results_len(&document)
+                );
+                break;
+            }
+        };
+
+        let rows = std::mem::take(next["results"].as_array_mut().expect("validated"));
+        let full = rows.len() >= upstream::PAGE_LIMIT;
+        document["results"]
+            .as_array_mut()
+            .expect("validated")
+            .extend(rows);
+
+        if !full {
+            break;
+        }
+        if page == state.config().upstream_max_pages {
+            tracing::warn!(
+                "Stopped after {} pages, results may be truncated",
+                state.config().upstream_max_pages
+            );
+        }
+    }
+
+    Ok(Some((document.to_string(), Validators::default())))
+}
+
+fn results_len(document: &serde_json::Value) -> usize {
+    document["results"].as_array().map_or(0, Vec::len)
+}
+
+/// One page as the upstream sent it.
+pub struct Page {
+    body: String,
+    /// `body` parsed.
+    document: serde_json::Value,
+    validators: Validators,
+}
+
+/// Fetches one page through the circuit breaker; `None` when the upstream
+/// answered 304 to `validators`. A transient failure that survives every
+/// retry counts against the circuit; any answer from the upstream, even an
+/// error status, counts as it being up.
+async fn fetch_page(
+    state: &AppState,
+    query: &UpstreamQuery,
+    page: u32,
+    validators: &Validators,
+) -> Result<Option<Page>, FetchError> {
+    if let Some(retry_in) = rate_limited_for(state) {
+        return Err(FetchError::Throttled { retry_in });
+    }
+    state
+        .breaker
+        .acquire()
+        .map_err(|retry_in| FetchError::CircuitOpen { retry_in })?;
+
+    let result = fetch_page_with_retries(state, query, page, validators).await;
+    match &result {
+        Err(e) if e.is_transient() => state.breaker.record_failure(),
+        _ => state.breaker.record_success(),
+    }
+    if let Err(FetchError::Status {
+        status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+        retry_after,
+        ..
+    }) = &result
+    {
+        hold_off(state, retry_after.unwrap_or(DEFAULT_RETRY_AFTER));
+    }
+    result
+}
+
+/// How much longer upstream requests are held off for, if they are.
+pub fn rate_limited_for(state: &AppState) -> Option<Duration> {
+    let until = (*state.rate_limited_until.lock().unwrap())?;
+    Some(until.saturating_duration_since(Instant::now())).filter(|wait| !wait.is_zero())
+}
+
+/// Holds off upstream requests for `wait`, at most `MAX_RETRY_AFTER`, so
+/// every cached entry is served stale until the upstream's limit resets.
+fn hold_off(state: &AppState, wait: Duration) {
+    let wait = wait.min(MAX_RETRY_AFTER);
+    let until = Instant::now() + wait;
+    let mut limited = state.rate_limited_until.lock().unwrap();
+    if limited.is_none_or(|current| current < until) {
+        *limited = Some(until);
+        tracing::warn!("Upstream rate limit hit, holding off requests for {:?}", wait);
+    }
+}
+
+/// Fetches one page, retrying transient failures with exponential backoff
+/// for up to `upstream_attempts` tries. Attempts and backoff together stay
+/// within `upstream_budget`, so a retry is skipped rather than overrun it.
+async fn fetch_page_with_retries(
+    state: &AppState,
+    query: &UpstreamQuery,
+    page: u32,
+    validators: &Validators,
+) -> Result<Option<Page>, FetchError> {
+    let deadline = Instant::now() + state.config().upstream_budget;
+    let mut attempt = 1;
+    loop {
+        let timeout = state
+            .config()
+            .upstream_attempt_timeout
+            .min(deadline.saturating_duration_since(Instant::now()));
+        let e = match attempt_page(state, query, page, timeout, attempt, validators).await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+
+        let delay = backoff(attempt);
+        if attempt >= state.config().upstream_attempts
+            || !e.is_transient()
+            || Instant::now() + delay >= deadline
+        {
+            return Err(e);
+        }
+
+        tracing::warn!(
+            "Upstream attempt {}/{} failed ({}), retrying in {:?}",
+            attempt,
+            state.config().upstream_attempts,
+            e,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Delay before retrying after failed attempt number `attempt`: the
+/// exponential step with equal jitter, so retries from coalesced or
+/// concurrent fetches don't land on the upstream in lockstep.
+pub fn backoff(attempt: u32) -> Duration {
+    let step = RETRY_BASE_DELAY * 2u32.pow(attempt.saturating_sub(1).min(16));
+    step / 2 + (step / 2).mul_f64(random_u64() as f64 / u64::MAX as f64)
+}
+
+/// `ttl` moved by up to `percent` percent either way, by where `random`
+/// falls between 0 and `u64::MAX`.
+fn jittered(ttl: Duration, percent: u32, random: u64) -> Duration {
+    let spread = ttl.mul_f64(f64::from(percent) / 100.0);
+    ttl - spread + (spread * 2).mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// A single upstream request, recorded in the metrics. `attempt` counts
+/// the tries at this page so far, this one included.
+pub async fn attempt_page(
+    state: &AppState,
+    query: &UpstreamQuery,
+    page: u32,
+    timeout: Duration,
+    attempt: u32,
+    validators: &Validators,
+) -> Result<Option<Page>, FetchError> {
+    let started = Instant::now();
+    let result = send_page(state, query, page, timeout, validators)
+        .instrument(tracing::info_span!("upstream", page, attempt))
+        .await;
+    state.metrics.record_upstream(started.elapsed(), result.is_ok());
+    result
+}
+
+async fn send_page(
+    state: &AppState,
+    query: &UpstreamQuery,
+    page: u32,
+    timeout: Duration,
+    validators: &Validators,
+) -> Result<Option<Page>, FetchError> {
+    let fetched = state
+        .fetcher
+        .fetch_if_modified(query, page, timeout, validators)
+        .await?;
+    let Fetched::Modified { body, validators } = fetched else {
+        return Ok(None);
+    };
+    let document = validate(query.kind(), &body)?;
+
+    Ok(Some(Page {
+        body,
+        document,
+        validators,
+    }))
+}
+
+/// Sanity-checks that the body is a JSON object carrying the array `kind`
+/// responses are built around (`results`, or `plot` for a time series)
+/// before it is allowed into the cache, returning the parsed document.
+fn validate(kind: QueryKind, body: &str) -> Result<serde_json::Value, FetchError> {
+    let value: serde_json::Value = serde_json::from_str(body)
+        .map_err(|e| FetchError::Invalid(format!("body is not JSON: {}", e)))?;
+
+    let field = match kind {
+        QueryKind::Leaderboard => "results",
+        QueryKind::Timeseries => "plot",
+        QueryKind::Breakdown => "results",
+        QueryKind::Realtime if value.is_u64() => return Ok(value),
+        QueryKind::Realtime => {
+            return Err(FetchError::Invalid(format!(
+                "expected a visitor count: {}",
+                snippet(body)
+            )));
+        }
+    };
+    if !value.get(field).is_some_and(serde_json::Value::is_array) {
+        return Err(FetchError::Invalid(format!(
+            "missing `{}` array: {}",
+            field,
+            snippet(body)
+        )));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// splitmix64 from a fixed seed, so every run draws the same values.
+    fn draws(mut seed: u64) -> impl Iterator<Item = u64> {
+        std::iter::repeat_with(move || {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+    }
+
+    #[test]
+    fn jittered_stays_within_percent_of_ttl() {
+        let ttl = Duration::from_secs(300);
+        let (low, high) = (Duration::from_secs(270), Duration::from_secs(330));
+        let mut below = 0;
+        for random in draws(89).take(10_000) {
+            let jittered = jittered(ttl, 10, random);
+            assert!(low <= jittered && jittered <= high, "{:?} from {}", jittered, random);
+            below += usize::from(jittered < ttl);
+        }
+        // Spread both ways, not bunched at one end.
+        assert!((4_500..5_500).contains(&below), "{} of 10000 below the TTL", below);
+    }
+
+    #[test]
+    fn jittered_reaches_the_ends_of_its_range() {
+        let ttl = Duration::from_secs(300);
+        assert_eq!(jittered(ttl, 10, 0), Duration::from_secs(270));
+        assert_eq!(jittered(ttl, 10, u64::MAX), Duration::from_secs(330));
+        assert_eq!(jittered(ttl, 0, u64::MAX), ttl);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::cache::{CacheEntry, CacheStatus};
+use crate::error::{ApiError, FetchError};
+use crate::params::{Format, View};
+use crate::plausible::PlausibleResponse;
+use crate::refresh::ttl;
+use crate::upstream::UpstreamQuery;
+use crate::{
+    cache, json_body, project, AppState, JSON, MSGPACK, X_CACHE, X_CACHE_EXPIRES_IN, X_COMPARED_TO,
+    X_FALLBACK,
+};
+use axum::{
+    http::{
+        header::{
+            HeaderValue, AGE, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY, WARNING,
+        },
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::time::SystemTime;
+
+/// The entry's leaderboard as `view` asks for it: with near-duplicate names
+/// and aliases merged and each row's share of visitors added unless raw, and
+/// excluded names dropped unless unfiltered. Borrowed only when nothing had
+/// to change. Shares are computed here, on every remaining row, so they stay
+/// global whatever the caller filters or truncates afterwards.
+pub fn leaderboard<'a>(
+    state: &AppState,
+    entry: &'a CacheEntry,
+    view: View,
+) -> Cow<'a, PlausibleResponse> {
+    let response = entry
+        .payload
+        .leaderboard()
+        .expect("leaderboard queries cache leaderboard payloads");
+    let mut board = if view.raw {
+        Cow::Borrowed(response)
+    } else {
+        Cow::Owned(response.merged(&state.aliases.read().unwrap()))
+    };
+
+    let exclusions = &state.config().exclusions;
+    if !view.unfiltered && board.results.iter().any(|row| exclusions.matches(&row.name)) {
+        board
+            .to_mut()
+            .results
+            .retain(|row| !exclusions.matches(&row.name));
+    }
+    if !view.raw {
+        board.to_mut().add_shares(state.config().share_decimals);
+    }
+    board
+}
+
+/// Adds each row's movement since the entry `query`'s cache entry
+/// replaced, with the previous leaderboard viewed and filtered the same way.
+/// Returns when that entry was fetched, or `None` when there is nothing to
+/// compare against yet.
+pub fn add_movement(
+    state: &AppState,
+    query: &UpstreamQuery,
+    view: View,
+    min_visitors: u64,
+    board: &mut Cow<'_, PlausibleResponse>,
+) -> Option<SystemTime> {
+    let previous = state.previous.lock().unwrap().get(&query.cache_key()).cloned()?;
+    let before = at_least(leaderboard(state, &previous, view), min_visitors);
+    board.to_mut().add_movement(&before);
+    Some(previous.fetched_at)
+}
+
+/// Labels a response with when the leaderboard it compares against was
+/// fetched.
+pub fn compared(mut response: Response, compared_to: Option<SystemTime>) -> Response {
+    if let Some(time) = compared_to {
+        let time = chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339();
+        response.headers_mut().insert(
+            X_COMPARED_TO,
+            HeaderValue::from_str(&time).expect("timestamps are ASCII"),
+        );
+    }
+    response
+}
+
+/// `board` without the rows that have fewer than `min_visitors` visitors.
+pub fn at_least(
+    mut board: Cow<'_, PlausibleResponse>,
+    min_visitors: u64,
+) -> Cow<'_, PlausibleResponse> {
+    if board.results.iter().any(|row| row.visitors < min_visitors) {
+        board.to_mut().results.retain(|row| row.visitors >= min_visitors);
+    }
+    board
+}
+
+/// `response` with only the rows in `rows`.
+pub fn truncate_results(
+    response: &PlausibleResponse,
+    rows: std::ops::Range<usize>,
+) -> PlausibleResponse {
+    PlausibleResponse {
+        results: response.results[rows].to_vec(),
+        extra: response.extra.clone(),
+    }
+}
+
+/// `response` with each row cut down to `fields`.
+pub fn project_results(response: &PlausibleResponse, fields: &[&str]) -> serde_json::Value {
+    let mut body = serde_json::to_value(response).expect("PlausibleResponse serializes");
+    if let Some(rows) = body["results"].as_array_mut() {
+        rows.iter_mut().for_each(|row| project(row, fields));
+    }
+    body
+}
+
+pub fn error_response(e: FetchError) -> Response {
+    let mut response = ApiError::from(e).into_response();
+    response.headers_mut().insert(X_CACHE, HeaderValue::from_static("ERROR"));
+    response
+}
+
+/// `fallback_file`, for a failed fetch with nothing cached, as long as no
+/// fetch has succeeded since startup. Never stored by caches, so clients
+/// pick up real data as soon as there is some.
+pub fn fallback_response(state: &AppState) -> Option<Response> {
+    let body = state.config().fallback.clone()?;
+    if state.last_success.lock().unwrap().is_some() {
+        return None;
+    }
+    tracing::warn!("Serving the fallback leaderboard");
+    let headers = [
+        (CONTENT_TYPE, HeaderValue::from_static(JSON)),
+        (CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        (X_FALLBACK, HeaderValue::from_static("true")),
+    ];
+    Some((headers, body).into_response())
+}
+
+/// Renders `entry` as cached. Cached bodies are validated JSON.
+pub fn cached_response(
+    state: &AppState,
+    entry: &CacheEntry,
+    status: CacheStatus,
+    request: &axum::http::HeaderMap,
+) -> Response {
+    respond(state, entry, status, request, entry.data.clone(), &entry.etag, JSON)
+}
+
+/// Renders `body`, a representation derived from `entry`, with its own ETag.
+pub fn render_response(
+    state: &AppState,
+    entry: &CacheEntry,
+    status: CacheStatus,
+    request: &axum::http::HeaderMap,
+    body: String,
+    content_type: &'static str,
+) -> Response {
+    let etag = cache::etag(&body);
+    respond(state, entry, status, request, body, &etag, content_type)
+}
+
+/// Renders `body`, derived from `entry`, as JSON or as MessagePack, each
+/// with the ETag of the bytes sent. MessagePack has structs as maps keyed
+/// by field name, so both carry the same document.
+pub fn render_json(
+    state: &AppState,
+    entry: &CacheEntry,
+    status: CacheStatus,
+    request: &axum::http::HeaderMap,
+    view: View,
+    body: &impl Serialize,
+    format: Format,
+) -> Response {
+    if format != Format::MessagePack {
+        return render_response(state, entry, status, request, json_body(view, body), JSON);
+    }
+    let body = rmp_serde::to_vec_named(body).expect("response bodies serialize");
+    let etag = cache::etag(&body);
+    respond(state, entry, status, request, body, &etag, MSGPACK)
+}
+
+/// Sends `body`, or a bodiless 304 when the client already holds `etag`,
+/// with `X-Cache`, `Age` and `X-Cache-Expires-In` describing where the body
+/// came from and how long until `entry` is considered stale. `Cache-Control`
+/// advertises the remaining lifetime so downstream caches expire in lockstep,
+/// and is `private` when `api_keys` is set.
+fn respond(
+    state: &AppState,
+    entry: &CacheEntry,
+    status: CacheStatus,
+    request: &axum::http::HeaderMap,
+    body: impl Into<axum::body::Body>,
+    etag: &str,
+    content_type: &'static str,
+) -> Response {
+    let age = entry.timestamp.elapsed();
+    let remaining = ttl(state, entry).saturating_sub(age);
+    let mut response = if etag_matches(request, etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        ([(CONTENT_TYPE, HeaderValue::from_static(content_type))], body.into()).into_response()
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(etag).expect("ETag is a quoted hex digest"),
+    );
+    headers.insert(X_CACHE, HeaderValue::from_static(status.as_str()));
+    headers.insert(AGE, HeaderValue::from(age.as_secs()));
+    headers.insert(X_CACHE_EXPIRES_IN, HeaderValue::from(remaining.as_secs()));
+    if status == CacheStatus::Stale {
+        headers.insert(
+            WARNING,
+            HeaderValue::from_static("110 - \"Response is Stale\""),
+        );
+    }
+
+    // Keyed answers mustn't be replayed by a shared cache to clients that
+    // don't hold a key.
+    let keyed = !state.config().api_keys.is_empty();
+    let scope = if keyed { "private" } else { "public" };
+    let mut cache_control = format!("{}, max-age={}", scope, remaining.as_secs());
+    if let Some(extra) = &state.config().cache_control_extra {
+        cache_control.push_str(", ");
+        cache_control.push_str(extra);
+    }
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&cache_control).expect("validated at startup"),
+    );
+    if keyed {
+        headers.append(VARY, HeaderValue::from_static("authorization, x-api-key"));
+    }
+    response
+}
+
+/// Whether `If-None-Match` lists `etag` (or `*`). Uses weak comparison, as
+/// RFC 9110 requires for this header.
+pub fn etag_matches(request: &axum::http::HeaderMap, etag: &str) -> bool {
+    let Some(value) = request.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    value.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
@@ -0,0 +1,10 @@
+pub mod admin;
+pub mod artists;
+pub mod breakdowns;
+pub mod compare;
+pub mod formats;
+pub mod graphql;
+pub mod health;
+pub mod history;
+pub mod leaderboard;
+pub mod live;
@@ -0,0 +1,361 @@
+use crate::error::{ApiError, FetchError};
+use crate::layers::is_admin;
+use crate::params::SelectedSite;
+use crate::publish::webhook_body;
+use crate::refresh::{lookup, rate_limited_for, refresh, ttl};
+use crate::respond::error_response;
+use crate::upstream::{Period, UpstreamQuery};
+use crate::{reload_config, webhook, AppState};
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::time::{Instant, SystemTime};
+use utoipa::IntoParams;
+
+/// Counters and gauges in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses(
+        (
+            status = 200,
+            description = "Prometheus text format",
+            body = String,
+            content_type = "text/plain",
+        ),
+    ),
+)]
+pub async fn metrics_handler(State(state): State<AppState>) -> Response {
+    let body = {
+        let cache = state.cache.read().await;
+        state.metrics.render(cache.usage())
+    };
+
+    (
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+/// Everything the service knows about itself, for diagnosing it: build,
+/// TTLs, every cache entry, the last upstream success and failure, the
+/// circuit breaker and background tasks. Needs the admin token when one is
+/// set. Unlike `/healthz`, it lists the whole cache, so it is not for
+/// frequent polling.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The service's internal state", body = Object),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 403, description = "Not from an allowed address", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn status(State(state): State<AppState>, headers: axum::http::HeaderMap) -> Response {
+    if state.config().admin_token.is_some() && !is_admin(&state, &headers) {
+        return ApiError::unauthorized().into_response();
+    }
+    let rfc3339 = |time: SystemTime| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339();
+
+    let (usage, mut entries) = {
+        let cache = state.cache.read().await;
+        let entries: Vec<_> = cache
+            .iter()
+            .map(|(key, entry)| {
+                let age = entry.timestamp.elapsed();
+                let ttl = ttl(&state, entry);
+                serde_json::json!({
+                    "key": key.to_string(),
+                    "kind": entry.payload.kind(),
+                    "bytes": entry.data.len(),
+                    "fetched_at": rfc3339(entry.fetched_at),
+                    "changed_at": rfc3339(entry.changed_at),
+                    "last_refresh": if entry.changed_at == entry.fetched_at {
+                        "changed"
+                    } else {
+                        "unchanged"
+                    },
+                    "content_hash": entry.content_hash,
+                    "age_secs": age.as_secs(),
+                    "ttl_secs": ttl.as_secs(),
+                    "expires_in_secs": ttl.saturating_sub(age).as_secs(),
+                })
+            })
+            .collect();
+        (cache.usage(), entries)
+    };
+    entries.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+
+    let last_success = state.last_success.lock().unwrap().map(|time| {
+        let duration = *state.last_success_duration.lock().unwrap();
+        serde_json::json!({
+            "at": rfc3339(time),
+            "duration_ms": duration.map(|duration| duration.as_millis() as u64),
+        })
+    });
+    let last_error = state.last_error.lock().unwrap().as_ref().map(|(time, e)| {
+        let status = match e {
+            FetchError::Status { status, .. } => Some(status.as_u16()),
+            _ => None,
+        };
+        serde_json::json!({
+            "at": rfc3339(*time),
+            "code": e.code(),
+            "message": e.to_string(),
+            "status": status,
+        })
+    });
+    let now = Instant::now();
+    let mut failures: Vec<serde_json::Value> = state
+        .failures
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, failure)| failure.until > now)
+        .map(|(key, failure)| {
+            serde_json::json!({
+                "key": key.to_string(),
+                "at": rfc3339(failure.at),
+                "code": failure.error.code(),
+                "message": failure.error.to_string(),
+                "status": failure.error.upstream_status(),
+                "expires_in_secs": (failure.until - now).as_secs(),
+            })
+        })
+        .collect();
+    failures.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    let tasks: serde_json::Map<String, serde_json::Value> = state
+        .tasks
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, task)| {
+            let status = if task.is_finished() { "finished" } else { "running" };
+            (name.to_string(), status.into())
+        })
+        .collect();
+    let config = state.config();
+
+    let body = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": option_env!("GIT_COMMIT"),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "ttls": {
+            "cache_ttl_secs": config.cache_ttl.as_secs(),
+            "realtime_cache_ttl_secs": config.realtime_cache_ttl.as_secs(),
+            "breakdown_cache_ttl_secs": config.breakdown_cache_ttl_secs,
+            "kind_cache_ttl_secs": config.kind_cache_ttl_secs,
+            "cache_ttl_jitter_percent": config.cache_ttl_jitter_percent,
+            "negative_cache_ttl_secs": config.negative_cache_ttl.as_secs(),
+        },
+        "cache": usage,
+        "cache_entries": entries,
+        "refreshes": state.metrics.refreshes(),
+        "inflight_fetches": state.inflight.lock().unwrap().len(),
+        "upstream_slots": {
+            "max": config.upstream_max_concurrency,
+            "available": state.upstream_slots.available_permits(),
+            "waiting": state.metrics.upstream_queued(),
+        },
+        "last_success": last_success,
+        "last_error": last_error,
+        "failing_keys": failures,
+        "upstream_circuit": state.breaker.status(),
+        "upstream_rate_limit_secs": rate_limited_for(&state).map(|wait| wait.as_secs()),
+        "tasks": tasks,
+    });
+    (
+        [(CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Reloads the configuration file and `aliases_file`, as SIGHUP does, for
+/// platforms without signals. An invalid configuration is rejected with 422
+/// and the running one kept. Settings only read at startup are listed under
+/// `restart_required` when they changed.
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Reloaded", body = Object),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 403, description = "Not from an allowed address", body = ErrorResponse),
+        (status = 422, description = "The new configuration is invalid", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn reload(State(state): State<AppState>, headers: axum::http::HeaderMap) -> Response {
+    if !is_admin(&state, &headers) {
+        return ApiError::unauthorized().into_response();
+    }
+    match reload_config(&state) {
+        Ok(restart_required) => Json(serde_json::json!({
+            "reloaded": true,
+            "restart_required": restart_required,
+        }))
+        .into_response(),
+        Err(e) => {
+            ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_config", e).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PurgeParams {
+    /// Fetch every site's all-time leaderboard again straight away.
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Drops every cached entry (and the persisted copy) and every remembered
+/// failure, optionally fetching a replacement straight away. Answers 204
+/// when there was nothing to purge.
+#[utoipa::path(
+    post,
+    path = "/cache/purge",
+    tag = "admin",
+    params(PurgeParams),
+    responses(
+        (status = 200, description = "Counts of what was purged", body = Object),
+        (status = 204, description = "Nothing was cached"),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 403, description = "Not from an allowed address", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn purge(
+    State(state): State<AppState>,
+    Query(params): Query<PurgeParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return ApiError::unauthorized().into_response();
+    }
+
+    let purged = state.cache.write().await.clear();
+    // So the refetch, and any after it, goes to the upstream.
+    state.failures.lock().unwrap().clear();
+    if let Some(path) = &state.config().cache_file {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove cache file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    let oldest = purged
+        .iter()
+        .map(|(_, entry)| entry.timestamp.elapsed().as_secs())
+        .max();
+    match oldest {
+        Some(age) => tracing::info!(
+            "Cache purged ({} entries, oldest {}s old)",
+            purged.len(),
+            age
+        ),
+        None => tracing::info!("Cache purge requested but cache was empty"),
+    }
+
+    let refreshed = if params.refresh {
+        let mut ok = true;
+        for site in state.config().sites.iter() {
+            ok &= refresh(&state, &UpstreamQuery::leaderboard(site, Period::default()))
+                .await
+                .is_ok();
+        }
+        Some(ok)
+    } else {
+        None
+    };
+
+    match oldest {
+        Some(age) => Json(serde_json::json!({
+            "purged_entries": purged.len(),
+            "purged_age_secs": age,
+            "refreshed": refreshed,
+        }))
+        .into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WebhookParams {
+    /// Must be `true`.
+    #[param(value_type = bool)]
+    test: Option<String>,
+}
+
+/// Fires a sample delivery to `webhook_url` with the current all-time
+/// leaderboard, reporting how it went. Only `?test=true` is supported.
+#[utoipa::path(
+    post,
+    path = "/webhook",
+    tag = "admin",
+    params(WebhookParams),
+    responses(
+        (status = 200, description = "Delivered", body = Delivery),
+        (status = 502, description = "The receiver failed", body = Delivery),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 403, description = "Not from an allowed address", body = ErrorResponse),
+        (status = 404, description = "No webhook is configured", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+    security(("admin_token" = [])),
+)]
+pub async fn webhook_test(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<WebhookParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return ApiError::unauthorized().into_response();
+    }
+    if params.test.as_deref() != Some("true") {
+        return ApiError::invalid_param("test", &["true"]).into_response();
+    }
+    let Some(url) = state.config().webhook_url.clone() else {
+        return ApiError::not_found("webhook_not_configured", "No webhook_url is configured")
+            .into_response();
+    };
+
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, _) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    let body = webhook_body(&state, &site, &entry, None, true);
+    let config = state.config();
+    let secret = config.webhook_secret.as_deref();
+    let delivery = webhook::deliver(&state.client, &url, secret, body).await;
+
+    let status = if delivery.delivered {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    (status, Json(delivery)).into_response()
+}
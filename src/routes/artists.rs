@@ -0,0 +1,332 @@
+use crate::cache::{CacheEntry, CacheStatus};
+use crate::error::ApiError;
+use crate::params::{FieldsParams, SelectedSite, View, ViewParams};
+use crate::refresh::lookup;
+use crate::respond::{add_movement, compared, error_response, leaderboard, render_response};
+use crate::upstream::{Period, Site, UpstreamQuery};
+use crate::{badge, finish_view, json_body, plausible, project, sparkline, AppState, JSON, SVG};
+use axum::{
+    extract::{Path, Query, State},
+    http::header::{HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+// Named path parameters, so the `:site` segment of the per-site routes is
+// ignored here and picked up by `SelectedSite` instead.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct ArtistPath {
+    /// Artist name, percent-encoded.
+    pub name: String,
+}
+
+/// Looks up a single artist in the all-time leaderboard. Matching is
+/// case-insensitive on the decoded path segment; names containing `/` must
+/// be sent percent-encoded (`AC%2FDC`).
+#[utoipa::path(
+    get,
+    path = "/artist/{name}",
+    tag = "artists",
+    params(ArtistPath, ViewParams, FieldsParams),
+    responses(
+        (status = 200, description = "The artist's row, with its `rank`", body = ArtistRow),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 404, description = "No such artist", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn artist(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(ArtistPath { name }): Path<ArtistPath>,
+    Query(view): Query<ViewParams>,
+    Query(fields): Query<FieldsParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (view, fields) = match (view.view(&state, &headers), fields.fields()) {
+        (Ok(view), Ok(fields)) => (view, fields),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let wanted = plausible::normalize_name(&name).to_lowercase();
+    let mut board = leaderboard(&state, &entry, view);
+    let compared_to = add_movement(&state, &query, view, 0, &mut board);
+    let found = board
+        .results
+        .iter()
+        .enumerate()
+        .find(|(_, row)| plausible::normalize_name(&row.name).to_lowercase() == wanted);
+
+    match found {
+        Some((index, row)) => {
+            let mut body = serde_json::to_value(row).expect("ArtistRow serializes");
+            body["rank"] = (index + 1).into();
+            if let Some(fields) = &fields {
+                project(&mut body, fields);
+            }
+            let body = json_body(view, &body);
+            let response = render_response(&state, &entry, status, &headers, body, JSON);
+            finish_view(view, compared(response, compared_to))
+        }
+        None => ApiError::not_found("artist_not_found", "Artist not found").into_response(),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TimeseriesParams {
+    /// `day`, `7d` or `30d`; defaults to `30d`.
+    #[param(value_type = Option<Period>)]
+    period: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArtistTimeseries<'a> {
+    artist: &'a str,
+    #[schema(value_type = Period)]
+    period: &'static str,
+    points: Vec<TimeseriesPoint<'a>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TimeseriesPoint<'a> {
+    /// `YYYY-MM-DD`.
+    date: &'a str,
+    visitors: u64,
+}
+
+/// Daily visitors for one artist. Only artists on the cached all-time
+/// leaderboard can be queried, so arbitrary names can't be passed through
+/// to upstream; the query covers every spelling merged into the artist's
+/// row.
+#[utoipa::path(
+    get,
+    path = "/artist/{name}/timeseries",
+    tag = "artists",
+    params(ArtistPath, TimeseriesParams),
+    responses(
+        (status = 200, description = "Visitors per day", body = ArtistTimeseries),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 404, description = "No such artist", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn timeseries(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(ArtistPath { name }): Path<ArtistPath>,
+    Query(params): Query<TimeseriesParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let period = match params.period.as_deref().map(str::parse) {
+        None => Period::ThirtyDays,
+        Some(Ok(period)) => period,
+        Some(Err(())) => {
+            return ApiError::invalid_param("period", &Period::accepted()).into_response()
+        }
+    };
+
+    let (artist, entry, status) = match artist_series(&state, &site, &name, period).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let series = entry
+        .payload
+        .timeseries()
+        .expect("timeseries queries cache timeseries payloads");
+    let points: Vec<TimeseriesPoint> = series
+        .labels
+        .iter()
+        .zip(&series.plot)
+        .filter_map(|(date, visitors)| Some(TimeseriesPoint { date, visitors: (*visitors)? }))
+        .collect();
+
+    let body = ArtistTimeseries {
+        artist: &artist,
+        period: period.as_str(),
+        points,
+    };
+    let body = serde_json::to_string(&body).expect("ArtistTimeseries serializes");
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SparklineParams {
+    /// `day`, `7d` or `30d`; defaults to `30d`.
+    #[param(value_type = Option<Period>)]
+    period: Option<String>,
+    /// Width in pixels, at most 1000. Defaults to 120.
+    #[param(value_type = Option<u32>, minimum = 1)]
+    w: Option<String>,
+    /// Height in pixels, at most 200. Defaults to 24.
+    #[param(value_type = Option<u32>, minimum = 1)]
+    h: Option<String>,
+    /// A shields.io color name, or a hex color, for the line.
+    color: Option<String>,
+    /// `interpolate`, the default, to bridge days without data, or `break`
+    /// to leave a gap.
+    gaps: Option<String>,
+}
+
+/// Largest `w` and `h` a sparkline is drawn at; larger ones are capped.
+const SPARKLINE_MAX_SIZE: (u32, u32) = (1000, 200);
+
+/// A tiny line chart of one artist's daily visitors, for inline use. Its
+/// validators and freshness are those of the timeseries behind it. So that
+/// embeds never show a broken image, an unknown artist gets a 1×1
+/// transparent image with a 404, and an unreachable upstream one with the
+/// error's status.
+#[utoipa::path(
+    get,
+    path = "/artist/{name}/sparkline.svg",
+    tag = "artists",
+    params(ArtistPath, SparklineParams),
+    responses(
+        (status = 200, description = "SVG line", body = String, content_type = "image/svg+xml"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (
+            status = 404,
+            description = "No such artist: a 1×1 transparent image",
+            body = String,
+            content_type = "image/svg+xml",
+        ),
+        (
+            status = 502,
+            description = "Upstream failed, nothing cached: a 1×1 transparent image",
+            body = String,
+            content_type = "image/svg+xml",
+        ),
+    ),
+)]
+pub async fn sparkline_handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(ArtistPath { name }): Path<ArtistPath>,
+    Query(params): Query<SparklineParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let period = match params.period.as_deref().map(str::parse) {
+        None => Period::ThirtyDays,
+        Some(Ok(period)) => period,
+        Some(Err(())) => {
+            return ApiError::invalid_param("period", &Period::accepted()).into_response()
+        }
+    };
+    let size = |value: Option<&str>, default: u32, max: u32, name: &str| match value {
+        None => Ok(default),
+        Some(value) => match value.parse::<u64>() {
+            Ok(size) if size > 0 => Ok(size.min(u64::from(max)) as u32),
+            _ => Err(ApiError::invalid_param(name, &["a positive integer"])),
+        },
+    };
+    let (max_width, max_height) = SPARKLINE_MAX_SIZE;
+    let (width, height) = match (
+        size(params.w.as_deref(), 120, max_width, "w"),
+        size(params.h.as_deref(), 24, max_height, "h"),
+    ) {
+        (Ok(width), Ok(height)) => (width, height),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+    let color = match params.color.as_deref().map(badge::color) {
+        None => badge::DEFAULT_COLOR.to_string(),
+        Some(Some(color)) => color,
+        Some(None) => {
+            return ApiError::invalid_param("color", &badge::accepted_colors()).into_response()
+        }
+    };
+    let interpolate = match params.gaps.as_deref() {
+        None | Some("interpolate") => true,
+        Some("break") => false,
+        Some(_) => {
+            return ApiError::invalid_param("gaps", &["interpolate", "break"]).into_response();
+        }
+    };
+
+    let (_, entry, status) = match artist_series(&state, &site, &name, period).await {
+        Ok(found) => found,
+        Err(response) => {
+            // The error's status and headers, with an image in place of its
+            // body.
+            let (mut parts, _) = response.into_parts();
+            parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static(SVG));
+            parts.headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            parts.headers.remove(CONTENT_LENGTH);
+            return Response::from_parts(parts, sparkline::blank().into());
+        }
+    };
+    let series = entry
+        .payload
+        .timeseries()
+        .expect("timeseries queries cache timeseries payloads");
+    let body = sparkline::svg(&series.plot, width, height, &color, interpolate);
+    render_response(&state, &entry, status, &headers, body, SVG)
+}
+
+/// `name`'s daily visitors over `period`, as the cache entry holding them,
+/// with the name as the leaderboard spells it. Only artists on the cached
+/// all-time leaderboard are looked up.
+async fn artist_series(
+    state: &AppState,
+    site: &Site,
+    name: &str,
+    period: Period,
+) -> Result<(String, Arc<CacheEntry>, CacheStatus), Response> {
+    let query = UpstreamQuery::leaderboard(site, Period::default());
+    let (entry, _) = lookup(state, &query).await.map_err(error_response)?;
+
+    let (artist, spellings) = {
+        let aliases = state.aliases.read().unwrap();
+        let merge_key = |name: &str| {
+            let name = plausible::normalize_name(name);
+            aliases.canonical(&name).unwrap_or(&name).to_lowercase()
+        };
+        let wanted = merge_key(name);
+        let exclusions = &state.config().exclusions;
+        let rows = &entry
+            .payload
+            .leaderboard()
+            .expect("leaderboard queries cache leaderboard payloads")
+            .results;
+
+        let mut spellings: Vec<String> = rows
+            .iter()
+            .filter(|row| !exclusions.matches(&row.name) && merge_key(&row.name) == wanted)
+            .map(|row| row.name.clone())
+            .collect();
+        spellings.sort_unstable();
+        spellings.dedup();
+        let view = View::PUBLIC;
+        let artist = leaderboard(state, &entry, view)
+            .results
+            .iter()
+            .find(|row| merge_key(&row.name) == wanted)
+            .map(|row| row.name.clone());
+        (artist, spellings)
+    };
+    let Some(artist) = artist.filter(|_| !spellings.is_empty()) else {
+        return Err(ApiError::not_found("artist_not_found", "Artist not found").into_response());
+    };
+
+    let query = UpstreamQuery::timeseries(site, period, &spellings);
+    let (entry, status) = lookup(state, &query).await.map_err(error_response)?;
+    Ok((artist, entry, status))
+}
@@ -0,0 +1,442 @@
+use crate::cache::{CacheEntry, CacheStatus};
+use crate::error::ApiError;
+use crate::params::{LeaderboardParams, SelectedSite};
+use crate::plausible::BreakdownRow;
+use crate::refresh::lookup;
+use crate::respond::{error_response, render_response};
+use crate::upstream::{Breakdown, Site, UpstreamQuery};
+use crate::{countries, sources, AppState, JSON};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+
+/// One row of a breakdown, the same on every `/breakdown/*` route.
+#[derive(Serialize, ToSchema)]
+pub struct BreakdownItem<'a> {
+    /// The property's value: an ISO 3166-1 alpha-2 code, a path, a
+    /// referrer source, a device type or a browser family.
+    value: &'a str,
+    /// English display name for a country's code, otherwise `value`.
+    name: &'a str,
+    visitors: u64,
+    events: u64,
+    /// Fraction of the visitors across every row, those cut by `limit`
+    /// included.
+    share: f64,
+}
+
+impl<'a> BreakdownItem<'a> {
+    pub fn new(value: &'a str, visitors: u64, events: u64) -> Self {
+        BreakdownItem {
+            value,
+            name: value,
+            visitors,
+            events,
+            share: 0.0,
+        }
+    }
+}
+
+/// Visitors converting on the goal by country, most first.
+#[utoipa::path(
+    get,
+    path = "/breakdown/country",
+    tag = "breakdowns",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "Visitors per country", body = [BreakdownItem]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn country_breakdown(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (entry, status, limit) =
+        match lookup_breakdown(&state, &site, &params, Breakdown::Country).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+    let body = breakdown_body(&state, country_rows(&entry), None, limit);
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+/// The rows of a cached country breakdown, named after their countries.
+pub fn country_rows(entry: &CacheEntry) -> Vec<BreakdownItem<'_>> {
+    breakdown_rows(entry)
+        .into_iter()
+        .map(|row| BreakdownItem {
+            name: countries::name(&row.value).unwrap_or(&row.value),
+            ..BreakdownItem::new(&row.value, row.visitors, row.events)
+        })
+        .collect()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    /// `prefix` rolls pages up by the first segment of their path, so
+    /// `/artist/x/album/1` counts towards `/artist`.
+    group: Option<String>,
+}
+
+/// Visitors converting on the goal by the page they converted on, most
+/// first. Grouped rows sum their pages, so a visitor converting on two
+/// pages of a group counts twice.
+#[utoipa::path(
+    get,
+    path = "/breakdown/page",
+    tag = "breakdowns",
+    params(LeaderboardParams, PageParams),
+    responses(
+        (status = 200, description = "Visitors per page", body = [BreakdownItem]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn page_breakdown(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(pages): Query<PageParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let grouped = match pages.group.as_deref() {
+        None => false,
+        Some("prefix") => true,
+        Some(_) => return ApiError::invalid_param("group", &["prefix"]).into_response(),
+    };
+    let (entry, status, limit) =
+        match lookup_breakdown(&state, &site, &params, Breakdown::Page).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+    let rows = breakdown_rows(&entry);
+    let rows = sum_by(&rows, |row| {
+        if grouped {
+            first_segment(&row.value)
+        } else {
+            &row.value
+        }
+    })
+    .into_iter()
+    .map(|(path, visitors, events)| BreakdownItem::new(path, visitors, events))
+    .collect();
+    let body = breakdown_body(&state, rows, None, limit);
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+/// `?merge_social=true` folds the aliases of a social network, such as
+/// `t.co` and `x.com`, into one row named after it.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SourceParams {
+    /// Fold known aliases, and those in `source_aliases`, into one row.
+    #[param(value_type = Option<bool>)]
+    merge_social: Option<String>,
+}
+
+/// Visitors converting on the goal by where they came from, most first.
+/// Sources below `source_min_visitors` are summed into one `Other` row,
+/// served last. Merged and `Other` rows sum their sources, so a visitor
+/// arriving from two of them counts twice.
+#[utoipa::path(
+    get,
+    path = "/breakdown/source",
+    tag = "breakdowns",
+    params(LeaderboardParams, SourceParams),
+    responses(
+        (status = 200, description = "Visitors per source", body = [BreakdownItem]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn source_breakdown(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(social): Query<SourceParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let merge = match social.merge_social.as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => {
+            return ApiError::invalid_param("merge_social", &["true", "false"]).into_response()
+        }
+    };
+    let (entry, status, limit) =
+        match lookup_breakdown(&state, &site, &params, Breakdown::Source).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+    let aliases = &state.config().source_aliases;
+    let rows = breakdown_rows(&entry);
+    let merged = sum_by(&rows, |row| {
+        if !merge {
+            return row.value.as_str();
+        }
+        match aliases.get(&row.value.to_ascii_lowercase()) {
+            Some(bucket) => bucket,
+            None => sources::social(&row.value).unwrap_or(&row.value),
+        }
+    });
+
+    let threshold = state.config().source_min_visitors;
+    let (kept, small): (Vec<_>, Vec<_>) =
+        merged.into_iter().partition(|(_, visitors, _)| *visitors >= threshold);
+    let rows = kept
+        .into_iter()
+        .map(|(source, visitors, events)| BreakdownItem::new(source, visitors, events))
+        .collect();
+    let other = (!small.is_empty()).then(|| {
+        BreakdownItem::new(
+            "Other",
+            small.iter().map(|(_, visitors, _)| visitors).sum(),
+            small.iter().map(|(_, _, events)| events).sum(),
+        )
+    });
+    let body = breakdown_body(&state, rows, other, limit);
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+/// Visitors converting on the goal by device type (`Desktop`, `Mobile`,
+/// `Tablet`), most first.
+#[utoipa::path(
+    get,
+    path = "/breakdown/device",
+    tag = "breakdowns",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "Visitors per device type", body = [BreakdownItem]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn device_breakdown(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    plain_breakdown(&state, &site, &params, &headers, Breakdown::Device).await
+}
+
+/// Visitors converting on the goal by browser family, most first.
+#[utoipa::path(
+    get,
+    path = "/breakdown/browser",
+    tag = "breakdowns",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "Visitors per browser", body = [BreakdownItem]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn browser_breakdown(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    plain_breakdown(&state, &site, &params, &headers, Breakdown::Browser).await
+}
+
+/// A breakdown served row for row as the upstream sent it.
+async fn plain_breakdown(
+    state: &AppState,
+    site: &Site,
+    params: &LeaderboardParams,
+    headers: &axum::http::HeaderMap,
+    breakdown: Breakdown,
+) -> Response {
+    let (entry, status, limit) = match lookup_breakdown(state, site, params, breakdown).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let rows = breakdown_rows(&entry)
+        .into_iter()
+        .map(|row| BreakdownItem::new(&row.value, row.visitors, row.events))
+        .collect();
+    let body = breakdown_body(state, rows, None, limit);
+    render_response(state, &entry, status, headers, body, JSON)
+}
+
+/// Checks `params` and looks up `breakdown` of `site` for the period they
+/// ask for, also returning the row limit they set.
+async fn lookup_breakdown(
+    state: &AppState,
+    site: &Site,
+    params: &LeaderboardParams,
+    breakdown: Breakdown,
+) -> Result<(Arc<CacheEntry>, CacheStatus, Option<usize>), Response> {
+    let (period, limit) = match (params.period(), params.limit()) {
+        (Ok(period), Ok(limit)) => (period, limit),
+        (Err(e), _) | (_, Err(e)) => return Err(e.into_response()),
+    };
+    let query = UpstreamQuery::breakdown(site, period, breakdown);
+    match lookup(state, &query).await {
+        Ok((entry, status)) => Ok((entry, status, limit)),
+        Err(e) => Err(error_response(e)),
+    }
+}
+
+/// `rows`, in the order given, cut to `limit` and followed by `other`,
+/// serialized with each row's share of the visitors across all of them.
+fn breakdown_body<'a>(
+    state: &AppState,
+    rows: Vec<BreakdownItem<'a>>,
+    other: Option<BreakdownItem<'a>>,
+    limit: Option<usize>,
+) -> String {
+    let rows = breakdown_items(state, rows, other, limit);
+    serde_json::to_string(&rows).expect("BreakdownItem serializes")
+}
+
+/// `rows` as [`breakdown_body`] serializes them.
+pub fn breakdown_items<'a>(
+    state: &AppState,
+    mut rows: Vec<BreakdownItem<'a>>,
+    other: Option<BreakdownItem<'a>>,
+    limit: Option<usize>,
+) -> Vec<BreakdownItem<'a>> {
+    let total: u64 = rows.iter().chain(&other).map(|row| row.visitors).sum();
+    rows.truncate(limit.unwrap_or(usize::MAX));
+    rows.extend(other);
+
+    let scale = 10f64.powi(state.config().share_decimals as i32);
+    for row in &mut rows {
+        let share = if total == 0 {
+            0.0
+        } else {
+            row.visitors as f64 / total as f64
+        };
+        row.share = (share * scale).round() / scale;
+    }
+    rows
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Realtime {
+    /// Visitors on the site in the last five minutes, goal or not.
+    pub current_visitors: u64,
+    /// Whether this is the last count known rather than a current one,
+    /// because the realtime API failed or is being asked again.
+    pub stale: bool,
+}
+
+/// How many people are on the site right now, cached for
+/// `realtime_cache_ttl_secs` so polls within that window share one
+/// upstream request.
+#[utoipa::path(
+    get,
+    path = "/realtime",
+    tag = "realtime",
+    responses(
+        (status = 200, description = "Current visitors", body = Realtime),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 501, description = "Not available from the backend", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn realtime(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (entry, status) = match lookup(&state, &UpstreamQuery::realtime(&site)).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let realtime = Realtime {
+        current_visitors: entry.payload.realtime().expect("realtime queries cache counts"),
+        stale: status == CacheStatus::Stale,
+    };
+    let body = serde_json::to_string(&realtime).expect("Realtime serializes");
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+/// The rows of a cached breakdown, most visitors first.
+pub fn breakdown_rows(entry: &CacheEntry) -> Vec<&BreakdownRow> {
+    let mut rows: Vec<&BreakdownRow> = entry
+        .payload
+        .breakdown()
+        .expect("breakdown queries cache breakdown payloads")
+        .results
+        .iter()
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.visitors));
+    rows
+}
+
+/// The visitors and events of `rows` summed by the name `key` gives each,
+/// most visitors first.
+fn sum_by<'a>(
+    rows: &[&'a BreakdownRow],
+    key: impl Fn(&'a BreakdownRow) -> &'a str,
+) -> Vec<(&'a str, u64, u64)> {
+    let mut sums: Vec<(&str, u64, u64)> = Vec::new();
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for &row in rows {
+        let name = key(row);
+        match index.get(name) {
+            Some(&i) => {
+                sums[i].1 += row.visitors;
+                sums[i].2 += row.events;
+            }
+            None => {
+                index.insert(name, sums.len());
+                sums.push((name, row.visitors, row.events));
+            }
+        }
+    }
+    sums.sort_by_key(|(_, visitors, _)| std::cmp::Reverse(*visitors));
+    sums
+}
+
+/// `/artist` for `/artist/x/album/1`; a path with one segment or none is
+/// its own first segment.
+fn first_segment(path: &str) -> &str {
+    let end = path
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| *c == '/')
+        .map_or(path.len(), |(i, _)| i);
+    &path[..end]
+}
@@ -0,0 +1,259 @@
+use crate::error::ApiError;
+use crate::params::{LeaderboardParams, SelectedSite, View};
+use crate::refresh::lookup;
+use crate::respond::{error_response, leaderboard, render_response};
+use crate::upstream::{Period, UpstreamQuery};
+use crate::{AppState, JSON};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct Trending<'a> {
+    #[schema(value_type = Period)]
+    period: &'static str,
+    current: DateRange,
+    previous: DateRange,
+    results: Vec<TrendingRow<'a>>,
+}
+
+/// Inclusive, as `YYYY-MM-DD` dates.
+#[derive(Serialize, ToSchema)]
+pub struct DateRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrendingRow<'a> {
+    name: &'a str,
+    visitors: u64,
+    previous_visitors: u64,
+    growth: i64,
+    /// Growth relative to the previous period, as a percentage. `None` for
+    /// new artists, which have nothing to grow from.
+    growth_pct: Option<f64>,
+    /// The artist had no visitors in the previous period.
+    new: bool,
+}
+
+/// Artists ranked by how much they grew over `period` compared with the
+/// equally long period just before it. Only periods of a fixed number of
+/// days can be compared; both are fetched as explicit date ranges so they
+/// line up exactly.
+#[utoipa::path(
+    get,
+    path = "/trending",
+    tag = "leaderboard",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "Artists by growth", body = Trending),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn trending(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let fixed: Vec<_> = Period::VALUES
+        .into_iter()
+        .filter(|period| period.days().is_some())
+        .map(Period::as_str)
+        .collect();
+    let (period, days, limit) = match (params.period_or(Period::SevenDays), params.limit()) {
+        (Ok(period), Ok(limit)) => match period.days() {
+            Some(days) => (period, days, limit),
+            None => return ApiError::invalid_param("period", &fixed).into_response(),
+        },
+        (Err(_), _) => return ApiError::invalid_param("period", &fixed).into_response(),
+        (_, Err(e)) => return e.into_response(),
+    };
+
+    let today = state.config().today();
+    let span = chrono::Days::new(u64::from(days));
+    let from = today - chrono::Days::new(u64::from(days) - 1);
+    let (previous_from, previous_to) = (from - span, today - span);
+    let current_query = UpstreamQuery::leaderboard_between(&site, from, today);
+    let previous_query = UpstreamQuery::leaderboard_between(&site, previous_from, previous_to);
+
+    let (current, previous) =
+        tokio::join!(lookup(&state, &current_query), lookup(&state, &previous_query));
+    let ((entry, status), (previous, _)) = match (current, previous) {
+        (Ok(current), Ok(previous)) => (current, previous),
+        (Err(e), _) | (_, Err(e)) => return error_response(e),
+    };
+
+    let view = View::PUBLIC;
+    let board = leaderboard(&state, &entry, view);
+    let before: HashMap<String, u64> = leaderboard(&state, &previous, view)
+        .results
+        .iter()
+        .map(|row| (row.name.to_lowercase(), row.visitors))
+        .collect();
+
+    let mut rows: Vec<TrendingRow> = board
+        .results
+        .iter()
+        .map(|row| {
+            let previous_visitors = before.get(&row.name.to_lowercase()).copied().unwrap_or(0);
+            let growth = row.visitors as i64 - previous_visitors as i64;
+            TrendingRow {
+                name: &row.name,
+                visitors: row.visitors,
+                previous_visitors,
+                growth,
+                growth_pct: (previous_visitors > 0).then(|| {
+                    (growth as f64 * 100.0 / previous_visitors as f64 * 10.0).round() / 10.0
+                }),
+                new: previous_visitors == 0,
+            }
+        })
+        .collect();
+    rows.sort_by_key(|row| (std::cmp::Reverse(row.growth), std::cmp::Reverse(row.visitors)));
+    rows.truncate(limit.unwrap_or(usize::MAX));
+
+    let range = |from: chrono::NaiveDate, to: chrono::NaiveDate| DateRange {
+        from: from.format("%Y-%m-%d").to_string(),
+        to: to.format("%Y-%m-%d").to_string(),
+    };
+    let body = Trending {
+        period: period.as_str(),
+        current: range(from, today),
+        previous: range(previous_from, previous_to),
+        results: rows,
+    };
+    let body = serde_json::to_string(&body).expect("Trending serializes");
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Comparison<'a> {
+    #[schema(value_type = Period)]
+    period: &'static str,
+    current: DateRange,
+    previous: DateRange,
+    results: Vec<ComparisonRow<'a>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ComparisonRow<'a> {
+    name: &'a str,
+    /// Visitors in the current period; zero for artists only seen before.
+    current: u64,
+    /// Visitors in the previous period; zero for new artists.
+    previous: u64,
+    delta: i64,
+    /// `delta` relative to `previous`, as a percentage. `null` for new
+    /// artists, which have nothing to change from.
+    percent_change: Option<f64>,
+}
+
+/// Visitors per artist over `period` next to the same period one period
+/// earlier, by current visitors and then previous ones. Artists seen in
+/// only one of them are included, with zero visitors in the other. The
+/// current half is the same query a plain leaderboard request for
+/// `period` makes, so the two share a cache entry.
+#[utoipa::path(
+    get,
+    path = "/compare",
+    tag = "leaderboard",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "Both periods per artist", body = Comparison),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn compare(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let ranged: Vec<_> = Period::VALUES
+        .into_iter()
+        .filter(|period| *period != Period::All)
+        .map(Period::as_str)
+        .collect();
+    let (period, limit) = match (params.period_or(Period::Month), params.limit()) {
+        (Ok(Period::All), _) | (Err(_), _) => {
+            return ApiError::invalid_param("period", &ranged).into_response()
+        }
+        (Ok(period), Ok(limit)) => (period, limit),
+        (_, Err(e)) => return e.into_response(),
+    };
+
+    let today = state.config().today();
+    let (from, to) = period.range(today).expect("every period but `all` has a range");
+    let (previous_from, previous_to) = period.previous_range(today).expect("and a previous one");
+    let current_query = UpstreamQuery::leaderboard(&site, period);
+    let previous_query = UpstreamQuery::leaderboard_between(&site, previous_from, previous_to);
+
+    let (current, previous) =
+        tokio::join!(lookup(&state, &current_query), lookup(&state, &previous_query));
+    let ((entry, status), (previous, _)) = match (current, previous) {
+        (Ok(current), Ok(previous)) => (current, previous),
+        (Err(e), _) | (_, Err(e)) => return error_response(e),
+    };
+
+    let view = View::PUBLIC;
+    let board = leaderboard(&state, &entry, view);
+    let before = leaderboard(&state, &previous, view);
+    let mut rows: Vec<ComparisonRow> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for row in &board.results {
+        index.insert(row.name.to_lowercase(), rows.len());
+        rows.push(ComparisonRow {
+            name: &row.name,
+            current: row.visitors,
+            previous: 0,
+            delta: 0,
+            percent_change: None,
+        });
+    }
+    for row in &before.results {
+        match index.get(&row.name.to_lowercase()) {
+            Some(&i) => rows[i].previous += row.visitors,
+            None => rows.push(ComparisonRow {
+                name: &row.name,
+                current: 0,
+                previous: row.visitors,
+                delta: 0,
+                percent_change: None,
+            }),
+        }
+    }
+    for row in &mut rows {
+        row.delta = row.current as i64 - row.previous as i64;
+        row.percent_change = (row.previous > 0)
+            .then(|| (row.delta as f64 * 100.0 / row.previous as f64 * 10.0).round() / 10.0);
+    }
+    rows.sort_by_key(|row| (std::cmp::Reverse(row.current), std::cmp::Reverse(row.previous)));
+    rows.truncate(limit.unwrap_or(usize::MAX));
+
+    let range = |from: chrono::NaiveDate, to: chrono::NaiveDate| DateRange {
+        from: from.format("%Y-%m-%d").to_string(),
+        to: to.format("%Y-%m-%d").to_string(),
+    };
+    let body = Comparison {
+        period: period.as_str(),
+        current: range(from, to),
+        previous: range(previous_from, previous_to),
+        results: rows,
+    };
+    let body = serde_json::to_string(&body).expect("Comparison serializes");
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
@@ -0,0 +1,482 @@
+use crate::error::ApiError;
+use crate::params::{default_site, LeaderboardParams, RangeParams, SelectedSite, View};
+use crate::publish::add_to_feed;
+use crate::refresh::lookup;
+use crate::respond::{error_response, etag_matches, leaderboard, render_response};
+use crate::routes::artists::ArtistPath;
+use crate::upstream::{Period, Site, UpstreamQuery};
+use crate::{
+    badge, cache, html, plausible, widget, AppState, ATOM, HTML, JAVASCRIPT, JSON, SVG, X_CACHE,
+};
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{
+        header::{
+            HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH,
+            X_CONTENT_TYPE_OPTIONS,
+        },
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct BadgePath {
+    /// Artist name followed by `.svg`, or `total.svg`.
+    file: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BadgeParams {
+    /// A shields.io color name, or a hex color.
+    color: Option<String>,
+    /// Show the exact count instead of an abbreviation like `12.3k`.
+    #[param(value_type = Option<bool>)]
+    exact: Option<String>,
+}
+
+/// An embeddable SVG badge with an artist's all-time visitors. So that
+/// embeds never show a broken image, an unknown artist gets a grey "not
+/// found" badge and an unreachable upstream a grey "unavailable" one, both
+/// with a 200.
+#[utoipa::path(
+    get,
+    path = "/badge/{file}",
+    tag = "badges",
+    params(BadgePath, BadgeParams),
+    responses(
+        (status = 200, description = "SVG badge", body = String, content_type = "image/svg+xml"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 404, description = "The file doesn't end in `.svg`", body = ErrorResponse),
+    ),
+)]
+pub async fn badge_handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(BadgePath { file }): Path<BadgePath>,
+    Query(params): Query<BadgeParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let Some(name) = file.strip_suffix(".svg") else {
+        return ApiError::not_found("not_found", "Badges end in .svg").into_response();
+    };
+    let color = match params.color.as_deref().map(badge::color) {
+        None => badge::DEFAULT_COLOR.to_string(),
+        Some(Some(color)) => color,
+        Some(None) => {
+            return ApiError::invalid_param("color", &badge::accepted_colors()).into_response()
+        }
+    };
+    let exact = match params.exact.as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return ApiError::invalid_param("exact", &["true", "false"]).into_response(),
+    };
+
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::warn!("Serving unavailable badge: {}", e);
+            let mut response = (
+                [(CONTENT_TYPE, SVG)],
+                badge::svg(name, "unavailable", badge::NOT_FOUND_COLOR),
+            )
+                .into_response();
+            let headers = response.headers_mut();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            headers.insert(X_CACHE, HeaderValue::from_static("ERROR"));
+            return response;
+        }
+    };
+
+    let wanted = plausible::normalize_name(name).to_lowercase();
+    let view = View::PUBLIC;
+    let board = leaderboard(&state, &entry, view);
+    let found = board
+        .results
+        .iter()
+        .find(|row| plausible::normalize_name(&row.name).to_lowercase() == wanted);
+
+    let body = match found {
+        Some(row) => {
+            let value = if exact {
+                badge::exact(row.visitors)
+            } else {
+                badge::human(row.visitors)
+            };
+            badge::svg(&row.name, &value, &color)
+        }
+        None => badge::svg(name, "not found", badge::NOT_FOUND_COLOR),
+    };
+    render_response(&state, &entry, status, &headers, body, SVG)
+}
+
+/// Body of a shields.io endpoint badge.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Shield<'a> {
+    schema_version: u8,
+    label: &'a str,
+    message: String,
+    color: &'static str,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    is_error: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_seconds: Option<u64>,
+}
+
+/// shields.io endpoint badge with an artist's all-time visitors.
+#[utoipa::path(
+    get,
+    path = "/shields/{name}",
+    tag = "badges",
+    params(ArtistPath),
+    responses(
+        (status = 200, description = "shields.io endpoint badge", body = Shield),
+        (status = 304, description = "Matches `If-None-Match`"),
+    ),
+)]
+pub async fn shields_artist(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(ArtistPath { name }): Path<ArtistPath>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    shields(&state, &site, &headers, Some(&name)).await
+}
+
+/// shields.io endpoint badge with the all-time visitors across all artists.
+/// An artist named "total" is shadowed by this route.
+#[utoipa::path(
+    get,
+    path = "/shields/total",
+    tag = "badges",
+    responses(
+        (status = 200, description = "shields.io endpoint badge", body = Shield),
+        (status = 304, description = "Matches `If-None-Match`"),
+    ),
+)]
+pub async fn shields_total(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    shields(&state, &site, &headers, None).await
+}
+
+/// Renders the shields.io endpoint schema for `artist`, or for the total
+/// when `None`. Like the SVG badges, failures still answer 200 with a badge
+/// describing them, and the payload can be fetched from any origin.
+async fn shields(
+    state: &AppState,
+    site: &Site,
+    headers: &axum::http::HeaderMap,
+    artist: Option<&str>,
+) -> Response {
+    let label = "artist clicks";
+    let query = UpstreamQuery::leaderboard(site, Period::default());
+    let mut response = match lookup(state, &query).await {
+        Ok((entry, status)) => {
+            let view = View::PUBLIC;
+            let board = leaderboard(state, &entry, view);
+            let visitors = match artist {
+                None => Some(board.results.iter().map(|row| row.visitors).sum()),
+                Some(name) => {
+                    let wanted = plausible::normalize_name(name).to_lowercase();
+                    board
+                        .results
+                        .iter()
+                        .find(|row| plausible::normalize_name(&row.name).to_lowercase() == wanted)
+                        .map(|row| row.visitors)
+                }
+            };
+            let remaining = state.config().cache_ttl.saturating_sub(entry.timestamp.elapsed());
+            let shield = Shield {
+                schema_version: 1,
+                label,
+                message: visitors.map_or_else(|| "not found".to_string(), badge::human),
+                color: if visitors.is_some() { "blue" } else { "lightgrey" },
+                is_error: false,
+                cache_seconds: Some(remaining.as_secs()),
+            };
+            let body = serde_json::to_string(&shield).expect("Shield serializes");
+            render_response(state, &entry, status, headers, body, JSON)
+        }
+        Err(e) => {
+            tracing::warn!("Serving unavailable shield: {}", e);
+            let shield = Shield {
+                schema_version: 1,
+                label,
+                message: "unavailable".to_string(),
+                color: "lightgrey",
+                is_error: true,
+                cache_seconds: None,
+            };
+            let mut response = Json(shield).into_response();
+            response
+                .headers_mut()
+                .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            response.headers_mut().insert(X_CACHE, HeaderValue::from_static("ERROR"));
+            response
+        }
+    };
+    response.headers_mut().insert(
+        axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    response
+}
+
+/// The leaderboard as a human-readable HTML page.
+#[utoipa::path(
+    get,
+    path = "/leaderboard",
+    tag = "leaderboard",
+    params(LeaderboardParams),
+    responses(
+        (status = 200, description = "HTML page", body = String, content_type = "text/html"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (
+            status = 503,
+            description = "No data could be fetched",
+            body = String,
+            content_type = "text/html",
+        ),
+    ),
+)]
+pub async fn leaderboard_page(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(range): Query<RangeParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let ((query, period), limit) =
+        match (range.leaderboard(&state, &site, &params), params.limit()) {
+            (Ok(query), Ok(limit)) => (query, limit),
+            (Err(e), _) | (_, Err(e)) => return e.into_response(),
+        };
+
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::warn!("Serving unavailable page: {}", e);
+            let mut response = (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(CONTENT_TYPE, HTML)],
+                html::unavailable(),
+            )
+                .into_response();
+            let headers = response.headers_mut();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            headers.insert(X_CACHE, HeaderValue::from_static("ERROR"));
+            return response;
+        }
+    };
+
+    let view = View::PUBLIC;
+    let board = leaderboard(&state, &entry, view);
+    let rows = &board.results;
+    let rows = &rows[..limit.unwrap_or(rows.len()).min(rows.len())];
+    let generated_at = chrono::DateTime::<chrono::Utc>::from(entry.fetched_at)
+        .format("%Y-%m-%d %H:%M UTC")
+        .to_string();
+    let body = html::leaderboard(period, rows, &generated_at);
+    render_response(&state, &entry, status, &headers, body, HTML)
+}
+
+/// Artists the widget lists unless its tag sets `data-limit`.
+const WIDGET_LIMIT: usize = 10;
+/// How long `/widget.js` may be kept before it is revalidated.
+const WIDGET_MAX_AGE: &str = "public, max-age=86400";
+
+/// A self-contained script that renders the top artists into the element
+/// its tag's `data-target` selects, `#artistgrid-stats` by default, as
+/// fetched from `/top/{n}` here. Where to fetch from and the defaults are
+/// filled in when it is served.
+#[utoipa::path(
+    get,
+    path = "/widget.js",
+    tag = "badges",
+    responses(
+        (
+            status = 200,
+            description = "The widget script",
+            body = String,
+            content_type = "application/javascript",
+        ),
+        (status = 304, description = "Matches `If-None-Match`"),
+    ),
+)]
+pub async fn widget_script(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let max_limit = state.config().top_max;
+    let base = widget_base(&state, &site, &headers);
+    let body = widget::script(&base, WIDGET_LIMIT.min(max_limit), max_limit);
+    let etag = cache::etag(&body);
+    let mut response = if etag_matches(&headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        ([(CONTENT_TYPE, HeaderValue::from_static(JAVASCRIPT))], body).into_response()
+    };
+    let headers = response.headers_mut();
+    headers.insert(ETAG, HeaderValue::from_str(&etag).expect("ETag is a quoted hex digest"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(WIDGET_MAX_AGE));
+    response
+}
+
+/// A page showing `/widget.js` live, with the tag to embed it.
+#[utoipa::path(
+    get,
+    path = "/widget/preview",
+    tag = "badges",
+    responses(
+        (status = 200, description = "HTML page", body = String, content_type = "text/html"),
+    ),
+)]
+pub async fn widget_preview(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let script = format!("{}/widget.js", widget_base(&state, &site, &headers));
+    let body = html::widget_preview(&script);
+    ([(CONTENT_TYPE, HeaderValue::from_static(HTML))], body).into_response()
+}
+
+/// The URL `site`'s routes are under, for the widget: below `public_url`
+/// when it is set, otherwise below the host the request was sent to, with
+/// the scheme left to the embedding page. Without either, the path alone.
+fn widget_base(state: &AppState, site: &Site, headers: &axum::http::HeaderMap) -> String {
+    let origin = match &state.config().public_url {
+        Some(url) => url.clone(),
+        None => headers
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .filter(|host| host.bytes().all(|b| b.is_ascii_alphanumeric() || b".-:[]".contains(&b)))
+            .map(|host| format!("//{}", host))
+            .unwrap_or_default(),
+    };
+    match site.key == default_site(state).key {
+        true => origin,
+        false => format!("{}/{}", origin, site.key),
+    }
+}
+
+/// Atom feed with an entry each time the top artists change.
+#[utoipa::path(
+    get,
+    path = "/feed.xml",
+    tag = "leaderboard",
+    responses(
+        (
+            status = 200,
+            description = "Atom feed",
+            body = String,
+            content_type = "application/atom+xml",
+        ),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn feed_handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    // Data loaded from the cache file at startup never went through a
+    // refresh, so the feed may not have seen it yet.
+    add_to_feed(&state, &site, &entry);
+    let body = state.feeds.lock().unwrap()[&site.key].render(&site.id);
+    render_response(&state, &entry, status, &headers, body, ATOM)
+}
+
+/// Longest `?callback=` name accepted.
+const MAX_CALLBACK_LEN: usize = 64;
+
+#[derive(Deserialize)]
+struct JsonpParams {
+    callback: Option<String>,
+}
+
+/// With `?callback=fn`, serves a successful JSON answer as the script
+/// `fn(...)` for pages that can only embed a `<script>`, with an ETag of
+/// its own. The name may only hold letters, digits, `_` and `.`, so it
+/// can't carry script of its own.
+pub async fn jsonp(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let params = Query::<JsonpParams>::try_from_uri(request.uri()).ok();
+    let callback = match params.and_then(|Query(params)| params.callback) {
+        Some(callback) if state.config().jsonp_enabled => callback,
+        _ => return next.run(request).await,
+    };
+    let valid = callback.len() <= MAX_CALLBACK_LEN
+        && callback.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && callback.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if !valid {
+        let accepted = format!(
+            "a name of letters, digits, `_` and `.`, at most {} characters",
+            MAX_CALLBACK_LEN
+        );
+        return ApiError::invalid_param("callback", &[&accepted]).into_response();
+    }
+
+    // Clients hold the wrapped body's ETag, so it is checked here instead.
+    let mut conditional = axum::http::HeaderMap::new();
+    if let Some(etag) = request.headers_mut().remove(IF_NONE_MATCH) {
+        conditional.insert(IF_NONE_MATCH, etag);
+    }
+    let response = next.run(request).await;
+    let is_json = response.headers().get(CONTENT_TYPE).is_some_and(|value| value == JSON);
+    if response.status() != StatusCode::OK || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!("Failed to read the body to wrap for JSONP: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // The leading comment keeps the script from starting with bytes the
+    // client picked, which some plugins would sniff as another format.
+    let wrapped = format!("/**/{}({});", callback, String::from_utf8_lossy(&body));
+    let headers = &mut parts.headers;
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(JAVASCRIPT));
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if headers.contains_key(ETAG) {
+        let etag = cache::etag(&wrapped);
+        headers.insert(
+            ETAG,
+            HeaderValue::from_str(&etag).expect("ETag is a quoted hex digest"),
+        );
+        if etag_matches(&conditional, &etag) {
+            headers.remove(CONTENT_TYPE);
+            parts.status = StatusCode::NOT_MODIFIED;
+            return Response::from_parts(parts, axum::body::Body::empty());
+        }
+    }
+    Response::from_parts(parts, axum::body::Body::from(wrapped))
+}
@@ -0,0 +1,193 @@
+use crate::error::ApiError;
+use crate::params::{SelectedSite, View, MAX_LIMIT};
+use crate::plausible::ArtistRow;
+use crate::refresh::lookup;
+use crate::respond::{add_movement, at_least, leaderboard};
+use crate::routes::{
+    history::{history_unavailable, rfc3339_secs, HistoryParams},
+    leaderboard::Summary,
+};
+use crate::upstream::{Period, Site, UpstreamQuery};
+use crate::{graphql, plausible, AppState};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+/// Answers a GraphQL query over the leaderboard read model: `leaderboard`,
+/// `artist`, `summary` and, with `history_db` set, `history`. Fields read
+/// the same cache entries as the REST routes, so a query asking for several
+/// fetches upstream no more than those routes would. Queries deeper than
+/// `graphql_max_depth` or selecting more than `graphql_max_complexity`
+/// fields are refused with 400, and `__schema` and `__type` answer only
+/// with `graphql_introspection` on. Failed fields are null, with an entry
+/// in `errors` carrying the REST route's error code.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "leaderboard",
+    request_body(
+        content = Object,
+        description = "`query`, with optional `variables` and `operationName`",
+    ),
+    responses(
+        (status = 200, description = "`data`, with `errors` for failed fields", body = Object),
+        (status = 400, description = "Invalid or refused query, in `errors`", body = Object),
+    ),
+)]
+pub async fn graphql_handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    request: Result<Json<graphql::Request>, axum::extract::rejection::JsonRejection>,
+) -> Response {
+    let rejected = |errors| {
+        (StatusCode::BAD_REQUEST, Json(graphql::Response::rejected(errors))).into_response()
+    };
+    let request = match request {
+        Ok(Json(request)) => request,
+        Err(e) => return rejected(vec![graphql::Error::new(e.body_text())]),
+    };
+    let config = state.config();
+    let options = graphql::Options {
+        history: state.history.is_some(),
+        introspection: config.graphql_introspection,
+        max_depth: config.graphql_max_depth,
+        max_complexity: config.graphql_max_complexity,
+    };
+    let query = match graphql::Query::prepare(request, &options) {
+        Ok(query) => query,
+        Err(errors) => return rejected(errors),
+    };
+    let fields = match query.root_fields() {
+        Ok(fields) => fields,
+        Err(e) => return rejected(vec![e]),
+    };
+
+    let mut resolved = Vec::with_capacity(fields.len());
+    for field in fields {
+        let value = match query.builtin(&field) {
+            Some(value) => Ok(value),
+            None => graphql_field(&state, &site, &field.name, &field.args)
+                .await
+                .map_err(|e| graphql::Error::with_code(e.message(), e.code())),
+        };
+        resolved.push((field, value));
+    }
+    Json(query.respond(resolved)).into_response()
+}
+
+/// The value of root field `name` of a GraphQL query, with every field its
+/// type has.
+async fn graphql_field(
+    state: &AppState,
+    site: &Site,
+    name: &str,
+    args: &serde_json::Map<String, serde_json::Value>,
+) -> Result<serde_json::Value, ApiError> {
+    use serde_json::{json, Value};
+
+    let text = |arg: &str| match args.get(arg) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(ApiError::invalid_param(arg, &["a string"])),
+    };
+    let count = |arg: &str, accepted: &str| match args.get(arg) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| ApiError::invalid_param(arg, &[accepted])),
+    };
+    let period = match text("period")? {
+        None => Period::default(),
+        Some(period) => period
+            .parse()
+            .map_err(|()| ApiError::invalid_param("period", &Period::accepted()))?,
+    };
+    let view = View::PUBLIC;
+    let artist_json = |index: usize, row: &ArtistRow| {
+        let movement = row.movement.as_ref();
+        json!({
+            "name": row.name,
+            "rank": index + 1,
+            "visitors": row.visitors,
+            "events": row.events,
+            "share": row.share,
+            "previousRank": movement.and_then(|movement| movement.previous_rank),
+            "rankDelta": movement.and_then(|movement| movement.rank_delta),
+        })
+    };
+
+    match name {
+        "leaderboard" => {
+            let limit = count("limit", "an integer from 1 to 1000")?;
+            if limit.is_some_and(|limit| !(1..=MAX_LIMIT as u64).contains(&limit)) {
+                return Err(ApiError::invalid_param("limit", &["an integer from 1 to 1000"]));
+            }
+            let min_visitors = count("minVisitors", "a non-negative integer")?.unwrap_or(0);
+            let query = UpstreamQuery::leaderboard(site, period);
+            let (entry, _) = lookup(state, &query).await?;
+            let mut board = at_least(leaderboard(state, &entry, view), min_visitors);
+            add_movement(state, &query, view, min_visitors, &mut board);
+            let limit = limit.map_or(usize::MAX, |limit| limit as usize);
+            let rows = board.results.iter().take(limit).enumerate();
+            Ok(rows.map(|(index, row)| artist_json(index, row)).collect())
+        }
+        "artist" => {
+            let wanted = text("name")?.unwrap_or_default();
+            let wanted = plausible::normalize_name(&wanted).to_lowercase();
+            let query = UpstreamQuery::leaderboard(site, Period::default());
+            let (entry, _) = lookup(state, &query).await?;
+            let mut board = leaderboard(state, &entry, view);
+            add_movement(state, &query, view, 0, &mut board);
+            let found = board
+                .results
+                .iter()
+                .enumerate()
+                .find(|(_, row)| plausible::normalize_name(&row.name).to_lowercase() == wanted);
+            Ok(found.map_or(Value::Null, |(index, row)| artist_json(index, row)))
+        }
+        "summary" => {
+            let query = UpstreamQuery::leaderboard(site, period);
+            let (entry, _) = lookup(state, &query).await?;
+            let board = leaderboard(state, &entry, view);
+            let summary = Summary::of(&board.results, &entry);
+            Ok(json!({
+                "totalVisitors": summary.total_visitors,
+                "totalEvents": summary.total_events,
+                "artistCount": summary.artist_count,
+                "topArtist": summary.top_artist,
+                "generatedAt": summary.generated_at,
+            }))
+        }
+        "history" => {
+            let history = state.history.clone().expect("history is in the schema with a database");
+            let params = HistoryParams {
+                artist: text("artist")?.filter(|artist| !artist.trim().is_empty()),
+                from: text("from")?,
+                to: text("to")?,
+            };
+            let Some(artist) = params.artist.clone() else {
+                return Err(ApiError::invalid_param("artist", &["an artist name"]));
+            };
+            let (from, to) = params.range(state.config().timezone())?;
+            let key = site.key.clone();
+            let series = move || history.series(&key, &artist, from, to);
+            let points = tokio::task::spawn_blocking(series)
+                .await
+                .map_err(history_unavailable)?
+                .map_err(history_unavailable)?;
+            let points = points.iter().map(|point| {
+                json!({
+                    "takenAt": rfc3339_secs(point.taken_at),
+                    "visitors": point.visitors,
+                    "events": point.events,
+                })
+            });
+            Ok(points.collect())
+        }
+        _ => unreachable!("queries are validated against the schema"),
+    }
+}
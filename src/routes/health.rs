@@ -0,0 +1,184 @@
+use crate::fetcher::Validators;
+use crate::params::default_site;
+use crate::refresh::{attempt_page, rate_limited_for};
+use crate::upstream::{Period, UpstreamQuery};
+use crate::{html, openapi, AppState, HTML};
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+use utoipa::IntoParams;
+
+/// This service's OpenAPI description.
+pub async fn openapi_handler(State(state): State<AppState>) -> Response {
+    Json(openapi::document(&state.config())).into_response()
+}
+
+/// Interactive API reference rendered from `/openapi.json`.
+pub async fn docs() -> Response {
+    ([(CONTENT_TYPE, HeaderValue::from_static(HTML))], html::docs()).into_response()
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HealthParams {
+    /// Also probe the upstream, answering 503 if it can't be reached.
+    #[serde(default)]
+    deep: bool,
+    /// Answer 503 while the startup warm-up is running, when
+    /// `warm_before_ready` is set.
+    #[serde(default)]
+    ready: bool,
+}
+
+/// Liveness and cache summary. Reads state only, so it never touches the
+/// upstream unless `?deep=true` asks for a reachability probe, in which case
+/// a failed probe turns the response into a 503. With `?ready=true` and
+/// `warm_before_ready`, it is a 503 until the cache is warm. `/livez` and
+/// `/readyz` answer just those questions; `/status` has the full picture.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    params(HealthParams),
+    responses(
+        (status = 200, description = "Up", body = Object),
+        (status = 503, description = "The deep probe failed, or still warming", body = Object),
+    ),
+)]
+pub async fn healthz(
+    State(state): State<AppState>,
+    Query(params): Query<HealthParams>,
+) -> Response {
+    let site = default_site(&state);
+    let key = UpstreamQuery::leaderboard(&site, Period::default()).cache_key();
+    let (age, usage) = {
+        let cache = state.cache.read().await;
+        let age = cache.get(&key).map(|entry| entry.timestamp.elapsed().as_secs());
+        (age, cache.usage())
+    };
+    let last_success = state
+        .last_success
+        .lock()
+        .unwrap()
+        .map(|time| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339());
+
+    let mut body = serde_json::json!({
+        "status": "ok",
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "cached": age.is_some(),
+        "cache_age_secs": age,
+        "cache": usage,
+        "warmed": state.warmed.load(Ordering::Relaxed),
+        "last_success": last_success,
+        "upstream_circuit": state.breaker.status(),
+        "upstream_rate_limit": rate_limited_for(&state).map(|wait| serde_json::json!({
+            "resumes_at": chrono::DateTime::<chrono::Utc>::from(SystemTime::now() + wait)
+                .to_rfc3339(),
+            "resumes_in_secs": wait.as_secs(),
+        })),
+    });
+
+    let mut status = StatusCode::OK;
+    if params.ready && state.config().warm_before_ready && !state.warmed.load(Ordering::Relaxed) {
+        status = StatusCode::SERVICE_UNAVAILABLE;
+        body["status"] = "warming".into();
+    }
+    if params.deep {
+        let timeout = state.config().upstream_attempt_timeout;
+        let probe = UpstreamQuery::probe(&site);
+        match attempt_page(&state, &probe, 1, timeout, 1, &Validators::default()).await {
+            Ok(_) => body["upstream"] = serde_json::json!({ "reachable": true }),
+            Err(e) => {
+                tracing::warn!("Health probe failed: {}", e);
+                status = StatusCode::SERVICE_UNAVAILABLE;
+                body["status"] = "degraded".into();
+                body["upstream"] = serde_json::json!({
+                    "reachable": false,
+                    "error": { "code": e.code(), "message": e.public_message() },
+                });
+            }
+        }
+    }
+
+    (
+        status,
+        [(CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(body),
+    )
+        .into_response()
+}
+
+/// Liveness alone: answers as long as the server can, whatever the state of
+/// the cache or the upstream, so a restart is only called for when it hangs.
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "operations",
+    responses((status = 200, description = "Up", body = Object)),
+)]
+pub async fn livez() -> Response {
+    let body = serde_json::json!({ "status": "ok" });
+    ([(CACHE_CONTROL, HeaderValue::from_static("no-store"))], Json(body)).into_response()
+}
+
+/// Readiness to serve real data, for load balancers: a 503 until a fetch
+/// has filled the cache or `fallback_file` is loaded, while warming with
+/// `warm_before_ready`, and once the freshest data is older than
+/// `ready_max_staleness_secs` because every refresh since has failed.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready", body = Object),
+        (status = 503, description = "No data yet, still warming, or stale", body = Object),
+    ),
+)]
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    let config = state.config();
+    let newest = {
+        let cache = state.cache.read().await;
+        cache.iter().map(|(_, entry)| entry.fetched_at).max()
+    };
+    let last_success = *state.last_success.lock().unwrap();
+    // Data loaded from the cache file dates from when it was fetched; the
+    // fallback has been all there is since startup.
+    let age = match (last_success.or(newest), &config.fallback) {
+        (Some(fetched_at), _) => Some(fetched_at.elapsed().unwrap_or_default()),
+        (None, Some(_)) => Some(state.started_at.elapsed()),
+        (None, None) => None,
+    };
+
+    let horizon = config.ready_max_staleness;
+    let warming = config.warm_before_ready && !state.warmed.load(Ordering::Relaxed);
+    let (status, ready) = match age {
+        None => (StatusCode::SERVICE_UNAVAILABLE, "no_data"),
+        Some(_) if warming => (StatusCode::SERVICE_UNAVAILABLE, "warming"),
+        Some(age) if !horizon.is_zero() && age > horizon => {
+            (StatusCode::SERVICE_UNAVAILABLE, "stale")
+        }
+        Some(_) => (StatusCode::OK, "ready"),
+    };
+    let rfc3339 = |time: SystemTime| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339();
+    let body = serde_json::json!({
+        "status": ready,
+        "data_age_secs": age.map(|age| age.as_secs()),
+        "last_success": last_success.map(rfc3339),
+        "fallback": last_success.is_none() && newest.is_none() && config.fallback.is_some(),
+    });
+    (
+        status,
+        [(CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(body),
+    )
+        .into_response()
+}
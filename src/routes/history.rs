@@ -0,0 +1,1270 @@
+use crate::cache::CacheEntry;
+use crate::error::ApiError;
+use crate::history::{History, Point};
+use crate::params::{SelectedSite, View, MAX_LIMIT};
+use crate::plausible::ArtistRow;
+use crate::refresh::lookup;
+use crate::respond::{error_response, leaderboard, render_response};
+use crate::upstream::{Period, UpstreamQuery};
+use crate::{export, plausible, search, upstream, AppState, CSV, JSON};
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryParams {
+    /// The artist to chart. Required.
+    pub artist: Option<String>,
+    /// Earliest snapshot, as an RFC 3339 timestamp or a `YYYY-MM-DD` date.
+    pub from: Option<String>,
+    /// Latest snapshot, as an RFC 3339 timestamp or a `YYYY-MM-DD` date,
+    /// which covers the whole day.
+    pub to: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ArtistHistory {
+    artist: String,
+    points: Vec<Point>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Snapshots {
+    /// RFC 3339 timestamps, oldest first.
+    snapshots: Vec<String>,
+}
+
+impl HistoryParams {
+    /// `from` and `to` in seconds since the Unix epoch. Bare dates are days
+    /// in `timezone`, and a bare `to` covers the whole day.
+    pub fn range(&self, timezone: chrono_tz::Tz) -> Result<(Option<i64>, Option<i64>), ApiError> {
+        let parse = |name: &str, value: Option<&str>, end_of_day: bool| {
+            let Some(value) = value else {
+                return Ok(None);
+            };
+            if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+                return Ok(Some(time.timestamp()));
+            }
+            match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+                Ok(date) if end_of_day => {
+                    let next = date.succ_opt().unwrap_or(date);
+                    Ok(Some(upstream::start_of_day(next, &timezone).timestamp() - 1))
+                }
+                Ok(date) => Ok(Some(upstream::start_of_day(date, &timezone).timestamp())),
+                Err(_) => Err(ApiError::invalid_param(
+                    name,
+                    &["an RFC 3339 timestamp", "a YYYY-MM-DD date"],
+                )),
+            }
+        };
+        Ok((
+            parse("from", self.from.as_deref(), false)?,
+            parse("to", self.to.as_deref(), true)?,
+        ))
+    }
+}
+
+/// One artist's totals over time, from the recorded snapshots of the
+/// all-time leaderboard.
+#[utoipa::path(
+    get,
+    path = "/history",
+    tag = "history",
+    params(HistoryParams),
+    responses(
+        (status = 200, description = "The artist's totals per snapshot", body = ArtistHistory),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+    ),
+)]
+pub async fn history_handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<HistoryParams>,
+) -> Response {
+    let history = state.history.clone().expect("history routes require a database");
+    let Some(artist) = params.artist.clone().filter(|artist| !artist.trim().is_empty()) else {
+        return ApiError::invalid_param("artist", &["an artist name"]).into_response();
+    };
+    let (from, to) = match params.range(state.config().timezone()) {
+        Ok(range) => range,
+        Err(e) => return e.into_response(),
+    };
+
+    let key = site.key.clone();
+    let name = artist.clone();
+    let points =
+        tokio::task::spawn_blocking(move || history.series(&key, &name, from, to)).await;
+    match points {
+        Ok(Ok(points)) => Json(ArtistHistory { artist, points }).into_response(),
+        Ok(Err(e)) => history_error(e),
+        Err(e) => history_error(e),
+    }
+}
+
+/// When each recorded snapshot was taken, oldest first.
+#[utoipa::path(
+    get,
+    path = "/history/snapshots",
+    tag = "history",
+    responses(
+        (status = 200, description = "Snapshot times", body = Snapshots),
+    ),
+)]
+pub async fn snapshots(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+) -> Response {
+    let history = state.history.clone().expect("history routes require a database");
+    let times = tokio::task::spawn_blocking(move || history.snapshots(&site.key)).await;
+    match times {
+        Ok(Ok(times)) => {
+            let times: Vec<String> = times
+                .into_iter()
+                .filter_map(|secs| chrono::DateTime::from_timestamp(secs, 0))
+                .map(|time| time.to_rfc3339())
+                .collect();
+            Json(Snapshots { snapshots: times }).into_response()
+        }
+        Ok(Err(e)) => history_error(e),
+        Err(e) => history_error(e),
+    }
+}
+
+/// Rows read from the database per chunk of a history export.
+const EXPORT_PAGE_ROWS: usize = 1000;
+
+/// The recorded snapshots as CSV, streamed as they are read: one row per
+/// snapshot with `artist`, otherwise one per artist per snapshot. An
+/// export matching more than `history_csv_max_rows` rows is refused with
+/// 400; narrow it with `from` and `to`.
+#[utoipa::path(
+    get,
+    path = "/history.csv",
+    tag = "history",
+    params(HistoryParams),
+    responses(
+        (
+            status = 200,
+            description = "The snapshots as CSV",
+            body = String,
+            content_type = "text/csv",
+        ),
+        (status = 400, description = "Invalid parameter, or too many rows", body = ErrorResponse),
+    ),
+)]
+pub async fn history_csv(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<HistoryParams>,
+) -> Response {
+    let history = state.history.clone().expect("history routes require a database");
+    let (from, to) = match params.range(state.config().timezone()) {
+        Ok(range) => range,
+        Err(e) => return e.into_response(),
+    };
+    let artist = params.artist.clone().filter(|artist| !artist.trim().is_empty());
+
+    let count = {
+        let history = history.clone();
+        let (key, artist) = (site.key.clone(), artist.clone());
+        tokio::task::spawn_blocking(move || history.count_rows(&key, artist.as_deref(), from, to))
+            .await
+    };
+    let max = state.config().history_csv_max_rows;
+    match count {
+        Ok(Ok(count)) if count > max => {
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "too_many_rows",
+                format!(
+                    "{} rows match, more than the {} exported at once; \
+                     narrow the range with `from` and `to`",
+                    count, max
+                ),
+            )
+            .into_response();
+        }
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => return history_error(e),
+        Err(e) => return history_error(e),
+    }
+
+    let day = |secs: i64| rfc3339_secs(secs)[..10].to_string();
+    let filename = format!(
+        "attachment; filename=\"artistgrid-history{}-{}-to-{}.csv\"",
+        artist.as_deref().map(|artist| format!("-{}", slug(artist))).unwrap_or_default(),
+        from.map_or("start".to_string(), day),
+        day(to.unwrap_or_else(|| unix_secs(SystemTime::now()))),
+    );
+    let by_artist = artist.is_some();
+    let header = futures_util::stream::once(async move {
+        Ok::<_, std::io::Error>(export::history_header(by_artist).to_string())
+    });
+    let pages = futures_util::stream::try_unfold(Some(None), move |after| {
+        let (history, key, artist) = (history.clone(), site.key.clone(), artist.clone());
+        async move {
+            let Some(after) = after else {
+                return Ok(None);
+            };
+            let page = tokio::task::spawn_blocking(move || {
+                history.export(&key, artist.as_deref(), from, to, after, EXPORT_PAGE_ROWS)
+            })
+            .await
+            .map_err(std::io::Error::other)?
+            .map_err(|e| {
+                tracing::error!("History export failed: {}", e);
+                std::io::Error::other(e)
+            })?;
+            if page.is_empty() {
+                return Ok(None);
+            }
+            let full = page.len() == EXPORT_PAGE_ROWS;
+            let next = full.then(|| page.last().map(|row| row.position));
+            Ok(Some((export::history_rows(&page, by_artist), next)))
+        }
+    });
+
+    (
+        [
+            (CONTENT_TYPE, HeaderValue::from_static(CSV)),
+            (
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(&filename).expect("filename is ASCII"),
+            ),
+        ],
+        axum::body::Body::from_stream(futures_util::StreamExt::chain(header, pages)),
+    )
+        .into_response()
+}
+
+/// `name` folded to lowercase ASCII where it can be, with everything but
+/// letters and digits turned into single dashes, for a filename.
+fn slug(name: &str) -> String {
+    let folded = search::fold_diacritics(name);
+    let words: Vec<&str> = folded
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    words.join("-")
+}
+
+fn history_error(e: impl std::fmt::Display) -> Response {
+    history_unavailable(e).into_response()
+}
+
+pub fn history_unavailable(e: impl std::fmt::Display) -> ApiError {
+    tracing::error!("History query failed: {}", e);
+    ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "history_unavailable",
+        "History could not be read",
+    )
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangesParams {
+    /// An RFC 3339 timestamp, or the ID of a recorded snapshot. Required.
+    since: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Changes {
+    since: Compared,
+    /// When the current leaderboard was fetched, as RFC 3339; pass it as
+    /// `since` next time to get what changed after it.
+    current: String,
+    added: Vec<Change>,
+    removed: Vec<Change>,
+    /// Artists in both whose visitors or rank changed.
+    changed: Vec<Change>,
+}
+
+/// The leaderboard changes are reported against.
+#[derive(Serialize, ToSchema)]
+pub struct Compared {
+    /// The recorded snapshot's ID. Absent without `history_db`, when the
+    /// leaderboard the current one replaced is compared against instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+    /// RFC 3339.
+    taken_at: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Change {
+    name: String,
+    /// Visitors now; 0 for a removed artist.
+    visitors: u64,
+    visitor_delta: i64,
+    /// Position now, from 1. Absent for a removed artist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank: Option<usize>,
+    /// Absent for an added artist.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_rank: Option<usize>,
+    /// Positive when the artist moved up. Only for artists in both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank_delta: Option<i64>,
+}
+
+/// What `since` names.
+#[derive(Clone, Copy)]
+enum Since {
+    /// Seconds since the Unix epoch.
+    At(i64),
+    Snapshot(i64),
+}
+
+impl Since {
+    fn parse(value: Option<&str>) -> Result<Self, ApiError> {
+        let accepted = ["an RFC 3339 timestamp", "a snapshot ID"];
+        let invalid = || ApiError::invalid_param("since", &accepted);
+        let value = value.map(str::trim).filter(|value| !value.is_empty()).ok_or_else(invalid)?;
+        if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+            return Ok(Since::At(time.timestamp()));
+        }
+        value.parse().map(Since::Snapshot).map_err(|_| invalid())
+    }
+}
+
+/// A leaderboard from the past, as `Compared` describes it.
+struct Baseline {
+    id: Option<i64>,
+    taken_at: i64,
+    rows: Vec<ArtistRow>,
+}
+
+/// What changed in the all-time leaderboard since the latest snapshot taken
+/// at or before `since`: the recorded ones with `history_db`, otherwise the
+/// current leaderboard and the one it replaced. Asking for a time before
+/// the oldest of those answers 410 with the oldest, so the caller knows to
+/// start over from the full leaderboard.
+#[utoipa::path(
+    get,
+    path = "/changes",
+    tag = "leaderboard",
+    params(ChangesParams),
+    responses(
+        (status = 200, description = "Added, removed and changed artists", body = Changes),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 404, description = "No snapshot has that ID", body = ErrorResponse),
+        (status = 410, description = "Nothing that old is retained", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn changes(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<ChangesParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let since = match Since::parse(params.since.as_deref()) {
+        Ok(since) => since,
+        Err(e) => return e.into_response(),
+    };
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    let view = View::PUBLIC;
+
+    let baseline = match (state.history.clone(), since) {
+        (Some(history), _) => {
+            let key = site.key.clone();
+            let found =
+                tokio::task::spawn_blocking(move || recorded_baseline(&history, &key, since)).await;
+            match found {
+                Ok(Ok(found)) => found,
+                Ok(Err(e)) => return history_error(e),
+                Err(e) => return history_error(e),
+            }
+        }
+        (None, Since::At(at)) => retained_baseline(&state, &query, &entry, view, at),
+        (None, Since::Snapshot(_)) => {
+            // Snapshots only have IDs once they are recorded.
+            Err(ApiError::invalid_param("since", &["an RFC 3339 timestamp"]))
+        }
+    };
+    let baseline = match baseline {
+        Ok(baseline) => baseline,
+        Err(e) => return e.into_response(),
+    };
+
+    let current = leaderboard(&state, &entry, view).into_owned().results;
+    let (added, removed, changed) = leaderboard_changes(&baseline.rows, &current);
+    let body = Changes {
+        since: Compared {
+            id: baseline.id,
+            taken_at: rfc3339_secs(baseline.taken_at),
+        },
+        current: rfc3339_secs(unix_secs(entry.fetched_at)),
+        added,
+        removed,
+        changed,
+    };
+    let body = serde_json::to_string(&body).expect("Changes serializes");
+    render_response(&state, &entry, status, &headers, body, JSON)
+}
+
+/// The recorded snapshot `since` names.
+fn recorded_baseline(
+    history: &History,
+    site: &str,
+    since: Since,
+) -> rusqlite::Result<Result<Baseline, ApiError>> {
+    let snapshot = match since {
+        Since::Snapshot(id) => history.snapshot(site, id)?.ok_or_else(|| {
+            ApiError::not_found("snapshot_not_found", format!("No snapshot has ID {}", id))
+        }),
+        Since::At(at) => match history.snapshot_at(site, at)? {
+            Some(snapshot) => Ok(snapshot),
+            None => Err(ApiError::gone(history.oldest(site)?.map(rfc3339_secs))),
+        },
+    };
+    Ok(snapshot.map(|snapshot| Baseline {
+        id: Some(snapshot.id),
+        taken_at: snapshot.taken_at,
+        rows: snapshot.rows,
+    }))
+}
+
+/// The newer of `entry` and the leaderboard it replaced that was fetched at
+/// or before `at`, viewed as `view`.
+fn retained_baseline(
+    state: &AppState,
+    query: &UpstreamQuery,
+    entry: &Arc<CacheEntry>,
+    view: View,
+    at: i64,
+) -> Result<Baseline, ApiError> {
+    let previous = state.previous.lock().unwrap().get(&query.cache_key()).cloned();
+    // Newest first.
+    let retained: Vec<Arc<CacheEntry>> =
+        [Some(entry.clone()), previous].into_iter().flatten().collect();
+    match retained.iter().find(|retained| unix_secs(retained.fetched_at) <= at) {
+        Some(found) => Ok(Baseline {
+            id: None,
+            taken_at: unix_secs(found.fetched_at),
+            rows: leaderboard(state, found, view).into_owned().results,
+        }),
+        None => {
+            let oldest = retained.last().map(|oldest| rfc3339_secs(unix_secs(oldest.fetched_at)));
+            Err(ApiError::gone(oldest))
+        }
+    }
+}
+
+/// The artists only in `after`, those only in `before`, and those in both
+/// whose visitors or rank differ, matching rows by normalized,
+/// case-insensitive name.
+fn leaderboard_changes(
+    before: &[ArtistRow],
+    after: &[ArtistRow],
+) -> (Vec<Change>, Vec<Change>, Vec<Change>) {
+    let key = |row: &ArtistRow| plausible::normalize_name(&row.name).to_lowercase();
+    let ranked = |rows: &[ArtistRow]| -> HashMap<String, usize> {
+        rows.iter().enumerate().map(|(index, row)| (key(row), index)).collect()
+    };
+    let (old, new) = (ranked(before), ranked(after));
+
+    let (mut added, mut changed) = (Vec::new(), Vec::new());
+    for (index, row) in after.iter().enumerate() {
+        let rank = index + 1;
+        match old.get(&key(row)).map(|&previous| (previous + 1, &before[previous])) {
+            Some((previous_rank, previous)) => {
+                if previous_rank == rank && previous.visitors == row.visitors {
+                    continue;
+                }
+                changed.push(Change {
+                    name: row.name.clone(),
+                    visitors: row.visitors,
+                    visitor_delta: row.visitors as i64 - previous.visitors as i64,
+                    rank: Some(rank),
+                    previous_rank: Some(previous_rank),
+                    rank_delta: Some(previous_rank as i64 - rank as i64),
+                });
+            }
+            None => added.push(Change {
+                name: row.name.clone(),
+                visitors: row.visitors,
+                visitor_delta: row.visitors as i64,
+                rank: Some(rank),
+                previous_rank: None,
+                rank_delta: None,
+            }),
+        }
+    }
+    let removed = before
+        .iter()
+        .enumerate()
+        .filter(|(_, row)| !new.contains_key(&key(row)))
+        .map(|(index, row)| Change {
+            name: row.name.clone(),
+            visitors: 0,
+            visitor_delta: -(row.visitors as i64),
+            rank: None,
+            previous_rank: Some(index + 1),
+            rank_delta: None,
+        })
+        .collect();
+    (added, removed, changed)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DigestParams {
+    /// The ISO week, as `YYYY-Www`. Required.
+    #[param(example = "2025-W45")]
+    week: Option<String>,
+    /// Most artists in each list. Defaults to 10.
+    #[param(value_type = Option<u32>, minimum = 1, maximum = 1000)]
+    limit: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WeeklyDigest {
+    week: String,
+    /// When the week starts and ends, as RFC 3339 in `digest_utc_offset`,
+    /// or else `site_timezone`.
+    week_start: String,
+    week_end: String,
+    /// The snapshots compared: the last one taken by the week's start, or
+    /// the week's first when there is none, and the week's last. Their
+    /// times are the actual boundaries of the figures below.
+    start: Compared,
+    end: Compared,
+    /// By clicks gained during the week.
+    top_gainers: Vec<WeeklyDigestRow>,
+    /// By ranks climbed, among artists in both snapshots.
+    climbers: Vec<WeeklyDigestRow>,
+    /// Artists missing from the start snapshot, by rank.
+    new_entrants: Vec<WeeklyDigestRow>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct WeeklyDigestRow {
+    name: String,
+    /// Clicks by the end of the week.
+    clicks: u64,
+    clicks_gained: i64,
+    visitors: u64,
+    visitors_gained: i64,
+    /// Position at the end of the week, from 1.
+    rank: usize,
+    /// Absent for a new entrant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_rank: Option<usize>,
+    /// Positive when the artist moved up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank_delta: Option<i64>,
+}
+
+/// One ISO week rolled into a report: the artists who gained the most
+/// clicks, climbed the most ranks and entered the leaderboard, comparing
+/// the recorded snapshots nearest the week's start and end. A week with
+/// fewer than two snapshots to compare answers 404 with the earliest week
+/// that has them.
+#[utoipa::path(
+    get,
+    path = "/digest",
+    tag = "history",
+    params(DigestParams),
+    responses(
+        (status = 200, description = "The week's report", body = WeeklyDigest),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 404, description = "Not enough snapshots that week", body = ErrorResponse),
+    ),
+)]
+pub async fn digest(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<DigestParams>,
+) -> Response {
+    let history = state.history.clone().expect("history routes require a database");
+    let config = state.config();
+    let week = params.week.as_deref().and_then(|week| match config.digest_offset() {
+        Some(offset) => week_bounds(week, &offset),
+        None => week_bounds(week, &config.timezone()),
+    });
+    let Some((week, start, end)) = week else {
+        return ApiError::invalid_param("week", &["an ISO week such as 2025-W45"]).into_response();
+    };
+    let limit = match params.limit.as_deref().map(str::parse::<usize>) {
+        None => 10,
+        Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => {
+            let accepted = ["an integer from 1 to 1000"];
+            return ApiError::invalid_param("limit", &accepted).into_response();
+        }
+    };
+
+    let key = site.key.clone();
+    let (from, to) = (start.timestamp(), end.timestamp());
+    let found = tokio::task::spawn_blocking(move || -> rusqlite::Result<_> {
+        let first = match history.snapshot_at(&key, from)? {
+            Some(snapshot) => Some(snapshot),
+            None => history.snapshot_from(&key, from)?,
+        };
+        let last = history.snapshot_at(&key, to - 1)?;
+        match (first, last) {
+            (Some(first), Some(last)) if last.taken_at >= from && last.id != first.id => {
+                Ok(Ok((first, last)))
+            }
+            _ => Ok(Err(history.snapshots(&key)?)),
+        }
+    })
+    .await;
+    let (first, last) = match found {
+        Ok(Ok(Ok(pair))) => pair,
+        Ok(Ok(Err(times))) => {
+            // A week can be served once it holds a snapshot with an earlier
+            // one to compare against, so from the second snapshot's week.
+            let earliest = times.get(1).map(|&secs| match config.digest_offset() {
+                Some(offset) => iso_week(secs, &offset),
+                None => iso_week(secs, &config.timezone()),
+            });
+            return ApiError::insufficient_history(earliest).into_response();
+        }
+        Ok(Err(e)) => return history_error(e),
+        Err(e) => return history_error(e),
+    };
+
+    let (top_gainers, climbers, new_entrants) = week_digest(&first.rows, &last.rows, limit);
+    let body = WeeklyDigest {
+        week,
+        week_start: start.to_rfc3339(),
+        week_end: end.to_rfc3339(),
+        start: Compared {
+            id: Some(first.id),
+            taken_at: rfc3339_secs(first.taken_at),
+        },
+        end: Compared {
+            id: Some(last.id),
+            taken_at: rfc3339_secs(last.taken_at),
+        },
+        top_gainers,
+        climbers,
+        new_entrants,
+    };
+    Json(body).into_response()
+}
+
+/// `week`, as `YYYY-Www`, normalized, and when it starts and ends: at
+/// midnight on its Monday and the next in `zone`.
+fn week_bounds<Z: chrono::TimeZone>(
+    week: &str,
+    zone: &Z,
+) -> Option<(
+    String,
+    chrono::DateTime<chrono::FixedOffset>,
+    chrono::DateTime<chrono::FixedOffset>,
+)> {
+    let (year, number) = week.trim().split_once("-W")?;
+    let (year, number) = (year.parse().ok()?, number.parse().ok()?);
+    let monday = chrono::NaiveDate::from_isoywd_opt(year, number, chrono::Weekday::Mon)?;
+    let start = upstream::start_of_day(monday, zone).fixed_offset();
+    let end = upstream::start_of_day(monday + chrono::Days::new(7), zone).fixed_offset();
+    Some((iso_week(start.timestamp(), zone), start, end))
+}
+
+/// The ISO week `secs` since the Unix epoch falls in, in `zone`.
+fn iso_week<Z: chrono::TimeZone>(secs: i64, zone: &Z) -> String {
+    use chrono::Datelike;
+
+    let week = chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .with_timezone(zone)
+        .iso_week();
+    format!("{}-W{:02}", week.year(), week.week())
+}
+
+/// The top `limit` gainers, climbers and new entrants going from `before`
+/// to `after`, matching rows by normalized, case-insensitive name.
+fn week_digest(
+    before: &[ArtistRow],
+    after: &[ArtistRow],
+    limit: usize,
+) -> (Vec<WeeklyDigestRow>, Vec<WeeklyDigestRow>, Vec<WeeklyDigestRow>) {
+    let key = |row: &ArtistRow| plausible::normalize_name(&row.name).to_lowercase();
+    let old: HashMap<String, usize> =
+        before.iter().enumerate().map(|(index, row)| (key(row), index)).collect();
+
+    let rows: Vec<WeeklyDigestRow> = after
+        .iter()
+        .enumerate()
+        .map(|(index, row)| {
+            let previous = old.get(&key(row)).map(|&previous| (previous + 1, &before[previous]));
+            let (clicks, visitors) = previous.map_or((0, 0), |(_, p)| (p.events, p.visitors));
+            WeeklyDigestRow {
+                name: row.name.clone(),
+                clicks: row.events,
+                clicks_gained: row.events as i64 - clicks as i64,
+                visitors: row.visitors,
+                visitors_gained: row.visitors as i64 - visitors as i64,
+                rank: index + 1,
+                previous_rank: previous.map(|(rank, _)| rank),
+                rank_delta: previous.map(|(rank, _)| rank as i64 - (index + 1) as i64),
+            }
+        })
+        .collect();
+
+    let mut top_gainers: Vec<WeeklyDigestRow> =
+        rows.iter().filter(|row| row.clicks_gained > 0).cloned().collect();
+    top_gainers.sort_by_key(|row| (std::cmp::Reverse(row.clicks_gained), row.rank));
+    top_gainers.truncate(limit);
+
+    let mut climbers: Vec<WeeklyDigestRow> =
+        rows.iter().filter(|row| row.rank_delta.is_some_and(|delta| delta > 0)).cloned().collect();
+    climbers.sort_by_key(|row| (std::cmp::Reverse(row.rank_delta), row.rank));
+    climbers.truncate(limit);
+
+    let new_entrants: Vec<WeeklyDigestRow> =
+        rows.into_iter().filter(|row| row.previous_rank.is_none()).take(limit).collect();
+    (top_gainers, climbers, new_entrants)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MoversParams {
+    /// How far back to look from the latest snapshot, as a number and a
+    /// unit: `m`, `h`, `d` or `w`. Defaults to `24h`.
+    #[param(example = "7d")]
+    window: Option<String>,
+    /// Most artists in each list. Defaults to 10.
+    #[param(value_type = Option<u32>, minimum = 1, maximum = 1000)]
+    n: Option<String>,
+    /// `up` for the largest gains, the default, or `down` for the largest
+    /// losses.
+    direction: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Movers {
+    window: String,
+    direction: &'static str,
+    /// The snapshots compared: the last one taken by the window's start
+    /// and the latest. Absent when fewer than two are recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<Compared>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<Compared>,
+    /// Less history than the window is recorded, so `from` is the oldest
+    /// snapshot instead.
+    partial: bool,
+    /// By visitors gained, or lost for `down`.
+    by_visitors: Vec<Mover>,
+    /// By `growth_pct`, leaving out new artists.
+    by_percent: Vec<Mover>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Mover {
+    name: String,
+    visitors: u64,
+    previous_visitors: u64,
+    growth: i64,
+    /// Growth relative to `previous_visitors`, as a percentage. `None` for
+    /// new artists, which have nothing to grow from.
+    growth_pct: Option<f64>,
+    /// The artist is missing from the `from` snapshot.
+    new: bool,
+}
+
+/// The artists whose visitors moved most between the recorded snapshots
+/// `window` apart, ending at the latest. With less history than that the
+/// oldest snapshot is used and `partial` set, rather than failing; artists
+/// missing from the latest snapshot are left out.
+#[utoipa::path(
+    get,
+    path = "/movers",
+    tag = "history",
+    params(MoversParams),
+    responses(
+        (status = 200, description = "The biggest movers", body = Movers),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+    ),
+)]
+pub async fn movers(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<MoversParams>,
+) -> Response {
+    let history = state.history.clone().expect("history routes require a database");
+    let window = params.window.as_deref().map_or("24h", str::trim).to_string();
+    let Some(span) = window_secs(&window) else {
+        let accepted = ["a number of minutes, hours, days or weeks, such as 24h or 7d"];
+        return ApiError::invalid_param("window", &accepted).into_response();
+    };
+    let n = match params.n.as_deref().map(str::parse::<usize>) {
+        None => 10,
+        Some(Ok(n)) if (1..=MAX_LIMIT).contains(&n) => n,
+        Some(_) => {
+            return ApiError::invalid_param("n", &["an integer from 1 to 1000"]).into_response();
+        }
+    };
+    let up = match params.direction.as_deref() {
+        None | Some("up") => true,
+        Some("down") => false,
+        Some(_) => return ApiError::invalid_param("direction", &["up", "down"]).into_response(),
+    };
+
+    let key = site.key.clone();
+    let found = tokio::task::spawn_blocking(move || -> rusqlite::Result<_> {
+        let Some(last) = history.snapshot_at(&key, i64::MAX)? else {
+            return Ok(None);
+        };
+        let start = last.taken_at.saturating_sub(span);
+        let first = match history.snapshot_at(&key, start)? {
+            Some(first) => (first, false),
+            None => (history.snapshot_from(&key, i64::MIN)?.expect("`last` is recorded"), true),
+        };
+        Ok(Some((first, last)))
+    })
+    .await;
+    let found = match found {
+        Ok(Ok(found)) => found.filter(|((first, _), last)| first.id != last.id),
+        Ok(Err(e)) => return history_error(e),
+        Err(e) => return history_error(e),
+    };
+
+    let direction = if up { "up" } else { "down" };
+    let Some(((first, partial), last)) = found else {
+        let body = Movers {
+            window,
+            direction,
+            from: None,
+            to: None,
+            partial: true,
+            by_visitors: Vec::new(),
+            by_percent: Vec::new(),
+        };
+        return Json(body).into_response();
+    };
+    let (by_visitors, by_percent) = top_movers(&first.rows, &last.rows, up, n);
+    let body = Movers {
+        window,
+        direction,
+        from: Some(Compared {
+            id: Some(first.id),
+            taken_at: rfc3339_secs(first.taken_at),
+        }),
+        to: Some(Compared {
+            id: Some(last.id),
+            taken_at: rfc3339_secs(last.taken_at),
+        }),
+        partial,
+        by_visitors,
+        by_percent,
+    };
+    Json(body).into_response()
+}
+
+/// `window`, such as `24h`, in seconds.
+fn window_secs(window: &str) -> Option<i64> {
+    let unit = match window.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: i64 = window[..window.len() - 1].parse().ok()?;
+    (count > 0).then_some(count).and_then(|count| count.checked_mul(unit))
+}
+
+/// The top `n` artists by visitors and by percentage gained going from
+/// `before` to `after`, or lost unless `up`, matching rows by normalized,
+/// case-insensitive name.
+fn top_movers(
+    before: &[ArtistRow],
+    after: &[ArtistRow],
+    up: bool,
+    n: usize,
+) -> (Vec<Mover>, Vec<Mover>) {
+    let key = |row: &ArtistRow| plausible::normalize_name(&row.name).to_lowercase();
+    let old: HashMap<String, u64> = before.iter().map(|row| (key(row), row.visitors)).collect();
+
+    let sign = if up { 1 } else { -1 };
+    let rows: Vec<Mover> = after
+        .iter()
+        .map(|row| {
+            let previous = old.get(&key(row)).copied();
+            let previous_visitors = previous.unwrap_or(0);
+            let growth = row.visitors as i64 - previous_visitors as i64;
+            Mover {
+                name: row.name.clone(),
+                visitors: row.visitors,
+                previous_visitors,
+                growth,
+                growth_pct: previous.filter(|&visitors| visitors > 0).map(|visitors| {
+                    (growth as f64 * 100.0 / visitors as f64 * 10.0).round() / 10.0
+                }),
+                new: previous.is_none(),
+            }
+        })
+        .filter(|row| row.growth * sign > 0)
+        .collect();
+
+    let mut by_visitors = rows.clone();
+    by_visitors.sort_by_key(|row| {
+        (std::cmp::Reverse(row.growth.abs()), std::cmp::Reverse(row.visitors))
+    });
+    by_visitors.truncate(n);
+
+    let mut by_percent: Vec<Mover> =
+        rows.into_iter().filter(|row| row.growth_pct.is_some()).collect();
+    by_percent.sort_by(|a, b| {
+        let pct = |row: &Mover| row.growth_pct.unwrap_or_default().abs();
+        pct(b).total_cmp(&pct(a)).then(b.visitors.cmp(&a.visitors))
+    });
+    by_percent.truncate(n);
+    (by_visitors, by_percent)
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    chrono::DateTime::<chrono::Utc>::from(time).timestamp()
+}
+
+pub fn rfc3339_secs(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0).unwrap_or_default().to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mock::MockFetcher;
+    use crate::params::default_site;
+    use crate::upstream::Site;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn row(name: &str, visitors: u64, events: u64) -> ArtistRow {
+        let row = serde_json::json!({ "name": name, "visitors": visitors, "events": events });
+        serde_json::from_value(row).unwrap()
+    }
+
+    fn names(changes: &[Change]) -> Vec<&str> {
+        changes.iter().map(|change| change.name.as_str()).collect()
+    }
+
+    /// State with a fresh history database under the system temp dir,
+    /// answering from the sample fixture.
+    async fn state(name: &str, config: Config) -> AppState {
+        let db = format!("plausible-proxy-history-{}-{}.db", std::process::id(), name);
+        let db = std::env::temp_dir().join(db);
+        let _ = std::fs::remove_file(&db);
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/sample.json");
+        let config = Config {
+            sites: vec![Site {
+                key: "default".to_string(),
+                id: "example.com".to_string(),
+                bearer_token: None,
+                goal: "Artist Click".to_string(),
+                property: "name".to_string(),
+            }],
+            history_db: Some(db),
+            ..config
+        };
+        let fetcher = MockFetcher::new(&[fixture]).unwrap();
+        AppState::new(config, Arc::new(fetcher)).await.unwrap()
+    }
+
+    fn site(state: &AppState) -> SelectedSite {
+        SelectedSite(default_site(state))
+    }
+
+    /// Records `rows` as the default site's leaderboard at `secs`.
+    fn record(state: &AppState, secs: i64, rows: &[ArtistRow]) {
+        let history = state.history.as_ref().unwrap();
+        let taken_at = UNIX_EPOCH + Duration::from_secs(secs as u64);
+        assert!(history.record("default", taken_at, Duration::ZERO, rows).unwrap());
+    }
+
+    async fn json(response: Response) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn changes_between_identical_boards_are_empty() {
+        let board = [row("Drake", 10, 12), row("SZA", 5, 6)];
+        let (added, removed, changed) = leaderboard_changes(&board, &board);
+        assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+
+        // Names match however they are spaced or cased.
+        let respelled = [row("drake", 10, 12), row("sza ", 5, 6)];
+        let (added, removed, changed) = leaderboard_changes(&board, &respelled);
+        assert!(added.is_empty() && removed.is_empty() && changed.is_empty());
+    }
+
+    #[test]
+    fn changes_from_or_to_an_empty_board_add_or_remove_everything() {
+        let board = [row("Drake", 10, 12), row("SZA", 5, 6)];
+
+        let (added, removed, changed) = leaderboard_changes(&[], &board);
+        assert_eq!(names(&added), ["Drake", "SZA"]);
+        assert_eq!(added[1].rank, Some(2));
+        assert_eq!(added[1].visitor_delta, 5);
+        assert!(removed.is_empty() && changed.is_empty());
+
+        let (added, removed, changed) = leaderboard_changes(&board, &[]);
+        assert_eq!(names(&removed), ["Drake", "SZA"]);
+        assert_eq!(removed[1].previous_rank, Some(2));
+        assert_eq!(removed[1].visitor_delta, -5);
+        assert!(added.is_empty() && changed.is_empty());
+    }
+
+    #[test]
+    fn changes_report_rank_and_visitor_deltas() {
+        let before = [row("Drake", 10, 12), row("SZA", 5, 6), row("Tame Impala", 3, 3)];
+        let after = [row("SZA", 12, 14), row("Drake", 11, 13), row("Tame Impala", 4, 4)];
+        let (added, removed, changed) = leaderboard_changes(&before, &after);
+        assert!(added.is_empty() && removed.is_empty());
+        assert_eq!(names(&changed), ["SZA", "Drake", "Tame Impala"]);
+        assert_eq!((changed[0].rank_delta, changed[0].visitor_delta), (Some(1), 7));
+        assert_eq!((changed[1].rank_delta, changed[1].visitor_delta), (Some(-1), 1));
+        // Same rank, more visitors.
+        assert_eq!((changed[2].rank_delta, changed[2].visitor_delta), (Some(0), 1));
+    }
+
+    #[tokio::test]
+    async fn changes_with_one_snapshot_against_it_are_empty() {
+        let state = state("changes-one", Config::default()).await;
+        let since = |since: &str| {
+            let params = ChangesParams {
+                since: Some(since.to_string()),
+            };
+            changes(State(state.clone()), site(&state), Query(params), Default::default())
+        };
+
+        // The request fetches the leaderboard, which records the only
+        // snapshot: nothing older is held.
+        let (status, body) = json(since("2000-01-01T00:00:00Z").await).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["error"]["code"], "history_expired");
+        assert!(body["error"]["oldest_available"].is_string(), "{}", body);
+
+        let (status, body) = json(since("1").await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["since"]["id"], 1);
+        for list in ["added", "removed", "changed"] {
+            assert_eq!(body[list], serde_json::json!([]), "{}", list);
+        }
+
+        let (status, body) = json(since("2").await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "snapshot_not_found");
+    }
+
+    /// 2025-11-02T22:30Z, 23:30 on the Sunday before ISO week 45 at +01:00.
+    const SUNDAY_NIGHT: i64 = 1_762_122_600;
+    /// 2025-11-02T23:30Z, half past midnight starting the week at +01:00,
+    /// but still the Sunday in UTC.
+    const MONDAY_AT_ONE: i64 = 1_762_126_200;
+    /// 2025-11-05T12:00Z, midweek.
+    const WEDNESDAY: i64 = 1_762_344_000;
+
+    async fn week_45(state: &AppState) -> (StatusCode, serde_json::Value) {
+        let params = DigestParams {
+            week: Some("2025-W45".to_string()),
+            limit: None,
+        };
+        json(digest(State(state.clone()), site(state), Query(params)).await).await
+    }
+
+    #[test]
+    fn weeks_start_at_midnight_in_the_zone() {
+        let plus_one = chrono::FixedOffset::east_opt(3600).unwrap();
+        let (week, start, end) = week_bounds(" 2025-W45 ", &plus_one).unwrap();
+        assert_eq!(week, "2025-W45");
+        assert_eq!(start.to_rfc3339(), "2025-11-03T00:00:00+01:00");
+        assert_eq!(end.to_rfc3339(), "2025-11-10T00:00:00+01:00");
+        for invalid in ["2025-W54", "2025-45", "W45", "2025-Wxx"] {
+            assert!(week_bounds(invalid, &plus_one).is_none(), "{}", invalid);
+        }
+
+        assert_eq!(iso_week(SUNDAY_NIGHT, &plus_one), "2025-W44");
+        assert_eq!(iso_week(MONDAY_AT_ONE, &plus_one), "2025-W45");
+        assert_eq!(iso_week(MONDAY_AT_ONE, &chrono::Utc), "2025-W44");
+    }
+
+    #[test]
+    fn digest_ranks_gainers_climbers_and_entrants() {
+        let before = [row("Drake", 10, 100), row("SZA", 8, 50), row("Tame Impala", 5, 40)];
+        let after = [
+            row("SZA", 12, 90),
+            row("Drake", 11, 110),
+            row("Tame Impala", 9, 80),
+            row("Doechii", 7, 30),
+        ];
+        let (gainers, climbers, entrants) = week_digest(&before, &after, 10);
+        let names = |rows: &[WeeklyDigestRow]| -> Vec<String> {
+            rows.iter().map(|row| row.name.clone()).collect()
+        };
+        // SZA and Tame Impala both gained 40 clicks; the higher rank first.
+        assert_eq!(names(&gainers), ["SZA", "Tame Impala", "Doechii", "Drake"]);
+        assert_eq!(names(&climbers), ["SZA"]);
+        assert_eq!(names(&entrants), ["Doechii"]);
+        assert_eq!(entrants[0].previous_rank, None);
+
+        let (gainers, _, _) = week_digest(&before, &after, 1);
+        assert_eq!(names(&gainers), ["SZA"]);
+    }
+
+    #[tokio::test]
+    async fn digest_needs_two_snapshots() {
+        let state = state("digest-few", Config::default()).await;
+        let board = [row("Drake", 10, 100)];
+
+        let (status, body) = week_45(&state).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "insufficient_history");
+        assert!(body["error"]["oldest_available"].is_null(), "{}", body);
+
+        record(&state, WEDNESDAY, &board);
+        let (status, body) = week_45(&state).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"]["oldest_available"].is_null(), "{}", body);
+
+        // A second snapshot a week later makes that week the first served.
+        record(&state, WEDNESDAY + 7 * 24 * 60 * 60, &board);
+        let (status, body) = week_45(&state).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["oldest_available"], "2025-W46");
+    }
+
+    #[tokio::test]
+    async fn digest_week_starts_at_midnight_in_digest_utc_offset() {
+        for (offset, start, week_start) in [
+            ("+01:00", SUNDAY_NIGHT, "2025-11-03T00:00:00+01:00"),
+            ("UTC", MONDAY_AT_ONE, "2025-11-03T00:00:00+00:00"),
+        ] {
+            let config = Config {
+                digest_utc_offset: offset.to_string(),
+                ..Config::default()
+            };
+            let state = state(&format!("digest-{}", offset), config).await;
+            record(&state, SUNDAY_NIGHT, &[row("Drake", 10, 100)]);
+            record(&state, MONDAY_AT_ONE, &[row("Drake", 11, 120)]);
+            record(&state, WEDNESDAY, &[row("Drake", 12, 150)]);
+
+            let (status, body) = week_45(&state).await;
+            assert_eq!(status, StatusCode::OK, "{}", offset);
+            assert_eq!(body["week_start"], week_start, "{}", offset);
+            // The last snapshot taken by the week's start is its baseline.
+            assert_eq!(body["start"]["taken_at"], rfc3339_secs(start), "{}", offset);
+            assert_eq!(body["end"]["taken_at"], rfc3339_secs(WEDNESDAY), "{}", offset);
+        }
+    }
+
+    async fn movers_over(state: &AppState, window: &str) -> (StatusCode, serde_json::Value) {
+        let params = MoversParams {
+            window: Some(window.to_string()),
+            n: None,
+            direction: None,
+        };
+        json(movers(State(state.clone()), site(state), Query(params)).await).await
+    }
+
+    fn mover_names(movers: &[Mover]) -> Vec<&str> {
+        movers.iter().map(|mover| mover.name.as_str()).collect()
+    }
+
+    #[test]
+    fn windows_are_a_count_and_a_unit() {
+        assert_eq!(window_secs("90m"), Some(90 * 60));
+        assert_eq!(window_secs("24h"), Some(24 * 60 * 60));
+        assert_eq!(window_secs("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(window_secs("2w"), Some(14 * 24 * 60 * 60));
+        for invalid in ["", "h", "0h", "-1d", "24", "1y", "1.5h", "99999999999999999w"] {
+            assert_eq!(window_secs(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn movers_tied_on_growth_go_by_visitors() {
+        let before = [
+            row("Drake", 100, 0),
+            row("SZA", 50, 0),
+            row("Frank Ocean", 200, 0),
+            row("Tame Impala", 25, 0),
+        ];
+        let after = [
+            row("Frank Ocean", 300, 0),
+            row("Drake", 150, 0),
+            row("SZA", 100, 0),
+            row("Doechii", 50, 0),
+            row("Tame Impala", 20, 0),
+        ];
+        let (by_visitors, by_percent) = top_movers(&before, &after, true, 10);
+        // Drake, SZA and Doechii all gained 50.
+        assert_eq!(mover_names(&by_visitors), ["Frank Ocean", "Drake", "SZA", "Doechii"]);
+        // Frank Ocean and Drake both grew 50%; Doechii is new, so has no percentage.
+        assert_eq!(mover_names(&by_percent), ["SZA", "Frank Ocean", "Drake"]);
+        assert!(by_visitors[3].new);
+        assert_eq!(by_percent[0].growth_pct, Some(100.0));
+
+        let (by_visitors, by_percent) = top_movers(&before, &after, true, 2);
+        assert_eq!(mover_names(&by_visitors), ["Frank Ocean", "Drake"]);
+        assert_eq!(mover_names(&by_percent), ["SZA", "Frank Ocean"]);
+
+        let (by_visitors, by_percent) = top_movers(&before, &after, false, 10);
+        assert_eq!(mover_names(&by_visitors), ["Tame Impala"]);
+        assert_eq!(by_visitors[0].growth, -5);
+        assert_eq!(by_percent[0].growth_pct, Some(-20.0));
+    }
+
+    #[tokio::test]
+    async fn movers_need_two_snapshots() {
+        let state = state("movers-few", Config::default()).await;
+        for recorded in [false, true] {
+            if recorded {
+                record(&state, WEDNESDAY, &[row("Drake", 100, 0)]);
+            }
+            let (status, body) = movers_over(&state, "24h").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["partial"], true, "{}", recorded);
+            assert!(body.get("from").is_none() && body.get("to").is_none(), "{}", body);
+            assert_eq!(body["by_visitors"], serde_json::json!([]));
+            assert_eq!(body["by_percent"], serde_json::json!([]));
+        }
+    }
+
+    #[tokio::test]
+    async fn movers_fall_back_to_the_oldest_snapshot() {
+        let state = state("movers-window", Config::default()).await;
+        record(&state, WEDNESDAY - 2 * 60 * 60, &[row("Drake", 100, 0)]);
+        record(&state, WEDNESDAY - 60 * 60, &[row("Drake", 120, 0)]);
+        record(&state, WEDNESDAY, &[row("Drake", 150, 0)]);
+
+        let (_, body) = movers_over(&state, "1h").await;
+        assert_eq!(body["partial"], false);
+        assert_eq!(body["from"]["taken_at"], rfc3339_secs(WEDNESDAY - 60 * 60));
+        assert_eq!(body["by_visitors"][0]["growth"], 30);
+
+        let (_, body) = movers_over(&state, "1d").await;
+        assert_eq!(body["partial"], true);
+        assert_eq!(body["from"]["taken_at"], rfc3339_secs(WEDNESDAY - 2 * 60 * 60));
+        assert_eq!(body["to"]["taken_at"], rfc3339_secs(WEDNESDAY));
+        assert_eq!(body["by_visitors"][0]["growth"], 50);
+    }
+}
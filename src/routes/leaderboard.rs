@@ -0,0 +1,745 @@
+use crate::cache::{CacheEntry, CacheKey, CacheStatus};
+use crate::error::{ApiError, FetchError};
+use crate::params::{
+    FieldsParams, FilterParams, Format, FormatParams, LeaderboardParams, PagingParams, RangeParams,
+    SearchParams, SelectedSite, SortParams, View, ViewParams,
+};
+use crate::plausible::{ArtistRow, Movement};
+use crate::refresh::{lookup, ttl};
+use crate::respond::{
+    add_movement, at_least, cached_response, compared, error_response, etag_matches,
+    fallback_response, leaderboard, project_results, render_json, render_response,
+    truncate_results,
+};
+use crate::routes::breakdowns::{
+    breakdown_items, breakdown_rows, country_rows, BreakdownItem, Realtime,
+};
+use crate::upstream::{Breakdown, Period, Site, UpstreamQuery};
+use crate::{
+    artists_xml, cache, export, finish_view, negotiated, project, ranked, ranked_rows, xml,
+    AppState, RankedRow, CSV, JSON, XML, X_CACHE,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{HeaderValue, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use utoipa::{IntoParams, ToSchema};
+
+/// Serves the cached leaderboard, as JSON, MessagePack or an `<artists>` XML
+/// document.
+/// `HEAD` is routed here too; axum strips the body, leaving the validators
+/// and cache headers intact.
+/// Until the first fetch succeeds, a failure with nothing cached serves
+/// `fallback_file` with `X-Fallback: true` instead of an error, when set.
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "leaderboard",
+    params(
+        LeaderboardParams,
+        RangeParams,
+        FilterParams,
+        ViewParams,
+        FieldsParams,
+        SortParams,
+        SearchParams,
+        PagingParams,
+        FormatParams,
+    ),
+    responses(
+        (status = 200, description = "Artists by visitors", body = PlausibleResponse),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn handler(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(range): Query<RangeParams>,
+    Query(filter): Query<FilterParams>,
+    Query(view): Query<ViewParams>,
+    Query(fields): Query<FieldsParams>,
+    Query(sort): Query<SortParams>,
+    Query(search): Query<SearchParams>,
+    Query(paging): Query<PagingParams>,
+    Query(format): Query<FormatParams>,
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (fields, sort, search, paging) =
+        match (fields.fields(), sort.sort(), search.search(), paging.paging()) {
+            (Ok(fields), Ok(sort), Ok(search), Ok(paging)) => (fields, sort, search, paging),
+            (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+                return e.into_response()
+            }
+        };
+    let format = match format.format(&headers) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    let view = view.view(&state, &headers);
+    let query = range.leaderboard(&state, &site, &params);
+    let (query, limit, min_visitors, view) =
+        match (query, params.limit(), filter.min_visitors(), view) {
+            (Ok((query, _)), Ok(limit), Ok(min_visitors), Ok(view)) => {
+                (query, limit, min_visitors, view)
+            }
+            (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+                return e.into_response()
+            }
+        };
+
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return fallback_response(&state).unwrap_or_else(|| error_response(e)),
+    };
+
+    let mut board = at_least(leaderboard(&state, &entry, view), min_visitors);
+    let compared_to = add_movement(&state, &query, view, min_visitors, &mut board);
+    if format == Format::Xml {
+        let rows = ranked_rows(&board, search.as_ref(), sort.as_ref(), limit);
+        let total = rows.len();
+        let page: Vec<RankedRow> = rows[paging.map_or(0..total, |paging| paging.rows(total))]
+            .iter()
+            .map(|&(rank, row)| RankedRow { rank, row })
+            .collect();
+        let body = artists_xml(&page, fields.as_deref());
+        let mut response = render_response(&state, &entry, status, &headers, body, XML);
+        if let Some(paging) = paging {
+            paging.add_headers(&mut response, &uri, total);
+        }
+        return negotiated(finish_view(view, compared(response, compared_to)));
+    }
+    if let Some(search) = search {
+        board.to_mut().results.retain(|row| search.matches(&row.name));
+    }
+    if let Some(sort) = sort {
+        board.to_mut().results.sort_by(|a, b| sort.compare(a, b));
+    }
+    let total = board.results.len().min(limit.unwrap_or(usize::MAX));
+    let rows = paging.map_or(0..total, |paging| paging.rows(total));
+    let unchanged = !view.pretty && paging.is_none() && format == Format::Json;
+    let mut response = match (limit, &board, &fields) {
+        // Nothing to change: serve the upstream body as is.
+        (None, Cow::Borrowed(_), None) if unchanged => {
+            cached_response(&state, &entry, status, &headers)
+        }
+        (_, board, fields) => {
+            let board = truncate_results(board, rows);
+            match fields {
+                None => render_json(&state, &entry, status, &headers, view, &board, format),
+                Some(fields) => {
+                    let body = project_results(&board, fields);
+                    render_json(&state, &entry, status, &headers, view, &body, format)
+                }
+            }
+        }
+    };
+    if let Some(paging) = paging {
+        paging.add_headers(&mut response, &uri, total);
+    }
+    negotiated(finish_view(view, compared(response, compared_to)))
+}
+
+/// The leaderboard as a CSV download, honoring the same `period`, `limit`
+/// and `min_visitors` parameters as the JSON route.
+#[utoipa::path(
+    get,
+    path = "/stats.csv",
+    tag = "leaderboard",
+    params(
+        LeaderboardParams,
+        RangeParams,
+        FilterParams,
+        ViewParams,
+        SortParams,
+        SearchParams,
+        PagingParams,
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The leaderboard as CSV",
+            body = String,
+            content_type = "text/csv",
+        ),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn stats_csv(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(range): Query<RangeParams>,
+    Query(filter): Query<FilterParams>,
+    Query(view): Query<ViewParams>,
+    Query(sort): Query<SortParams>,
+    Query(search): Query<SearchParams>,
+    Query(paging): Query<PagingParams>,
+    uri: axum::http::Uri,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (sort, search, paging) = match (sort.sort(), search.search(), paging.paging()) {
+        (Ok(sort), Ok(search), Ok(paging)) => (sort, search, paging),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+    };
+    let view = view.view(&state, &headers);
+    let query = range.leaderboard(&state, &site, &params);
+    let (query, limit, min_visitors, view) =
+        match (query, params.limit(), filter.min_visitors(), view) {
+            (Ok((query, _)), Ok(limit), Ok(min_visitors), Ok(view)) => {
+                (query, limit, min_visitors, view)
+            }
+            (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+                return e.into_response()
+            }
+        };
+
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let mut board = at_least(leaderboard(&state, &entry, view), min_visitors);
+    let compared_to = add_movement(&state, &query, view, min_visitors, &mut board);
+    let rows = ranked_rows(&board, search.as_ref(), sort.as_ref(), limit);
+    let total = rows.len();
+    let page = &rows[paging.map_or(0..total, |paging| paging.rows(total))];
+    let mut response = render_response(
+        &state,
+        &entry,
+        status,
+        &headers,
+        export::csv(page),
+        CSV,
+    );
+    if let Some(paging) = paging {
+        paging.add_headers(&mut response, &uri, total);
+    }
+
+    let filename = format!(
+        "attachment; filename=\"artistgrid-stats-{}.csv\"",
+        state.config().today().format("%Y-%m-%d")
+    );
+    response.headers_mut().insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&filename).expect("filename is ASCII"),
+    );
+    finish_view(view, compared(response, compared_to))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct PropPath {
+    /// The site's own property or one of `props`.
+    prop: String,
+}
+
+/// The leaderboard of another custom property than the artist one, such
+/// as `album`, with its rows as the upstream sends them: artist aliases and
+/// exclusions don't apply.
+#[utoipa::path(
+    get,
+    path = "/prop/{prop}",
+    tag = "leaderboard",
+    params(PropPath, LeaderboardParams, RangeParams, FieldsParams),
+    responses(
+        (status = 200, description = "Values by visitors", body = PlausibleResponse),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn prop(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(PropPath { prop }): Path<PropPath>,
+    Query(params): Query<LeaderboardParams>,
+    Query(range): Query<RangeParams>,
+    Query(fields): Query<FieldsParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let config = state.config();
+    if prop != site.property && !config.props.contains(&prop) {
+        let mut accepted = vec![site.property.as_str()];
+        accepted.extend(config.props.iter().map(String::as_str));
+        return ApiError::invalid_param("prop", &accepted).into_response();
+    }
+    let site = Site {
+        property: prop,
+        ..site
+    };
+    let query = range.leaderboard(&state, &site, &params);
+    let (query, limit, fields) = match (query, params.limit(), fields.fields()) {
+        (Ok((query, _)), Ok(limit), Ok(fields)) => (query, limit, fields),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+    };
+
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    match (limit, fields) {
+        (None, None) => cached_response(&state, &entry, status, &headers),
+        (limit, fields) => {
+            let board = entry
+                .payload
+                .leaderboard()
+                .expect("leaderboard queries cache leaderboard payloads");
+            let rows = 0..board.results.len().min(limit.unwrap_or(usize::MAX));
+            let board = truncate_results(board, rows);
+            let body = match fields {
+                None => serde_json::to_string(&board).expect("PlausibleResponse serializes"),
+                Some(fields) => project_results(&board, &fields).to_string(),
+            };
+            render_response(&state, &entry, status, &headers, body, JSON)
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+pub struct TopPath {
+    /// How many artists, from 1 to `top_max`.
+    #[param(value_type = u32, minimum = 1)]
+    n: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TopRow<'a> {
+    rank: usize,
+    name: &'a str,
+    visitors: u64,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Movement>)]
+    movement: Option<&'a Movement>,
+}
+
+/// Slim `{rank, name, visitors}` rows for the top `n` artists, ordered by
+/// visitors regardless of upstream ordering, as JSON, MessagePack or an
+/// `<artists>` XML document.
+#[utoipa::path(
+    get,
+    path = "/top/{n}",
+    tag = "leaderboard",
+    params(
+        TopPath,
+        FilterParams,
+        ViewParams,
+        FieldsParams,
+        SortParams,
+        SearchParams,
+        FormatParams,
+    ),
+    responses(
+        (status = 200, description = "The top `n` artists", body = [TopRow]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn top(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Path(TopPath { n }): Path<TopPath>,
+    Query(filter): Query<FilterParams>,
+    Query(view): Query<ViewParams>,
+    Query(fields): Query<FieldsParams>,
+    Query(sort): Query<SortParams>,
+    Query(search): Query<SearchParams>,
+    Query(format): Query<FormatParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (fields, sort, search) = match (fields.fields(), sort.sort(), search.search()) {
+        (Ok(fields), Ok(sort), Ok(search)) => (fields, sort, search),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+    };
+    let format = match format.format(&headers) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    let (min_visitors, view) = match (filter.min_visitors(), view.view(&state, &headers)) {
+        (Ok(min_visitors), Ok(view)) => (min_visitors, view),
+        (Err(e), _) | (_, Err(e)) => return e.into_response(),
+    };
+
+    let n = match n.parse::<usize>() {
+        Ok(n) if (1..=state.config().top_max).contains(&n) => n,
+        _ => {
+            let accepted = format!("an integer from 1 to {}", state.config().top_max);
+            return ApiError::invalid_param("n", &[&accepted]).into_response();
+        }
+    };
+
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let mut board = at_least(leaderboard(&state, &entry, view), min_visitors);
+    let compared_to = add_movement(&state, &query, view, min_visitors, &mut board);
+    let mut rows: Vec<_> = board.results.iter().collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.visitors));
+
+    let mut ranked = ranked(rows);
+    if let Some(search) = search {
+        ranked.retain(|(_, row)| search.matches(&row.name));
+    }
+    ranked.truncate(n);
+    if let Some(sort) = sort {
+        ranked.sort_by(|(_, a), (_, b)| sort.compare(a, b));
+    }
+
+    let top: Vec<TopRow> = ranked
+        .into_iter()
+        .map(|(rank, row)| TopRow {
+            rank,
+            name: &row.name,
+            visitors: row.visitors,
+            movement: row.movement.as_ref(),
+        })
+        .collect();
+
+    let response = match (format, fields) {
+        (Format::Xml, fields) => {
+            let body = artists_xml(&top, fields.as_deref());
+            render_response(&state, &entry, status, &headers, body, XML)
+        }
+        (format, None) => render_json(&state, &entry, status, &headers, view, &top, format),
+        (format, Some(fields)) => {
+            let mut rows = serde_json::to_value(&top).expect("TopRow serializes");
+            if let Some(rows) = rows.as_array_mut() {
+                rows.iter_mut().for_each(|row| project(row, &fields));
+            }
+            render_json(&state, &entry, status, &headers, view, &rows, format)
+        }
+    };
+    negotiated(finish_view(view, compared(response, compared_to)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Summary<'a> {
+    pub total_visitors: u64,
+    pub total_events: u64,
+    pub artist_count: usize,
+    pub top_artist: Option<&'a str>,
+    /// When the leaderboard behind the totals was fetched upstream.
+    pub generated_at: String,
+}
+
+impl<'a> Summary<'a> {
+    /// The totals of `rows`, from `entry`'s leaderboard.
+    pub fn of(rows: &'a [ArtistRow], entry: &CacheEntry) -> Self {
+        Summary {
+            total_visitors: rows.iter().map(|row| row.visitors).sum(),
+            total_events: rows.iter().map(|row| row.events).sum(),
+            artist_count: rows.len(),
+            top_artist: rows
+                .iter()
+                .min_by_key(|row| std::cmp::Reverse(row.visitors))
+                .map(|row| row.name.as_str()),
+            generated_at: chrono::DateTime::<chrono::Utc>::from(entry.fetched_at).to_rfc3339(),
+        }
+    }
+}
+
+/// Totals across the whole leaderboard for `period`, computed from the same
+/// cache entry the list routes serve, as JSON, MessagePack or a `<summary>`
+/// XML document.
+#[utoipa::path(
+    get,
+    path = "/summary",
+    tag = "leaderboard",
+    params(LeaderboardParams, RangeParams, ViewParams, FormatParams),
+    responses(
+        (status = 200, description = "Leaderboard totals", body = Summary),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorResponse),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn summary(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<LeaderboardParams>,
+    Query(range): Query<RangeParams>,
+    Query(view): Query<ViewParams>,
+    Query(format): Query<FormatParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let view = view.view(&state, &headers);
+    let query = range.leaderboard(&state, &site, &params);
+    let (query, view, format) = match (query, view, format.format(&headers)) {
+        (Ok((query, _)), Ok(view), Ok(format)) => (query, view, format),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+    };
+
+    let (entry, status) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let board = leaderboard(&state, &entry, view);
+    let summary = Summary::of(&board.results, &entry);
+    let response = match format {
+        Format::Xml => {
+            let body = xml::render(&summary, "summary", "item");
+            render_response(&state, &entry, status, &headers, body, XML)
+        }
+        format => render_json(&state, &entry, status, &headers, view, &summary, format),
+    };
+    negotiated(finish_view(view, response))
+}
+
+/// The sections `/bundle` can include.
+const SECTIONS: [&str; 6] = ["leaderboard", "summary", "realtime", "country", "device", "browser"];
+/// What `/bundle` includes without `include`.
+const DEFAULT_SECTIONS: &str = "leaderboard,summary,realtime";
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BundleParams {
+    /// Comma-separated sections: `leaderboard`, `summary`, `realtime`,
+    /// `country`, `device` or `browser`. Defaults to
+    /// `leaderboard,summary,realtime`.
+    include: Option<String>,
+}
+
+impl BundleParams {
+    fn sections(&self) -> Result<Vec<&str>, ApiError> {
+        let mut sections = Vec::new();
+        for section in self.include.as_deref().unwrap_or(DEFAULT_SECTIONS).split(',') {
+            let section = section.trim();
+            if !SECTIONS.contains(&section) {
+                return Err(ApiError::invalid_param("include", &SECTIONS));
+            }
+            if !sections.contains(&section) {
+                sections.push(section);
+            }
+        }
+        Ok(sections)
+    }
+}
+
+/// Each section holds what its own route would answer, or
+/// `{"error": {...}}` when that has no data to answer with.
+#[derive(Serialize, ToSchema)]
+pub struct Bundle {
+    /// When the newest data in the bundle was fetched upstream.
+    generated_at: String,
+    #[schema(value_type = Period)]
+    period: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<PlausibleResponse>)]
+    leaderboard: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Summary>)]
+    summary: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Realtime>)]
+    realtime: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<BreakdownItem>>)]
+    country: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<BreakdownItem>>)]
+    device: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<BreakdownItem>>)]
+    browser: Option<serde_json::Value>,
+}
+
+/// Several routes' answers in one, built from the cache as it stood at a
+/// single moment, so a refresh landing halfway through can't leave the
+/// sections disagreeing. The leaderboard and summary come from the same
+/// entry. A section whose data can't be had carries the error instead,
+/// and keeps the bundle out of shared caches; the rest are still served.
+#[utoipa::path(
+    get,
+    path = "/bundle",
+    tag = "leaderboard",
+    params(BundleParams, LeaderboardParams),
+    responses(
+        (status = 200, description = "The sections asked for", body = Bundle),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameter", body = ErrorResponse),
+    ),
+)]
+pub async fn bundle(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    Query(params): Query<BundleParams>,
+    Query(board_params): Query<LeaderboardParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let (sections, period, limit) =
+        match (params.sections(), board_params.period(), board_params.limit()) {
+            (Ok(sections), Ok(period), Ok(limit)) => (sections, period, limit),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return e.into_response(),
+        };
+
+    let query = |section: &str| match section {
+        "leaderboard" | "summary" => UpstreamQuery::leaderboard(&site, period),
+        "realtime" => UpstreamQuery::realtime(&site),
+        "country" => UpstreamQuery::breakdown(&site, period, Breakdown::Country),
+        "device" => UpstreamQuery::breakdown(&site, period, Breakdown::Device),
+        "browser" => UpstreamQuery::breakdown(&site, period, Breakdown::Browser),
+        _ => unreachable!("sections are validated"),
+    };
+    let mut queries: Vec<UpstreamQuery> = Vec::new();
+    for query in sections.iter().map(|section| query(section)) {
+        if !queries.iter().any(|known| known.cache_key() == query.cache_key()) {
+            queries.push(query);
+        }
+    }
+
+    // Fetch whatever is missing or expired first, then take every entry
+    // under one read of the cache.
+    let looked_up =
+        futures_util::future::join_all(queries.iter().map(|query| lookup(&state, query))).await;
+    let found: HashMap<CacheKey, Result<(Arc<CacheEntry>, CacheStatus), FetchError>> = {
+        let cache = state.cache.read().await;
+        queries
+            .iter()
+            .zip(looked_up)
+            .map(|(query, result)| {
+                let key = query.cache_key();
+                let result = match (cache.get(&key), result) {
+                    (Some(entry), Ok((_, status))) => Ok((entry, status)),
+                    (Some(entry), Err(_)) => Ok((entry, CacheStatus::Stale)),
+                    // Too big to cache, but fetched just now.
+                    (None, result) => result,
+                };
+                (key, result)
+            })
+            .collect()
+    };
+
+    let view = View::PUBLIC;
+    let mut body = Bundle {
+        generated_at: String::new(),
+        period: period.as_str(),
+        leaderboard: None,
+        summary: None,
+        realtime: None,
+        country: None,
+        device: None,
+        browser: None,
+    };
+    for section in &sections {
+        let value = match &found[&query(section).cache_key()] {
+            Err(e) => serde_json::json!({ "error": ApiError::from(e.clone()).body() }),
+            Ok((entry, status)) => match *section {
+                "leaderboard" => {
+                    let board = leaderboard(&state, entry, view);
+                    let rows = board.results.len().min(limit.unwrap_or(usize::MAX));
+                    serde_json::to_value(truncate_results(&board, 0..rows))
+                        .expect("PlausibleResponse serializes")
+                }
+                "summary" => {
+                    let board = leaderboard(&state, entry, view);
+                    serde_json::to_value(Summary::of(&board.results, entry))
+                        .expect("Summary serializes")
+                }
+                "realtime" => serde_json::to_value(Realtime {
+                    current_visitors: entry
+                        .payload
+                        .realtime()
+                        .expect("realtime queries cache counts"),
+                    stale: *status == CacheStatus::Stale,
+                })
+                .expect("Realtime serializes"),
+                breakdown => {
+                    let rows = match breakdown {
+                        "country" => country_rows(entry),
+                        _ => breakdown_rows(entry)
+                            .into_iter()
+                            .map(|row| BreakdownItem::new(&row.value, row.visitors, row.events))
+                            .collect(),
+                    };
+                    serde_json::to_value(breakdown_items(&state, rows, None, limit))
+                        .expect("BreakdownItem serializes")
+                }
+            },
+        };
+        let slot = match *section {
+            "leaderboard" => &mut body.leaderboard,
+            "summary" => &mut body.summary,
+            "realtime" => &mut body.realtime,
+            "country" => &mut body.country,
+            "device" => &mut body.device,
+            _ => &mut body.browser,
+        };
+        *slot = Some(value);
+    }
+
+    let entries: Vec<(&Arc<CacheEntry>, CacheStatus)> = found
+        .values()
+        .filter_map(|result| result.as_ref().ok())
+        .map(|(entry, status)| (entry, *status))
+        .collect();
+    let newest = entries.iter().map(|(entry, _)| entry.fetched_at).max();
+    let generated_at = newest.unwrap_or_else(SystemTime::now);
+    body.generated_at = chrono::DateTime::<chrono::Utc>::from(generated_at).to_rfc3339();
+    let body = serde_json::to_string(&body).expect("Bundle serializes");
+
+    // Described by the entry that goes stale first, and as stale as the
+    // stalest.
+    let remaining =
+        |entry: &CacheEntry| ttl(&state, entry).saturating_sub(entry.timestamp.elapsed());
+    let Some((entry, _)) = entries.iter().min_by_key(|(entry, _)| remaining(entry)) else {
+        let etag = cache::etag(&body);
+        let mut response = if etag_matches(&headers, &etag) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            ([(CONTENT_TYPE, HeaderValue::from_static(JSON))], body).into_response()
+        };
+        let response_headers = response.headers_mut();
+        response_headers.insert(ETAG, HeaderValue::from_str(&etag).expect("ETag is ASCII"));
+        response_headers.insert(X_CACHE, HeaderValue::from_static("ERROR"));
+        response_headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        return response;
+    };
+    let status = [CacheStatus::Stale, CacheStatus::Miss]
+        .into_iter()
+        .find(|worse| entries.iter().any(|(_, status)| status == worse))
+        .unwrap_or(CacheStatus::Hit);
+    let mut response = render_response(&state, entry, status, &headers, body, JSON);
+    if entries.len() < found.len() {
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    response
+}
@@ -0,0 +1,311 @@
+use crate::cache::{CacheEntry, CacheKey};
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::params::{SelectedSite, View};
+use crate::plausible::ArtistRow;
+use crate::refresh::lookup;
+use crate::respond::{error_response, leaderboard};
+use crate::upstream::{Period, UpstreamQuery};
+use crate::{AppState, Update, KEEP_ALIVE};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// Server-sent events with the all-time leaderboard: the current one on
+/// connect, then a new one each time a refresh changes it.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "streaming",
+    responses(
+        (
+            status = 200,
+            description = "`leaderboard` events, each with a whole leaderboard",
+            body = String,
+            content_type = "text/event-stream",
+        ),
+        (status = 502, description = "Upstream failed, nothing cached", body = ErrorResponse),
+        (status = 503, description = "Upstream circuit open, nothing cached", body = ErrorResponse),
+        (status = 504, description = "Upstream timed out, nothing cached", body = ErrorResponse),
+    ),
+)]
+pub async fn events(State(state): State<AppState>, SelectedSite(site): SelectedSite) -> Response {
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    // Subscribed before the lookup so no refresh can slip in between.
+    let updates = state.updates.subscribe();
+    let (entry, _) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    let initial = leaderboard_event(&state, &entry);
+    let shutdown = state.shutdown.subscribe();
+    let key = query.cache_key();
+    let stream = futures_util::stream::unfold(
+        (state, key, Some(initial), updates, shutdown),
+        |(state, key, mut initial, mut updates, mut shutdown)| async move {
+            if let Some(event) = initial.take() {
+                let event = Ok::<_, std::convert::Infallible>(event);
+                return Some((event, (state, key, initial, updates, shutdown)));
+            }
+            // Reconnecting gets a client the current leaderboard, so an
+            // idle stream is cheaper closed than kept.
+            let idle_timeout = state.config().stream_idle_timeout;
+            let idle = tokio::time::sleep(idle_timeout);
+            tokio::pin!(idle);
+            loop {
+                tokio::select! {
+                    update = updates.recv() => match update {
+                        Ok(update) if update.key == key => {
+                            let event = leaderboard_event(&state, &update.entry);
+                            return Some((Ok(event), (state, key, initial, updates, shutdown)));
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            tracing::info!("Dropping event stream {} updates behind", missed);
+                            return None;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = &mut idle => {
+                        tracing::info!("Closing event stream idle for {:?}", idle_timeout);
+                        return None;
+                    }
+                    _ = shutdown.changed() => return None,
+                }
+            }
+        },
+    );
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::new().interval(KEEP_ALIVE).text("keep-alive"))
+        .into_response()
+}
+
+fn leaderboard_event(state: &AppState, entry: &CacheEntry) -> Event {
+    let view = View::PUBLIC;
+    let board = leaderboard(state, entry, view);
+    Event::default()
+        .event("leaderboard")
+        .id(entry.etag.trim_matches('"'))
+        .data(serde_json::to_string(&*board).expect("PlausibleResponse serializes"))
+}
+
+/// Holds one of the `ws_max_connections` WebSocket slots until dropped,
+/// whether or not the upgrade ever completes.
+struct WebSocketSlot(Arc<Metrics>);
+
+impl Drop for WebSocketSlot {
+    fn drop(&mut self) {
+        self.0.close_websocket();
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientMessage {
+    #[serde(default)]
+    resync: bool,
+}
+
+/// Incremental all-time leaderboard updates over a WebSocket. Clients get
+/// the full leaderboard on connect and on `{"resync": true}`, and after
+/// each refresh only the rows whose visitors changed plus any artists
+/// added or removed. Every message carries a sequence number one higher
+/// than the last, so clients can tell when they missed one.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "streaming",
+    responses(
+        (status = 101, description = "Switching to a WebSocket of `full` and `delta` messages"),
+        (status = 503, description = "Too many WebSocket connections", body = ErrorResponse),
+    ),
+)]
+pub async fn ws(
+    State(state): State<AppState>,
+    SelectedSite(site): SelectedSite,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let max = u64::from(state.config().ws_max_connections);
+    let open = match state.metrics.try_open_websocket(max) {
+        Ok(open) => open,
+        Err(_) => {
+            tracing::warn!("Refusing WebSocket connection, {} already open", max);
+            return ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too_many_connections",
+                "Too many WebSocket connections",
+            )
+            .into_response();
+        }
+    };
+    let slot = WebSocketSlot(state.metrics.clone());
+
+    let query = UpstreamQuery::leaderboard(&site, Period::default());
+    let updates = state.updates.subscribe();
+    let (entry, _) = match lookup(&state, &query).await {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+
+    tracing::info!("WebSocket connected ({} open)", open);
+    upgrade.on_upgrade(move |socket| async move {
+        stream_updates(state, query.cache_key(), entry, updates, socket).await;
+        drop(slot);
+        tracing::info!("WebSocket disconnected");
+    })
+}
+
+async fn stream_updates(
+    state: AppState,
+    key: CacheKey,
+    entry: Arc<CacheEntry>,
+    mut updates: broadcast::Receiver<Update>,
+    mut socket: WebSocket,
+) {
+    let view = View::PUBLIC;
+    let rows = |entry: &CacheEntry| leaderboard(&state, entry, view).into_owned().results;
+    let mut shutdown = state.shutdown.subscribe();
+    let mut current = rows(&entry);
+    let mut seq = 0;
+
+    let full = |seq: u64, rows: &[ArtistRow]| {
+        serde_json::json!({ "type": "full", "seq": seq, "results": rows }).to_string()
+    };
+    if socket.send(Message::Text(full(seq, &current))).await.is_err() {
+        return;
+    }
+
+    // Clients answer pings on their own, so one that stays silent through
+    // several is gone or stuck.
+    let idle_timeout = state.config().stream_idle_timeout;
+    let mut ping = tokio::time::interval(KEEP_ALIVE);
+    let mut heard = Instant::now();
+    loop {
+        let message = tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) if update.key == key => {
+                    let next = rows(&update.entry);
+                    let message = leaderboard_delta(&current, &next).map(|delta| {
+                        seq += 1;
+                        delta.with_seq(seq)
+                    });
+                    current = next;
+                    match message {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // Updates were missed; start the client over from the
+                    // latest data.
+                    if let Some(entry) = state.cache.read().await.get(&key) {
+                        current = rows(&entry);
+                    }
+                    seq += 1;
+                    full(seq, &current)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            received = socket.recv() => {
+                if let Some(Ok(_)) = received {
+                    heard = Instant::now();
+                }
+                match received {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<ClientMessage>(&text) {
+                            Ok(ClientMessage { resync: true }) => {
+                                seq += 1;
+                                full(seq, &current)
+                            }
+                            _ => continue,
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            }
+            _ = ping.tick() => {
+                if heard.elapsed() >= idle_timeout {
+                    tracing::info!("Closing WebSocket idle for {:?}", idle_timeout);
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            _ = shutdown.changed() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+        };
+        if socket.send(Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// What changed between two leaderboards, or `None` if no artist's
+/// visitors did.
+pub struct Delta<'a> {
+    pub changed: Vec<&'a ArtistRow>,
+    pub added: Vec<&'a ArtistRow>,
+    pub removed: Vec<&'a str>,
+}
+
+impl Delta<'_> {
+    fn with_seq(&self, seq: u64) -> String {
+        serde_json::json!({
+            "type": "delta",
+            "seq": seq,
+            "changed": self.changed,
+            "added": self.added,
+            "removed": self.removed,
+        })
+        .to_string()
+    }
+}
+
+pub fn leaderboard_delta<'a>(before: &'a [ArtistRow], after: &'a [ArtistRow]) -> Option<Delta<'a>> {
+    let by_name = |rows: &'a [ArtistRow]| -> HashMap<String, &'a ArtistRow> {
+        rows.iter().map(|row| (row.name.to_lowercase(), row)).collect()
+    };
+    let (old, new) = (by_name(before), by_name(after));
+
+    let mut delta = Delta {
+        changed: Vec::new(),
+        added: Vec::new(),
+        removed: Vec::new(),
+    };
+    for row in after {
+        match old.get(&row.name.to_lowercase()) {
+            Some(previous) if previous.visitors != row.visitors => delta.changed.push(row),
+            Some(_) => {}
+            None => delta.added.push(row),
+        }
+    }
+    for row in before {
+        if !new.contains_key(&row.name.to_lowercase()) {
+            delta.removed.push(&row.name);
+        }
+    }
+
+    let empty = delta.changed.is_empty() && delta.added.is_empty() && delta.removed.is_empty();
+    (!empty).then_some(delta)
+}
@@ -0,0 +1,223 @@
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Tries per upload, the first included.
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A bucket on any S3-compatible store (AWS, MinIO, R2), addressed
+/// path-style as `{endpoint}/{bucket}/{key}` because not every store
+/// supports virtual-hosted buckets. Requests are signed with SigV4.
+pub struct Bucket {
+    client: reqwest::Client,
+    endpoint: String,
+    name: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl Bucket {
+    pub fn new(
+        client: reqwest::Client,
+        endpoint: &str,
+        name: &str,
+        region: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Self {
+        Bucket {
+            client,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            name: name.to_string(),
+            region: region.to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+        }
+    }
+
+    /// Uploads `body` as `key`, retrying with backoff when the store can't
+    /// be reached, times out, or answers 5xx. Other errors are final.
+    pub async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        content_type: &str,
+        cache_control: &str,
+    ) -> Result<(), String> {
+        let headers = [("cache-control", cache_control), ("content-type", content_type)];
+        let mut attempt = 1;
+        loop {
+            let (error, retry) = match self.send(Method::PUT, key, &headers, body.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+            if !retry || attempt >= ATTEMPTS {
+                return Err(error);
+            }
+            let delay = crate::refresh::backoff(attempt);
+            tracing::debug!(
+                "Upload attempt {}/{} of {} failed ({}), retrying in {:?}",
+                attempt,
+                ATTEMPTS,
+                key,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        self.send(Method::DELETE, key, &[], Vec::new())
+            .await
+            .map_err(|(error, _)| error)
+    }
+
+    /// One signed request. Fails with the error and whether it is worth
+    /// retrying.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        extra: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<(), (String, bool)> {
+        let url = format!("{}/{}/{}", self.endpoint, encode(&self.name), encode_key(key));
+        let url = Url::parse(&url).map_err(|e| (format!("bad object URL {}: {}", url, e), false))?;
+        let now = chrono::Utc::now();
+        let headers = self.sign(&method, &url, extra, &body, now);
+
+        let response = self
+            .client
+            .request(method, url)
+            .headers(headers)
+            .timeout(TIMEOUT)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| (e.to_string(), true))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await.unwrap_or_default();
+        let error = format!("{} answered {}: {}", self.endpoint, status, crate::snippet(&body));
+        Err((error, status.is_server_error()))
+    }
+
+    /// Headers for an AWS Signature Version 4 request: every header in
+    /// `extra` plus the signed date, payload hash and `Authorization`.
+    fn sign(
+        &self,
+        method: &Method,
+        url: &Url,
+        extra: &[(&str, &str)],
+        body: &[u8],
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> HeaderMap {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = format!("{:x}", Sha256::digest(body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut signed: Vec<(&str, &str)> = vec![
+            ("host", &host),
+            ("x-amz-content-sha256", &payload_hash),
+            ("x-amz-date", &amz_date),
+        ];
+        signed.extend_from_slice(extra);
+        signed.sort();
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            url.path(),
+            url.query().unwrap_or_default(),
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+
+        let key = format!("AWS4{}", self.secret_access_key);
+        let key = hmac(key.as_bytes(), date.as_bytes());
+        let key = hmac(&key, self.region.as_bytes());
+        let key = hmac(&key, b"s3");
+        let key = hmac(&key, b"aws4_request");
+        let signature: String = hmac(&key, string_to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &signed {
+            // reqwest sets `Host` from the URL.
+            if *name == "host" {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&authorization).expect("credentials are validated at startup"),
+        );
+        headers
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters, as SigV4
+/// expects of each path segment.
+fn encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+/// Encodes an object key segment by segment, keeping its `/`s.
+fn encode_key(key: &str) -> String {
+    key.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
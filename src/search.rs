@@ -0,0 +1,48 @@
+/// Lowercase Latin letters with diacritics, grouped by what they are
+/// searched as.
+const FOLDS: [(&str, &str); 19] = [
+    ("a", "àáâãäåāăą"),
+    ("c", "çćĉċč"),
+    ("d", "ďđð"),
+    ("e", "èéêëēĕėęě"),
+    ("g", "ĝğġģ"),
+    ("h", "ĥħ"),
+    ("i", "ìíîïĩīĭįı"),
+    ("j", "ĵ"),
+    ("k", "ķ"),
+    ("l", "ĺļľŀł"),
+    ("n", "ñńņňŉ"),
+    ("o", "òóôõöøōŏő"),
+    ("r", "ŕŗř"),
+    ("s", "śŝşšș"),
+    ("t", "ţťŧț"),
+    ("u", "ùúûüũūŭůűų"),
+    ("w", "ŵ"),
+    ("y", "ýÿŷ"),
+    ("z", "źżž"),
+];
+
+/// Letters written as two in plain ASCII.
+const LIGATURES: [(char, &str); 4] = [('æ', "ae"), ('œ', "oe"), ('ß', "ss"), ('þ', "th")];
+
+/// `text` lowercased, with the diacritics dropped from Latin letters, so
+/// `Björk` reads as `bjork`. Combining marks, as in decomposed text, are
+/// dropped too.
+pub fn fold_diacritics(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if ('\u{300}'..='\u{36f}').contains(&c) {
+            continue;
+        }
+        let base = FOLDS
+            .iter()
+            .find(|(_, letters)| letters.contains(c))
+            .map(|(base, _)| *base)
+            .or_else(|| LIGATURES.iter().find(|(letter, _)| *letter == c).map(|(_, ascii)| *ascii));
+        match base {
+            Some(base) => folded.push_str(base),
+            None => folded.push(c),
+        }
+    }
+    folded
+}
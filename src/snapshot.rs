@@ -0,0 +1,180 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The stamp format: UTC to the minute, as in `2025-11-07T12-00Z`. Local
+/// time would repeat an hour each autumn, handing two snapshots one name.
+const STAMP: &str = "%Y-%m-%dT%H-%MZ";
+/// The format before stamps moved to UTC: local time, with no `Z`. Copies
+/// stamped this way are still pruned, as older than any UTC one.
+const LOCAL_STAMP: &str = "%Y-%m-%dT%H-%M";
+
+/// Writes `contents` as `latest.<extension>` in `dir`, plus a copy stamped
+/// with `taken_at` (`2025-11-07T12-00Z.json`), then prunes all but the
+/// newest `retention` stamped copies. Each file is written to a temporary
+/// name and renamed into place, so readers never see half of one.
+pub async fn write(
+    dir: &Path,
+    extension: &str,
+    taken_at: SystemTime,
+    contents: &str,
+    retention: usize,
+) -> io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    replace(&dir.join(format!("{}.{}", stamp(taken_at), extension)), contents).await?;
+    replace(&dir.join(format!("latest.{}", extension)), contents).await?;
+    prune(dir, extension, retention).await
+}
+
+async fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Removes the oldest stamped copies beyond `retention`. The stamps sort
+/// chronologically, so the names alone give the order, with local-time
+/// stamps from before the move to UTC first.
+async fn prune(dir: &Path, extension: &str, retention: usize) -> io::Result<()> {
+    let mut stamped = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(stem) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(extension)?.strip_suffix('.'))
+        else {
+            continue;
+        };
+        if is_stamp(stem) {
+            stamped.push((stem.ends_with('Z'), entry.path()));
+        }
+    }
+
+    stamped.sort();
+    let excess = stamped.len().saturating_sub(retention);
+    for (_, path) in &stamped[..excess] {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+fn stamp(taken_at: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(taken_at).format(STAMP).to_string()
+}
+
+/// Whether `stem` looks like `2025-11-07T12-00Z`, or the older local
+/// `2025-11-07T13-00`, so unrelated files in the directory are never pruned.
+fn is_stamp(stem: &str) -> bool {
+    [STAMP, LOCAL_STAMP]
+        .into_iter()
+        .any(|format| chrono::NaiveDateTime::parse_from_str(stem, format).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// 2025-10-26T00:30Z, which is 02:30 in Berlin on the first pass through
+    /// the hour the clocks go back.
+    const FIRST_PASS: u64 = 1_761_438_600;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// A fresh directory under the system temp dir, removed first in case a
+    /// previous run left it behind.
+    fn scratch(name: &str) -> PathBuf {
+        let name = format!("plausible-proxy-snapshot-{}-{}", std::process::id(), name);
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn stamped(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "latest.json")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn stamps_are_utc_to_the_minute() {
+        assert_eq!(stamp(at(FIRST_PASS + 59)), "2025-10-26T00-30Z");
+        assert!(is_stamp("2025-10-26T00-30Z"));
+        assert!(is_stamp("2025-10-26T02-30"));
+        assert!(!is_stamp("2025-10-26T02-30+01:00"));
+        assert!(!is_stamp("2025-10-26"));
+        assert!(!is_stamp("latest"));
+    }
+
+    #[test]
+    fn repeated_hour_gets_distinct_ordered_stamps() {
+        let local = |secs| {
+            chrono::DateTime::<chrono::Utc>::from(at(secs))
+                .with_timezone(&chrono_tz::Europe::Berlin)
+                .naive_local()
+        };
+        assert_eq!(local(FIRST_PASS), local(FIRST_PASS + 3600));
+
+        let first = stamp(at(FIRST_PASS));
+        let second = stamp(at(FIRST_PASS + 3600));
+        assert_ne!(first, second);
+        assert!(first < second);
+    }
+
+    #[test]
+    fn stamps_across_midnight_sort_chronologically() {
+        // 23:59 and 00:00 UTC on either side of 2025-11-07.
+        let before = stamp(at(1_762_473_540));
+        let after = stamp(at(1_762_473_600));
+        assert_eq!(before, "2025-11-06T23-59Z");
+        assert_eq!(after, "2025-11-07T00-00Z");
+        assert!(before < after);
+    }
+
+    #[tokio::test]
+    async fn write_keeps_both_passes_of_the_repeated_hour_and_prunes_the_oldest() {
+        let dir = scratch("fall-back");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.json"), "{}").unwrap();
+
+        let passes = [FIRST_PASS - 3600, FIRST_PASS, FIRST_PASS + 3600];
+        for (i, secs) in passes.into_iter().enumerate() {
+            write(&dir, "json", at(secs), &i.to_string(), 2).await.unwrap();
+        }
+
+        // The copy from before the hour is pruned; the unrelated file stays.
+        let expected = ["2025-10-26T00-30Z.json", "2025-10-26T01-30Z.json", "notes.json"];
+        assert_eq!(stamped(&dir), expected);
+        assert_eq!(std::fs::read_to_string(dir.join("latest.json")).unwrap(), "2");
+        assert_eq!(std::fs::read_to_string(dir.join("2025-10-26T00-30Z.json")).unwrap(), "1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_stamps_from_before_utc_are_pruned_first() {
+        let dir = scratch("local-stamps");
+        std::fs::create_dir_all(&dir).unwrap();
+        // Written at +01:00 just before the upgrade, so they sort after the
+        // first UTC stamp by name.
+        for name in ["2025-11-07T12-30.json", "2025-11-07T13-00.json"] {
+            std::fs::write(dir.join(name), "{}").unwrap();
+        }
+
+        // 2025-11-07T12-05Z and 12-10Z.
+        write(&dir, "json", at(1_762_517_100), "0", 3).await.unwrap();
+        let expected = ["2025-11-07T12-05Z.json", "2025-11-07T12-30.json", "2025-11-07T13-00.json"];
+        assert_eq!(stamped(&dir), expected);
+        write(&dir, "json", at(1_762_517_400), "1", 2).await.unwrap();
+        assert_eq!(stamped(&dir), ["2025-11-07T12-05Z.json", "2025-11-07T12-10Z.json"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+/// Referrer sources that are one social network under another name: the
+/// names Plausible gives them, their link shorteners and their old or
+/// mobile domains. Keys are lowercase.
+const SOCIAL: [(&str, &str); 25] = [
+    ("bsky.app", "Bluesky"),
+    ("bluesky", "Bluesky"),
+    ("discord", "Discord"),
+    ("discord.com", "Discord"),
+    ("discordapp.com", "Discord"),
+    ("facebook", "Facebook"),
+    ("l.facebook.com", "Facebook"),
+    ("lm.facebook.com", "Facebook"),
+    ("m.facebook.com", "Facebook"),
+    ("instagram", "Instagram"),
+    ("l.instagram.com", "Instagram"),
+    ("new.reddit.com", "Reddit"),
+    ("np.reddit.com", "Reddit"),
+    ("old.reddit.com", "Reddit"),
+    ("out.reddit.com", "Reddit"),
+    ("reddit", "Reddit"),
+    ("reddit.com", "Reddit"),
+    ("t.co", "Twitter/X"),
+    ("twitter", "Twitter/X"),
+    ("twitter.com", "Twitter/X"),
+    ("x", "Twitter/X"),
+    ("x.com", "Twitter/X"),
+    ("m.youtube.com", "YouTube"),
+    ("youtube", "YouTube"),
+    ("youtube.com", "YouTube"),
+];
+
+/// The network `source` belongs to, if it is a known alias of one.
+pub fn social(source: &str) -> Option<&'static str> {
+    let source = source.to_ascii_lowercase();
+    SOCIAL
+        .iter()
+        .find(|(alias, _)| *alias == source)
+        .map(|(_, network)| *network)
+}
@@ -0,0 +1,91 @@
+use std::fmt::Write;
+
+/// Width of the line, which is also kept clear around it so it isn't
+/// clipped at the top and bottom.
+const STROKE: f64 = 1.5;
+
+/// A 1×1 transparent image, for artists with no series to draw.
+pub fn blank() -> String {
+    r#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1"/>"#.to_string()
+}
+
+/// `values` as a `width` by `height` line in `color`, oldest on the left,
+/// scaled so the lowest and highest touch the bottom and top. Missing
+/// values are bridged by a straight line when `interpolate`, and otherwise
+/// break it. A single value, or only equal ones, draws a flat line through
+/// the middle, and none at all one along the bottom. `color` must already
+/// be resolved by [`crate::badge::color`].
+pub fn svg(
+    values: &[Option<u64>],
+    width: u32,
+    height: u32,
+    color: &str,
+    interpolate: bool,
+) -> String {
+    let (w, h) = (f64::from(width), f64::from(height));
+    let present: Vec<u64> = values.iter().flatten().copied().collect();
+    let (low, high) = match (present.iter().min(), present.iter().max()) {
+        (Some(&low), Some(&high)) => (low as f64, high as f64),
+        _ => (0.0, 0.0),
+    };
+    let y = |value: u64| {
+        if high > low {
+            h - STROKE - (value as f64 - low) / (high - low) * (h - 2.0 * STROKE)
+        } else if present.is_empty() {
+            h - STROKE
+        } else {
+            h / 2.0
+        }
+    };
+    let step = (w - 2.0 * STROKE) / values.len().saturating_sub(1).max(1) as f64;
+
+    // Runs of connected points; one spanning the width when there is only
+    // a single value, or none.
+    let mut runs: Vec<Vec<(f64, f64)>> = Vec::new();
+    if present.len() <= 1 {
+        let level = y(present.first().copied().unwrap_or(0));
+        runs.push(vec![(STROKE, level), (w - STROKE, level)]);
+    } else {
+        let mut run = Vec::new();
+        for (index, value) in values.iter().enumerate() {
+            match value {
+                Some(value) => run.push((STROKE + index as f64 * step, y(*value))),
+                None if !interpolate && !run.is_empty() => runs.push(std::mem::take(&mut run)),
+                None => {}
+            }
+        }
+        runs.push(run);
+    }
+
+    let mut out = format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+            r#"width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+        ),
+        width, height
+    );
+    for run in runs.iter().filter(|run| !run.is_empty()) {
+        if let [(x, y)] = run[..] {
+            // A point on its own between gaps.
+            let _ = write!(
+                out,
+                r#"<circle cx="{:.1}" cy="{:.1}" r="{}" fill="{}"/>"#,
+                x, y, STROKE, color
+            );
+            continue;
+        }
+        let points: Vec<String> = run.iter().map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect();
+        let _ = write!(
+            out,
+            concat!(
+                r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="{}" "#,
+                r#"stroke-linejoin="round" stroke-linecap="round"/>"#,
+            ),
+            points.join(" "),
+            color,
+            STROKE
+        );
+    }
+    out.push_str("</svg>");
+    out
+}
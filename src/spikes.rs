@@ -0,0 +1,200 @@
+use crate::plausible::{normalize_name, ArtistRow};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// When a jump in visitors counts as a spike.
+pub struct Thresholds {
+    /// Standard deviations above the mean of recent deltas.
+    pub stddevs: f64,
+    /// Smallest delta that can count, however quiet the artist was.
+    pub min_delta: u64,
+    /// Recent deltas the baseline is computed from, and the deltas an
+    /// artist needs on record before a new one can count as a spike.
+    pub samples: usize,
+    /// Least time between alerts for the same artist.
+    pub cooldown: Duration,
+}
+
+/// Spots artists whose visitors jumped between one leaderboard and the
+/// next, against each artist's own recent deltas.
+#[derive(Default)]
+pub struct Detector {
+    /// By normalized, lowercased name.
+    artists: HashMap<String, Artist>,
+}
+
+struct Artist {
+    visitors: u64,
+    /// Oldest first.
+    deltas: VecDeque<i64>,
+    alerted_at: Option<Instant>,
+}
+
+/// A detected spike.
+#[derive(Debug, Serialize)]
+pub struct Spike {
+    pub artist: String,
+    pub visitors: u64,
+    /// Visitors gained since the previous leaderboard.
+    pub delta: i64,
+    pub baseline: Baseline,
+}
+
+/// The recent deltas a spike was measured against.
+#[derive(Debug, Serialize)]
+pub struct Baseline {
+    pub mean: f64,
+    pub stddev: f64,
+    pub samples: usize,
+    /// The delta that had to be exceeded.
+    pub threshold: f64,
+}
+
+impl Detector {
+    /// Takes `rows` as the latest leaderboard, returning the artists whose
+    /// delta since the previous one beats `thresholds`, unless they were
+    /// alerted on within the cooldown. Artists missing from `rows` are
+    /// forgotten.
+    pub fn observe(
+        &mut self,
+        rows: &[ArtistRow],
+        now: Instant,
+        thresholds: &Thresholds,
+    ) -> Vec<Spike> {
+        let mut previous = std::mem::take(&mut self.artists);
+        let mut spikes = Vec::new();
+        for row in rows {
+            let key = normalize_name(&row.name).to_lowercase();
+            let Some(mut artist) = previous.remove(&key) else {
+                let artist = Artist {
+                    visitors: row.visitors,
+                    deltas: VecDeque::new(),
+                    alerted_at: None,
+                };
+                self.artists.insert(key, artist);
+                continue;
+            };
+
+            let delta = row.visitors as i64 - artist.visitors as i64;
+            if let Some(baseline) = baseline(&artist.deltas, thresholds) {
+                let cooled = artist
+                    .alerted_at
+                    .is_none_or(|alerted_at| now.duration_since(alerted_at) >= thresholds.cooldown);
+                let spiked =
+                    delta >= thresholds.min_delta as i64 && delta as f64 > baseline.threshold;
+                if spiked && cooled {
+                    artist.alerted_at = Some(now);
+                    spikes.push(Spike {
+                        artist: row.name.clone(),
+                        visitors: row.visitors,
+                        delta,
+                        baseline,
+                    });
+                }
+            }
+            artist.visitors = row.visitors;
+            artist.deltas.push_back(delta);
+            while artist.deltas.len() > thresholds.samples {
+                artist.deltas.pop_front();
+            }
+            self.artists.insert(key, artist);
+        }
+        spikes
+    }
+}
+
+/// The mean and population standard deviation of `deltas`, once there are
+/// `thresholds.samples` of them, with the threshold `thresholds.stddevs`
+/// above the mean.
+fn baseline(deltas: &VecDeque<i64>, thresholds: &Thresholds) -> Option<Baseline> {
+    if deltas.len() < thresholds.samples {
+        return None;
+    }
+    let count = deltas.len() as f64;
+    let mean = deltas.iter().map(|&delta| delta as f64).sum::<f64>() / count;
+    let variance = deltas.iter().map(|&delta| (delta as f64 - mean).powi(2)).sum::<f64>() / count;
+    let stddev = variance.sqrt();
+    Some(Baseline {
+        mean,
+        stddev,
+        samples: deltas.len(),
+        threshold: mean + thresholds.stddevs * stddev,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        stddevs: 3.0,
+        min_delta: 50,
+        samples: 4,
+        cooldown: Duration::from_secs(3600),
+    };
+
+    fn board(visitors: u64) -> Vec<ArtistRow> {
+        let row = serde_json::json!({ "name": "Drake", "visitors": visitors, "events": 0 });
+        vec![serde_json::from_value(row).unwrap()]
+    }
+
+    /// Observes Drake at each of `visitors` in turn, a minute apart from
+    /// `start`, returning the deltas alerted on.
+    fn observe(detector: &mut Detector, start: Instant, visitors: &[u64]) -> Vec<i64> {
+        let mut alerted = Vec::new();
+        for (minute, &visitors) in visitors.iter().enumerate() {
+            let now = start + Duration::from_secs(60 * minute as u64);
+            let spikes = detector.observe(&board(visitors), now, &THRESHOLDS);
+            alerted.extend(spikes.iter().map(|spike| spike.delta));
+        }
+        alerted
+    }
+
+    #[test]
+    fn no_spike_before_the_baseline_has_its_samples() {
+        let mut detector = Detector::default();
+        // Three steady deltas of 10, then a jump of 500, one sample short.
+        assert!(observe(&mut detector, Instant::now(), &[100, 110, 120, 130, 630]).is_empty());
+    }
+
+    #[test]
+    fn no_spike_below_the_min_delta() {
+        let mut detector = Detector::default();
+        // 40 is far beyond the steady deltas of 1, but below `min_delta`.
+        let visitors = [100, 101, 102, 103, 104, 144];
+        assert!(observe(&mut detector, Instant::now(), &visitors).is_empty());
+
+        let mut detector = Detector::default();
+        let visitors = [100, 101, 102, 103, 104, 154];
+        assert_eq!(observe(&mut detector, Instant::now(), &visitors), [50]);
+    }
+
+    #[test]
+    fn large_deviation_alerts_once_per_cooldown() {
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        // The jump alerts; the next, larger still against the raised
+        // baseline, falls within the cooldown.
+        let visitors = [100, 110, 120, 130, 140, 640, 2640];
+        assert_eq!(observe(&mut detector, start, &visitors), [500]);
+
+        let spikes = detector.observe(&board(2650), start + Duration::from_secs(600), &THRESHOLDS);
+        assert!(spikes.is_empty());
+        let after = start + THRESHOLDS.cooldown + Duration::from_secs(60 * 6);
+        let spikes = detector.observe(&board(20_000), after, &THRESHOLDS);
+        assert_eq!(spikes.len(), 1);
+        assert_eq!(spikes[0].artist, "Drake");
+        assert_eq!(spikes[0].delta, 20_000 - 2650);
+        assert_eq!(spikes[0].baseline.samples, THRESHOLDS.samples);
+    }
+
+    #[test]
+    fn artists_missing_from_a_board_start_over() {
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        observe(&mut detector, start, &[100, 110, 120, 130, 140]);
+        assert!(detector.observe(&[], start, &THRESHOLDS).is_empty());
+        assert!(observe(&mut detector, start, &[140, 1140]).is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::config::{Config, LogFormat};
+use axum::http::HeaderMap;
+use chrono::{SecondsFormat, Utc};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::Resource;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::OnceLock;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// The provider spans are exported through, to flush on shutdown.
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// Installs the global subscriber: log lines in `log_format`, filtered by
+/// `RUST_LOG` (INFO and above by default), and, when
+/// `otel_exporter_otlp_endpoint` is set, span export over OTLP/HTTP with
+/// incoming `traceparent` headers honoured. Without a `config`, as when it
+/// failed to load, only logs, as text.
+pub fn init_logging(config: Option<&Config>) {
+    let endpoint = config.and_then(|config| config.otel_exporter_otlp_endpoint.as_deref());
+    let (provider, error) = match endpoint.map(provider) {
+        Some(Ok(provider)) => (Some(provider), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let export = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+    let format = config.map_or(LogFormat::Text, |config| config.log_format);
+    let (text, json) = match format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => {
+            let layer = tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(Json);
+            (None, Some(layer))
+        }
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(export)
+        .with(text)
+        .with(json)
+        .init();
+
+    if let Some(e) = error {
+        tracing::error!("Not exporting traces: {}", e);
+    }
+    if let Some(provider) = provider {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        tracing::info!("Exporting traces to {}", endpoint.unwrap_or_default());
+        let _ = PROVIDER.set(provider);
+    }
+}
+
+fn provider(endpoint: &str) -> Result<TracerProvider, TraceError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint))
+        .build()?;
+
+    // OTEL_SERVICE_NAME and OTEL_RESOURCE_ATTRIBUTES are read by the
+    // default resource; without a name it would be `unknown_service`.
+    let mut resource = Resource::default();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        let name = KeyValue::new("service.name", env!("CARGO_PKG_NAME"));
+        resource = resource.merge(&Resource::new([name]));
+    }
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(resource)
+        .build())
+}
+
+/// Exports the spans still buffered and stops the exporter, if there is one.
+pub async fn shutdown() {
+    let Some(provider) = PROVIDER.get() else {
+        return;
+    };
+    // Blocks until the batch is sent, so it must not hold up a runtime thread.
+    let result = tokio::task::spawn_blocking(|| provider.shutdown()).await;
+    if let Ok(Err(e)) = result {
+        tracing::warn!("Failed to flush traces: {}", e);
+    }
+}
+
+/// The trace an incoming request belongs to, from its `traceparent`
+/// header. Empty unless export is enabled.
+pub fn parent(headers: &HeaderMap) -> opentelemetry::Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Headers(headers))
+    })
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Writes each event as one JSON object: `timestamp`, `level`, `target`,
+/// the fields of every span it is in (inner spans winning) and its own
+/// fields, message included, all as top-level keys.
+struct Json;
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // Events forwarded from the `log` crate carry their real target in
+        // fields instead of their metadata.
+        let normalized = event.normalized_metadata();
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut object = Map::new();
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        object.insert("timestamp".to_string(), timestamp.into());
+        object.insert("level".to_string(), meta.level().as_str().into());
+        object.insert("target".to_string(), meta.target().into());
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                    continue;
+                };
+                // `JsonFields` keeps each span's fields as a JSON object.
+                if let Ok(Value::Object(fields)) = serde_json::from_str(fields) {
+                    object.extend(fields);
+                }
+            }
+        }
+        event.record(&mut Fields(&mut object));
+
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Fields<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // The `log` crate's metadata, already used for `target`.
+        if !field.name().starts_with("log.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value).into());
+    }
+}
@@ -0,0 +1,124 @@
+use crate::config::Config;
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+
+/// How often the certificate and key files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The certificate served over HTTPS and the files it was read from.
+#[derive(Clone)]
+pub struct Tls {
+    config: RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl Tls {
+    /// Reads the PEM certificate chain at `cert` and private key at `key`,
+    /// failing if either is unreadable or the key doesn't match the
+    /// certificate.
+    pub async fn load(cert: &Path, key: &Path) -> Result<Self, String> {
+        // Every handshake uses the process-wide provider, so it must be
+        // installed before the first config is built. Installing it twice
+        // is harmless.
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let (cert_pem, key_pem) = read(cert, key).await?;
+        let config = RustlsConfig::from_pem(cert_pem, key_pem)
+            .await
+            .map_err(|e| invalid(cert, key, e))?;
+        Ok(Tls {
+            config,
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+        })
+    }
+
+    /// Re-reads both files. Connections opened afterwards get the new
+    /// certificate; if it fails to load, the current one stays in use.
+    pub async fn reload(&self) {
+        let result = match read(&self.cert, &self.key).await {
+            Ok((cert_pem, key_pem)) => self
+                .config
+                .reload_from_pem(cert_pem, key_pem)
+                .await
+                .map_err(|e| invalid(&self.cert, &self.key, e)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => tracing::info!("Reloaded TLS certificate {}", self.cert.display()),
+            Err(e) => tracing::error!("{}, keeping the current certificate", e),
+        }
+    }
+
+    /// Reloads whenever either file's modification time changes, as when a
+    /// renewal replaces them. Exits when `shutdown` flips.
+    pub async fn watch(self, mut shutdown: watch::Receiver<bool>) {
+        let mut seen = (modified(&self.cert).await, modified(&self.key).await);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            let current = (modified(&self.cert).await, modified(&self.key).await);
+            if current != seen {
+                seen = current;
+                self.reload().await;
+            }
+        }
+    }
+}
+
+/// Serves `app` over HTTPS on `listener` until `shutdown` resolves, then
+/// waits for open connections to finish.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls: &Tls,
+    config: &Config,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.await;
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    let mut server =
+        axum_server::from_tcp_rustls(listener.into_std()?, tls.config.clone()).handle(handle);
+    crate::limit_connections(server.http_builder(), config);
+    server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await
+}
+
+async fn read(cert: &Path, key: &Path) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cert_pem = tokio::fs::read(cert)
+        .await
+        .map_err(|e| format!("Failed to read TLS certificate {}: {}", cert.display(), e))?;
+    let key_pem = tokio::fs::read(key)
+        .await
+        .map_err(|e| format!("Failed to read TLS key {}: {}", key.display(), e))?;
+    Ok((cert_pem, key_pem))
+}
+
+fn invalid(cert: &Path, key: &Path, e: std::io::Error) -> String {
+    format!(
+        "TLS certificate {} and key {} are not a usable pair: {}",
+        cert.display(),
+        key.display(),
+        e
+    )
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.and_then(|meta| meta.modified()).ok()
+}
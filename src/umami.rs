@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::error::FetchError;
+use crate::fetcher::StatsFetcher;
+use crate::upstream::{self, Period, QueryKind, UpstreamQuery};
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeDelta, Utc};
+use reqwest::header::AUTHORIZATION;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Fetches from an Umami instance, answering each query with the body
+/// Plausible would have sent for it, so everything above the fetcher works
+/// unchanged. Umami counts property values by event, with no unique
+/// visitors, so each row's `visitors` is its event count.
+pub struct UmamiFetcher {
+    client: reqwest::Client,
+    base_url: String,
+    /// Read for every site when set, instead of the site's own ID.
+    website_id: Option<String>,
+    api_token: String,
+    max_body_bytes: u64,
+    /// Where the days ranges start and end on are reckoned.
+    timezone: chrono_tz::Tz,
+}
+
+/// One row of `/event-data/values`.
+#[derive(Deserialize)]
+struct PropertyValue {
+    value: serde_json::Value,
+    total: u64,
+}
+
+impl UmamiFetcher {
+    pub fn new(config: &Config) -> Self {
+        UmamiFetcher {
+            client: crate::http_client(config),
+            base_url: config.umami_base_url.clone().unwrap_or_default(),
+            website_id: config.umami_website_id.clone(),
+            api_token: config.umami_api_token.clone().unwrap_or_default(),
+            max_body_bytes: config.upstream_max_body_bytes,
+            timezone: config.timezone(),
+        }
+    }
+}
+
+#[axum::async_trait]
+impl StatsFetcher for UmamiFetcher {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError> {
+        match query.kind() {
+            QueryKind::Leaderboard => {}
+            QueryKind::Timeseries => {
+                return Err(FetchError::Unsupported("artist time series".to_string()));
+            }
+            QueryKind::Breakdown => {
+                return Err(FetchError::Unsupported("breakdowns".to_string()));
+            }
+            QueryKind::Realtime => {
+                return Err(FetchError::Unsupported("realtime visitors".to_string()));
+            }
+        }
+        let (start, end) = range(query, Utc::now(), self.timezone)?;
+        let website = self.website_id.as_deref().unwrap_or(query.site_id());
+
+        let response = self
+            .client
+            .get(format!("{}/api/websites/{}/event-data/values", self.base_url, website))
+            .query(&[
+                ("startAt", start.timestamp_millis().to_string()),
+                ("endAt", end.timestamp_millis().to_string()),
+                ("eventName", query.goal().to_string()),
+                ("propertyName", query.property().to_string()),
+            ])
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_token))
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(FetchError::from_request)?;
+
+        let status = response.status();
+        let code = i64::from(status.as_u16());
+        tracing::Span::current().set_attribute("http.response.status_code", code);
+        let retry_after = crate::fetcher::retry_after(response.headers());
+        let body = crate::fetcher::read_body(response, self.max_body_bytes).await?;
+
+        if !status.is_success() {
+            return Err(FetchError::Status {
+                status,
+                snippet: crate::snippet(&body),
+                retry_after,
+            });
+        }
+
+        let limit = query
+            .param("limit")
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(upstream::PAGE_LIMIT);
+        leaderboard(&body, page, limit)
+    }
+}
+
+/// Maps a `/event-data/values` body to the `results` document of a
+/// Plausible breakdown, ordered the same way. Umami sends every value at
+/// once, so pages are cut from it here.
+fn leaderboard(body: &str, page: u32, limit: usize) -> Result<String, FetchError> {
+    let mut values: Vec<PropertyValue> = serde_json::from_str(body).map_err(|e| {
+        FetchError::Invalid(format!("unexpected Umami response ({}): {}", e, crate::snippet(body)))
+    })?;
+    values.sort_by_key(|row| std::cmp::Reverse(row.total));
+
+    let skip = (page.saturating_sub(1) as usize).saturating_mul(limit);
+    let results: Vec<serde_json::Value> = values
+        .into_iter()
+        .skip(skip)
+        .take(limit)
+        .map(|row| {
+            let name = match row.value {
+                serde_json::Value::String(name) => name,
+                other => other.to_string(),
+            };
+            serde_json::json!({ "name": name, "visitors": row.total, "events": row.total })
+        })
+        .collect();
+    Ok(serde_json::json!({ "results": results }).to_string())
+}
+
+/// The start and end of the time range `query` asks Plausible for, with
+/// days as they fall in `timezone`, in UTC as Umami takes it.
+fn range(
+    query: &UpstreamQuery,
+    now: DateTime<Utc>,
+    timezone: chrono_tz::Tz,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), FetchError> {
+    let invalid = |what: &str| FetchError::Invalid(format!("query has no valid {}", what));
+    let date = |name: &str| {
+        query
+            .param(name)
+            .and_then(|value| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok())
+            .ok_or_else(|| invalid(name))
+    };
+    let midnight = |day: NaiveDate| upstream::start_of_day(day, &timezone).to_utc();
+
+    let today = now.with_timezone(&timezone).date_naive();
+    let period = query.param("period").ok_or_else(|| invalid("period"))?;
+    if period == "custom" {
+        let end = midnight(date("to")? + TimeDelta::days(1)) - TimeDelta::milliseconds(1);
+        return Ok((midnight(date("from")?), end));
+    }
+
+    let period: Period = period.parse().map_err(|_| invalid("period"))?;
+    let first_of_month = today.with_day(1).expect("every month has a first day");
+    let start = match period {
+        Period::Day | Period::SevenDays | Period::ThirtyDays => {
+            let days = period.days().expect("fixed-length period");
+            midnight(today - TimeDelta::days(i64::from(days) - 1))
+        }
+        Period::Month => midnight(first_of_month),
+        Period::SixMonths => midnight(first_of_month - Months::new(5)),
+        Period::TwelveMonths => midnight(first_of_month - Months::new(11)),
+        Period::All => DateTime::UNIX_EPOCH,
+    };
+    Ok((start, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plausible::Payload;
+    use crate::upstream::Site;
+
+    const VALUES: &str = include_str!("../fixtures/umami/values.json");
+
+    fn rows(body: &str) -> Vec<(String, u64, u64)> {
+        let Ok(Payload::Leaderboard(response)) = Payload::parse(QueryKind::Leaderboard, body)
+        else {
+            panic!("not a leaderboard: {}", body);
+        };
+        let rows = response.results.into_iter();
+        rows.map(|row| (row.name, row.visitors, row.events)).collect()
+    }
+
+    fn site() -> Site {
+        Site {
+            key: "grid".to_string(),
+            id: "artistgrid.cx".to_string(),
+            bearer_token: None,
+            goal: "Artist Click".to_string(),
+            property: "name".to_string(),
+        }
+    }
+
+    fn utc(stamp: &str) -> DateTime<Utc> {
+        stamp.parse().unwrap()
+    }
+
+    #[test]
+    fn values_map_to_a_leaderboard_by_total() {
+        let rows = rows(&leaderboard(VALUES, 1, 100).unwrap());
+        let names: Vec<&str> = rows.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Playboi Carti",
+                "Kanye West",
+                "Travis Scott",
+                "Frank Ocean",
+                "Tyler, The Creator",
+                "2014"
+            ]
+        );
+        // Umami only counts events, which stand in for visitors too.
+        assert_eq!(rows[0], ("Playboi Carti".to_string(), 1840, 1840));
+    }
+
+    #[test]
+    fn values_are_paged() {
+        let second = rows(&leaderboard(VALUES, 2, 4).unwrap());
+        let names: Vec<&str> = second.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, ["Tyler, The Creator", "2014"]);
+        assert!(rows(&leaderboard(VALUES, 3, 4).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn unexpected_body_is_invalid() {
+        let error = leaderboard(r#"{"error":"Unauthorized"}"#, 1, 100).unwrap_err();
+        assert!(matches!(error, FetchError::Invalid(_)), "{}", error);
+    }
+
+    #[test]
+    fn custom_range_covers_whole_local_days() {
+        let from = NaiveDate::from_ymd_opt(2026, 3, 1).unwrap();
+        let to = NaiveDate::from_ymd_opt(2026, 3, 31).unwrap();
+        let query = UpstreamQuery::leaderboard_between(&site(), from, to);
+        let now = utc("2026-04-10T12:00:00Z");
+
+        let (start, end) = range(&query, now, chrono_tz::Europe::Berlin).unwrap();
+        assert_eq!(start, utc("2026-02-28T23:00:00Z"));
+        // Summer time started on the 29th.
+        assert_eq!(end, utc("2026-03-31T21:59:59.999Z"));
+    }
+
+    #[test]
+    fn periods_start_on_local_days() {
+        // Already the 1st of July in Berlin.
+        let now = utc("2026-06-30T22:30:00Z");
+        let start = |period| {
+            let query = UpstreamQuery::leaderboard(&site(), period);
+            let (start, end) = range(&query, now, chrono_tz::Europe::Berlin).unwrap();
+            assert_eq!(end, now);
+            start
+        };
+        assert_eq!(start(Period::Day), utc("2026-06-30T22:00:00Z"));
+        assert_eq!(start(Period::SevenDays), utc("2026-06-24T22:00:00Z"));
+        assert_eq!(start(Period::Month), utc("2026-06-30T22:00:00Z"));
+        assert_eq!(start(Period::SixMonths), utc("2026-01-31T23:00:00Z"));
+        assert_eq!(start(Period::All), DateTime::UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::config::Config;
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UnixListener;
+
+/// Ownership and permissions given to the socket once it is bound.
+pub struct Access<'a> {
+    pub mode: Option<u32>,
+    pub owner: Option<&'a str>,
+    pub group: Option<&'a str>,
+}
+
+/// Listens on `path`, first removing a socket file a crashed run left
+/// behind. A socket something still answers on is never removed.
+pub fn bind(path: &Path, access: &Access) -> Result<UnixListener, String> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(format!(
+                        "{} is in use by another running server; stop it or pick another path",
+                        path.display()
+                    ));
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path).map_err(|e| {
+                        format!("Failed to remove stale socket {}: {}", path.display(), e)
+                    })?;
+                    tracing::info!("Removed stale socket {}", path.display());
+                }
+                Err(e) => return Err(format!("Failed to check {}: {}", path.display(), e)),
+            }
+        }
+        Ok(_) => {
+            return Err(format!(
+                "{} exists and is not a socket; move it away or pick another path",
+                path.display()
+            ));
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to check {}: {}", path.display(), e)),
+    }
+
+    let uid = access.owner.map(|owner| lookup("/etc/passwd", owner)).transpose()?;
+    let gid = access.group.map(|group| lookup("/etc/group", group)).transpose()?;
+    let listener = UnixListener::bind(path).map_err(|e| {
+        let hint = match e.kind() {
+            ErrorKind::NotFound => "; create its directory first",
+            ErrorKind::PermissionDenied => "; make its directory writable by this user",
+            _ => "",
+        };
+        format!("Failed to bind unix:{}: {}{}", path.display(), e, hint)
+    })?;
+
+    let applied = set_access(path, access.mode, uid, gid);
+    if applied.is_err() {
+        remove(path);
+    }
+    applied.map(|()| listener)
+}
+
+fn set_access(
+    path: &Path,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<(), String> {
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set the mode of {}: {}", path.display(), e))?;
+    }
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid).map_err(|e| {
+            format!(
+                "Failed to change the owner of {}: {} (handing it to another user needs \
+                 root, to a group membership of that group)",
+                path.display(),
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// The ID `name` has in `/etc/passwd` or `/etc/group`, whose third field
+/// is the ID. Numeric names are taken as the ID itself.
+fn lookup(file: &str, name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let contents =
+        std::fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {}", file, e))?;
+    contents
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&name))
+        .and_then(|fields| fields.get(2)?.parse().ok())
+        .ok_or_else(|| format!("No entry for {} in {}", name, file))
+}
+
+/// Serves `app` on `listener` until `shutdown` resolves, then waits for open
+/// connections to finish. Peers have no address, so they are all given the
+/// loopback one; behind a proxy, `trust_proxy` tells clients apart.
+pub async fn serve(
+    listener: UnixListener,
+    app: Router,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let app = app.layer(Extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0)))));
+    let mut builder = Builder::new(TokioExecutor::new());
+    crate::limit_connections(&mut builder, config);
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Usually out of file descriptors; give some a chance to close.
+                    tracing::warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = TowerToHyperService::new(app.clone());
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            // Fails when a client hangs up without sending a request.
+            let _ = connection.await;
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+/// Removes the socket file on the way out, so the next run starts clean.
+pub fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != ErrorKind::NotFound {
+            tracing::warn!("Failed to remove socket {}: {}", path.display(), e);
+        }
+    }
+}
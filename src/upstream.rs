@@ -0,0 +1,637 @@
+use crate::cache::CacheKey;
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use utoipa::openapi::{ObjectBuilder, RefOr, Schema, SchemaType};
+
+/// Rows per page requested from paginated endpoints.
+pub const PAGE_LIMIT: usize = 100;
+
+/// Relative time range understood by Plausible's `period` parameter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    SevenDays,
+    ThirtyDays,
+    Month,
+    SixMonths,
+    TwelveMonths,
+    #[default]
+    All,
+}
+
+impl Period {
+    pub const VALUES: [Period; 7] = [
+        Period::Day,
+        Period::SevenDays,
+        Period::ThirtyDays,
+        Period::Month,
+        Period::SixMonths,
+        Period::TwelveMonths,
+        Period::All,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::SevenDays => "7d",
+            Period::ThirtyDays => "30d",
+            Period::Month => "month",
+            Period::SixMonths => "6mo",
+            Period::TwelveMonths => "12mo",
+            Period::All => "all",
+        }
+    }
+
+    /// Length in days of periods that are a fixed number of days ending
+    /// today; calendar periods and `all` have none.
+    pub fn days(self) -> Option<u32> {
+        match self {
+            Period::Day => Some(1),
+            Period::SevenDays => Some(7),
+            Period::ThirtyDays => Some(30),
+            Period::Month | Period::SixMonths | Period::TwelveMonths | Period::All => None,
+        }
+    }
+
+    pub fn accepted() -> Vec<&'static str> {
+        Self::VALUES.iter().map(|period| period.as_str()).collect()
+    }
+
+    /// The first and last day Plausible counts for the period as of
+    /// `today`; `all` has no fixed start.
+    pub fn range(self, today: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+        let first_of_month = today.with_day(1).expect("every month has a first day");
+        let from = match self {
+            Period::Day | Period::SevenDays | Period::ThirtyDays => {
+                let days = self.days().expect("fixed-length period");
+                today - Days::new(u64::from(days) - 1)
+            }
+            Period::Month => first_of_month,
+            Period::SixMonths => first_of_month - Months::new(5),
+            Period::TwelveMonths => first_of_month - Months::new(11),
+            Period::All => return None,
+        };
+        Some((from, today))
+    }
+
+    /// The range the period covered one period earlier: as many days
+    /// before for fixed-length periods, the same days of the month as many
+    /// months before for calendar ones, so a month so far is compared with
+    /// as much of the month before it.
+    pub fn previous_range(self, today: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+        let (from, to) = self.range(today)?;
+        let shifted = match self {
+            Period::Day | Period::SevenDays | Period::ThirtyDays => {
+                let span = Days::new(u64::from(self.days().expect("fixed-length period")));
+                (from - span, to - span)
+            }
+            Period::Month => (from - Months::new(1), to - Months::new(1)),
+            Period::SixMonths => (from - Months::new(6), to - Months::new(6)),
+            Period::TwelveMonths => (from - Months::new(12), to - Months::new(12)),
+            Period::All => unreachable!("`all` has no range"),
+        };
+        Some(shifted)
+    }
+}
+
+impl<'s> utoipa::ToSchema<'s> for Period {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let schema = ObjectBuilder::new()
+            .schema_type(SchemaType::String)
+            .enum_values(Some(Self::VALUES.map(Period::as_str)))
+            .default(Some(Period::default().as_str().into()));
+        ("Period", schema.into())
+    }
+}
+
+impl FromStr for Period {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::VALUES
+            .into_iter()
+            .find(|period| period.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// A property conversions are broken down by, each served on
+/// `/breakdown/<name>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Breakdown {
+    Country,
+    Page,
+    Source,
+    Device,
+    Browser,
+}
+
+impl Breakdown {
+    pub const VALUES: [Breakdown; 5] = [
+        Breakdown::Country,
+        Breakdown::Page,
+        Breakdown::Source,
+        Breakdown::Device,
+        Breakdown::Browser,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Breakdown::Country => "country",
+            Breakdown::Page => "page",
+            Breakdown::Source => "source",
+            Breakdown::Device => "device",
+            Breakdown::Browser => "browser",
+        }
+    }
+
+    /// The property Plausible breaks down by.
+    fn property(self) -> &'static str {
+        match self {
+            Breakdown::Country => "visit:country",
+            Breakdown::Page => "event:page",
+            Breakdown::Source => "visit:source",
+            Breakdown::Device => "visit:device",
+            Breakdown::Browser => "visit:browser",
+        }
+    }
+
+    pub fn accepted() -> Vec<&'static str> {
+        Self::VALUES.iter().map(|breakdown| breakdown.as_str()).collect()
+    }
+}
+
+impl FromStr for Breakdown {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::VALUES
+            .into_iter()
+            .find(|breakdown| breakdown.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// Which upstream endpoint a query targets, and so how its body parses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryKind {
+    Leaderboard,
+    Timeseries,
+    Breakdown,
+    Realtime,
+}
+
+impl QueryKind {
+    pub const VALUES: [QueryKind; 4] = [
+        QueryKind::Leaderboard,
+        QueryKind::Timeseries,
+        QueryKind::Breakdown,
+        QueryKind::Realtime,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QueryKind::Leaderboard => "leaderboard",
+            QueryKind::Timeseries => "timeseries",
+            QueryKind::Breakdown => "breakdown",
+            QueryKind::Realtime => "realtime",
+        }
+    }
+
+    pub fn accepted() -> Vec<&'static str> {
+        Self::VALUES.iter().map(|kind| kind.as_str()).collect()
+    }
+}
+
+impl FromStr for QueryKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::VALUES.into_iter().find(|kind| kind.as_str() == s).ok_or(())
+    }
+}
+
+/// A Plausible site this service exposes, addressed by `key` in routes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    pub key: String,
+    /// Plausible's site ID, usually the domain.
+    pub id: String,
+    /// Overrides `bearer_token` for this site's requests.
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    /// Overrides `goal` for this site. Filled in from it when left empty.
+    #[serde(default)]
+    pub goal: String,
+    /// Overrides `property` for this site. Filled in from it when left
+    /// empty.
+    #[serde(default)]
+    pub property: String,
+}
+
+/// One request against the Plausible stats API, minus the `date` parameter,
+/// which is filled in with the current date each time the query is sent.
+#[derive(Clone, Debug)]
+pub struct UpstreamQuery {
+    path: String,
+    params: Vec<(&'static str, String)>,
+    kind: QueryKind,
+    bearer_token: Option<String>,
+    site_id: String,
+    goal: String,
+    property: String,
+}
+
+impl UpstreamQuery {
+    /// Custom property breakdown for the goal on `site`: the artist
+    /// leaderboard.
+    pub fn leaderboard(site: &Site, period: Period) -> Self {
+        Self::new(
+            site,
+            QueryKind::Leaderboard,
+            format!("custom-prop-values/{}/", path_segment(&site.property)),
+            vec![
+                ("period", period.as_str().to_string()),
+                ("filters", filters(site, &[])),
+                ("with_imported", "true".to_string()),
+                ("detailed", "true".to_string()),
+                ("order_by", serde_json::json!([["visitors", "desc"]]).to_string()),
+                ("limit", PAGE_LIMIT.to_string()),
+            ],
+        )
+    }
+
+    /// The artist leaderboard for the days from `from` to `to` inclusive.
+    pub fn leaderboard_between(site: &Site, from: NaiveDate, to: NaiveDate) -> Self {
+        let mut query = Self::leaderboard(site, Period::All);
+        for (name, value) in &mut query.params {
+            if *name == "period" {
+                *value = "custom".to_string();
+            }
+        }
+        query.params.push(("from", from.format("%Y-%m-%d").to_string()));
+        query.params.push(("to", to.format("%Y-%m-%d").to_string()));
+        query
+    }
+
+    /// Daily visitors converting on the goal on `site` with any of `names`
+    /// as the `name` property: one artist's time series, under each spelling
+    /// it was tracked with.
+    pub fn timeseries(site: &Site, period: Period, names: &[String]) -> Self {
+        Self::new(
+            site,
+            QueryKind::Timeseries,
+            "main-graph".to_string(),
+            vec![
+                ("period", period.as_str().to_string()),
+                ("filters", filters(site, names)),
+                ("with_imported", "true".to_string()),
+                ("metric", "visitors".to_string()),
+                ("interval", "day".to_string()),
+            ],
+        )
+    }
+
+    /// Visitors and events converting on the goal on `site`, broken down
+    /// by `breakdown`'s property, from the breakdown API.
+    pub fn breakdown(site: &Site, period: Period, breakdown: Breakdown) -> Self {
+        // `|` separates alternatives in these filters.
+        let goal = site.goal.replace('|', "\\|");
+        Self::at(
+            site,
+            QueryKind::Breakdown,
+            "/api/v1/stats/breakdown".to_string(),
+            vec![
+                ("site_id", site.id.clone()),
+                ("period", period.as_str().to_string()),
+                ("property", breakdown.property().to_string()),
+                ("filters", format!("event:goal=={}", goal)),
+                ("metrics", "visitors,events".to_string()),
+                ("limit", PAGE_LIMIT.to_string()),
+            ],
+        )
+    }
+
+    /// Visitors on `site` in the last five minutes, from the realtime API.
+    /// Not limited to the goal: Plausible doesn't filter realtime counts.
+    pub fn realtime(site: &Site) -> Self {
+        Self::at(
+            site,
+            QueryKind::Realtime,
+            "/api/v1/stats/realtime/visitors".to_string(),
+            vec![("site_id", site.id.clone())],
+        )
+    }
+
+    /// A query against `endpoint` of `site`'s stats API.
+    fn new(
+        site: &Site,
+        kind: QueryKind,
+        endpoint: String,
+        params: Vec<(&'static str, String)>,
+    ) -> Self {
+        Self::at(site, kind, format!("/api/stats/{}/{}", site.id, endpoint), params)
+    }
+
+    /// A query against `path` on the upstream, on behalf of `site`.
+    fn at(site: &Site, kind: QueryKind, path: String, params: Vec<(&'static str, String)>) -> Self {
+        UpstreamQuery {
+            path,
+            params,
+            kind,
+            bearer_token: site.bearer_token.clone(),
+            site_id: site.id.clone(),
+            goal: site.goal.clone(),
+            property: site.property.clone(),
+        }
+    }
+
+    /// Smallest useful authenticated request, for reachability checks.
+    pub fn probe(site: &Site) -> Self {
+        let mut query = Self::leaderboard(site, Period::Day);
+        for (name, value) in &mut query.params {
+            if *name == "limit" {
+                *value = "1".to_string();
+            }
+        }
+        query
+    }
+
+    pub fn kind(&self) -> QueryKind {
+        self.kind
+    }
+
+    /// Token to send instead of the global one, if the site has its own.
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.bearer_token.as_deref()
+    }
+
+    /// The site's ID, for backends that address it differently.
+    pub fn site_id(&self) -> &str {
+        &self.site_id
+    }
+
+    /// The goal whose conversions are counted.
+    pub fn goal(&self) -> &str {
+        &self.goal
+    }
+
+    /// The custom property rows are keyed by.
+    pub fn property(&self) -> &str {
+        &self.property
+    }
+
+    /// Value of the query string parameter `name`, if the query sets it.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The breakdown this query fetches, if it is one.
+    pub fn breakdown_of(&self) -> Option<Breakdown> {
+        let property = self.param("property")?;
+        Breakdown::VALUES
+            .into_iter()
+            .find(|breakdown| breakdown.property() == property)
+    }
+
+    /// Whether results are split into pages of `PAGE_LIMIT` rows.
+    pub fn paginated(&self) -> bool {
+        matches!(self.kind, QueryKind::Leaderboard | QueryKind::Breakdown)
+    }
+
+    /// Normalized form of this query, used to key the cache. Excludes the
+    /// date so an entry stays addressable across midnight.
+    pub fn cache_key(&self) -> CacheKey {
+        CacheKey::new(
+            &self.path,
+            self.params.iter().map(|(name, value)| (*name, value.as_str())),
+        )
+    }
+
+    pub fn url(&self, base_url: &str) -> String {
+        format!("{}{}", base_url, self.path)
+    }
+
+    /// Query string parameters for a request sent on `today`, asking for
+    /// `page` when the query is paginated. Values are left unencoded;
+    /// `reqwest` percent-encodes them.
+    pub fn params(&self, today: NaiveDate, page: u32) -> Vec<(&'static str, String)> {
+        let mut params = self.params.clone();
+        params.push(("date", today.format("%Y-%m-%d").to_string()));
+        if self.paginated() {
+            params.push(("page", page.to_string()));
+        }
+        params
+    }
+}
+
+/// The date it is now in `timezone`, which is the one Plausible's dashboard
+/// shows for a site set to it.
+pub fn today(timezone: chrono_tz::Tz) -> NaiveDate {
+    date_at(Utc::now(), timezone)
+}
+
+fn date_at(now: DateTime<Utc>, timezone: chrono_tz::Tz) -> NaiveDate {
+    now.with_timezone(&timezone).date_naive()
+}
+
+/// When `day` starts in `zone`: its midnight, or the first moment after it
+/// when the clocks skip midnight that day.
+pub fn start_of_day<Z: TimeZone>(day: NaiveDate, zone: &Z) -> DateTime<Z> {
+    let midnight = day.and_time(NaiveTime::MIN);
+    zone.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| zone.from_local_datetime(&(midnight + TimeDelta::hours(1))).earliest())
+        .unwrap_or_else(|| zone.from_utc_datetime(&midnight))
+}
+
+/// Parses the `SITES` variable: comma-separated `key:site_id` pairs, e.g.
+/// `grid:artistgrid.cx,other:example.com`. Fails with a message naming the
+/// offending entry.
+pub fn parse_sites(value: &str) -> Result<Vec<Site>, String> {
+    let mut sites: Vec<Site> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (key, id) = entry
+            .split_once(':')
+            .map(|(key, id)| (key.trim(), id.trim()))
+            .filter(|(key, id)| !key.is_empty() && !id.is_empty())
+            .ok_or_else(|| format!("`{}` is not of the form key:site_id", entry))?;
+        if !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("site key `{}` may only contain A-Z, a-z, 0-9, - and _", key));
+        }
+        if sites.iter().any(|site| site.key == key) {
+            return Err(format!("site key `{}` is listed twice", key));
+        }
+        sites.push(Site {
+            key: key.to_string(),
+            id: id.to_string(),
+            bearer_token: None,
+            goal: String::new(),
+            property: String::new(),
+        });
+    }
+
+    if sites.is_empty() {
+        return Err("no sites listed".to_string());
+    }
+    Ok(sites)
+}
+
+/// Plausible filter restricting results to conversions of `site`'s goal,
+/// and to those `names` of its property unless empty.
+fn filters(site: &Site, names: &[String]) -> String {
+    let mut filters = vec![serde_json::json!(["is", "event:goal", [site.goal]])];
+    if !names.is_empty() {
+        let property = format!("event:props:{}", site.property);
+        filters.push(serde_json::json!(["is", property, names]));
+    }
+    serde_json::Value::from(filters).to_string()
+}
+
+/// `segment` percent-encoded to stand as one segment of a URL path.
+fn path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site() -> Site {
+        Site {
+            key: "grid".to_string(),
+            id: "artistgrid.cx".to_string(),
+            bearer_token: None,
+            goal: "Artist Click".to_string(),
+            property: "name".to_string(),
+        }
+    }
+
+    /// The URL `query` is sent to on `today`, encoded as `reqwest` sends it.
+    fn sent(query: &UpstreamQuery, today: NaiveDate, page: u32) -> String {
+        reqwest::Client::new()
+            .get(query.url("https://plausible.example"))
+            .query(&query.params(today, page))
+            .build()
+            .unwrap()
+            .url()
+            .to_string()
+    }
+
+    #[test]
+    fn leaderboard_url_carries_the_date_and_encoded_filters() {
+        let today = NaiveDate::from_ymd_opt(2026, 3, 9).unwrap();
+        let query = UpstreamQuery::leaderboard(&site(), Period::All);
+        assert_eq!(
+            sent(&query, today, 2),
+            "https://plausible.example/api/stats/artistgrid.cx/custom-prop-values/name/\
+             ?period=all\
+             &filters=%5B%5B%22is%22%2C%22event%3Agoal%22%2C%5B%22Artist+Click%22%5D%5D%5D\
+             &with_imported=true&detailed=true\
+             &order_by=%5B%5B%22visitors%22%2C%22desc%22%5D%5D\
+             &limit=100&date=2026-03-09&page=2"
+        );
+    }
+
+    #[test]
+    fn date_is_the_one_asked_for_and_not_in_the_cache_key() {
+        let query = UpstreamQuery::leaderboard(&site(), Period::Day);
+        let monday = NaiveDate::from_ymd_opt(2026, 3, 9).unwrap();
+        let tuesday = monday.succ_opt().unwrap();
+        assert!(query.params(monday, 1).contains(&("date", "2026-03-09".to_string())));
+        assert!(query.params(tuesday, 1).contains(&("date", "2026-03-10".to_string())));
+        assert!(!query.cache_key().to_string().contains("2026"));
+    }
+
+    #[test]
+    fn timeseries_filters_name_every_spelling() {
+        let names = ["Tyler, The Creator".to_string(), "Tyler & Co".to_string()];
+        let query = UpstreamQuery::timeseries(&site(), Period::Month, &names);
+        let today = NaiveDate::from_ymd_opt(2026, 3, 9).unwrap();
+        let url = sent(&query, today, 1);
+        assert!(url.contains(
+            "&filters=%5B%5B%22is%22%2C%22event%3Agoal%22%2C%5B%22Artist+Click%22%5D%5D%2C\
+             %5B%22is%22%2C%22event%3Aprops%3Aname%22%2C\
+             %5B%22Tyler%2C+The+Creator%22%2C%22Tyler+%26+Co%22%5D%5D%5D&"
+        ));
+        // Not paginated, so no page.
+        assert!(url.ends_with("&date=2026-03-09"), "{}", url);
+    }
+
+    #[test]
+    fn property_is_escaped_as_a_path_segment() {
+        let site = Site {
+            property: "artist name/alias".to_string(),
+            ..site()
+        };
+        let query = UpstreamQuery::leaderboard(&site, Period::All);
+        assert!(query.url("").ends_with("/custom-prop-values/artist%20name%2Falias/"));
+    }
+
+    fn utc(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn date_turns_over_at_local_midnight() {
+        use chrono_tz::{America::New_York, Europe::Berlin};
+        // 23:59:59 and 00:00 in Berlin on a winter night.
+        assert_eq!(date_at(utc("2025-11-06T22:59:59Z"), Berlin), day(2025, 11, 6));
+        assert_eq!(date_at(utc("2025-11-06T23:00:00Z"), Berlin), day(2025, 11, 7));
+        // The same instants are still the 6th in New York.
+        assert_eq!(date_at(utc("2025-11-06T23:00:00Z"), New_York), day(2025, 11, 6));
+        assert_eq!(date_at(utc("2025-11-07T05:00:00Z"), New_York), day(2025, 11, 7));
+        // On the night the clocks go back, midnight is still on summer time.
+        assert_eq!(date_at(utc("2025-11-02T03:59:59Z"), New_York), day(2025, 11, 1));
+        assert_eq!(date_at(utc("2025-11-02T04:00:00Z"), New_York), day(2025, 11, 2));
+        // The repeated hour is the same day on both passes.
+        assert_eq!(date_at(utc("2025-11-02T05:30:00Z"), New_York), day(2025, 11, 2));
+        assert_eq!(date_at(utc("2025-11-02T06:30:00Z"), New_York), day(2025, 11, 2));
+    }
+
+    #[test]
+    fn day_starts_at_midnight_on_ordinary_and_changeover_days() {
+        use chrono_tz::Europe::Berlin;
+        let start = |date| start_of_day(date, &Berlin).with_timezone(&Utc);
+        assert_eq!(start(day(2025, 11, 7)), utc("2025-11-06T23:00:00Z"));
+        // The clocks change at 02:00 or 03:00, well after midnight.
+        assert_eq!(start(day(2025, 3, 30)), utc("2025-03-29T23:00:00Z"));
+        assert_eq!(start(day(2025, 10, 26)), utc("2025-10-25T22:00:00Z"));
+    }
+
+    #[test]
+    fn day_starts_after_a_skipped_midnight() {
+        use chrono_tz::{America::Santiago, Asia::Beirut};
+        // Both go from 23:59:59 straight to 01:00.
+        let start = start_of_day(day(2024, 9, 8), &Santiago);
+        assert_eq!(start.with_timezone(&Utc), utc("2024-09-08T04:00:00Z"));
+        assert_eq!(start.naive_local(), day(2024, 9, 8).and_hms_opt(1, 0, 0).unwrap());
+        let start = start_of_day(day(2025, 3, 30), &Beirut);
+        assert_eq!(start.with_timezone(&Utc), utc("2025-03-29T22:00:00Z"));
+    }
+
+    #[test]
+    fn day_starts_at_the_first_of_a_repeated_midnight() {
+        // Havana goes back from 01:00 to midnight, so midnight happens twice.
+        let start = start_of_day(day(2025, 11, 2), &chrono_tz::America::Havana);
+        assert_eq!(start.with_timezone(&Utc), utc("2025-11-02T04:00:00Z"));
+    }
+}
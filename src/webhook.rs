@@ -0,0 +1,132 @@
+use crate::plausible::ArtistRow;
+use crate::spikes::Spike;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Artists included in each delivery.
+pub const TOP: usize = 10;
+/// Tries per delivery, the first included.
+const ATTEMPTS: u32 = 3;
+/// Time each try gets before it counts as failed.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Carries `sha256=<hex HMAC-SHA256 of the body>` when a secret is set.
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Body POSTed to `webhook_url`.
+#[derive(Serialize)]
+pub struct Notification<'a> {
+    pub site: &'a str,
+    /// When the refresh that changed the data was fetched, RFC 3339.
+    pub refreshed_at: String,
+    /// Set on deliveries fired from the admin test endpoint.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub test: bool,
+    pub top: &'a [ArtistRow],
+    pub diff: Diff,
+}
+
+/// Body POSTed to `webhook_url` when artists' visitors spike.
+#[derive(Serialize)]
+pub struct SpikeNotification<'a> {
+    /// Always `spike`, telling these apart from leaderboard deliveries.
+    pub event: &'static str,
+    pub site: &'a str,
+    /// When the refresh the spikes showed up in was fetched, RFC 3339.
+    pub detected_at: String,
+    pub spikes: &'a [Spike],
+}
+
+/// How the whole leaderboard changed since the previous refresh.
+#[derive(Default, Serialize)]
+pub struct Diff {
+    /// Artists whose visitors changed.
+    pub changed: usize,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// How a delivery went.
+#[derive(Serialize, ToSchema)]
+pub struct Delivery {
+    pub delivered: bool,
+    /// The receiver's last answer, if it gave one.
+    pub status: Option<u16>,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// POSTs `body` to `url`, retrying with backoff when the receiver can't be
+/// reached, times out, or answers 429 or 5xx. Other answers are final.
+pub async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: Option<&str>,
+    body: String,
+) -> Delivery {
+    let signature = secret.map(|secret| sign(secret, &body));
+    let mut attempt = 1;
+    loop {
+        let mut request = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(TIMEOUT)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        let (status, error, retry) = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return Delivery {
+                    delivered: true,
+                    status: Some(response.status().as_u16()),
+                    attempts: attempt,
+                    error: None,
+                };
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retry = status.is_server_error() || status.as_u16() == 429;
+                (Some(status.as_u16()), format!("receiver answered {}", status), retry)
+            }
+            Err(e) => (None, e.to_string(), true),
+        };
+
+        if !retry || attempt >= ATTEMPTS {
+            return Delivery {
+                delivered: false,
+                status,
+                attempts: attempt,
+                error: Some(error),
+            };
+        }
+        let delay = crate::refresh::backoff(attempt);
+        tracing::debug!(
+            "Webhook attempt {}/{} failed ({}), retrying in {:?}",
+            attempt,
+            ATTEMPTS,
+            error,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", digest)
+}
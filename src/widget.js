@@ -0,0 +1,69 @@
+(function () {
+  "use strict";
+  var BASE = __BASE__;
+  var LIMIT = __LIMIT__;
+  var MAX_LIMIT = __MAX_LIMIT__;
+  var STYLE =
+    ".agw{font:14px/1.4 system-ui,sans-serif;color:#222;max-width:24rem}" +
+    ".agw ol{list-style:none;margin:0;padding:0}" +
+    ".agw li{display:flex;gap:.5rem;padding:.3rem 0;border-bottom:1px solid #ddd}" +
+    ".agw .agw-rank{min-width:1.6rem;color:#666;text-align:right}" +
+    ".agw .agw-name{flex:1;overflow-wrap:anywhere}" +
+    ".agw .agw-count{font-variant-numeric:tabular-nums}" +
+    ".agw .agw-note{color:#666}";
+
+  var script = document.currentScript;
+  var selector = (script && script.getAttribute("data-target")) || "#artistgrid-stats";
+  var limit = parseInt(script && script.getAttribute("data-limit"), 10);
+  if (!(limit >= 1)) limit = LIMIT;
+  limit = Math.min(limit, MAX_LIMIT);
+
+  function text(tag, className, content) {
+    var element = document.createElement(tag);
+    element.className = className;
+    element.textContent = content;
+    return element;
+  }
+
+  function render(target, rows) {
+    var list = document.createElement("ol");
+    rows.forEach(function (row) {
+      var item = document.createElement("li");
+      item.appendChild(text("span", "agw-rank", row.rank));
+      item.appendChild(text("span", "agw-name", row.name));
+      item.appendChild(text("span", "agw-count", row.visitors.toLocaleString()));
+      list.appendChild(item);
+    });
+    target.replaceChildren(rows.length ? list : text("p", "agw-note", "No artists yet."));
+  }
+
+  function start() {
+    var target = document.querySelector(selector);
+    if (!target) return;
+    if (!document.getElementById("agw-style")) {
+      var style = document.createElement("style");
+      style.id = "agw-style";
+      style.textContent = STYLE;
+      document.head.appendChild(style);
+    }
+    target.classList.add("agw");
+    target.replaceChildren(text("p", "agw-note", "Loading…"));
+    fetch(BASE + "/top/" + limit)
+      .then(function (response) {
+        if (!response.ok) throw new Error(response.status);
+        return response.json();
+      })
+      .then(function (rows) {
+        render(target, rows);
+      })
+      .catch(function () {
+        target.replaceChildren(text("p", "agw-note", "Stats are unavailable right now."));
+      });
+  }
+
+  if (document.readyState === "loading") {
+    document.addEventListener("DOMContentLoaded", start);
+  } else {
+    start();
+  }
+})();
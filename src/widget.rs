@@ -0,0 +1,15 @@
+/// The widget script, with `__BASE__`, `__LIMIT__` and `__MAX_LIMIT__` filled
+/// in when it is served.
+const TEMPLATE: &str = include_str!("widget.js");
+
+/// The widget script, fetching from `base`, the URL the site's routes are
+/// under, `limit` artists unless its tag asks for a different number up to
+/// `max_limit`.
+pub fn script(base: &str, limit: usize, max_limit: usize) -> String {
+    // As a JSON string, `base` is also a valid JavaScript one.
+    let base = serde_json::to_string(base).expect("strings serialize");
+    TEMPLATE
+        .replace("__BASE__", &base)
+        .replace("__MAX_LIMIT__", &max_limit.to_string())
+        .replace("__LIMIT__", &limit.to_string())
+}
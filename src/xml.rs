@@ -0,0 +1,195 @@
+use crate::badge::escape;
+use serde::de::{Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Serialize;
+use std::fmt::{self, Write};
+
+/// Fields written as attributes of their element rather than as children.
+const ATTRIBUTES: [&str; 1] = ["rank"];
+
+/// `value` as XML, read back from its JSON so the two formats come from the
+/// same structs and can't drift apart. A list is a `root` element with an
+/// `item` element per entry; an object is a `root` element with a child per
+/// field, in the order the JSON has them. Null fields are left out, as are
+/// keys that aren't XML names.
+pub fn render(value: &impl Serialize, root: &str, item: &str) -> String {
+    let json = serde_json::to_string(value).expect("response bodies serialize");
+    let node: Node = serde_json::from_str(&json).expect("serialized JSON parses");
+    let mut out = String::from(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    match &node {
+        Node::List(items) => {
+            let _ = write!(out, "<{}>", root);
+            for entry in items {
+                element(&mut out, item, entry);
+            }
+            let _ = write!(out, "</{}>", root);
+        }
+        node => element(&mut out, root, node),
+    }
+    out
+}
+
+/// A JSON value with its object keys in document order.
+enum Node {
+    Null,
+    /// A string, number or boolean, as its text.
+    Text(String),
+    List(Vec<Node>),
+    Object(Vec<(String, Node)>),
+}
+
+fn element(out: &mut String, name: &str, node: &Node) {
+    match node {
+        Node::Null => {}
+        Node::Text(text) => {
+            let _ = write!(out, "<{}>{}</{}>", name, escape(text), name);
+        }
+        Node::List(items) => {
+            let _ = write!(out, "<{}>", name);
+            for entry in items {
+                element(out, "item", entry);
+            }
+            let _ = write!(out, "</{}>", name);
+        }
+        Node::Object(fields) => {
+            let fields = fields.iter().filter(|(key, _)| is_name(key));
+            let _ = write!(out, "<{}", name);
+            for (key, value) in fields.clone() {
+                if let (true, Node::Text(text)) = (ATTRIBUTES.contains(&key.as_str()), value) {
+                    let _ = write!(out, r#" {}="{}""#, key, escape(text));
+                }
+            }
+            out.push('>');
+            for (key, value) in fields {
+                if !(ATTRIBUTES.contains(&key.as_str()) && matches!(value, Node::Text(_))) {
+                    element(out, key, value);
+                }
+            }
+            let _ = write!(out, "</{}>", name);
+        }
+    }
+}
+
+/// Whether `key` can name an element: ASCII letters, digits, `_`, `-` and
+/// `.`, starting with a letter or `_`, and not starting with `xml`.
+fn is_name(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !key.to_ascii_lowercase().starts_with("xml")
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+    type Value = Node;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Node, E> {
+        Ok(Node::Null)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Node, E> {
+        Ok(Node::Text(value.to_string()))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Node, E> {
+        Ok(Node::Text(value.to_string()))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Node, E> {
+        Ok(Node::Text(value.to_string()))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Node, E> {
+        // Formatted as the JSON has it.
+        Ok(Node::Text(serde_json::Value::from(value).to_string()))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Node, E> {
+        Ok(Node::Text(value.to_string()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Node, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Node::List(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+        let mut fields = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            fields.push(entry);
+        }
+        Ok(Node::Object(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row<'a> {
+        rank: usize,
+        name: &'a str,
+        visitors: u64,
+        share: Option<f64>,
+        aliases: Vec<&'a str>,
+        #[serde(rename = "xmlns")]
+        namespace: &'a str,
+    }
+
+    #[test]
+    fn rows_render_as_the_fixture() {
+        let rows = [
+            Row {
+                rank: 1,
+                name: "Tyler, The Creator",
+                visitors: 1840,
+                share: Some(0.25),
+                aliases: vec!["Tyler", "T & C"],
+                namespace: "ignored",
+            },
+            Row {
+                rank: 2,
+                name: "<Kanye> \"Ye\" West's",
+                visitors: 1622,
+                share: None,
+                aliases: Vec::new(),
+                namespace: "ignored",
+            },
+        ];
+        let expected = include_str!("../fixtures/xml/artists.xml");
+        assert_eq!(render(&rows, "artists", "artist"), expected.trim_end());
+    }
+
+    #[test]
+    fn characters_xml_disallows_are_replaced() {
+        let name = "a\u{0}b\u{8}c\u{b}\u{c}d\u{1b}e\u{1f}f\u{fffe}\u{ffff}";
+        let row = Row {
+            rank: 1,
+            name,
+            visitors: 0,
+            share: None,
+            aliases: vec!["tab\tnewline\nreturn\r", "\u{7f}\u{85}\u{10ffff}"],
+            namespace: "",
+        };
+        let xml = render(&row, "artist", "item");
+        let replaced = "a\u{fffd}b\u{fffd}c\u{fffd}\u{fffd}d\u{fffd}e\u{fffd}f\u{fffd}\u{fffd}";
+        assert!(xml.contains(&format!("<name>{}</name>", replaced)), "{}", xml);
+        // Whitespace controls and everything past U+001F but the two
+        // non-characters are allowed.
+        assert!(xml.contains("<item>tab\tnewline\nreturn\r</item>"), "{}", xml);
+        assert!(xml.contains("<item>\u{7f}\u{85}\u{10ffff}</item>"), "{}", xml);
+    }
+}
@@ -0,0 +1,825 @@
+//! Drives the router end to end against fixture data: no network, no
+//! background tasks, one `AppState` per test.
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::{Extension, Router};
+use plausible_proxy::config::{Config, ForwardedHeader};
+use plausible_proxy::error::FetchError;
+use plausible_proxy::upstream::{Site, UpstreamQuery};
+use plausible_proxy::{build_router, AppState, MockFetcher, StatsFetcher};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+/// A MockFetcher that fails every fetch while `down` is set, counting the
+/// attempts it turned away.
+struct Flaky {
+    inner: MockFetcher,
+    down: AtomicBool,
+    refused: AtomicUsize,
+}
+
+#[axum::async_trait]
+impl StatsFetcher for Flaky {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError> {
+        if self.down.load(Ordering::SeqCst) {
+            self.refused.fetch_add(1, Ordering::SeqCst);
+            return Err(FetchError::Request("connection reset".into()));
+        }
+        self.inner.fetch(query, page, timeout).await
+    }
+}
+
+/// A MockFetcher that takes `delay` over every fetch, so requests sent
+/// together overlap it.
+struct Slow {
+    inner: Arc<MockFetcher>,
+    delay: Duration,
+}
+
+#[axum::async_trait]
+impl StatsFetcher for Slow {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError> {
+        tokio::time::sleep(self.delay).await;
+        self.inner.fetch(query, page, timeout).await
+    }
+}
+
+/// A Slow fetcher that also records the most fetches it had running at
+/// once.
+struct Gauged {
+    inner: Slow,
+    running: AtomicUsize,
+    most: AtomicUsize,
+}
+
+#[axum::async_trait]
+impl StatsFetcher for Gauged {
+    async fn fetch(
+        &self,
+        query: &UpstreamQuery,
+        page: u32,
+        timeout: Duration,
+    ) -> Result<String, FetchError> {
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.most.fetch_max(running, Ordering::SeqCst);
+        let result = self.inner.fetch(query, page, timeout).await;
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Fails every fetch with `error`, counting the calls.
+struct Failing {
+    error: FetchError,
+    calls: AtomicUsize,
+}
+
+impl Failing {
+    fn new(error: FetchError) -> Self {
+        Failing {
+            error,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[axum::async_trait]
+impl StatsFetcher for Failing {
+    async fn fetch(
+        &self,
+        _query: &UpstreamQuery,
+        _page: u32,
+        _timeout: Duration,
+    ) -> Result<String, FetchError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Err(self.error.clone())
+    }
+}
+
+fn mock() -> MockFetcher {
+    let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/sample.json");
+    MockFetcher::new(&[fixture]).expect("the sample fixture parses")
+}
+
+/// One site on a fixed TTL with rate limiting off, so tests see exactly
+/// the cache behaviour they set up.
+fn config(cache_ttl: Duration) -> Config {
+    Config {
+        sites: vec![Site {
+            key: "default".to_string(),
+            id: "example.com".to_string(),
+            bearer_token: None,
+            goal: "Artist Click".to_string(),
+            property: "name".to_string(),
+        }],
+        cache_ttl,
+        cache_ttl_jitter_percent: 0,
+        rate_limit_per_minute: 0,
+        ..Config::default()
+    }
+}
+
+async fn router(config: Config, fetcher: Arc<dyn StatsFetcher>) -> Router {
+    router_for(config, fetcher, SocketAddr::from(([127, 0, 0, 1], 4000))).await
+}
+
+/// A router seeing every request as coming from `peer`.
+async fn router_for(config: Config, fetcher: Arc<dyn StatsFetcher>, peer: SocketAddr) -> Router {
+    let state = AppState::new(config, fetcher).await.expect("the state builds");
+    build_router(state).layer(Extension(ConnectInfo(peer)))
+}
+
+struct Answer {
+    status: StatusCode,
+    cache: String,
+    headers: HeaderMap,
+    body: serde_json::Value,
+}
+
+async fn get(app: &Router, uri: &str) -> Answer {
+    send(app, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+async fn post_json(app: &Router, uri: &str, body: serde_json::Value) -> Answer {
+    let request = Request::post(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+/// The body of `uri` as MessagePack, decoded.
+async fn get_msgpack(app: &Router, uri: &str) -> serde_json::Value {
+    let request = Request::get(uri)
+        .header("accept", "application/msgpack")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "application/msgpack");
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    rmp_serde::from_slice(&bytes).expect("the body is MessagePack")
+}
+
+async fn send(app: &Router, request: Request<Body>) -> Answer {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let cache = response
+        .headers()
+        .get("x-cache")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+    Answer {
+        status,
+        cache,
+        headers,
+        body,
+    }
+}
+
+/// Waits up to a second for `done`, for work a request left running in the
+/// background.
+async fn eventually(done: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for background work");
+}
+
+#[tokio::test]
+async fn first_request_misses_and_the_next_hits() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let first = get(&app, "/").await;
+    assert_eq!(first.status, StatusCode::OK);
+    assert_eq!(first.cache, "MISS");
+    assert_eq!(first.body["results"][0]["name"], "Playboi Carti");
+
+    let second = get(&app, "/").await;
+    assert_eq!(second.status, StatusCode::OK);
+    assert_eq!(second.cache, "HIT");
+    assert_eq!(second.body, first.body);
+    assert_eq!(fetcher.fetches(), 1);
+}
+
+#[tokio::test]
+async fn routes_over_one_query_share_its_entry() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    assert_eq!(get(&app, "/").await.cache, "MISS");
+    let top = get(&app, "/top/3").await;
+    assert_eq!(top.status, StatusCode::OK);
+    assert_eq!(top.cache, "HIT");
+    assert_eq!(fetcher.fetches(), 1);
+}
+
+#[tokio::test]
+async fn expired_entry_is_served_stale_then_revalidated() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_millis(50)), fetcher.clone()).await;
+
+    assert_eq!(get(&app, "/").await.cache, "MISS");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let stale = get(&app, "/").await;
+    assert_eq!(stale.status, StatusCode::OK);
+    assert_eq!(stale.cache, "STALE");
+    eventually(|| fetcher.fetches() == 2).await;
+
+    assert_eq!(get(&app, "/").await.cache, "HIT");
+}
+
+#[tokio::test]
+async fn zero_ttl_fetches_every_time() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::ZERO), fetcher.clone()).await;
+
+    assert_eq!(get(&app, "/").await.cache, "MISS");
+    assert_eq!(get(&app, "/").await.cache, "MISS");
+    assert_eq!(fetcher.fetches(), 2);
+}
+
+#[tokio::test]
+async fn failure_with_nothing_cached_is_an_error() {
+    let fetcher = Arc::new(Flaky {
+        inner: mock(),
+        down: AtomicBool::new(true),
+        refused: AtomicUsize::new(0),
+    });
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let failed = get(&app, "/").await;
+    assert_eq!(failed.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(failed.cache, "ERROR");
+    assert!(failed.body["error"].is_object(), "{}", failed.body);
+
+    // The failure is remembered, so the retry doesn't reach the upstream.
+    assert_eq!(get(&app, "/").await.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(fetcher.refused.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn failures_are_remembered_for_the_negative_cache_ttl() {
+    // Not transient, so the circuit breaker stays out of it.
+    let fetcher = Arc::new(Failing::new(FetchError::Invalid("not a leaderboard".into())));
+    let config = Config {
+        negative_cache_ttl: Duration::from_millis(200),
+        upstream_attempts: 1,
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher.clone()).await;
+
+    let failed = get(&app, "/").await;
+    assert_eq!(failed.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(fetcher.calls(), 1);
+    let remembered = get(&app, "/").await;
+    assert_eq!(remembered.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(remembered.body, failed.body);
+    assert_eq!(fetcher.calls(), 1);
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(get(&app, "/").await.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(fetcher.calls(), 2);
+}
+
+#[tokio::test]
+async fn failure_after_expiry_serves_the_last_good_data() {
+    let fetcher = Arc::new(Flaky {
+        inner: mock(),
+        down: AtomicBool::new(false),
+        refused: AtomicUsize::new(0),
+    });
+    let app = router(config(Duration::from_millis(50)), fetcher.clone()).await;
+
+    let good = get(&app, "/").await;
+    assert_eq!(good.cache, "MISS");
+    fetcher.down.store(true, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let stale = get(&app, "/").await;
+    assert_eq!(stale.status, StatusCode::OK);
+    assert_eq!(stale.cache, "STALE");
+    assert_eq!(stale.body, good.body);
+    eventually(|| fetcher.refused.load(Ordering::SeqCst) == 1).await;
+
+    // The failed revalidation is remembered; the old data keeps being served.
+    let again = get(&app, "/").await;
+    assert_eq!(again.status, StatusCode::OK);
+    assert_eq!(again.cache, "STALE");
+    assert_eq!(fetcher.refused.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn invalid_parameter_is_rejected_before_fetching() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let answer = get(&app, "/?period=fortnight").await;
+    assert_eq!(answer.status, StatusCode::BAD_REQUEST);
+    assert_eq!(answer.body["error"]["code"], "invalid_parameter");
+    assert_eq!(fetcher.fetches(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_requests_all_get_the_same_data() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let answers = futures_util::future::join_all((0..16).map(|_| get(&app, "/"))).await;
+    for answer in &answers {
+        assert_eq!(answer.status, StatusCode::OK);
+        assert_eq!(answer.body, answers[0].body);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_misses_share_one_upstream_fetch() {
+    let counted = Arc::new(mock());
+    let fetcher = Arc::new(Slow {
+        inner: counted.clone(),
+        delay: Duration::from_millis(100),
+    });
+    let app = router(config(Duration::from_secs(60)), fetcher).await;
+
+    let answers = futures_util::future::join_all((0..32).map(|_| get(&app, "/"))).await;
+    for answer in &answers {
+        assert_eq!(answer.status, StatusCode::OK);
+        // Every one waited on the fetch rather than finding it cached.
+        assert_eq!(answer.cache, "MISS");
+    }
+    assert_eq!(counted.fetches(), 1);
+}
+
+#[tokio::test]
+async fn deeply_nested_graphql_is_refused() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let query = format!("{}{}", "{ a ".repeat(5000), "}".repeat(5000));
+    let answer = post_json(&app, "/graphql", serde_json::json!({ "query": query })).await;
+    assert_eq!(answer.status, StatusCode::BAD_REQUEST);
+    assert_eq!(answer.body["errors"][0]["extensions"]["code"], "query_too_deep");
+    assert_eq!(fetcher.fetches(), 0);
+}
+
+#[tokio::test]
+async fn msgpack_carries_the_same_document_as_json() {
+    let app = router(config(Duration::from_secs(60)), Arc::new(mock())).await;
+
+    for uri in ["/?limit=3", "/?fields=name&limit=2", "/top/3", "/summary"] {
+        let json = get(&app, uri).await;
+        assert_eq!(json.status, StatusCode::OK);
+        assert_eq!(get_msgpack(&app, uri).await, json.body, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn keyed_answers_are_private_to_the_key() {
+    let config = Config {
+        api_keys: vec!["reader-key".to_string()],
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, Arc::new(mock())).await;
+
+    assert_eq!(get(&app, "/").await.status, StatusCode::UNAUTHORIZED);
+
+    let request = Request::get("/")
+        .header("x-api-key", "reader-key")
+        .body(Body::empty())
+        .unwrap();
+    let answer = send(&app, request).await;
+    assert_eq!(answer.status, StatusCode::OK);
+    let cache_control = answer.headers["cache-control"].to_str().unwrap();
+    assert!(cache_control.starts_with("private, max-age="), "{}", cache_control);
+    let vary: Vec<_> = answer.headers.get_all("vary").iter().collect();
+    assert!(vary.iter().any(|v| *v == "authorization, x-api-key"), "{:?}", vary);
+}
+
+#[tokio::test]
+async fn open_answers_are_public() {
+    let app = router(config(Duration::from_secs(60)), Arc::new(mock())).await;
+    let answer = get(&app, "/").await;
+    let cache_control = answer.headers["cache-control"].to_str().unwrap();
+    assert!(cache_control.starts_with("public, max-age="), "{}", cache_control);
+}
+
+/// Admin routes open to one v4 and one v6 range, with `trust_proxy` set but
+/// no `trusted_proxies`.
+fn allowlisted() -> Config {
+    Config {
+        admin_token: Some("admin-token".to_string()),
+        admin_allow_nets: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+        trust_proxy: true,
+        ..config(Duration::from_secs(60))
+    }
+}
+
+async fn admin_status(app: &Router, forwarded: Option<(&str, &str)>) -> StatusCode {
+    let mut request = Request::get("/status").header("authorization", "Bearer admin-token");
+    if let Some((name, value)) = forwarded {
+        request = request.header(name, value);
+    }
+    send(app, request.body(Body::empty()).unwrap()).await.status
+}
+
+#[tokio::test]
+async fn admin_routes_refuse_peers_outside_the_allowlist() {
+    for peer in ["192.0.2.7:4000", "[2001:db8::7]:4000"] {
+        let peer = peer.parse().unwrap();
+        let app = router_for(allowlisted(), Arc::new(mock()), peer).await;
+        assert_eq!(admin_status(&app, None).await, StatusCode::FORBIDDEN, "{}", peer);
+        // Only admin routes are restricted.
+        assert_eq!(get(&app, "/").await.status, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn admin_routes_allow_peers_inside_the_allowlist() {
+    for peer in ["10.1.2.3:4000", "[fd00::7]:4000"] {
+        let peer = peer.parse().unwrap();
+        let app = router_for(allowlisted(), Arc::new(mock()), peer).await;
+        assert_eq!(admin_status(&app, None).await, StatusCode::OK, "{}", peer);
+    }
+}
+
+#[tokio::test]
+async fn admin_allowlist_ignores_addresses_forwarded_by_untrusted_peers() {
+    let peer = "192.0.2.7:4000".parse().unwrap();
+    let spoofed = [
+        (ForwardedHeader::XForwardedFor, ("x-forwarded-for", "10.1.2.3")),
+        (ForwardedHeader::Forwarded, ("forwarded", "for=10.1.2.3")),
+    ];
+    for (forwarded_header, header) in spoofed {
+        let config = Config {
+            forwarded_header,
+            ..allowlisted()
+        };
+        let app = router_for(config, Arc::new(mock()), peer).await;
+        assert_eq!(admin_status(&app, Some(header)).await, StatusCode::FORBIDDEN, "{:?}", header);
+
+        // The same header from a trusted proxy is believed.
+        let config = Config {
+            forwarded_header,
+            trusted_proxy_nets: vec!["192.0.2.0/24".parse().unwrap()],
+            ..allowlisted()
+        };
+        let app = router_for(config, Arc::new(mock()), peer).await;
+        assert_eq!(admin_status(&app, Some(header)).await, StatusCode::OK, "{:?}", header);
+    }
+}
+
+#[tokio::test]
+async fn open_circuit_refuses_without_fetching_then_probes_once() {
+    let fetcher = Arc::new(Failing::new(FetchError::Connect("connection refused".into())));
+    let config = Config {
+        circuit_failure_threshold: 2,
+        circuit_cooldown: Duration::from_millis(100),
+        negative_cache_ttl: Duration::ZERO,
+        upstream_attempts: 1,
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher.clone()).await;
+
+    for _ in 0..2 {
+        assert_eq!(get(&app, "/").await.status, StatusCode::GATEWAY_TIMEOUT);
+    }
+    assert_eq!(fetcher.calls(), 2);
+
+    let refused = get(&app, "/").await;
+    assert_eq!(refused.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(refused.body["error"]["code"], "upstream_circuit_open");
+    assert_eq!(fetcher.calls(), 2);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(get(&app, "/").await.status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(fetcher.calls(), 3);
+    // The probe failed, so the circuit is open again.
+    assert_eq!(get(&app, "/").await.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(fetcher.calls(), 3);
+}
+
+#[tokio::test]
+async fn clients_past_their_burst_are_told_when_to_retry() {
+    let config = Config {
+        rate_limit_per_minute: 60,
+        rate_limit_burst: 2,
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, Arc::new(mock())).await;
+
+    for _ in 0..2 {
+        assert_eq!(get(&app, "/").await.status, StatusCode::OK);
+    }
+    let limited = get(&app, "/").await;
+    assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(limited.body["error"]["code"], "rate_limited");
+    assert_eq!(limited.headers["retry-after"], "1");
+}
+
+#[tokio::test]
+async fn etag_revalidation_is_304_until_the_data_changes() {
+    let fixtures = ["fixtures/sample.json", "fixtures/sample-2.json"]
+        .map(|file| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(file));
+    let fetcher = Arc::new(MockFetcher::new(&fixtures).unwrap());
+    let config = Config {
+        admin_token: Some("admin-token".to_string()),
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher.clone()).await;
+
+    let first = get(&app, "/").await;
+    assert_eq!(first.status, StatusCode::OK);
+    let etag = first.headers["etag"].clone();
+    let revalidate = |etag| {
+        let request = Request::get("/").header("if-none-match", etag);
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let unchanged = revalidate(etag.clone()).await.unwrap();
+    assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(unchanged.headers()["etag"], etag);
+    assert!(to_bytes(unchanged.into_body(), usize::MAX).await.unwrap().is_empty());
+
+    // The next fetch comes from the second fixture, with other numbers.
+    let purge = Request::post("/cache/purge").header("authorization", "Bearer admin-token");
+    let purged = send(&app, purge.body(Body::empty()).unwrap()).await;
+    assert_eq!(purged.status, StatusCode::OK);
+    let changed = revalidate(etag.clone()).await.unwrap();
+    assert_eq!(changed.status(), StatusCode::OK);
+    assert_ne!(changed.headers()["etag"], etag);
+    assert!(!to_bytes(changed.into_body(), usize::MAX).await.unwrap().is_empty());
+    assert_eq!(fetcher.fetches(), 2);
+}
+
+#[tokio::test]
+async fn upstream_rate_limit_holds_off_every_fetch_for_its_retry_after() {
+    let fetcher = Arc::new(Failing::new(FetchError::Status {
+        status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+        snippet: String::new(),
+        retry_after: Some(Duration::from_secs(30)),
+    }));
+    let config = Config {
+        negative_cache_ttl: Duration::ZERO,
+        upstream_attempts: 1,
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher.clone()).await;
+
+    let limited = get(&app, "/").await;
+    assert_eq!(limited.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(limited.body["error"]["upstream_status"], 429);
+    assert_eq!(limited.headers["retry-after"], "30");
+    assert_eq!(fetcher.calls(), 1);
+
+    // Held off, whatever the query, until the upstream's limit resets.
+    for uri in ["/", "/?period=7d"] {
+        let held = get(&app, uri).await;
+        assert_eq!(held.status, StatusCode::SERVICE_UNAVAILABLE, "{}", uri);
+        assert_eq!(held.body["error"]["code"], "upstream_rate_limited");
+        assert_eq!(held.headers["retry-after"], "30");
+    }
+    assert_eq!(fetcher.calls(), 1);
+}
+
+/// The `name`s of `rows`, in order.
+fn names(rows: &serde_json::Value) -> Vec<&str> {
+    let rows = rows.as_array().expect("rows are a list");
+    rows.iter().map(|row| row["name"].as_str().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn sort_orders_rows_but_keeps_visitor_ranks() {
+    let app = router(config(Duration::from_secs(60)), Arc::new(mock())).await;
+
+    let by_name = get(&app, "/?sort=name&limit=3").await;
+    assert_eq!(by_name.status, StatusCode::OK);
+    assert_eq!(names(&by_name.body["results"]), ["Beyoncé", "Drake", "Frank Ocean"]);
+
+    let fewest = get(&app, "/top/10?order=asc").await;
+    assert_eq!(fewest.status, StatusCode::OK);
+    assert_eq!(fewest.body[0]["name"], "SZA");
+    assert_eq!(fewest.body[0]["rank"], 10);
+
+    let invalid = get(&app, "/?sort=rank").await;
+    assert_eq!(invalid.status, StatusCode::BAD_REQUEST);
+    assert_eq!(invalid.body["error"]["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn search_keeps_only_matching_names() {
+    let app = router(config(Duration::from_secs(60)), Arc::new(mock())).await;
+
+    let found = get(&app, "/?q=K").await;
+    assert_eq!(found.status, StatusCode::OK);
+    assert_eq!(
+        names(&found.body["results"]),
+        ["Kanye West", "Frank Ocean", "Kendrick Lamar", "Drake"]
+    );
+
+    let folded = get(&app, "/?q=beyonce&fold_diacritics=true").await;
+    assert_eq!(names(&folded.body["results"]), ["Beyoncé"]);
+    let none = get(&app, "/?q=beyonce").await;
+    assert_eq!(none.status, StatusCode::OK);
+    assert!(names(&none.body["results"]).is_empty());
+
+    let too_long = get(&app, &format!("/?q={}", "a".repeat(101))).await;
+    assert_eq!(too_long.status, StatusCode::BAD_REQUEST);
+    assert_eq!(too_long.body["error"]["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn paging_serves_one_page_with_totals() {
+    let app = router(config(Duration::from_secs(60)), Arc::new(mock())).await;
+
+    let last = get(&app, "/?page=3&per_page=4").await;
+    assert_eq!(last.status, StatusCode::OK);
+    assert_eq!(names(&last.body["results"]), ["Drake", "SZA"]);
+    assert_eq!(last.headers["x-total-count"], "10");
+    assert_eq!(last.headers["x-total-pages"], "3");
+
+    // Past the last page, `limit` included, is empty rather than an error.
+    let past = get(&app, "/?page=2&per_page=4&limit=4").await;
+    assert_eq!(past.status, StatusCode::OK);
+    assert!(names(&past.body["results"]).is_empty());
+    assert_eq!(past.headers["x-total-count"], "4");
+
+    for uri in ["/?page=0", "/?per_page=1001", "/?limit=1001", "/top/101"] {
+        let invalid = get(&app, uri).await;
+        assert_eq!(invalid.status, StatusCode::BAD_REQUEST, "{}", uri);
+        assert_eq!(invalid.body["error"]["code"], "invalid_parameter", "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn slow_answers_time_out_but_the_fetch_carries_on() {
+    let counted = Arc::new(mock());
+    let fetcher = Arc::new(Slow {
+        inner: counted.clone(),
+        delay: Duration::from_millis(300),
+    });
+    let config = Config {
+        request_timeout: Duration::from_millis(100),
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher).await;
+
+    let timed_out = get(&app, "/").await;
+    assert_eq!(timed_out.status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(timed_out.body["error"]["code"], "request_timeout");
+
+    eventually(|| counted.fetches() == 1).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(get(&app, "/").await.cache, "HIT");
+}
+
+#[tokio::test]
+async fn get_with_a_body_is_refused() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    for request in [Request::get("/"), Request::head("/top/3")] {
+        let answer = send(&app, request.body(Body::from("{}")).unwrap()).await;
+        assert_eq!(answer.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let answer = send(&app, Request::get("/").body(Body::from("{}")).unwrap()).await;
+    assert_eq!(answer.body["error"]["code"], "body_not_allowed");
+    assert_eq!(fetcher.fetches(), 0);
+}
+
+fn gauged(delay: Duration) -> Arc<Gauged> {
+    Arc::new(Gauged {
+        inner: Slow {
+            inner: Arc::new(mock()),
+            delay,
+        },
+        running: AtomicUsize::new(0),
+        most: AtomicUsize::new(0),
+    })
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn upstream_fetches_are_capped_at_max_concurrency() {
+    let fetcher = gauged(Duration::from_millis(100));
+    let config = Config {
+        upstream_max_concurrency: 2,
+        ..config(Duration::from_secs(60))
+    };
+    let app = router(config, fetcher.clone()).await;
+
+    let periods = ["day", "7d", "30d", "month", "6mo", "12mo"];
+    let uris: Vec<String> = periods.iter().map(|period| format!("/?period={}", period)).collect();
+    let answers = futures_util::future::join_all(uris.iter().map(|uri| get(&app, uri))).await;
+    for answer in &answers {
+        assert_eq!(answer.status, StatusCode::OK);
+        assert_eq!(answer.cache, "MISS");
+    }
+    assert_eq!(fetcher.most.load(Ordering::SeqCst), 2);
+    assert_eq!(fetcher.inner.inner.fetches(), 6);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn fetch_queued_past_the_timeout_is_busy() {
+    let fallback = r#"{"results": [{"name": "Fallback", "visitors": 1, "events": 1}]}"#;
+    for fallback in [None, Some(fallback.to_string())] {
+        let fetcher = gauged(Duration::from_millis(300));
+        let config = Config {
+            upstream_max_concurrency: 1,
+            upstream_queue_timeout: Duration::from_millis(50),
+            fallback: fallback.clone(),
+            ..config(Duration::from_secs(60))
+        };
+        let app = router(config, fetcher.clone()).await;
+
+        let first = tokio::spawn({
+            let app = app.clone();
+            async move { get(&app, "/").await.status }
+        });
+        eventually(|| fetcher.running.load(Ordering::SeqCst) == 1).await;
+
+        let queued = get(&app, "/?period=7d").await;
+        match &fallback {
+            None => {
+                assert_eq!(queued.status, StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(queued.body["error"]["code"], "upstream_busy");
+            }
+            Some(_) => {
+                assert_eq!(queued.status, StatusCode::OK);
+                assert_eq!(queued.headers["x-fallback"], "true");
+                assert_eq!(queued.body["results"][0]["name"], "Fallback");
+            }
+        }
+        assert_eq!(first.await.unwrap(), StatusCode::OK);
+        assert_eq!(fetcher.most.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[tokio::test]
+async fn bundle_combines_each_section_as_its_own_route_answers() {
+    let fetcher = Arc::new(mock());
+    let app = router(config(Duration::from_secs(60)), fetcher.clone()).await;
+
+    let uri = "/bundle?include=leaderboard,summary,realtime,country,summary&limit=3";
+    let bundle = get(&app, uri).await;
+    assert_eq!(bundle.status, StatusCode::OK);
+    assert_eq!(bundle.cache, "MISS");
+    assert_eq!(bundle.headers["content-type"], "application/json");
+    let cache_control = bundle.headers["cache-control"].to_str().unwrap();
+    assert!(cache_control.starts_with("public, max-age="), "{}", cache_control);
+    // The leaderboard and summary share an entry.
+    assert_eq!(fetcher.fetches(), 3);
+
+    let body = bundle.body.as_object().unwrap();
+    let mut sections: Vec<&str> = body.keys().map(String::as_str).collect();
+    sections.sort_unstable();
+    let expected = ["country", "generated_at", "leaderboard", "period", "realtime", "summary"];
+    assert_eq!(sections, expected);
+    assert_eq!(bundle.body["period"], "all");
+    assert_eq!(names(&bundle.body["leaderboard"]["results"]).len(), 3);
+    for (section, route) in [
+        ("leaderboard", "/?limit=3"),
+        ("summary", "/summary"),
+        ("realtime", "/realtime"),
+        ("country", "/breakdown/country?limit=3"),
+    ] {
+        let answer = get(&app, route).await;
+        assert_eq!(answer.cache, "HIT", "{}", route);
+        assert_eq!(bundle.body[section], answer.body, "{}", section);
+    }
+    assert_eq!(fetcher.fetches(), 3);
+
+    let again = get(&app, uri).await;
+    assert_eq!(again.cache, "HIT");
+    assert_eq!(again.body, bundle.body);
+    let etag = bundle.headers["etag"].clone();
+    assert_eq!(again.headers["etag"], etag);
+    let request = Request::get(uri).header("if-none-match", etag).body(Body::empty()).unwrap();
+    assert_eq!(send(&app, request).await.status, StatusCode::NOT_MODIFIED);
+}